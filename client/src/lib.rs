@@ -0,0 +1,138 @@
+//! Client for the jobclerk API.
+
+mod runner;
+
+pub use runner::{JobContext, Runner};
+
+use fehler::{throw, throws};
+use jobclerk_types::{
+    GetJobRequest, Job, JobId, Request, Response, API_VERSION,
+};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::de::DeserializeOwned;
+
+/// Header with the ID of a request, which the server includes in its
+/// logs for the request.
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The request couldn't be sent, or the server responded with an
+    /// HTTP error.
+    #[error("HTTP error: {message} (request ID {request_id})")]
+    Http { message: String, request_id: String },
+    #[error("invalid response: {0}")]
+    InvalidResponse(#[from] std::io::Error),
+    /// A job's data couldn't be converted to the type the caller
+    /// asked for.
+    #[error("job {job_id} has invalid data: {source}")]
+    InvalidJobData {
+        job_id: JobId,
+        source: serde_json::Error,
+    },
+    /// The server responded with an error, such as `NotFound`. The
+    /// request ID can be used to find the request in the server's
+    /// logs.
+    #[error("error response: {response:?} (request ID {request_id})")]
+    Response {
+        response: Box<Response>,
+        request_id: String,
+    },
+    /// The server responded with a different type of response than
+    /// the request should get.
+    #[error("unexpected response: {0:?}")]
+    UnexpectedResponse(Box<Response>),
+}
+
+/// Connection settings for a jobclerk server.
+#[derive(Clone, Debug)]
+pub struct Client {
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl Client {
+    /// Create a client for the server at `base_url`, including the
+    /// scheme (e.g. `http://localhost:8000`).
+    pub fn new(base_url: &str) -> Client {
+        Client {
+            base_url: base_url.trim_end_matches('/').into(),
+            api_key: None,
+        }
+    }
+
+    /// Send this API key with each request.
+    pub fn with_api_key(mut self, api_key: &str) -> Client {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// Send a request. Error responses are returned as
+    /// `Error::Response`.
+    #[throws]
+    pub fn send<R: Into<Request>>(&self, req: R) -> Response {
+        let url = format!("{}/api/v{}", self.base_url, API_VERSION);
+        let request_id: String =
+            thread_rng().sample_iter(&Alphanumeric).take(16).collect();
+        let mut http_req = ureq::post(&url);
+        http_req.set(REQUEST_ID_HEADER, &request_id);
+        if let Some(api_key) = &self.api_key {
+            http_req.set("Authorization", &format!("Bearer {}", api_key));
+        }
+        let req: Request = req.into();
+        let http_resp = http_req.send_json(
+            serde_json::to_value(req)
+                .expect("failed to convert request to JSON"),
+        );
+        if let Some(err) = http_resp.synthetic_error() {
+            throw!(Error::Http {
+                message: err.to_string(),
+                request_id,
+            });
+        }
+        if !http_resp.ok() {
+            throw!(Error::Http {
+                message: format!(
+                    "{} {}",
+                    http_resp.status(),
+                    http_resp.status_text()
+                ),
+                request_id,
+            });
+        }
+        let resp: Response = http_resp.into_json_deserialize()?;
+        if resp.is_error() {
+            throw!(Error::Response {
+                response: Box::new(resp),
+                request_id,
+            });
+        }
+        resp
+    }
+
+    /// Get a job with its data converted to `T`.
+    #[throws]
+    pub fn get_job<T: DeserializeOwned>(
+        &self,
+        project_name: &str,
+        job_id: JobId,
+    ) -> Job<T> {
+        let resp = self.send(GetJobRequest {
+            project_name: project_name.into(),
+            job_id,
+        })?;
+        match resp {
+            Response::GetJob(resp) => with_job_data(resp.job)?,
+            resp => throw!(Error::UnexpectedResponse(Box::new(resp))),
+        }
+    }
+}
+
+/// Convert a job's data to `T`.
+#[throws]
+pub(crate) fn with_job_data<T: DeserializeOwned>(job: Job) -> Job<T> {
+    let job_id = job.id;
+    job.with_data()
+        .map_err(|source| Error::InvalidJobData { job_id, source })?
+}
@@ -0,0 +1,296 @@
+use crate::{with_job_data, Client, Error};
+use chrono::Utc;
+use fehler::{throw, throws};
+use jobclerk_types::*;
+use serde::de::DeserializeOwned;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait for a job before checking again.
+const TAKE_WAIT: Duration = Duration::from_secs(30);
+
+/// How long to wait before retrying a heartbeat that failed, or that
+/// didn't say when the next one is due.
+const HEARTBEAT_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Takes jobs from a project and runs them, handling the job's
+/// heartbeats and final state.
+///
+/// While the job runs, a background thread sends heartbeats often
+/// enough to stay within the project's heartbeat expiration (a third
+/// of the time until the deadline). If the job is asked to cancel,
+/// `JobContext::is_cancel_requested` starts returning true, and the
+/// job is marked as canceled when it returns. Otherwise the job is
+/// marked as succeeded or failed depending on what it returns.
+///
+/// The job's data is converted to the `JobContext`'s type before the
+/// job runs, and jobs whose data doesn't match are failed.
+pub struct Runner {
+    client: Client,
+    project_name: String,
+    name: String,
+    capabilities: Vec<String>,
+}
+
+/// A job being run by a `Runner`, with its data as a `T`.
+pub struct JobContext<T = serde_json::Value> {
+    client: Client,
+    job: Job<T>,
+    token: JobToken,
+    cancel_requested: Arc<AtomicBool>,
+}
+
+impl<T> JobContext<T> {
+    pub fn job(&self) -> &Job<T> {
+        &self.job
+    }
+
+    /// Check if the job has been asked to cancel. The job should stop
+    /// soon after this returns true.
+    pub fn is_cancel_requested(&self) -> bool {
+        self.cancel_requested.load(Ordering::Relaxed)
+    }
+
+    /// Add output to the job's log.
+    #[throws]
+    pub fn append_log(&self, text: &str) {
+        self.client.send(AppendJobLogRequest {
+            project_name: self.job.project_name.clone(),
+            job_id: self.job.id,
+            token: self.token.clone(),
+            text: text.into(),
+        })?;
+    }
+
+    /// Add a job with this data to the project as a child of this job,
+    /// and return the child's ID.
+    #[throws]
+    pub fn add_child_job(&self, data: serde_json::Value) -> JobId {
+        let resp = self.client.send(AddChildJobRequest {
+            project_name: self.job.project_name.clone(),
+            job_id: self.job.id,
+            token: self.token.clone(),
+            child: AddJobRequest {
+                project_name: self.job.project_name.clone(),
+                data,
+                ..Default::default()
+            },
+        })?;
+        match resp {
+            Response::AddJob(resp) => resp.job_id,
+            resp => throw!(Error::UnexpectedResponse(Box::new(resp))),
+        }
+    }
+}
+
+/// Send heartbeats until `stop` receives a message or is dropped.
+fn send_heartbeats(
+    client: Client,
+    job: (String, JobId, JobToken),
+    cancel_requested: Arc<AtomicBool>,
+    stop: mpsc::Receiver<()>,
+) {
+    let (project_name, job_id, token) = job;
+    loop {
+        let resp = client.send(UpdateJobRequest {
+            project_name: project_name.clone(),
+            job_id,
+            token: token.clone(),
+            state: None,
+            data: None,
+            steps: Vec::new(),
+        });
+        let mut interval = HEARTBEAT_RETRY_INTERVAL;
+        if let Ok(Response::UpdateJob(resp)) = resp {
+            if resp.cancel_requested {
+                cancel_requested.store(true, Ordering::Relaxed);
+            }
+            if let Some(deadline) = resp.heartbeat_deadline {
+                if let Ok(remaining) = (deadline - Utc::now()).to_std() {
+                    interval = remaining / 3;
+                }
+            }
+        }
+        match stop.recv_timeout(interval) {
+            Err(RecvTimeoutError::Timeout) => {}
+            _ => break,
+        }
+    }
+}
+
+impl Runner {
+    pub fn new(client: Client, project_name: &str, name: &str) -> Runner {
+        Runner {
+            client,
+            project_name: project_name.into(),
+            name: name.into(),
+            capabilities: Vec::new(),
+        }
+    }
+
+    /// Only take jobs whose requirements are in `capabilities`.
+    pub fn with_capabilities(mut self, capabilities: Vec<String>) -> Runner {
+        self.capabilities = capabilities;
+        self
+    }
+
+    #[throws]
+    fn take_job(&self) -> Option<TakeJobResponseJob> {
+        let resp = self.client.send(TakeJobRequest {
+            project_name: self.project_name.clone(),
+            runner: self.name.clone(),
+            capabilities: self.capabilities.clone(),
+            lease_millis: None,
+            // Heartbeats start as soon as the job is taken
+            first_heartbeat_millis: None,
+            min_priority: None,
+            max_priority: None,
+            wait_millis: Some(TAKE_WAIT.as_millis() as i32),
+        })?;
+        match resp {
+            Response::TakeJob(resp) => resp.job,
+            resp => throw!(Error::UnexpectedResponse(Box::new(resp))),
+        }
+    }
+
+    #[throws]
+    fn get_job(&self, taken: &TakeJobResponseJob) -> Job {
+        let resp = self.client.send(GetJobRequest {
+            project_name: taken.project_name.clone(),
+            job_id: taken.job_id,
+        })?;
+        match resp {
+            Response::GetJob(resp) => resp.job,
+            resp => throw!(Error::UnexpectedResponse(Box::new(resp))),
+        }
+    }
+
+    /// Mark a job as failed without running it, adding `message` to
+    /// its log.
+    #[throws]
+    fn fail_job(&self, taken: &TakeJobResponseJob, message: &str) {
+        self.client.send(AppendJobLogRequest {
+            project_name: taken.project_name.clone(),
+            job_id: taken.job_id,
+            token: taken.job_token.clone(),
+            text: format!("error: {}\n", message),
+        })?;
+        self.client.send(UpdateJobRequest {
+            project_name: taken.project_name.clone(),
+            job_id: taken.job_id,
+            token: taken.job_token.clone(),
+            state: Some(JobState::Failed),
+            data: None,
+            steps: Vec::new(),
+        })?;
+    }
+
+    #[throws]
+    fn run_job<T, F, E>(&self, taken: TakeJobResponseJob, run_job: F)
+    where
+        T: DeserializeOwned,
+        F: FnOnce(&JobContext<T>) -> Result<(), E>,
+        E: Display,
+    {
+        match with_job_data(self.get_job(&taken)?) {
+            Ok(job) => self.run_typed_job(taken, job, run_job)?,
+            Err(err) => self.fail_job(&taken, &err.to_string())?,
+        }
+    }
+
+    #[throws]
+    fn run_typed_job<T, F, E>(
+        &self,
+        taken: TakeJobResponseJob,
+        job: Job<T>,
+        run_job: F,
+    ) where
+        F: FnOnce(&JobContext<T>) -> Result<(), E>,
+        E: Display,
+    {
+        let context = JobContext {
+            client: self.client.clone(),
+            job,
+            token: taken.job_token,
+            cancel_requested: Arc::new(AtomicBool::new(false)),
+        };
+
+        let (stop_heartbeats, stop) = mpsc::channel();
+        let heartbeats = {
+            let client = self.client.clone();
+            let job = (
+                context.job.project_name.clone(),
+                context.job.id,
+                context.token.clone(),
+            );
+            let cancel_requested = context.cancel_requested.clone();
+            thread::spawn(move || {
+                send_heartbeats(client, job, cancel_requested, stop)
+            })
+        };
+
+        let result = run_job(&context);
+
+        // Stop the heartbeats before the final update, so that a late
+        // heartbeat doesn't fail after the job has finished
+        let _ = stop_heartbeats.send(());
+        let _ = heartbeats.join();
+
+        let state = if context.is_cancel_requested() {
+            JobState::Canceled
+        } else if let Err(err) = &result {
+            context.append_log(&format!("error: {}\n", err))?;
+            JobState::Failed
+        } else {
+            JobState::Succeeded
+        };
+        self.client.send(UpdateJobRequest {
+            project_name: context.job.project_name.clone(),
+            job_id: context.job.id,
+            token: context.token.clone(),
+            state: Some(state),
+            data: None,
+            steps: Vec::new(),
+        })?;
+    }
+
+    /// Wait for a job and run it with `run_job`. Returns false if no
+    /// job became available while waiting.
+    ///
+    /// An error returned by `run_job` fails the job and is added to
+    /// its log. Errors from the server are returned, in which case the
+    /// job may be left running until its heartbeat expires.
+    #[throws]
+    pub fn run_one<T, F, E>(&self, run_job: F) -> bool
+    where
+        T: DeserializeOwned,
+        F: FnOnce(&JobContext<T>) -> Result<(), E>,
+        E: Display,
+    {
+        match self.take_job()? {
+            Some(taken) => {
+                self.run_job(taken, run_job)?;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Run jobs one after another until an error occurs.
+    pub fn run<T, F, E>(&self, mut run_job: F) -> Error
+    where
+        T: DeserializeOwned,
+        F: FnMut(&JobContext<T>) -> Result<(), E>,
+        E: Display,
+    {
+        loop {
+            if let Err(err) = self.run_one(&mut run_job) {
+                return err;
+            }
+        }
+    }
+}
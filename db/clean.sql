@@ -1,2 +1,30 @@
+DROP TABLE ui_sessions;
+DROP TABLE ui_users;
+DROP TABLE api_keys;
+DROP TABLE audit_log;
+DROP TABLE archived_jobs;
+DROP TABLE job_attempts;
+DROP TABLE job_templates;
+DROP TABLE workflow_run_jobs;
+DROP TABLE workflow_runs;
+DROP TABLE workflows;
+DROP TABLE job_dependencies;
+DROP TABLE batch_jobs;
+DROP TABLE batches;
+DROP TABLE job_artifacts;
+DROP TABLE job_steps;
+DROP TABLE job_logs;
+DROP TABLE job_events;
 DROP TABLE jobs;
 DROP TABLE projects;
+DROP TABLE organizations;
+DROP TABLE runners;
+DROP TABLE schema_migrations;
+DROP FUNCTION cancel_dependent_jobs;
+DROP FUNCTION cancel_blocked_job;
+DROP FUNCTION record_job_attempt;
+DROP FUNCTION job_dependencies_met;
+DROP FUNCTION record_job_event;
+DROP FUNCTION notify_job_change;
+DROP FUNCTION notify_job_log;
+DROP FUNCTION notify_job_event;
@@ -0,0 +1,7 @@
+-- Key shared by jobs that shouldn't run at the same time. Only one
+-- job per key can be in a non-terminal state in each project.
+ALTER TABLE jobs ADD COLUMN unique_key TEXT;
+
+CREATE UNIQUE INDEX jobs_active_unique_key ON jobs (project, unique_key)
+  WHERE state NOT IN ('canceled', 'succeeded', 'failed', 'expired',
+                      'timed_out');
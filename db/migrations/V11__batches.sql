@@ -0,0 +1,25 @@
+-- Groups of jobs added together, whose progress is tracked as a whole
+CREATE TABLE IF NOT EXISTS batches (
+  id BIGSERIAL PRIMARY KEY,
+  project BIGINT REFERENCES projects NOT NULL,
+  created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  -- URL to POST the batch's status to once all of its jobs finish
+  webhook_url TEXT,
+  -- Number of times sending the webhook failed
+  webhook_attempts INT NOT NULL DEFAULT 0,
+  -- When the webhook was sent
+  webhook_sent TIMESTAMPTZ
+);
+
+-- Jobs in each batch. A job that already existed when the batch was
+-- added (because of its idempotency key, unique key or data) can be
+-- in more than one batch.
+CREATE TABLE IF NOT EXISTS batch_jobs (
+  batch BIGINT REFERENCES batches ON DELETE CASCADE NOT NULL,
+  job BIGINT REFERENCES jobs ON DELETE CASCADE NOT NULL,
+
+  PRIMARY KEY (batch, job)
+);
+
+CREATE INDEX IF NOT EXISTS batch_jobs_job ON batch_jobs (job);
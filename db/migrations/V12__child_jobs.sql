@@ -0,0 +1,4 @@
+-- Job that added this job while it was running, if any
+ALTER TABLE jobs ADD COLUMN parent BIGINT REFERENCES jobs ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS jobs_parent ON jobs (parent);
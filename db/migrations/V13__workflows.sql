@@ -0,0 +1,51 @@
+-- Jobs that have to succeed before a job can be taken
+CREATE TABLE IF NOT EXISTS job_dependencies (
+  job BIGINT REFERENCES jobs ON DELETE CASCADE NOT NULL,
+  depends_on BIGINT REFERENCES jobs ON DELETE CASCADE NOT NULL,
+
+  PRIMARY KEY (job, depends_on)
+);
+
+CREATE INDEX IF NOT EXISTS job_dependencies_depends_on
+  ON job_dependencies (depends_on);
+
+-- Check if all of a job's dependencies have succeeded
+CREATE OR REPLACE FUNCTION job_dependencies_met(job_id BIGINT)
+RETURNS BOOLEAN AS $$
+  SELECT NOT EXISTS (
+    SELECT 1 FROM job_dependencies
+    JOIN jobs ON jobs.id = job_dependencies.depends_on
+    WHERE job_dependencies.job = job_id
+      AND jobs.state != 'succeeded')
+$$ LANGUAGE SQL STABLE;
+
+-- Named templates of jobs and their dependencies
+CREATE TABLE IF NOT EXISTS workflows (
+  id BIGSERIAL PRIMARY KEY,
+  project BIGINT REFERENCES projects NOT NULL,
+  name TEXT NOT NULL,
+
+  -- The workflow's nodes as a JSON array, each of which becomes a job
+  -- when the workflow is started
+  nodes JSONB NOT NULL,
+
+  updated TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  UNIQUE (project, name)
+);
+
+-- Each time a workflow is started
+CREATE TABLE IF NOT EXISTS workflow_runs (
+  id BIGSERIAL PRIMARY KEY,
+  workflow BIGINT REFERENCES workflows ON DELETE CASCADE NOT NULL,
+  created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- The job added for each of a workflow run's nodes
+CREATE TABLE IF NOT EXISTS workflow_run_jobs (
+  run BIGINT REFERENCES workflow_runs ON DELETE CASCADE NOT NULL,
+  node TEXT NOT NULL,
+  job BIGINT REFERENCES jobs ON DELETE CASCADE NOT NULL,
+
+  PRIMARY KEY (run, node)
+);
@@ -0,0 +1,14 @@
+-- Named defaults for jobs added from a template
+CREATE TABLE IF NOT EXISTS job_templates (
+  id BIGSERIAL PRIMARY KEY,
+  project BIGINT REFERENCES projects NOT NULL,
+  name TEXT NOT NULL,
+
+  -- The template's fields as JSON, which requests to add a job from
+  -- the template are merged into
+  template JSONB NOT NULL,
+
+  updated TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  UNIQUE (project, name)
+);
@@ -0,0 +1,38 @@
+-- Each time a job was taken, and how that attempt ended
+CREATE TABLE IF NOT EXISTS job_attempts (
+  job BIGINT REFERENCES jobs ON DELETE CASCADE NOT NULL,
+  -- The job's attempts count when it was taken, starting from 1
+  attempt INT NOT NULL,
+  runner TEXT,
+  started TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  finished TIMESTAMPTZ,
+  -- How the attempt ended, or NULL if the job still holds the
+  -- attempt. This is the state the job moved to, except that the
+  -- server records failed or timed_out for attempts that were retried
+  outcome TEXT,
+
+  PRIMARY KEY (job, attempt)
+);
+
+-- Record an attempt when a job is taken, and finish it when the job
+-- leaves the states a runner holds it in
+CREATE OR REPLACE FUNCTION record_job_attempt() RETURNS TRIGGER AS $$
+BEGIN
+  IF NEW.state = 'activating' AND OLD.state != 'activating' THEN
+    INSERT INTO job_attempts (job, attempt, runner)
+    VALUES (NEW.id, NEW.attempts, NEW.runner)
+    ON CONFLICT (job, attempt) DO NOTHING;
+  ELSIF OLD.state IN ('activating', 'running', 'canceling')
+        AND NEW.state NOT IN ('activating', 'running', 'canceling') THEN
+    UPDATE job_attempts
+    SET finished = CURRENT_TIMESTAMP, outcome = NEW.state
+    WHERE job = NEW.id AND attempt = NEW.attempts AND finished IS NULL;
+  END IF;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS record_job_attempt ON jobs;
+CREATE TRIGGER record_job_attempt
+AFTER UPDATE OF state ON jobs
+FOR EACH ROW EXECUTE FUNCTION record_job_attempt();
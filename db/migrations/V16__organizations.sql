@@ -0,0 +1,18 @@
+-- Organizations group projects, so that one server can be shared by
+-- several teams
+CREATE TABLE IF NOT EXISTS organizations (
+  id BIGSERIAL PRIMARY KEY,
+  name TEXT NOT NULL UNIQUE,
+  created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- NULL means the project isn't in an organization
+ALTER TABLE projects ADD COLUMN organization BIGINT REFERENCES organizations;
+
+-- Organization the key can be used with. Like project_name, NULL means
+-- any organization. A key can be limited to a project or to an
+-- organization, but not both.
+ALTER TABLE api_keys
+  ADD COLUMN organization_name TEXT
+    REFERENCES organizations (name) ON DELETE CASCADE,
+  ADD CHECK (project_name IS NULL OR organization_name IS NULL);
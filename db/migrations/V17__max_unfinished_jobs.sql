@@ -0,0 +1,7 @@
+-- Maximum number of jobs the project can have that haven't finished.
+-- Adding a job beyond this fails. NULL means there is no limit.
+ALTER TABLE projects ADD COLUMN max_unfinished_jobs INT;
+
+-- For counting a project's unfinished jobs against the limit
+CREATE INDEX IF NOT EXISTS jobs_unfinished ON jobs (project)
+  WHERE state IN ('available', 'activating', 'running', 'canceling');
@@ -0,0 +1,17 @@
+-- If true, jobs removed by the project's retention policy are moved
+-- to archived_jobs instead of being deleted
+ALTER TABLE projects
+  ADD COLUMN retention_archive BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE IF NOT EXISTS archived_jobs (
+  -- ID the job had in the jobs table
+  id BIGINT PRIMARY KEY,
+  project BIGINT REFERENCES projects NOT NULL,
+  finished TIMESTAMPTZ,
+  archived TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  -- The job's row as JSON, other than its token
+  job JSONB NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS archived_jobs_project ON archived_jobs (project, id);
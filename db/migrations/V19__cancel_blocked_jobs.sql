@@ -0,0 +1,45 @@
+-- Cancel available jobs that depend on a job that can no longer
+-- succeed, which would otherwise never be taken. Canceling a job this
+-- way cancels the jobs that depend on it in turn.
+CREATE OR REPLACE FUNCTION cancel_dependent_jobs() RETURNS TRIGGER AS $$
+BEGIN
+  IF NEW.state IN ('canceled', 'failed', 'expired', 'timed_out')
+     AND OLD.state IS DISTINCT FROM NEW.state THEN
+    UPDATE jobs
+    SET state = 'canceled',
+        finished = CURRENT_TIMESTAMP
+    WHERE state = 'available'
+      AND id IN (SELECT job FROM job_dependencies
+                 WHERE depends_on = NEW.id);
+  END IF;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS cancel_dependent_jobs ON jobs;
+CREATE TRIGGER cancel_dependent_jobs
+AFTER UPDATE OF state ON jobs
+FOR EACH ROW EXECUTE FUNCTION cancel_dependent_jobs();
+
+-- A job added with a dependency that has already failed is canceled
+-- right away
+CREATE OR REPLACE FUNCTION cancel_blocked_job() RETURNS TRIGGER AS $$
+BEGIN
+  UPDATE jobs
+  SET state = 'canceled',
+      finished = CURRENT_TIMESTAMP
+  WHERE id = NEW.job
+    AND state = 'available'
+    AND EXISTS (
+      SELECT 1 FROM jobs AS dependency
+      WHERE dependency.id = NEW.depends_on
+        AND dependency.state IN ('canceled', 'failed', 'expired',
+                                 'timed_out'));
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS cancel_blocked_job ON job_dependencies;
+CREATE TRIGGER cancel_blocked_job
+AFTER INSERT ON job_dependencies
+FOR EACH ROW EXECUTE FUNCTION cancel_blocked_job();
@@ -0,0 +1,267 @@
+CREATE TABLE IF NOT EXISTS projects (
+  id BIGSERIAL PRIMARY KEY,
+  name TEXT NOT NULL UNIQUE,
+
+  -- Number of milliseconds after a heartbeat when the job will be
+  -- considered stuck and marked as timed_out, or requeued if the
+  -- retry policy allows another attempt.
+  heartbeat_expiration_millis INT NOT NULL,
+
+  -- Default number of milliseconds a job may run before it is marked
+  -- as timed_out, even if its runner is still sending heartbeats. NULL
+  -- means there is no limit.
+  max_runtime_millis INT,
+
+  -- Retry policy for failed jobs. If max_attempts is NULL, jobs that
+  -- fail or whose heartbeat expires are not retried, and end up
+  -- failed or timed_out. Otherwise both kinds of failure requeue the
+  -- job after a delay until it has been attempted max_attempts
+  -- times. Valid backoffs: fixed, exponential
+  max_attempts INT,
+  retry_backoff TEXT,
+  retry_delay_millis INT,
+
+  -- Maximum number of jobs a single runner can be running at once.
+  -- NULL means there is no limit.
+  max_jobs_per_runner INT,
+
+  -- Finished jobs are deleted this long after they finish. NULL
+  -- means jobs are kept until they are purged.
+  retention_millis BIGINT,
+
+  -- Relative share of jobs the project gets when runners take jobs
+  -- from several projects with weighted fairness
+  weight INT NOT NULL DEFAULT 1,
+
+  -- Arbitrary JSON configuration
+  data JSONB NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS jobs (
+  id BIGSERIAL PRIMARY KEY,
+  project BIGINT REFERENCES projects NOT NULL,
+  runner TEXT,
+
+  -- Valid states: available, activating, running, canceling,
+  -- canceled, succeeded, failed, expired, timed_out
+  state TEXT NOT NULL DEFAULT 'available',
+
+  -- Time that the job was created
+  created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  -- Time that the job was started
+  started TIMESTAMPTZ,
+
+  -- Time that the job was either canceled, or it succeeded or failed
+  finished TIMESTAMPTZ,
+
+  -- Time that the last heartbeat was received from the job's runner
+  heartbeat TIMESTAMPTZ,
+
+  -- Overrides the project's heartbeat_expiration_millis for the
+  -- runner that currently holds the job
+  lease_millis INT,
+
+  -- The job is considered stuck if it's still running at this time.
+  -- Heartbeats push it forward by the lease duration, and runners can
+  -- extend it explicitly.
+  lease_expires_at TIMESTAMPTZ,
+
+  -- Number of times the job has been taken by a runner
+  attempts INT NOT NULL DEFAULT 0,
+
+  -- The job cannot be taken before this time. Used to delay retries.
+  available_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  -- When a job is taken (moved from available to running) the token
+  -- is set to a random value. The runner that took the job must use
+  -- this token to update the job.
+  --
+  -- This handles the following case:
+  -- 1. Client Alpha starts running the job
+  -- 2. Alpha gets stuck and stops sending a heartbeat
+  -- 3. The job gets moved back to the available state
+  -- 4. Client Beta starts running the job
+  -- 5. Alpha gets unstuck and continues running the job
+  -- 6. Without the token, this would result in conflicting updates
+  --    from Alpha and Beta. With the token, the updates from Alpha
+  --    can be rejected (and assuming Alpha is paying attention to the
+  --    response, it can stop trying to run the job).
+  token TEXT,
+
+  -- An additional layer of priority beyond just getting the
+  -- earliest-created available job.
+  priority INT NOT NULL DEFAULT 0,
+
+  -- Overrides the project's max_runtime_millis for this job
+  max_runtime_millis INT,
+
+  -- Optional key provided by the submitter so that retried requests
+  -- to add the same job return the existing job
+  idempotency_key TEXT,
+
+  -- If the job is still available at this time it is expired
+  -- instead of being run
+  expires_at TIMESTAMPTZ,
+
+  -- Capabilities a runner must have to take the job
+  requirements TEXT[] NOT NULL DEFAULT '{}',
+
+  -- Sequence number of the most recent log chunk
+  log_sequence INT NOT NULL DEFAULT 0,
+
+  -- Arbitrary JSON payload
+  data JSONB NOT NULL,
+
+  UNIQUE (project, idempotency_key)
+);
+
+-- History of job state changes
+CREATE TABLE IF NOT EXISTS job_events (
+  id BIGSERIAL PRIMARY KEY,
+  job BIGINT REFERENCES jobs ON DELETE CASCADE NOT NULL,
+
+  -- NULL when the job was created
+  old_state TEXT,
+  new_state TEXT NOT NULL,
+
+  -- Runner that held the job when the state changed, if any
+  runner TEXT,
+
+  created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Log output sent by the job's runner
+CREATE TABLE IF NOT EXISTS job_logs (
+  job BIGINT REFERENCES jobs ON DELETE CASCADE NOT NULL,
+
+  -- Starts at 1 and increments for each chunk appended to the job
+  sequence INT NOT NULL,
+
+  text TEXT NOT NULL,
+  created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  PRIMARY KEY (job, sequence)
+);
+
+-- Named steps declared when the job was added
+CREATE TABLE IF NOT EXISTS job_steps (
+  job BIGINT REFERENCES jobs ON DELETE CASCADE NOT NULL,
+
+  -- Starts at 1 and gives the order the steps run in
+  position INT NOT NULL,
+
+  name TEXT NOT NULL,
+
+  -- Valid states: pending, running, succeeded, failed, skipped
+  state TEXT NOT NULL DEFAULT 'pending',
+
+  started TIMESTAMPTZ,
+  finished TIMESTAMPTZ,
+
+  PRIMARY KEY (job, position),
+  UNIQUE (job, name)
+);
+
+-- Files produced by a job and stored elsewhere
+CREATE TABLE IF NOT EXISTS job_artifacts (
+  job BIGINT REFERENCES jobs ON DELETE CASCADE NOT NULL,
+  name TEXT NOT NULL,
+
+  -- Where the artifact is stored
+  url TEXT NOT NULL,
+
+  -- Size in bytes
+  size BIGINT,
+
+  content_type TEXT,
+  created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  PRIMARY KEY (job, name)
+);
+
+-- Runners that have sent a runner heartbeat
+CREATE TABLE IF NOT EXISTS runners (
+  name TEXT PRIMARY KEY,
+
+  -- Time of the most recent heartbeat
+  last_seen TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  -- Set when the runner is finishing its current jobs and will not
+  -- take new ones
+  draining BOOLEAN NOT NULL DEFAULT FALSE,
+
+  capabilities TEXT[] NOT NULL DEFAULT '{}'
+);
+
+-- Mutating API requests, for finding out who changed what
+CREATE TABLE IF NOT EXISTS audit_log (
+  id BIGSERIAL PRIMARY KEY,
+  created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  -- Request type, e.g. AddJob
+  request TEXT NOT NULL,
+
+  -- Runner that sent the request, if known
+  actor TEXT,
+
+  -- Address of the client that sent the request, if known
+  remote_addr TEXT,
+
+  -- Not references, so that entries outlive purged jobs
+  project_name TEXT,
+  job BIGINT,
+
+  -- The request as JSON, with secrets removed
+  summary JSONB NOT NULL
+);
+
+CREATE OR REPLACE FUNCTION record_job_event() RETURNS TRIGGER AS $$
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    INSERT INTO job_events (job, new_state, runner)
+    VALUES (NEW.id, NEW.state, NEW.runner);
+  ELSIF OLD.state IS DISTINCT FROM NEW.state THEN
+    INSERT INTO job_events (job, old_state, new_state, runner)
+    VALUES (NEW.id, OLD.state, NEW.state, COALESCE(NEW.runner, OLD.runner));
+  END IF;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS record_job_event ON jobs;
+CREATE TRIGGER record_job_event
+AFTER INSERT OR UPDATE OF state ON jobs
+FOR EACH ROW EXECUTE FUNCTION record_job_event();
+
+-- Wake up runners waiting to take a job in the project, and log
+-- streams waiting for the job to finish
+CREATE OR REPLACE FUNCTION notify_job_change() RETURNS TRIGGER AS $$
+BEGIN
+  IF NEW.state = 'available' THEN
+    PERFORM pg_notify('jobclerk_jobs',
+                      (SELECT name FROM projects WHERE id = NEW.project));
+  ELSIF NEW.finished IS NOT NULL THEN
+    PERFORM pg_notify('jobclerk_job_logs', NEW.id::TEXT);
+  END IF;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS notify_job_change ON jobs;
+CREATE TRIGGER notify_job_change
+AFTER INSERT OR UPDATE OF state ON jobs
+FOR EACH ROW EXECUTE FUNCTION notify_job_change();
+
+-- Wake up log streams waiting for new output
+CREATE OR REPLACE FUNCTION notify_job_log() RETURNS TRIGGER AS $$
+BEGIN
+  PERFORM pg_notify('jobclerk_job_logs', NEW.job::TEXT);
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS notify_job_log ON job_logs;
+CREATE TRIGGER notify_job_log
+AFTER INSERT ON job_logs
+FOR EACH ROW EXECUTE FUNCTION notify_job_log();
@@ -0,0 +1,7 @@
+-- Like API keys, UI users have a role that limits the requests they
+-- can make from the UI's forms, and can be limited to one project.
+-- Existing users become viewers.
+ALTER TABLE ui_users
+  ADD COLUMN IF NOT EXISTS project_name TEXT
+    REFERENCES projects (name) ON DELETE CASCADE,
+  ADD COLUMN IF NOT EXISTS role TEXT NOT NULL DEFAULT 'viewer';
@@ -0,0 +1,19 @@
+-- Also wake up the runner holding the job when the job is asked to
+-- cancel or finishes, so that runners connected over a WebSocket get
+-- cancellations and new jobs right away
+CREATE OR REPLACE FUNCTION notify_job_change() RETURNS TRIGGER AS $$
+BEGIN
+  IF NEW.state = 'available' THEN
+    PERFORM pg_notify('jobclerk_jobs',
+                      (SELECT name FROM projects WHERE id = NEW.project));
+  ELSIF NEW.finished IS NOT NULL THEN
+    PERFORM pg_notify('jobclerk_job_logs', NEW.id::TEXT);
+  END IF;
+
+  IF NEW.runner IS NOT NULL
+     AND (NEW.state = 'canceling' OR NEW.finished IS NOT NULL) THEN
+    PERFORM pg_notify('jobclerk_runners', NEW.runner);
+  END IF;
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
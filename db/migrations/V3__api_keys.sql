@@ -0,0 +1,20 @@
+-- Keys that clients send in the Authorization header
+CREATE TABLE IF NOT EXISTS api_keys (
+  id BIGSERIAL PRIMARY KEY,
+
+  -- SHA-256 of the key, hex encoded. The key itself isn't stored.
+  key_hash TEXT NOT NULL UNIQUE,
+
+  -- Project the key can be used with. NULL means any project, which
+  -- is required for requests that aren't about a single project.
+  project_name TEXT REFERENCES projects (name) ON DELETE CASCADE,
+
+  -- Valid values: read, write
+  access TEXT NOT NULL,
+
+  description TEXT,
+  created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  -- Set when the key is revoked; revoked keys are rejected
+  revoked TIMESTAMPTZ
+);
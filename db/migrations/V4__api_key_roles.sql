@@ -0,0 +1,12 @@
+-- Replace each API key's access with a role. Write keys could make
+-- every request except managing projects, which is what the admin
+-- role allows, so they become admin keys.
+ALTER TABLE api_keys ADD COLUMN role TEXT;
+
+UPDATE api_keys
+  SET role = CASE access WHEN 'read' THEN 'viewer' ELSE 'admin' END;
+
+-- Valid values: admin, submitter, runner, viewer
+ALTER TABLE api_keys ALTER COLUMN role SET NOT NULL;
+
+ALTER TABLE api_keys DROP COLUMN access;
@@ -0,0 +1,3 @@
+-- Maximum size of a job's data as JSON, in bytes. NULL means the
+-- server's default limit applies.
+ALTER TABLE projects ADD COLUMN max_data_bytes INT;
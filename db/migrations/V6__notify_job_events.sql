@@ -0,0 +1,15 @@
+-- Tell project pages that one of the project's jobs changed state
+CREATE OR REPLACE FUNCTION notify_job_event() RETURNS TRIGGER AS $$
+BEGIN
+  PERFORM pg_notify('jobclerk_job_events',
+                    (SELECT projects.name FROM jobs
+                     JOIN projects ON projects.id = jobs.project
+                     WHERE jobs.id = NEW.job));
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS notify_job_event ON job_events;
+CREATE TRIGGER notify_job_event
+AFTER INSERT ON job_events
+FOR EACH ROW EXECUTE FUNCTION notify_job_event();
@@ -0,0 +1,16 @@
+-- Users who can log in to the UI. Passwords are stored as bcrypt
+-- hashes.
+CREATE TABLE IF NOT EXISTS ui_users (
+  name TEXT PRIMARY KEY,
+  password_hash TEXT NOT NULL,
+  created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Logged in UI sessions. Like API keys, session IDs are only stored
+-- as a hash.
+CREATE TABLE IF NOT EXISTS ui_sessions (
+  id_hash TEXT PRIMARY KEY,
+  user_name TEXT NOT NULL REFERENCES ui_users (name) ON DELETE CASCADE,
+  created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  expires TIMESTAMPTZ NOT NULL
+);
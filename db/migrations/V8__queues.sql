@@ -0,0 +1,7 @@
+-- Named queue within the project that the job is in
+ALTER TABLE jobs ADD COLUMN queue TEXT NOT NULL DEFAULT 'default';
+
+-- Relative share of taken jobs each of the project's queues gets, as
+-- a JSON object of queue names to weights. If empty, jobs are taken
+-- by priority regardless of their queue.
+ALTER TABLE projects ADD COLUMN queue_weights JSONB NOT NULL DEFAULT '{}';
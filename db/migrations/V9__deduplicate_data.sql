@@ -0,0 +1,9 @@
+-- If true, adding a job whose data is the same as an available job in
+-- the project returns the existing job instead of adding a new one
+ALTER TABLE projects ADD COLUMN deduplicate_data BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Used to find available jobs with the same data. JSONB's text form
+-- doesn't depend on key order or whitespace, so equal data gets
+-- equal hashes.
+CREATE INDEX jobs_available_data_hash ON jobs (project, md5(data::TEXT))
+  WHERE state = 'available';
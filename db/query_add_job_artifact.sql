@@ -0,0 +1,13 @@
+INSERT INTO job_artifacts (job, name, url, size, content_type)
+SELECT id, $4, $5, $6, $7
+FROM jobs
+WHERE id = $2
+  AND project = (SELECT id FROM projects WHERE name = $1)
+  AND state IN ('activating', 'running', 'canceling')
+  AND token = $3
+ON CONFLICT (job, name) DO UPDATE
+SET url = EXCLUDED.url,
+    size = EXCLUDED.size,
+    content_type = EXCLUDED.content_type,
+    created = CURRENT_TIMESTAMP
+RETURNING job
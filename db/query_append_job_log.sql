@@ -0,0 +1,12 @@
+WITH job AS (
+  UPDATE jobs
+  SET log_sequence = log_sequence + 1
+  WHERE id = $2
+    AND project = (SELECT id FROM projects WHERE name = $1)
+    AND state IN ('activating', 'running', 'canceling')
+    AND token = $3
+  RETURNING id, log_sequence
+)
+INSERT INTO job_logs (job, sequence, text)
+SELECT id, log_sequence, $4 FROM job
+RETURNING sequence
@@ -0,0 +1,17 @@
+WITH expired AS (
+  DELETE FROM jobs
+  USING projects
+  WHERE projects.id = jobs.project
+    AND jobs.state = ANY($1)
+    AND jobs.finished <
+        CURRENT_TIMESTAMP - projects.retention_millis * INTERVAL '1 millisecond'
+  RETURNING jobs.*, projects.retention_archive
+), archived AS (
+  INSERT INTO archived_jobs (id, project, finished, job)
+  SELECT id, project, finished,
+         to_jsonb(expired) - 'token' - 'retention_archive'
+  FROM expired
+  WHERE retention_archive
+  RETURNING id
+)
+SELECT (SELECT COUNT(*) FROM expired), (SELECT COUNT(*) FROM archived)
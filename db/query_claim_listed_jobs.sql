@@ -0,0 +1,50 @@
+-- Take jobs listed in a project's Redis list in one statement, without
+-- the transaction and runner lock used to search for jobs. Projects
+-- that limit each runner's jobs need that lock to count the runner's
+-- jobs, so none of their jobs are taken here.
+--
+-- Returns each listed job that was still available, with its new
+-- token if it was taken, so that the rest can be put back in the list,
+-- and whether the project limits each runner's jobs.
+WITH project AS (
+  SELECT id, max_jobs_per_runner
+  FROM projects WHERE name = $1
+), listed AS (
+  SELECT id FROM jobs
+  WHERE project = (SELECT id FROM project)
+    AND id = ANY($3::BIGINT[])
+    AND state = 'available'
+), claimed AS (
+  SELECT id, ROW_NUMBER() OVER () AS n
+  FROM (
+    SELECT id FROM jobs
+    WHERE id IN (SELECT id FROM listed)
+      AND (SELECT max_jobs_per_runner FROM project) IS NULL
+      AND available_at <= CURRENT_TIMESTAMP
+      AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+      AND requirements <@ $4::TEXT[]
+      AND ($5::INT IS NULL OR priority >= $5)
+      AND ($6::INT IS NULL OR priority <= $6)
+      AND job_dependencies_met(id)
+    FOR UPDATE OF jobs SKIP LOCKED
+  ) AS locked
+), taken AS (
+  UPDATE jobs
+  SET state = 'activating',
+      runner = $2,
+      started = CURRENT_TIMESTAMP,
+      heartbeat = CURRENT_TIMESTAMP,
+      lease_millis = $8,
+      lease_expires_at = CURRENT_TIMESTAMP + COALESCE(
+        $9, $8, projects.heartbeat_expiration_millis
+      ) * INTERVAL '1 millisecond',
+      token = ($7::TEXT[])[claimed.n],
+      attempts = attempts + 1
+  FROM claimed, projects
+  WHERE jobs.id = claimed.id AND projects.id = jobs.project
+  RETURNING jobs.id, jobs.token
+)
+SELECT listed.id, taken.token,
+       (SELECT max_jobs_per_runner FROM project) IS NOT NULL
+FROM listed
+LEFT JOIN taken ON taken.id = listed.id
@@ -0,0 +1,10 @@
+UPDATE jobs
+SET lease_expires_at = GREATEST(lease_expires_at, CURRENT_TIMESTAMP) +
+    $4::BIGINT * INTERVAL '1 millisecond',
+    -- Like a heartbeat, this shows the runner has started the job
+    state = CASE WHEN state = 'activating' THEN 'running' ELSE state END
+WHERE id = $2
+  AND project = (SELECT id FROM projects WHERE name = $1)
+  AND state IN ('activating', 'running', 'canceling')
+  AND token = $3
+RETURNING lease_expires_at
@@ -0,0 +1,8 @@
+UPDATE jobs
+SET state = 'expired',
+    finished = CURRENT_TIMESTAMP
+FROM projects
+WHERE projects.id = jobs.project
+  AND jobs.state = 'available'
+  AND jobs.expires_at <= CURRENT_TIMESTAMP
+RETURNING jobs.id, projects.name, jobs.expires_at
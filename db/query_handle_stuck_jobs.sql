@@ -1,13 +1,14 @@
-UPDATE jobs
-SET state = 'available',
-    runner = NULL,
-    started = NULL,
-    token = NULL
-WHERE state = 'running'
-  AND (heartbeat +
-       make_interval(secs => ((
-         SELECT heartbeat_expiration_millis
-         FROM projects
-         WHERE projects.id = jobs.project) / 1000
-       ))) < CURRENT_TIMESTAMP
-RETURNING jobs.id
+SELECT jobs.id,
+       jobs.attempts,
+       projects.max_attempts,
+       projects.retry_backoff,
+       projects.retry_delay_millis,
+       projects.name,
+       jobs.runner,
+       jobs.lease_expires_at,
+       jobs.state
+FROM jobs
+JOIN projects ON projects.id = jobs.project
+WHERE jobs.state IN ('activating', 'running', 'canceling')
+  AND jobs.lease_expires_at < CURRENT_TIMESTAMP
+FOR UPDATE OF jobs SKIP LOCKED
@@ -0,0 +1,15 @@
+-- Jobs that were already being canceled when they ran out of time are
+-- canceled rather than timed out
+UPDATE jobs
+SET state = CASE WHEN jobs.state = 'canceling' THEN 'canceled'
+                 ELSE 'timed_out' END,
+    finished = CURRENT_TIMESTAMP,
+    token = NULL
+FROM projects
+WHERE projects.id = jobs.project
+  AND jobs.state IN ('activating', 'running', 'canceling')
+  AND (jobs.started +
+       COALESCE(jobs.max_runtime_millis, projects.max_runtime_millis) *
+       INTERVAL '1 millisecond') < CURRENT_TIMESTAMP
+RETURNING jobs.id, projects.name, jobs.runner, jobs.lease_expires_at,
+          jobs.state
@@ -0,0 +1,22 @@
+WITH bounds AS (
+  SELECT COALESCE($3, CURRENT_TIMESTAMP) -
+           $2::BIGINT * INTERVAL '1 millisecond' AS window_start,
+         COALESCE($3, CURRENT_TIMESTAMP) AS window_end
+)
+SELECT
+  (EXTRACT(EPOCH FROM AVG(started - created)
+     FILTER (WHERE started > window_start AND started <= window_end))
+   * 1000)::BIGINT,
+  (EXTRACT(EPOCH FROM AVG(finished - started)
+     FILTER (WHERE finished > window_start AND finished <= window_end))
+   * 1000)::BIGINT,
+  (EXTRACT(EPOCH FROM PERCENTILE_CONT(0.5)
+     WITHIN GROUP (ORDER BY finished - started)
+     FILTER (WHERE finished > window_start AND finished <= window_end))
+   * 1000)::BIGINT,
+  (EXTRACT(EPOCH FROM PERCENTILE_CONT(0.95)
+     WITHIN GROUP (ORDER BY finished - started)
+     FILTER (WHERE finished > window_start AND finished <= window_end))
+   * 1000)::BIGINT
+FROM jobs, bounds
+WHERE project = $1
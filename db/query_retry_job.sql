@@ -0,0 +1,7 @@
+UPDATE jobs
+SET state = 'available',
+    runner = NULL,
+    started = NULL,
+    token = NULL,
+    available_at = CURRENT_TIMESTAMP + $2::BIGINT * INTERVAL '1 millisecond'
+WHERE id = $1
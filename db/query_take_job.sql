@@ -1,17 +1,35 @@
-UPDATE jobs
-SET state = 'running',
-    runner = $2,
-    started = CURRENT_TIMESTAMP,
-    heartbeat = CURRENT_TIMESTAMP,
-    token = $3
-WHERE id = (
-  SELECT id
-  FROM jobs
-  WHERE project = (
-    SELECT id FROM projects WHERE name = $1
-  ) AND state = 'available'
-  ORDER BY priority, created
-  LIMIT 1
-  FOR UPDATE SKIP LOCKED
+-- Find the jobs to take and lock them until the transaction ends.
+-- Jobs locked by other runners taking jobs at the same time are
+-- skipped rather than waited for, so each job goes to one runner.
+WITH project AS (
+  SELECT id, max_jobs_per_runner
+  FROM projects WHERE name = $1
+), limits AS (
+  -- Don't let the runner go over the project's limit on concurrent
+  -- jobs
+  SELECT GREATEST(0, LEAST(
+    $3::INT,
+    project.max_jobs_per_runner - (
+      SELECT COUNT(*) FROM jobs
+      WHERE jobs.project = project.id
+        AND jobs.runner = $2
+        AND jobs.state IN ('activating', 'running', 'canceling')
+    )
+  )) AS max_jobs
+  FROM project
 )
-RETURNING id, token
+SELECT id
+FROM jobs
+WHERE project = (SELECT id FROM project)
+  AND state = 'available'
+  AND available_at <= CURRENT_TIMESTAMP
+  AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+  AND requirements <@ $4::TEXT[]
+  AND ($5::BIGINT[] IS NULL OR id = ANY($5))
+  AND ($6::INT IS NULL OR priority >= $6)
+  AND ($7::INT IS NULL OR priority <= $7)
+  AND ($8::TEXT IS NULL OR queue = $8)
+  AND job_dependencies_met(id)
+ORDER BY priority, created
+LIMIT (SELECT max_jobs FROM limits)
+FOR UPDATE OF jobs SKIP LOCKED
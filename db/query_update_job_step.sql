@@ -0,0 +1,12 @@
+UPDATE job_steps
+SET state = $3,
+    started = CASE
+      WHEN $3 = 'pending' THEN NULL
+      WHEN $3 = 'skipped' THEN started
+      ELSE COALESCE(started, CURRENT_TIMESTAMP)
+    END,
+    finished = CASE
+      WHEN $3 IN ('succeeded', 'failed', 'skipped') THEN CURRENT_TIMESTAMP
+    END
+WHERE job = $1 AND name = $2
+RETURNING position
@@ -0,0 +1,50 @@
+-- Sample projects and jobs for trying out the UI and pagination
+-- locally. Running it again adds another batch of jobs.
+INSERT INTO projects
+  (name, heartbeat_expiration_millis, max_attempts, retry_backoff,
+   retry_delay_millis, data)
+VALUES
+  ('seed-builds', 30000, 3, 'exponential', 1000, '{"repo": "jobclerk"}'),
+  ('seed-tests', 60000, NULL, NULL, NULL, '{}'),
+  ('seed-reports', 300000, NULL, NULL, NULL, '{"schedule": "nightly"}')
+ON CONFLICT (name) DO NOTHING;
+
+-- A hundred jobs per project, cycling through every state and created
+-- over the past week
+WITH seed AS (
+  SELECT p.id AS project,
+         n,
+         (ARRAY['available', 'running', 'canceling', 'canceled',
+                'succeeded', 'failed', 'expired', 'timed_out'])[1 + n % 8]
+           AS state,
+         CURRENT_TIMESTAMP - n * INTERVAL '97 minutes' AS created
+  FROM projects p, generate_series(1, 100) AS n
+  WHERE p.name IN ('seed-builds', 'seed-tests', 'seed-reports')
+)
+INSERT INTO jobs
+  (project, runner, state, created, started, finished, heartbeat,
+   lease_expires_at, attempts, token, expires_at, priority, data)
+SELECT
+  project,
+  CASE WHEN state NOT IN ('available', 'expired')
+    THEN 'seed-runner-' || n % 4 END,
+  state,
+  created,
+  CASE WHEN state NOT IN ('available', 'expired')
+    THEN created + INTERVAL '1 minute' END,
+  CASE WHEN state IN ('canceled', 'succeeded', 'failed', 'timed_out')
+    THEN created + (2 + n % 13) * INTERVAL '1 minute'
+    WHEN state = 'expired' THEN created + INTERVAL '1 hour' END,
+  CASE WHEN state IN ('running', 'canceling')
+    THEN CURRENT_TIMESTAMP END,
+  CASE WHEN state IN ('running', 'canceling')
+    THEN CURRENT_TIMESTAMP + INTERVAL '1 hour' END,
+  CASE WHEN state IN ('available', 'expired') THEN 0
+    WHEN state = 'failed' THEN 1 + n % 3
+    ELSE 1 END,
+  CASE WHEN state IN ('running', 'canceling')
+    THEN md5(random()::TEXT) END,
+  CASE WHEN state = 'expired' THEN created + INTERVAL '1 hour' END,
+  n % 3,
+  jsonb_build_object('seed', n)
+FROM seed;
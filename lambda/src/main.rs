@@ -1,15 +1,114 @@
 use env_logger::Env;
 use jobclerk_server::api::handle_request;
-use jobclerk_server::{make_pool, Pool, DEFAULT_POSTGRES_PORT};
+use jobclerk_server::{
+    check_schema_version, database_url_from_env, make_pool, Pool,
+};
 use jobclerk_types::{Request, Response};
 use lambda::{handler_fn, Context};
 use once_cell::sync::OnceCell;
+use rusoto_core::Region;
+use rusoto_secretsmanager::{
+    GetSecretValueRequest, SecretsManager, SecretsManagerClient,
+};
+use serde::Deserialize;
 use std::convert::Infallible;
+use std::env;
 
 // Keep the pool in a OnceCell so that we know it's only initialized
 // once.
 static POOL: OnceCell<Pool> = OnceCell::new();
 
+/// Quote a value for a key/value connection string.
+fn quote_connection_value(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Database settings stored in Secrets Manager, in the format RDS
+/// uses for its secrets.
+#[derive(Default, Deserialize)]
+struct DbSecret {
+    username: Option<String>,
+    password: Option<String>,
+    host: Option<String>,
+    port: Option<u16>,
+    dbname: Option<String>,
+}
+
+/// Get the database secret from Secrets Manager.
+async fn get_db_secret(secret_arn: &str) -> DbSecret {
+    let client = SecretsManagerClient::new(Region::default());
+    let resp = client
+        .get_secret_value(GetSecretValueRequest {
+            secret_id: secret_arn.into(),
+            ..Default::default()
+        })
+        .await
+        .expect("failed to get database secret");
+    let secret = resp.secret_string.expect("database secret is not a string");
+    serde_json::from_str(&secret).expect("invalid database secret")
+}
+
+/// Get the database connection string. If `DATABASE_URL` isn't set,
+/// the connection settings are read from the same variables libpq
+/// uses: `PGHOST`, `PGPORT`, `PGUSER`, `PGPASSWORD` and `PGDATABASE`.
+/// This lets the lambda connect to RDS without putting the settings
+/// together into a URL.
+///
+/// If `DB_SECRET_ARN` is set, the user and password come from that
+/// secret instead, so they don't have to be in the environment. The
+/// secret's host, port and database are used if the variables for
+/// them aren't set. The secret is only read when the lambda starts;
+/// the pool then reuses it for every invocation.
+async fn database_url() -> String {
+    if env::var_os("DATABASE_URL").is_some() {
+        database_url_from_env()
+    } else {
+        let secret = match env::var("DB_SECRET_ARN") {
+            Ok(secret_arn) => get_db_secret(&secret_arn).await,
+            Err(_) => DbSecret::default(),
+        };
+        let var = |name| env::var(name).ok();
+        let params = vec![
+            (
+                "host",
+                Some(
+                    var("PGHOST")
+                        .or(secret.host)
+                        .unwrap_or_else(|| "localhost".into()),
+                ),
+            ),
+            (
+                "port",
+                Some(
+                    var("PGPORT")
+                        .or(secret.port.map(|port| port.to_string()))
+                        .unwrap_or_else(|| "5432".into()),
+                ),
+            ),
+            (
+                "user",
+                Some(
+                    secret
+                        .username
+                        .or_else(|| var("PGUSER"))
+                        .unwrap_or_else(|| "postgres".into()),
+                ),
+            ),
+            ("password", secret.password.or_else(|| var("PGPASSWORD"))),
+            ("dbname", var("PGDATABASE").or(secret.dbname)),
+        ];
+        params
+            .into_iter()
+            .filter_map(|(key, value)| {
+                value.map(|value| {
+                    format!("{}={}", key, quote_connection_value(&value))
+                })
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
 async fn lambda_handler(
     req: Request,
     _: Context,
@@ -22,13 +121,15 @@ async fn lambda_handler(
 async fn main() {
     env_logger::from_env(Env::default().default_filter_or("info")).init();
 
-    // TODO: need to add host and such to the params here
     POOL.set(
-        make_pool(DEFAULT_POSTGRES_PORT)
+        make_pool(&database_url().await)
             .await
             .expect("failed to initialize pool"),
     )
     .expect("pool is already initialized");
+    check_schema_version(POOL.get().unwrap())
+        .await
+        .expect("database schema is out of date");
 
     let func = handler_fn(lambda_handler);
     lambda::run(func).await.expect("failed to run lambda");
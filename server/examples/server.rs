@@ -1,11 +1,79 @@
+use actix::{
+    Actor, ActorContext, ActorFuture, AsyncContext, StreamHandler, WrapFuture,
+};
+use actix_http::HttpService;
+use actix_rt::net::TcpStream;
+use actix_rt::signal;
+use actix_rt::signal::unix::SignalKind;
+use actix_service::map_config;
+use actix_tls::rustls::TlsStream;
 use actix_web::body::Body;
-use actix_web::{middleware, App, HttpServer};
-use actix_web::{web, HttpResponse, Responder};
-use env_logger::Env;
-use fehler::throws;
-use jobclerk_server::{api, ui};
-use jobclerk_server::{make_pool, Pool, DEFAULT_POSTGRES_PORT};
-use log::error;
+use actix_web::cookie::SameSite;
+use actix_web::dev::{
+    AppConfig, Payload, Service, ServiceRequest, ServiceResponse,
+};
+use actix_web::error::InternalError;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::http::{header, Cookie};
+use actix_web::web::Bytes;
+use actix_web::{middleware, App, FromRequest, HttpServer};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use actix_web_actors::ws;
+use argh::FromArgs;
+use chrono::{DateTime, Utc};
+use fehler::{throw, throws};
+use futures::channel::oneshot;
+use futures::future::LocalBoxFuture;
+use futures::{future, Future, FutureExt, StreamExt};
+use jobclerk_server::circuit_breaker::{self, CircuitBreakerSettings};
+use jobclerk_server::rate_limit::{self, Rate, RateLimits};
+use jobclerk_server::{api, metrics, notify, ui};
+use jobclerk_server::{
+    check_schema_version, create_ui_session, database_url_from_env,
+    delete_ui_session, get_ui_session_user, is_admin_key,
+    make_pool_with_options, migrate, set_request_timeout_millis,
+    set_slow_query_millis, Pool, PoolOptions, RequestContext,
+    DEFAULT_REQUEST_TIMEOUT_MILLIS, DEFAULT_SLOW_QUERY_MILLIS,
+};
+use jobclerk_types::{
+    AddJobRequest, AddProjectRequest, BadRequestError, CancelJobRequest,
+    CancelJobResponse, Cursor, ErrorCode, GetJobAttemptsRequest,
+    GetJobHistoryRequest, GetJobRequest, GetJobsRequest,
+    GetProjectStatsRequest, JobFilter, JobId, JobLogChunk, JobState,
+    ListProjectsRequest, Page, ProjectJobEvent, Response, RetryJobRequest,
+    RunnerEvent, RunnerMessage, ServerMessage, SortOrder, UpdateJobRequest,
+    API_VERSION,
+};
+use log::{error, info};
+use once_cell::sync::OnceCell;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use rustls::internal::pemfile;
+use rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, Certificate, NoClientAuth,
+    PrivateKey, RootCertStore, ServerConfig, Session,
+};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Result as JsonResult, Value};
+use std::env;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::time;
+use tracing::info_span;
+use tracing_futures::Instrument;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
+
+/// Address the server listens on.
+const BIND_ADDRESS: &str = "127.0.0.1:8000";
+
+/// How often the background task handles stuck jobs and applies
+/// project retention policies.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(10);
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -29,51 +97,1699 @@ impl actix_web::ResponseError for Error {
     }
 }
 
+/// Set at startup from `Opt::ui_login`.
+static REQUIRE_UI_LOGIN: AtomicBool = AtomicBool::new(false);
+
+/// Cookie holding the ID of the browser's UI session.
+const SESSION_COOKIE: &str = "jobclerk_session";
+
+/// User logged in to the UI, or `None` if the server doesn't require
+/// logging in. Extracting this redirects to the login page if the
+/// browser doesn't have a valid session.
+struct UiUser(Option<String>);
+
+impl FromRequest for UiUser {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<UiUser, actix_web::Error>>;
+    type Config = ();
+
+    fn from_request(http_req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if !REQUIRE_UI_LOGIN.load(Ordering::Relaxed) {
+            return future::ok(UiUser(None)).boxed_local();
+        }
+        let pool = http_req.app_data::<web::Data<Pool>>().cloned();
+        let session = http_req
+            .cookie(SESSION_COOKIE)
+            .map(|cookie| cookie.value().to_string());
+        async move {
+            let user = match (pool, session) {
+                (Some(pool), Some(session)) => {
+                    get_ui_session_user(pool.get_ref(), &session)
+                        .await
+                        .map_err(Error::from)?
+                }
+                _ => None,
+            };
+            match user {
+                Some(user) => Ok(UiUser(Some(user))),
+                None => Err(InternalError::from_response(
+                    "not logged in",
+                    HttpResponse::SeeOther()
+                        .header(header::LOCATION, "/login")
+                        .finish(),
+                )
+                .into()),
+            }
+        }
+        .boxed_local()
+    }
+}
+
 #[throws]
-async fn list_projects(pool: web::Data<Pool>) -> impl Responder {
-    HttpResponse::Ok().body(ui::list_projects(pool.get_ref()).await?)
+async fn list_projects(pool: web::Data<Pool>, user: UiUser) -> impl Responder {
+    HttpResponse::Ok()
+        .body(ui::list_projects(pool.get_ref(), user.0.as_deref()).await?)
+}
+
+#[throws]
+async fn list_runners(pool: web::Data<Pool>, _user: UiUser) -> impl Responder {
+    HttpResponse::Ok().body(ui::list_runners(pool.get_ref()).await?)
+}
+
+/// Cookie holding the token that the UI's forms must send back, so
+/// that other sites can't submit them.
+const CSRF_COOKIE: &str = "jobclerk_csrf";
+
+/// Cookie holding a message to show on the next page, such as the
+/// result of submitting a form.
+const FLASH_COOKIE: &str = "jobclerk_flash";
+
+/// Get the CSRF token and flash message for a UI page. A new CSRF
+/// token is created if the browser doesn't have one yet.
+fn get_form_context(http_req: &HttpRequest) -> ui::FormContext {
+    ui::FormContext {
+        csrf_token: match http_req.cookie(CSRF_COOKIE) {
+            Some(cookie) => cookie.value().to_string(),
+            None => ui::new_csrf_token(),
+        },
+        flash: http_req
+            .cookie(FLASH_COOKIE)
+            .map(|cookie| cookie.value().to_string()),
+    }
+}
+
+/// Respond with a UI page that has forms. This sets the CSRF cookie
+/// if the token is new, and clears the flash message now that it has
+/// been shown.
+fn form_page_response(
+    http_req: &HttpRequest,
+    body: String,
+    form: &ui::FormContext,
+) -> HttpResponse {
+    let mut resp = HttpResponse::Ok();
+    if http_req.cookie(CSRF_COOKIE).is_none() {
+        resp.cookie(
+            Cookie::build(CSRF_COOKIE, form.csrf_token.clone())
+                .path("/")
+                .http_only(true)
+                .same_site(SameSite::Strict)
+                .finish(),
+        );
+    }
+    if form.flash.is_some() {
+        resp.del_cookie(&Cookie::build(FLASH_COOKIE, "").path("/").finish());
+    }
+    resp.body(body)
 }
 
 #[throws]
 async fn get_project(
     pool: web::Data<Pool>,
+    http_req: HttpRequest,
+    _user: UiUser,
     path: web::Path<(String,)>,
+    query: web::Query<ui::ProjectPageQuery>,
 ) -> impl Responder {
     let project_name = &path.0;
+    let form = get_form_context(&http_req);
+    let body =
+        ui::get_project(pool.get_ref(), project_name, &query, &form).await?;
+    form_page_response(&http_req, body, &form)
+}
+
+#[throws]
+async fn get_job(
+    pool: web::Data<Pool>,
+    http_req: HttpRequest,
+    _user: UiUser,
+    path: web::Path<(String, JobId)>,
+) -> impl Responder {
+    let (project_name, job_id) = path.into_inner();
+    let form = get_form_context(&http_req);
+    let body =
+        ui::get_job(pool.get_ref(), &project_name, job_id, &form).await?;
+    form_page_response(&http_req, body, &form)
+}
+
+#[derive(Deserialize)]
+struct JobForm {
+    csrf_token: String,
+    /// Page to go back to after submitting the form.
+    return_to: String,
+}
+
+/// Check that a UI form's CSRF token matches the browser's cookie.
+fn check_csrf_token(
+    http_req: &HttpRequest,
+    csrf_token: &str,
+) -> Result<(), HttpResponse> {
+    match http_req.cookie(CSRF_COOKIE) {
+        Some(cookie) if cookie.value() == csrf_token => Ok(()),
+        _ => Err(HttpResponse::Forbidden().body("invalid CSRF token")),
+    }
+}
+
+/// Check a UI form's CSRF token and make its request. Requests from
+/// users logged in to the UI are authorized with the user's role and
+/// project, and the user is recorded as the actor in the audit log.
+/// Otherwise the request is authorized the same way as API requests,
+/// so it fails if the server requires an API key. Errors are returned
+/// as a response to send instead of going back to the form's page.
+async fn handle_form_request(
+    pool: &Pool,
+    http_req: &HttpRequest,
+    user: &UiUser,
+    csrf_token: &str,
+    req: jobclerk_types::Request,
+) -> Result<Response, HttpResponse> {
+    check_csrf_token(http_req, csrf_token)?;
+
+    let mut context = request_context(http_req);
+    if let UiUser(Some(user)) = user {
+        context.ui_user = Some(user.clone());
+        context.client_identity = Some(format!("ui:{}", user));
+    }
+    match api::handle_request_with_context(pool, &req, &context).await {
+        Response::BadRequest(err) => {
+            Err(HttpResponse::BadRequest().body(err.message))
+        }
+        Response::NotFound => Err(HttpResponse::NotFound().body("not found")),
+        Response::Unauthorized => {
+            Err(HttpResponse::Unauthorized().body("missing or invalid API key"))
+        }
+        Response::Forbidden(msg) => Err(HttpResponse::Forbidden().body(msg)),
+        Response::TooManyRequests { .. } => {
+            Err(HttpResponse::TooManyRequests().body("too many requests"))
+        }
+        Response::Unavailable { .. } => Err(HttpResponse::ServiceUnavailable()
+            .body("the database is unavailable, try again later")),
+        Response::Timeout => {
+            Err(HttpResponse::GatewayTimeout().body("the request timed out"))
+        }
+        Response::InternalError => {
+            Err(HttpResponse::InternalServerError().body(ui::internal_error()))
+        }
+        resp => Ok(resp),
+    }
+}
+
+/// Check if a form's `return_to` is a page in the UI that has forms.
+/// Anything else is refused so that a crafted form can't redirect to
+/// another site, including paths like `/\example.com` that browsers
+/// treat as a different host.
+fn is_form_page(return_to: &str) -> bool {
+    (return_to.starts_with("/projects/") || return_to.starts_with("/runners"))
+        && !return_to.contains("//")
+        && return_to.chars().all(|c| c.is_ascii_graphic() && c != '\\')
+}
+
+/// Go back to the page a form was on, showing `flash` there. If
+/// `return_to` isn't a page in the UI, go to `fallback` instead.
+fn form_redirect(
+    return_to: &str,
+    fallback: String,
+    flash: &str,
+) -> HttpResponse {
+    let return_to = if is_form_page(return_to) {
+        return_to.to_string()
+    } else {
+        fallback
+    };
+    let flash_cookie = Cookie::build(FLASH_COOKIE, flash.to_string())
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .finish();
+    HttpResponse::SeeOther()
+        .header(header::LOCATION, return_to)
+        // Actix doesn't encode cookies it sets, and the message has
+        // spaces
+        .header(header::SET_COOKIE, flash_cookie.encoded().to_string())
+        .finish()
+}
+
+/// Cancel a job from the UI.
+async fn cancel_job(
+    pool: web::Data<Pool>,
+    http_req: HttpRequest,
+    user: UiUser,
+    path: web::Path<(String, JobId)>,
+    form: web::Form<JobForm>,
+) -> HttpResponse {
+    let (project_name, job_id) = path.into_inner();
+    let req = CancelJobRequest {
+        project_name: project_name.clone(),
+        job_id,
+    }
+    .into();
+    let resp = match handle_form_request(
+        pool.get_ref(),
+        &http_req,
+        &user,
+        &form.csrf_token,
+        req,
+    )
+    .await
+    {
+        Ok(resp) => resp,
+        Err(resp) => return resp,
+    };
+    let flash = match resp {
+        Response::CancelJob(CancelJobResponse {
+            state: JobState::Canceling,
+        }) => format!("Asked the runner of job {} to stop", job_id),
+        _ => format!("Canceled job {}", job_id),
+    };
+    form_redirect(
+        &form.return_to,
+        format!("/projects/{}/jobs/{}", project_name, job_id),
+        &flash,
+    )
+}
+
+#[derive(Deserialize)]
+struct AddJobForm {
+    csrf_token: String,
+    return_to: String,
+    /// The job's data as JSON.
+    data: String,
+}
+
+/// Add a job from the UI.
+async fn add_job(
+    pool: web::Data<Pool>,
+    http_req: HttpRequest,
+    user: UiUser,
+    path: web::Path<(String,)>,
+    form: web::Form<AddJobForm>,
+) -> HttpResponse {
+    let project_name = path.into_inner().0;
+    let project_url = format!("/projects/{}", project_name);
+    if let Err(resp) = check_csrf_token(&http_req, &form.csrf_token) {
+        return resp;
+    }
+    let data = match serde_json::from_str(&form.data) {
+        Ok(data) => data,
+        Err(err) => {
+            return form_redirect(
+                &form.return_to,
+                project_url,
+                &format!("Invalid job data: {}", err),
+            )
+        }
+    };
+    let req = AddJobRequest {
+        project_name,
+        data,
+        ..Default::default()
+    }
+    .into();
+    let flash = match handle_form_request(
+        pool.get_ref(),
+        &http_req,
+        &user,
+        &form.csrf_token,
+        req,
+    )
+    .await
+    {
+        Ok(Response::AddJob(resp)) => format!("Added job {}", resp.job_id),
+        Ok(_) => "Added job".into(),
+        Err(resp) => return resp,
+    };
+    form_redirect(&form.return_to, project_url, &flash)
+}
+
+/// Requeue a failed or canceled job from the UI.
+async fn retry_job(
+    pool: web::Data<Pool>,
+    http_req: HttpRequest,
+    user: UiUser,
+    path: web::Path<(String, JobId)>,
+    form: web::Form<JobForm>,
+) -> HttpResponse {
+    let (project_name, job_id) = path.into_inner();
+    let req = RetryJobRequest {
+        project_name: project_name.clone(),
+        job_id,
+    }
+    .into();
+    if let Err(resp) = handle_form_request(
+        pool.get_ref(),
+        &http_req,
+        &user,
+        &form.csrf_token,
+        req,
+    )
+    .await
+    {
+        return resp;
+    }
+    let flash = format!("Job {} will be retried", job_id);
+    form_redirect(
+        &form.return_to,
+        format!("/projects/{}/jobs/{}", project_name, job_id),
+        &flash,
+    )
+}
+
+/// How long browsers can use a static file before checking whether it
+/// has changed.
+const STATIC_MAX_AGE_SECS: u32 = 60 * 60;
+
+/// Request and queue metrics for Prometheus to scrape.
+#[throws]
+async fn get_metrics(pool: web::Data<Pool>) -> HttpResponse {
     HttpResponse::Ok()
-        .body(ui::get_project(pool.get_ref(), project_name).await?)
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render(pool.get_ref()).await?)
+}
+
+/// Serve one of the UI's static files. These don't require logging in,
+/// since the login page uses them too.
+async fn get_static_file(
+    http_req: HttpRequest,
+    path: web::Path<(String,)>,
+) -> HttpResponse {
+    let file = match ui::get_static_file(&path.0) {
+        Some(file) => file,
+        None => return HttpResponse::NotFound().body("not found"),
+    };
+    let etag = file.etag();
+    let cache_control = format!("public, max-age={}", STATIC_MAX_AGE_SECS);
+    let not_modified = http_req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .map(|value| value == etag.as_str())
+        .unwrap_or(false);
+    if not_modified {
+        HttpResponse::NotModified()
+            .header(header::ETAG, etag)
+            .header(header::CACHE_CONTROL, cache_control)
+            .finish()
+    } else {
+        HttpResponse::Ok()
+            .content_type(file.content_type)
+            .header(header::ETAG, etag)
+            .header(header::CACHE_CONTROL, cache_control)
+            .body(file.contents)
+    }
+}
+
+#[throws]
+async fn get_login(http_req: HttpRequest) -> impl Responder {
+    let form = get_form_context(&http_req);
+    form_page_response(&http_req, ui::login_page(&form)?, &form)
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    csrf_token: String,
+    user: String,
+    password: String,
+}
+
+/// Log in to the UI and go to the projects page.
+#[throws]
+async fn login(
+    pool: web::Data<Pool>,
+    http_req: HttpRequest,
+    form: web::Form<LoginForm>,
+) -> HttpResponse {
+    if let Err(resp) = check_csrf_token(&http_req, &form.csrf_token) {
+        resp
+    } else {
+        match create_ui_session(pool.get_ref(), &form.user, &form.password)
+            .await
+        {
+            Ok(session) => HttpResponse::SeeOther()
+                .header(header::LOCATION, "/projects")
+                .cookie(
+                    Cookie::build(SESSION_COOKIE, session)
+                        .path("/")
+                        .http_only(true)
+                        // Lax rather than strict so that links to the
+                        // UI from other sites work
+                        .same_site(SameSite::Lax)
+                        .finish(),
+                )
+                .finish(),
+            Err(jobclerk_server::Error::Unauthorized) => form_redirect(
+                "/login",
+                "/login".into(),
+                "Invalid user or password",
+            ),
+            Err(err) => throw!(err),
+        }
+    }
+}
+
+/// End the browser's UI session and go back to the login page.
+#[throws]
+async fn logout(pool: web::Data<Pool>, http_req: HttpRequest) -> HttpResponse {
+    if let Some(cookie) = http_req.cookie(SESSION_COOKIE) {
+        delete_ui_session(pool.get_ref(), cookie.value()).await?;
+    }
+    HttpResponse::SeeOther()
+        .header(header::LOCATION, "/login")
+        .del_cookie(&Cookie::build(SESSION_COOKIE, "").path("/").finish())
+        .finish()
+}
+
+/// Format log chunks as server-sent events. The event ID is the
+/// chunk's sequence number and the data is the chunk as JSON.
+fn log_chunks_to_events(chunks: Vec<JobLogChunk>) -> Bytes {
+    let mut events = String::new();
+    for chunk in chunks {
+        let data = serde_json::to_string(&chunk)
+            .expect("failed to convert log chunk to JSON");
+        events += &format!("id: {}\ndata: {}\n\n", chunk.sequence, data);
+    }
+    Bytes::from(events)
+}
+
+#[throws]
+async fn stream_job_log(
+    pool: web::Data<Pool>,
+    _user: UiUser,
+    path: web::Path<(String, JobId)>,
+) -> impl Responder {
+    let (project_name, job_id) = path.into_inner();
+    let chunks =
+        api::stream_job_log(pool.get_ref().clone(), project_name, job_id)
+            .await?;
+    let events = chunks
+        .map(|chunks| chunks.map(log_chunks_to_events).map_err(Error::from));
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .streaming(Box::pin(events))
+}
+
+/// Format job state changes as server-sent events. The event ID is
+/// the job event's ID and the data is the event as JSON.
+fn job_events_to_events(job_events: Vec<ProjectJobEvent>) -> Bytes {
+    let mut events = String::new();
+    for job_event in job_events {
+        let data = serde_json::to_string(&job_event)
+            .expect("failed to convert job event to JSON");
+        events += &format!("id: {}\ndata: {}\n\n", job_event.id, data);
+    }
+    Bytes::from(events)
+}
+
+#[throws]
+async fn stream_job_events(
+    pool: web::Data<Pool>,
+    _user: UiUser,
+    path: web::Path<(String,)>,
+    http_req: HttpRequest,
+) -> impl Responder {
+    let project_name = path.into_inner().0;
+    // Browsers send the ID of the last event they got when they
+    // reconnect
+    let after_id = http_req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    let job_events =
+        api::stream_job_events(pool.get_ref().clone(), project_name, after_id)
+            .await?;
+    let events = job_events.map(|job_events| {
+        job_events.map(job_events_to_events).map_err(Error::from)
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .streaming(Box::pin(events))
+}
+
+/// Set at startup from `Opt::require_auth`.
+static REQUIRE_AUTH: AtomicBool = AtomicBool::new(false);
+
+/// Set at startup from `$JOBCLERK_ADMIN_KEY`, if it's set.
+static ADMIN_KEY: OnceCell<String> = OnceCell::new();
+
+/// Get the API key from an `Authorization: Bearer <key>` header.
+fn get_api_key(http_req: &HttpRequest) -> Option<String> {
+    let header = http_req.headers().get(header::AUTHORIZATION)?;
+    let key = header.to_str().ok()?.strip_prefix("Bearer ")?;
+    Some(key.trim().to_string())
+}
+
+/// Set at startup if `Opt::client_ca` is set.
+static REQUIRE_CLIENT_CERT: AtomicBool = AtomicBool::new(false);
+
+/// Subject of the client's TLS certificate. This is stored in the
+/// extensions of each request on the connection.
+#[derive(Clone)]
+struct ClientIdentity(String);
+
+/// Get the subject of the client's certificate, which has already been
+/// verified against the client CA.
+fn get_client_identity(
+    stream: &TlsStream<TcpStream>,
+) -> Option<ClientIdentity> {
+    let (_, session) = stream.get_ref();
+    let certs = session.get_peer_certificates()?;
+    let (_, cert) = x509_parser::parse_x509_der(&certs.first()?.0).ok()?;
+    Some(ClientIdentity(cert.tbs_certificate.subject.to_string()))
+}
+
+fn client_identity(msg: &impl HttpMessage) -> Option<String> {
+    let extensions = msg.extensions();
+    let identity = extensions.get::<Option<ClientIdentity>>()?.as_ref()?;
+    Some(identity.0.clone())
+}
+
+/// Middleware that rejects requests without a client certificate if
+/// the server requires one.
+fn check_client_cert<S>(
+    req: ServiceRequest,
+    service: &mut S,
+) -> future::Either<
+    future::Ready<Result<ServiceResponse, actix_web::Error>>,
+    S::Future,
+>
+where
+    S: Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+    >,
+{
+    if REQUIRE_CLIENT_CERT.load(Ordering::Relaxed)
+        && client_identity(&req).is_none()
+    {
+        let resp = HttpResponse::Unauthorized()
+            .json(json!({ "error": "a client certificate is required" }));
+        future::Either::Left(future::ok(req.into_response(resp)))
+    } else {
+        future::Either::Right(service.call(req))
+    }
+}
+
+/// Header with the ID of a request. Clients can set it to find their
+/// requests in the server's logs; otherwise the server makes one up.
+/// Either way it's sent back with the response.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest request ID the server accepts from clients.
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+/// Get the request's ID from the client, or create one if the client
+/// didn't send a usable one.
+fn get_request_id(req: &ServiceRequest) -> String {
+    let is_valid = |id: &&str| {
+        !id.is_empty()
+            && id.len() <= MAX_REQUEST_ID_LEN
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+    };
+    match req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(is_valid)
+    {
+        Some(id) => id.to_string(),
+        None => thread_rng().sample_iter(&Alphanumeric).take(16).collect(),
+    }
+}
+
+/// Set at startup from `Opt::json_logs`.
+static JSON_LOGS: AtomicBool = AtomicBool::new(false);
+
+/// Middleware that handles each request in a span with the request's
+/// ID, so that the ID is in all of the request's log lines, and adds
+/// the ID to the response. With JSON logs, this also logs each
+/// request in place of the text access log.
+fn assign_request_id<S>(
+    req: ServiceRequest,
+    service: &mut S,
+) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>>
+where
+    S: Service<
+        Request = ServiceRequest,
+        Response = ServiceResponse,
+        Error = actix_web::Error,
+    >,
+{
+    let request_id = get_request_id(&req);
+    let span = info_span!("request", id = %request_id);
+    let method = req.method().to_string();
+    let path = req.path().to_string();
+    let start = Instant::now();
+    let resp = span.in_scope(|| service.call(req));
+    async move {
+        let mut resp = resp.await?;
+        if JSON_LOGS.load(Ordering::Relaxed) {
+            tracing::info!(
+                method = method.as_str(),
+                path = path.as_str(),
+                status = resp.status().as_u16(),
+                latency_millis = start.elapsed().as_millis() as u64,
+                "request finished"
+            );
+        }
+        resp.headers_mut().insert(
+            HeaderName::from_static(REQUEST_ID_HEADER),
+            HeaderValue::from_str(&request_id)
+                .expect("request ID is not a valid header value"),
+        );
+        Ok(resp)
+    }
+    .instrument(span)
+}
+
+fn request_context(http_req: &HttpRequest) -> RequestContext {
+    let api_key = get_api_key(http_req);
+    // The admin key isn't in the database, so check it here
+    let admin = ADMIN_KEY
+        .get()
+        .map(|admin_key| is_admin_key(api_key.as_deref(), admin_key))
+        .unwrap_or(false);
+    RequestContext {
+        remote_addr: http_req.connection_info().remote().map(String::from),
+        api_key,
+        require_auth: REQUIRE_AUTH.load(Ordering::Relaxed),
+        admin,
+        ui_user: None,
+        client_identity: client_identity(http_req),
+    }
+}
+
+fn invalid_request(err: serde_json::Error) -> BadRequestError {
+    BadRequestError::new(
+        ErrorCode::InvalidRequest,
+        &format!("invalid request: {}", err),
+    )
+}
+
+async fn get_api_response(
+    pool: &Pool,
+    http_req: &HttpRequest,
+    body: Value,
+) -> Response {
+    // Parse the request here rather than with the extractor so that
+    // unknown request types get a BadRequest response like any other
+    // invalid request
+    match serde_json::from_value(body) {
+        Ok(req) => {
+            let context = request_context(http_req);
+            api::handle_request_with_context(pool, &req, &context).await
+        }
+        Err(err) => Response::BadRequest(invalid_request(err)),
+    }
 }
 
 async fn handle_api_request(
     pool: web::Data<Pool>,
-    req: web::Json<jobclerk_types::Request>,
+    http_req: HttpRequest,
+    body: web::Json<Value>,
 ) -> impl Responder {
-    HttpResponse::Ok().json(api::handle_request(pool.get_ref(), &req).await)
+    let resp =
+        get_api_response(pool.get_ref(), &http_req, body.into_inner()).await;
+    HttpResponse::Ok().json(resp)
 }
 
-pub fn app_config(config: &mut web::ServiceConfig) {
-    config.service(
-        web::scope("")
-            .route("/projects", web::get().to(list_projects))
-            .route("/projects/{project_name}", web::get().to(get_project))
-            .route("/api", web::post().to(handle_api_request)),
+/// Handle a request from a client of version 1 of the API, where
+/// `BadRequest` responses only had a message.
+async fn handle_api_request_v1(
+    pool: web::Data<Pool>,
+    http_req: HttpRequest,
+    body: web::Json<Value>,
+) -> impl Responder {
+    match get_api_response(pool.get_ref(), &http_req, body.into_inner()).await {
+        Response::BadRequest(err) => {
+            HttpResponse::Ok().json(json!({ "BadRequest": err.message }))
+        }
+        resp => HttpResponse::Ok().json(resp),
+    }
+}
+
+/// Build a request from a JSON body. Fields from the URL path override
+/// any in the body.
+fn request_from_body<T>(mut body: Value, path_fields: Value) -> JsonResult<T>
+where
+    T: DeserializeOwned,
+{
+    if let (Value::Object(body), Value::Object(fields)) =
+        (&mut body, path_fields)
+    {
+        body.extend(fields);
+    }
+    serde_json::from_value(body)
+}
+
+/// Handle a request from one of the REST routes. Errors are sent with
+/// the matching status code, and successful responses are sent
+/// without the `Response` envelope used by `/api`.
+async fn handle_rest_request<T>(
+    pool: &Pool,
+    http_req: &HttpRequest,
+    req: JsonResult<T>,
+) -> HttpResponse
+where
+    T: Into<jobclerk_types::Request>,
+{
+    let req = match req {
+        Ok(req) => req.into(),
+        Err(err) => {
+            let err = invalid_request(err);
+            return HttpResponse::BadRequest()
+                .json(json!({ "error": err.message, "code": err.code }));
+        }
+    };
+    let context = request_context(http_req);
+    match api::handle_request_with_context(pool, &req, &context).await {
+        Response::BadRequest(err) => HttpResponse::BadRequest()
+            .json(json!({ "error": err.message, "code": err.code })),
+        Response::NotFound => {
+            HttpResponse::NotFound().json(json!({ "error": "not found" }))
+        }
+        Response::Unauthorized => HttpResponse::Unauthorized()
+            .json(json!({ "error": "missing or invalid API key" })),
+        Response::Forbidden(msg) => {
+            HttpResponse::Forbidden().json(json!({ "error": msg }))
+        }
+        Response::InternalError => HttpResponse::InternalServerError()
+            .json(json!({ "error": "internal error" })),
+        Response::TooManyRequests { retry_after_millis } => {
+            // Retry-After is in whole seconds
+            let retry_after = retry_after_millis.div_ceil(1000);
+            HttpResponse::TooManyRequests()
+                .header(header::RETRY_AFTER, retry_after.to_string())
+                .json(json!({
+                    "error": "too many requests",
+                    "retry_after_millis": retry_after_millis,
+                }))
+        }
+        Response::Unavailable { retry_after_millis } => {
+            let retry_after = retry_after_millis.div_ceil(1000);
+            HttpResponse::ServiceUnavailable()
+                .header(header::RETRY_AFTER, retry_after.to_string())
+                .json(json!({
+                    "error": "unavailable",
+                    "retry_after_millis": retry_after_millis,
+                }))
+        }
+        Response::Timeout => HttpResponse::GatewayTimeout()
+            .json(json!({ "error": "the request timed out" })),
+        Response::Empty => HttpResponse::NoContent().finish(),
+        resp => {
+            // Responses are serialized as {"ResponseType": {...}}
+            let body = match serde_json::to_value(&resp)
+                .expect("failed to convert response to JSON")
+            {
+                Value::Object(fields) => {
+                    fields.into_iter().next().map(|(_, body)| body)
+                }
+                _ => None,
+            };
+            HttpResponse::Ok().json(body)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ProjectStatsQuery {
+    window_millis: i64,
+    window_end: Option<DateTime<Utc>>,
+}
+
+/// Query parameters for a page of a list, see `Page`.
+#[derive(Deserialize)]
+struct PageQuery {
+    cursor: Option<String>,
+    limit: Option<i64>,
+    #[serde(default)]
+    order: SortOrder,
+}
+
+impl From<PageQuery> for Page {
+    fn from(query: PageQuery) -> Page {
+        Page {
+            cursor: query.cursor.map(Cursor),
+            limit: query.limit,
+            order: query.order,
+        }
+    }
+}
+
+/// Query parameters for a `JobFilter`. Lists are comma-separated.
+#[derive(Deserialize)]
+struct JobsQuery {
+    state: Option<String>,
+    runner: Option<String>,
+    requirement: Option<String>,
+    created_after: Option<DateTime<Utc>>,
+    created_before: Option<DateTime<Utc>>,
+    /// JSON value, see `JobFilter::data_filter`
+    data_filter: Option<String>,
+    data_search: Option<String>,
+}
+
+impl JobsQuery {
+    fn job_filter(self) -> JsonResult<JobFilter> {
+        let split = |list: Option<String>| -> Vec<String> {
+            list.map(|list| list.split(',').map(String::from).collect())
+                .unwrap_or_default()
+        };
+        Ok(JobFilter {
+            states: serde_json::from_value(json!(split(self.state)))?,
+            runner: self.runner,
+            requirements: split(self.requirement),
+            created_after: self.created_after,
+            created_before: self.created_before,
+            data_filter: self
+                .data_filter
+                .map(|filter| serde_json::from_str(&filter))
+                .transpose()?,
+            data_search: self.data_search,
+        })
+    }
+}
+
+async fn rest_add_project(
+    pool: web::Data<Pool>,
+    http_req: HttpRequest,
+    body: web::Json<Value>,
+) -> impl Responder {
+    let req =
+        request_from_body::<AddProjectRequest>(body.into_inner(), json!({}));
+    handle_rest_request(pool.get_ref(), &http_req, req).await
+}
+
+/// Query parameters for listing projects, besides the page.
+#[derive(Deserialize)]
+struct ListProjectsQuery {
+    organization: Option<String>,
+}
+
+async fn rest_list_projects(
+    pool: web::Data<Pool>,
+    http_req: HttpRequest,
+    page: web::Query<PageQuery>,
+    query: web::Query<ListProjectsQuery>,
+) -> impl Responder {
+    let req = ListProjectsRequest {
+        page: page.into_inner().into(),
+        organization_name: query.into_inner().organization,
+    };
+    handle_rest_request(pool.get_ref(), &http_req, Ok(req)).await
+}
+
+async fn rest_get_project(
+    pool: web::Data<Pool>,
+    http_req: HttpRequest,
+    path: web::Path<(String,)>,
+    query: web::Query<ProjectStatsQuery>,
+) -> impl Responder {
+    let req = GetProjectStatsRequest {
+        project_name: path.into_inner().0,
+        window_millis: query.window_millis,
+        window_end: query.window_end,
+    };
+    handle_rest_request(pool.get_ref(), &http_req, Ok(req)).await
+}
+
+async fn rest_add_job(
+    pool: web::Data<Pool>,
+    http_req: HttpRequest,
+    path: web::Path<(String,)>,
+    body: web::Json<Value>,
+) -> impl Responder {
+    let (project_name,) = path.into_inner();
+    let req = request_from_body::<AddJobRequest>(
+        body.into_inner(),
+        json!({ "project_name": project_name }),
+    );
+    handle_rest_request(pool.get_ref(), &http_req, req).await
+}
+
+/// Query parameters for listing jobs, besides the filter and page.
+#[derive(Deserialize)]
+struct GetJobsQuery {
+    #[serde(default)]
+    exclude_data: bool,
+}
+
+async fn rest_get_jobs(
+    pool: web::Data<Pool>,
+    http_req: HttpRequest,
+    path: web::Path<(String,)>,
+    query: web::Query<JobsQuery>,
+    page: web::Query<PageQuery>,
+    options: web::Query<GetJobsQuery>,
+) -> impl Responder {
+    let req = query
+        .into_inner()
+        .job_filter()
+        .map(|filter| GetJobsRequest {
+            project_name: path.into_inner().0,
+            filter,
+            page: page.into_inner().into(),
+            exclude_data: options.exclude_data,
+        });
+    handle_rest_request(pool.get_ref(), &http_req, req).await
+}
+
+async fn rest_get_job(
+    pool: web::Data<Pool>,
+    http_req: HttpRequest,
+    path: web::Path<(String, JobId)>,
+) -> impl Responder {
+    let (project_name, job_id) = path.into_inner();
+    let req = GetJobRequest {
+        project_name,
+        job_id,
+    };
+    handle_rest_request(pool.get_ref(), &http_req, Ok(req)).await
+}
+
+async fn rest_update_job(
+    pool: web::Data<Pool>,
+    http_req: HttpRequest,
+    path: web::Path<(String, JobId)>,
+    body: web::Json<Value>,
+) -> impl Responder {
+    let (project_name, job_id) = path.into_inner();
+    let req = request_from_body::<UpdateJobRequest>(
+        body.into_inner(),
+        json!({ "project_name": project_name, "job_id": job_id }),
     );
+    handle_rest_request(pool.get_ref(), &http_req, req).await
+}
+
+async fn rest_get_job_history(
+    pool: web::Data<Pool>,
+    http_req: HttpRequest,
+    path: web::Path<(String, JobId)>,
+) -> impl Responder {
+    let (project_name, job_id) = path.into_inner();
+    let req = GetJobHistoryRequest {
+        project_name,
+        job_id,
+    };
+    handle_rest_request(pool.get_ref(), &http_req, Ok(req)).await
+}
+
+async fn rest_get_job_attempts(
+    pool: web::Data<Pool>,
+    http_req: HttpRequest,
+    path: web::Path<(String, JobId)>,
+) -> impl Responder {
+    let (project_name, job_id) = path.into_inner();
+    let req = GetJobAttemptsRequest {
+        project_name,
+        job_id,
+    };
+    handle_rest_request(pool.get_ref(), &http_req, Ok(req)).await
+}
+
+/// A runner's WebSocket connection. Messages in both directions are
+/// JSON: the runner sends `RunnerMessage`s and the server sends
+/// `ServerMessage`s.
+struct RunnerSocket {
+    pool: Pool,
+    context: RequestContext,
+    subscribed: bool,
+}
+
+impl RunnerSocket {
+    fn send(ctx: &mut ws::WebsocketContext<Self>, message: &ServerMessage) {
+        ctx.text(
+            serde_json::to_string(message)
+                .expect("failed to convert message to JSON"),
+        );
+    }
+
+    fn handle_message(
+        &mut self,
+        message: RunnerMessage,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let pool = self.pool.clone();
+        match message {
+            RunnerMessage::Subscribe(req) => {
+                if self.subscribed {
+                    let error = "already subscribed".to_string();
+                    Self::send(ctx, &ServerMessage::Error(error));
+                    return;
+                }
+                self.subscribed = true;
+                let context = self.context.clone();
+                let events = api::stream_runner_events(pool, req, context);
+                ctx.spawn(events.into_actor(self).map(|result, act, ctx| {
+                    match result {
+                        Ok(events) => {
+                            ctx.add_stream(events);
+                        }
+                        Err(err) => {
+                            act.subscribed = false;
+                            Self::send(
+                                ctx,
+                                &ServerMessage::Error(err.to_string()),
+                            );
+                        }
+                    }
+                }));
+            }
+            RunnerMessage::Request { id, request } => {
+                let context = self.context.clone();
+                let response = async move {
+                    api::handle_request_with_context(&pool, &request, &context)
+                        .await
+                };
+                ctx.spawn(response.into_actor(self).map(
+                    move |response, _act, ctx| {
+                        Self::send(
+                            ctx,
+                            &ServerMessage::Response { id, response },
+                        );
+                    },
+                ));
+            }
+        }
+    }
+}
+
+impl Actor for RunnerSocket {
+    type Context = ws::WebsocketContext<Self>;
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for RunnerSocket {
+    fn handle(
+        &mut self,
+        message: Result<ws::Message, ws::ProtocolError>,
+        ctx: &mut Self::Context,
+    ) {
+        match message {
+            Ok(ws::Message::Text(text)) => match serde_json::from_str(&text) {
+                Ok(message) => self.handle_message(message, ctx),
+                Err(err) => {
+                    let error = format!("invalid message: {}", err);
+                    Self::send(ctx, &ServerMessage::Error(error));
+                    ctx.stop();
+                }
+            },
+            Ok(ws::Message::Ping(bytes)) => ctx.pong(&bytes),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Ok(_) => {}
+            Err(err) => {
+                error!("runner socket error: {}", err);
+                ctx.stop();
+            }
+        }
+    }
+}
+
+impl StreamHandler<Result<RunnerEvent, jobclerk_server::Error>>
+    for RunnerSocket
+{
+    fn handle(
+        &mut self,
+        event: Result<RunnerEvent, jobclerk_server::Error>,
+        ctx: &mut Self::Context,
+    ) {
+        match event {
+            Ok(event) => Self::send(ctx, &ServerMessage::Event(event)),
+            Err(err) => {
+                error!("runner event stream failed: {}", err);
+                ctx.stop();
+            }
+        }
+    }
+}
+
+async fn runner_socket(
+    pool: web::Data<Pool>,
+    http_req: HttpRequest,
+    stream: web::Payload,
+) -> Result<HttpResponse, actix_web::Error> {
+    let socket = RunnerSocket {
+        pool: pool.get_ref().clone(),
+        context: request_context(&http_req),
+        subscribed: false,
+    };
+    ws::start(socket, &http_req, stream)
+}
+
+/// Routes for the API. These are served both at `/api/v{API_VERSION}`
+/// and at `/api`.
+fn api_config(config: &mut web::ServiceConfig) {
+    config.route("", web::post().to(handle_api_request));
+    rest_config(config);
+}
+
+/// Routes for version 1 of the API, which only differs from the
+/// current version in how `BadRequest` responses are sent.
+fn api_v1_config(config: &mut web::ServiceConfig) {
+    config.route("", web::post().to(handle_api_request_v1));
+    rest_config(config);
+}
+
+/// REST routes that map onto the same requests as the API's main
+/// route. Their errors have an error code whatever the version.
+fn rest_config(config: &mut web::ServiceConfig) {
+    config
+        .route("/projects", web::get().to(rest_list_projects))
+        .route("/projects", web::post().to(rest_add_project))
+        .route("/projects/{project_name}", web::get().to(rest_get_project))
+        .route(
+            "/projects/{project_name}/jobs",
+            web::get().to(rest_get_jobs),
+        )
+        .route(
+            "/projects/{project_name}/jobs",
+            web::post().to(rest_add_job),
+        )
+        .route(
+            "/projects/{project_name}/jobs/{job_id}",
+            web::get().to(rest_get_job),
+        )
+        .route(
+            "/projects/{project_name}/jobs/{job_id}",
+            web::patch().to(rest_update_job),
+        )
+        .route(
+            "/projects/{project_name}/jobs/{job_id}/history",
+            web::get().to(rest_get_job_history),
+        )
+        .route(
+            "/projects/{project_name}/jobs/{job_id}/attempts",
+            web::get().to(rest_get_job_attempts),
+        );
+}
+
+pub fn app_config(config: &mut web::ServiceConfig) {
+    config
+        .service(
+            web::scope(&format!("/api/v{}", API_VERSION))
+                .wrap_fn(check_client_cert)
+                .configure(api_config),
+        )
+        .service(
+            web::scope("/api/v1")
+                .wrap_fn(check_client_cert)
+                .configure(api_v1_config),
+        )
+        .service(
+            web::scope("/api")
+                .wrap_fn(check_client_cert)
+                .configure(api_config),
+        )
+        .service(
+            web::resource("/ws/runner")
+                .wrap_fn(check_client_cert)
+                .route(web::get().to(runner_socket)),
+        )
+        .route("/metrics", web::get().to(get_metrics))
+        .service(
+            web::scope("")
+                .route("/static/{name}", web::get().to(get_static_file))
+                .route("/login", web::get().to(get_login))
+                .route("/login", web::post().to(login))
+                .route("/logout", web::post().to(logout))
+                .route("/projects", web::get().to(list_projects))
+                .route("/runners", web::get().to(list_runners))
+                .route("/projects/{project_name}", web::get().to(get_project))
+                .route(
+                    "/projects/{project_name}/events",
+                    web::get().to(stream_job_events),
+                )
+                .route(
+                    "/projects/{project_name}/jobs/{job_id}",
+                    web::get().to(get_job),
+                )
+                .route("/projects/{project_name}/jobs", web::post().to(add_job))
+                .route(
+                    "/projects/{project_name}/jobs/{job_id}/cancel",
+                    web::post().to(cancel_job),
+                )
+                .route(
+                    "/projects/{project_name}/jobs/{job_id}/retry",
+                    web::post().to(retry_job),
+                )
+                .route(
+                    "/projects/{project_name}/jobs/{job_id}/log/stream",
+                    web::get().to(stream_job_log),
+                ),
+        );
+}
+
+/// Run the jobclerk server.
+#[derive(FromArgs)]
+// The JWT options are only used with the jwt feature
+#[cfg_attr(not(feature = "jwt"), allow(dead_code))]
+struct Opt {
+    /// postgres URL to connect to (defaults to $DATABASE_URL, or the
+    /// local database if that isn't set)
+    #[argh(option)]
+    database_url: Option<String>,
+
+    /// send traces to the Jaeger agent at this address (host:port)
+    #[argh(option)]
+    jaeger_agent: Option<String>,
+
+    /// write logs as one JSON object per line, with the fields of the
+    /// request they're for (ID, project, job, outcome and latency)
+    #[argh(switch)]
+    json_logs: bool,
+
+    /// log a warning for each database query that takes longer than
+    /// this many milliseconds (0 turns this off)
+    #[argh(option, default = "DEFAULT_SLOW_QUERY_MILLIS")]
+    slow_query_millis: u64,
+
+    /// require an API key (sent as `Authorization: Bearer <key>`) for
+    /// API requests; adding projects requires the admin key from
+    /// $JOBCLERK_ADMIN_KEY
+    #[argh(switch)]
+    require_auth: bool,
+
+    /// require logging in to the UI as a user added with `dbctl
+    /// add-ui-user`
+    #[argh(switch)]
+    ui_login: bool,
+
+    /// don't apply pending schema migrations at startup; the server
+    /// will refuse to start if the schema is out of date
+    #[argh(switch)]
+    no_migrate: bool,
+
+    /// use the Redis server at this URL to find available jobs (requires
+    /// the redis feature)
+    #[argh(option)]
+    redis_url: Option<String>,
+
+    /// accept JWTs from this issuer as API keys (requires the jwt
+    /// feature, --jwt-audience, and --jwt-jwks-url)
+    #[argh(option)]
+    jwt_issuer: Option<String>,
+
+    /// audience that JWTs must be for
+    #[argh(option)]
+    jwt_audience: Option<String>,
+
+    /// URL of the issuer's JSON Web Key Set
+    #[argh(option)]
+    jwt_jwks_url: Option<String>,
+
+    /// JWT claim with the project the token can be used with (tokens
+    /// without it can be used with any project)
+    #[argh(option, default = "String::from(\"jobclerk_project\")")]
+    jwt_project_claim: String,
+
+    /// JWT claim with the token's role
+    #[argh(option, default = "String::from(\"jobclerk_role\")")]
+    jwt_role_claim: String,
+
+    /// serve HTTPS with the certificate chain in this PEM file
+    /// (requires --tls-key)
+    #[argh(option)]
+    tls_cert: Option<String>,
+
+    /// PEM file with the private key for --tls-cert
+    #[argh(option)]
+    tls_key: Option<String>,
+
+    /// require clients of the API to present a certificate signed by
+    /// the CA in this PEM file (requires --tls-cert)
+    #[argh(option)]
+    client_ca: Option<String>,
+
+    /// limit each client (by client certificate, API key, or address)
+    /// to this many API requests per second
+    #[argh(option)]
+    client_rate_limit: Option<f64>,
+
+    /// requests a client can make at once before --client-rate-limit
+    /// applies (defaults to one second's worth)
+    #[argh(option)]
+    client_burst: Option<u32>,
+
+    /// limit each project to this many API requests per second
+    #[argh(option)]
+    project_rate_limit: Option<f64>,
+
+    /// requests for a project that can be made at once before
+    /// --project-rate-limit applies (defaults to one second's worth)
+    #[argh(option)]
+    project_burst: Option<u32>,
+
+    /// maximum number of database connections
+    #[argh(option)]
+    pool_max_size: Option<u32>,
+
+    /// number of idle database connections to keep open
+    #[argh(option)]
+    pool_min_idle: Option<u32>,
+
+    /// seconds to wait for a database connection before failing
+    #[argh(option)]
+    pool_connection_timeout: Option<u64>,
+
+    /// seconds after which idle database connections are closed
+    #[argh(option)]
+    pool_idle_timeout: Option<u64>,
+
+    /// seconds after which database connections are closed
+    #[argh(option)]
+    pool_max_lifetime: Option<u64>,
+
+    /// don't check that database connections still work before using
+    /// them
+    #[argh(switch)]
+    pool_no_test_on_check_out: bool,
+
+    /// milliseconds to wait for an API request to be handled before
+    /// giving up on it, or 0 to wait as long as it takes (default:
+    /// 60000)
+    #[argh(option, default = "DEFAULT_REQUEST_TIMEOUT_MILLIS")]
+    request_timeout_millis: u64,
+
+    /// milliseconds after which the database cancels a query, or 0 for
+    /// no limit (defaults to --request-timeout-millis)
+    #[argh(option)]
+    statement_timeout_millis: Option<u64>,
+
+    /// number of requests in a row that fail to reach the database
+    /// before requests are rejected without trying (default: 5)
+    #[argh(option)]
+    circuit_breaker_failures: Option<u32>,
+
+    /// seconds to reject requests for once the database is
+    /// unreachable, before trying it again (default: 10)
+    #[argh(option)]
+    circuit_breaker_open_secs: Option<u64>,
+}
+
+impl Opt {
+    fn pool_options(&self) -> PoolOptions {
+        let defaults = PoolOptions::default();
+        PoolOptions {
+            max_size: self.pool_max_size.unwrap_or(defaults.max_size),
+            min_idle: self.pool_min_idle.or(defaults.min_idle),
+            connection_timeout: self
+                .pool_connection_timeout
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.connection_timeout),
+            idle_timeout: self
+                .pool_idle_timeout
+                .map(Duration::from_secs)
+                .or(defaults.idle_timeout),
+            max_lifetime: self
+                .pool_max_lifetime
+                .map(Duration::from_secs)
+                .or(defaults.max_lifetime),
+            test_on_check_out: !self.pool_no_test_on_check_out,
+            statement_timeout: match self
+                .statement_timeout_millis
+                .unwrap_or(self.request_timeout_millis)
+            {
+                0 => None,
+                millis => Some(Duration::from_millis(millis)),
+            },
+        }
+    }
+
+    fn rate_limits(&self) -> RateLimits {
+        let rate = |per_second: Option<f64>, burst: Option<u32>| {
+            per_second.map(|per_second| Rate {
+                per_second,
+                burst: burst
+                    .unwrap_or_else(|| (per_second.ceil() as u32).max(1)),
+            })
+        };
+        RateLimits {
+            per_client: rate(self.client_rate_limit, self.client_burst),
+            per_project: rate(self.project_rate_limit, self.project_burst),
+        }
+    }
+
+    fn circuit_breaker_settings(&self) -> CircuitBreakerSettings {
+        let defaults = CircuitBreakerSettings::default();
+        CircuitBreakerSettings {
+            failure_threshold: self
+                .circuit_breaker_failures
+                .unwrap_or(defaults.failure_threshold),
+            open_duration: self
+                .circuit_breaker_open_secs
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.open_duration),
+        }
+    }
+
+    /// Get the TLS settings, if the server should use TLS.
+    #[throws(anyhow::Error)]
+    fn tls_config(&self) -> Option<ServerConfig> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => {
+                let verifier = match &self.client_ca {
+                    Some(client_ca) => {
+                        let mut roots = RootCertStore::empty();
+                        let mut reader = BufReader::new(File::open(client_ca)?);
+                        roots.add_pem_file(&mut reader).map_err(|()| {
+                            anyhow::anyhow!(
+                                "invalid certificate file: {}",
+                                client_ca
+                            )
+                        })?;
+                        // Other clients can still use the UI; the API
+                        // checks for a certificate itself
+                        AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+                    }
+                    None => NoClientAuth::new(),
+                };
+                let mut config = ServerConfig::new(verifier);
+                config.set_single_cert(
+                    read_pem_certs(cert)?,
+                    read_pem_key(key)?,
+                )?;
+                Some(config)
+            }
+            (None, None) if self.client_ca.is_none() => None,
+            _ => throw!(anyhow::anyhow!(
+                "--tls-cert and --tls-key must be used together, and \
+                 --client-ca requires them"
+            )),
+        }
+    }
+}
+
+#[throws(anyhow::Error)]
+fn read_pem_certs(path: &str) -> Vec<Certificate> {
+    let mut reader = BufReader::new(File::open(path)?);
+    pemfile::certs(&mut reader)
+        .map_err(|()| anyhow::anyhow!("invalid certificate file: {}", path))?
+}
+
+#[throws(anyhow::Error)]
+fn read_pem_key(path: &str) -> PrivateKey {
+    let pem = fs::read(path)?;
+    let mut keys =
+        pemfile::pkcs8_private_keys(&mut pem.as_slice()).unwrap_or_default();
+    if keys.is_empty() {
+        keys =
+            pemfile::rsa_private_keys(&mut pem.as_slice()).unwrap_or_default();
+    }
+    match keys.pop() {
+        Some(key) => key,
+        None => throw!(anyhow::anyhow!("no private key in {}", path)),
+    }
+}
+
+#[cfg(feature = "redis")]
+#[throws(anyhow::Error)]
+async fn connect_redis(redis_url: &str) {
+    jobclerk_server::redis_queue::connect(redis_url).await?;
+}
+
+#[cfg(not(feature = "redis"))]
+async fn connect_redis(_redis_url: &str) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!("built without the redis feature"))
+}
+
+#[cfg(feature = "jwt")]
+#[throws(anyhow::Error)]
+async fn configure_jwt(opt: &Opt, issuer: &str) {
+    let (audience, jwks_url) = match (&opt.jwt_audience, &opt.jwt_jwks_url) {
+        (Some(audience), Some(jwks_url)) => (audience, jwks_url),
+        _ => throw!(anyhow::anyhow!(
+            "--jwt-issuer requires --jwt-audience and --jwt-jwks-url"
+        )),
+    };
+    jobclerk_server::jwt::configure(jobclerk_server::jwt::JwtConfig {
+        issuer: issuer.into(),
+        audience: audience.clone(),
+        jwks_url: jwks_url.clone(),
+        project_claim: opt.jwt_project_claim.clone(),
+        role_claim: opt.jwt_role_claim.clone(),
+    })
+    .await?;
+}
+
+#[cfg(not(feature = "jwt"))]
+async fn configure_jwt(_opt: &Opt, _issuer: &str) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!("built without the jwt feature"))
+}
+
+/// Wait for SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let mut terminate = signal::unix::signal(SignalKind::terminate())
+        .expect("failed to listen for SIGTERM");
+    future::select(Box::pin(signal::ctrl_c()), Box::pin(terminate.recv()))
+        .await;
 }
 
 #[throws(anyhow::Error)]
 #[actix_rt::main]
 async fn main() {
-    env_logger::from_env(Env::default().default_filter_or("info")).init();
+    let opt: Opt = argh::from_env();
+    let pool_options = opt.pool_options();
+    let tls_config = opt.tls_config()?;
+    REQUIRE_CLIENT_CERT.store(opt.client_ca.is_some(), Ordering::Relaxed);
+    rate_limit::configure(opt.rate_limits())?;
+    circuit_breaker::configure(opt.circuit_breaker_settings())?;
+    REQUIRE_AUTH.store(opt.require_auth, Ordering::Relaxed);
+    REQUIRE_UI_LOGIN.store(opt.ui_login, Ordering::Relaxed);
+    set_slow_query_millis(opt.slow_query_millis);
+    set_request_timeout_millis(opt.request_timeout_millis);
+    if let Ok(admin_key) = env::var("JOBCLERK_ADMIN_KEY") {
+        if admin_key.is_empty() {
+            throw!(anyhow::anyhow!("JOBCLERK_ADMIN_KEY is empty"));
+        }
+        // This is the only place it's set
+        let _ = ADMIN_KEY.set(admin_key);
+    }
 
-    let pool = make_pool(DEFAULT_POSTGRES_PORT).await?;
+    let mut filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+    JSON_LOGS.store(opt.json_logs, Ordering::Relaxed);
+    // Only one of these is set
+    let (text_layer, json_layer) = if opt.json_logs {
+        // Requests are logged by assign_request_id instead of the text
+        // access log
+        filter =
+            filter.add_directive("actix_web::middleware::logger=off".parse()?);
+        let layer =
+            fmt::layer().json().flatten_event(true).with_span_list(true);
+        (None, Some(layer))
+    } else {
+        (Some(fmt::layer()), None)
+    };
+    let subscriber = tracing_subscriber::registry()
+        .with(filter)
+        .with(text_layer)
+        .with(json_layer);
+    // Traces stop being exported when this is dropped, so keep it
+    // around until the server exits
+    let _uninstall_tracer;
+    if let Some(agent) = &opt.jaeger_agent {
+        let (tracer, uninstall) = opentelemetry_jaeger::new_pipeline()
+            .with_service_name("jobclerk")
+            .with_agent_endpoint(agent)
+            .install()
+            .map_err(|err| {
+                anyhow::anyhow!("failed to install tracer: {}", err)
+            })?;
+        _uninstall_tracer = uninstall;
+        subscriber
+            .with(tracing_opentelemetry::layer().with_tracer(tracer))
+            .init();
+    } else {
+        subscriber.init();
+    }
+
+    let database_url = opt
+        .database_url
+        .clone()
+        .unwrap_or_else(database_url_from_env);
+    let pool = make_pool_with_options(&database_url, &pool_options).await?;
+    if !opt.no_migrate {
+        for version in migrate(&mut *pool.get().await?).await? {
+            info!("applied migration {}", version);
+        }
+    }
+    check_schema_version(&pool).await?;
+    notify::listen(&database_url).await?;
+    if let Some(redis_url) = &opt.redis_url {
+        connect_redis(redis_url).await?;
+    }
+    if let Some(issuer) = &opt.jwt_issuer {
+        configure_jwt(&opt, issuer).await?;
+    }
 
-    HttpServer::new(move || {
+    // The maintenance task finishes its current run before stopping,
+    // so that jobs aren't left half-handled during a deploy
+    let (stop_maintenance, mut maintenance_stopped) = oneshot::channel();
+    let maintenance_pool = pool.clone();
+    let maintenance = async move {
+        loop {
+            api::run_maintenance(&maintenance_pool).await;
+            let stop =
+                time::timeout(MAINTENANCE_INTERVAL, &mut maintenance_stopped);
+            if stop.await.is_ok() {
+                break;
+            }
+        }
+    };
+
+    let make_app = move || {
         App::new()
-            .wrap(middleware::Logger::default())
+            .wrap_fn(assign_request_id)
+            // The default format, plus the request ID
+            .wrap(middleware::Logger::new(
+                "%a \"%r\" %s %b \"%{Referer}i\" \"%{User-Agent}i\" %T \
+                 %{x-request-id}o",
+            ))
             .configure(app_config)
             .data(pool.clone())
-    })
-    .bind("127.0.0.1:8000")?
-    .run()
-    .await?;
+    };
+    // actix only stops gracefully on SIGTERM, so handle the signals
+    // here to treat SIGINT the same way
+    let server = match tls_config {
+        // HttpServer doesn't give handlers access to the connection, so
+        // build the service directly to get the client certificate
+        Some(tls_config) => actix_server::Server::build()
+            .disable_signals()
+            .bind("jobclerk-tls", BIND_ADDRESS, move || {
+                HttpService::build()
+                    .on_connect(get_client_identity)
+                    .finish(map_config(make_app(), |_| AppConfig::default()))
+                    .rustls(tls_config.clone())
+            })?
+            .run(),
+        None => HttpServer::new(make_app)
+            .disable_signals()
+            .bind(BIND_ADDRESS)?
+            .run(),
+    };
+    let server_handle = server.clone();
+    actix_rt::spawn(async move {
+        shutdown_signal().await;
+        info!("shutting down, waiting for in-flight requests");
+        // Stop accepting connections and wait for in-flight requests
+        server_handle.stop(true).await;
+    });
+    let server = async {
+        let result = server.await;
+        info!("server stopped, waiting for maintenance to finish");
+        // This only fails if maintenance has already stopped
+        let _ = stop_maintenance.send(());
+        result
+    };
+
+    let (result, ()) = future::join(server, maintenance).await;
+    result?;
+    // All of the pool's connections are closed when the last clone of
+    // the pool is dropped, which has happened by now
+    info!("shutdown complete");
 }
@@ -1,227 +1,2778 @@
-use crate::{Error, Pool};
+#[cfg(feature = "redis")]
+use crate::redis_queue;
+#[cfg(feature = "webhooks")]
+use crate::webhook;
+use crate::{
+    audit, auth, circuit_breaker, job_template, metrics, notify, organization,
+    rate_limit, request_timeout, workflow, Error, Pool, QueryExt,
+    RequestContext,
+};
 use fehler::{throw, throws};
+use futures::stream::{self, Stream};
 use jobclerk_types::*;
-use log::{error, info};
-use rand::distributions::Alphanumeric;
+use log::{error, info, warn};
+use rand::distributions::{Alphanumeric, Distribution, WeightedIndex};
 use rand::{thread_rng, Rng};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::time::{self, Instant};
+use tokio_postgres::error::SqlState;
 use tokio_postgres::types::ToSql;
+use tokio_postgres::{Client, Row, Transaction};
+use tracing::{field, info_span, Span};
+use tracing_futures::Instrument;
 
-fn make_random_string(length: usize) -> String {
+pub(crate) fn make_random_string(length: usize) -> String {
     thread_rng()
         .sample_iter(&Alphanumeric)
         .take(length)
         .collect()
 }
 
+/// Check that a take request's priority window isn't empty.
+#[throws]
+fn check_priority_window(min_priority: Option<i32>, max_priority: Option<i32>) {
+    if let (Some(min), Some(max)) = (min_priority, max_priority) {
+        if min > max {
+            throw!(Error::bad_request(
+                ErrorCode::InvalidArgument,
+                format!("min_priority {} is above max_priority {}", min, max)
+            ));
+        }
+    }
+}
+
+#[throws]
+fn check_max_runtime(max_runtime_millis: Option<i32>) {
+    if let Some(millis) = max_runtime_millis {
+        if millis <= 0 {
+            throw!(Error::invalid_argument("max_runtime_millis", millis));
+        }
+    }
+}
+
+/// Check a page's limit and get the number of rows to query for it.
+/// One more row than the limit is queried to find out if there's
+/// another page.
+#[throws]
+pub(crate) fn page_query_limit(page: &Page) -> Option<i64> {
+    match page.limit {
+        Some(limit) if limit <= 0 => {
+            throw!(Error::invalid_argument("limit", limit))
+        }
+        limit => limit.map(|limit| limit + 1),
+    }
+}
+
+/// Get the comparison operator and direction for a page's order.
+pub(crate) fn page_order(page: &Page) -> (&'static str, &'static str) {
+    match page.order {
+        SortOrder::Ascending => (">", "ASC"),
+        SortOrder::Descending => ("<", "DESC"),
+    }
+}
+
+/// Parse a page's cursor, which is the sort key of the last item on
+/// the previous page.
+#[throws]
+pub(crate) fn page_cursor<T: FromStr>(page: &Page) -> Option<T> {
+    match &page.cursor {
+        Some(cursor) => Some(cursor.0.parse().map_err(|_| {
+            Error::bad_request(
+                ErrorCode::InvalidCursor,
+                format!("invalid cursor: {}", cursor.0),
+            )
+        })?),
+        None => None,
+    }
+}
+
+/// Remove the extra row queried because of `page_query_limit`, and get
+/// the cursor for the next page if there is one.
+pub(crate) fn finish_page<T, K, F>(
+    items: &mut Vec<T>,
+    page: &Page,
+    key: F,
+) -> Option<Cursor>
+where
+    K: ToString,
+    F: Fn(&T) -> K,
+{
+    let limit = page.limit? as usize;
+    if items.len() > limit {
+        items.truncate(limit);
+        items.last().map(|item| Cursor(key(item).to_string()))
+    } else {
+        None
+    }
+}
+
+#[throws]
+fn check_retry_policy(retry_policy: &Option<RetryPolicy>) {
+    if let Some(policy) = retry_policy {
+        if policy.max_attempts <= 0 {
+            throw!(Error::invalid_argument(
+                "max_attempts",
+                policy.max_attempts
+            ));
+        }
+        if policy.delay_millis < 0 {
+            throw!(Error::invalid_argument(
+                "delay_millis",
+                policy.delay_millis
+            ));
+        }
+    }
+}
+
+/// Read a project's retry policy from a row containing the
+/// `max_attempts`, `retry_backoff` and `retry_delay_millis` columns,
+/// starting at `index`.
+#[throws]
+fn get_retry_policy(row: &Row, index: usize) -> Option<RetryPolicy> {
+    let max_attempts: Option<i32> = row.get(index);
+    let backoff: Option<String> = row.get(index + 1);
+    let delay_millis: Option<i32> = row.get(index + 2);
+    match (max_attempts, backoff, delay_millis) {
+        (Some(max_attempts), Some(backoff), Some(delay_millis)) => {
+            Some(RetryPolicy {
+                max_attempts,
+                backoff: backoff.parse()?,
+                delay_millis,
+            })
+        }
+        _ => None,
+    }
+}
+
+#[throws]
+fn check_retention_millis(retention_millis: Option<i64>) {
+    if let Some(millis) = retention_millis {
+        if millis < 0 {
+            throw!(Error::invalid_argument("retention_millis", millis));
+        }
+    }
+}
+
 #[throws]
 async fn add_project(
     pool: &Pool,
-    req: &AddProjectRequest,
-) -> AddProjectResponse {
-    if req.heartbeat_expiration_millis <= 0 {
-        throw!(Error::BadRequest(format!(
-            "invalid heartbeat_expiration_millis: {}",
-            req.heartbeat_expiration_millis
-        ),));
+    req: &AddProjectRequest,
+) -> AddProjectResponse {
+    if req.heartbeat_expiration_millis <= 0 {
+        throw!(Error::invalid_argument(
+            "heartbeat_expiration_millis",
+            req.heartbeat_expiration_millis
+        ));
+    }
+    check_max_runtime(req.max_runtime_millis)?;
+    check_retry_policy(&req.retry_policy)?;
+    check_retention_millis(req.retention_millis)?;
+    if let Some(weight) = req.weight {
+        if weight < 0 {
+            throw!(Error::invalid_argument("weight", weight));
+        }
+    }
+    if let Some(max_jobs) = req.max_jobs_per_runner {
+        if max_jobs <= 0 {
+            throw!(Error::invalid_argument("max_jobs_per_runner", max_jobs));
+        }
+    }
+    if let Some(max_bytes) = req.max_data_bytes {
+        if max_bytes <= 0 {
+            throw!(Error::invalid_argument("max_data_bytes", max_bytes));
+        }
+    }
+    if let Some(max_jobs) = req.max_unfinished_jobs {
+        if max_jobs <= 0 {
+            throw!(Error::invalid_argument("max_unfinished_jobs", max_jobs));
+        }
+    }
+    for (queue, weight) in &req.queue_weights {
+        check_queue_name(queue)?;
+        if *weight < 0 {
+            throw!(Error::invalid_argument("queue weight", weight));
+        }
+    }
+
+    let policy = req.retry_policy.as_ref();
+    let max_attempts = policy.map(|p| p.max_attempts);
+    let retry_backoff = policy.map(|p| p.backoff.as_ref());
+    let retry_delay_millis = policy.map(|p| p.delay_millis);
+
+    let conn = pool.get().await?;
+    let organization_id: Option<OrganizationId> = match &req.organization_name {
+        Some(name) => {
+            let row = conn
+                .query_opt(
+                    "SELECT id FROM organizations WHERE name = $1",
+                    &[name],
+                )
+                .instrument_query("get_organization_id")
+                .await?;
+            match row {
+                Some(row) => Some(row.get(0)),
+                None => {
+                    throw!(Error::invalid_argument("organization_name", name))
+                }
+            }
+        }
+        None => None,
+    };
+    let row = conn
+        .query_one(
+            "INSERT INTO projects (name, heartbeat_expiration_millis,
+                                   max_runtime_millis, max_attempts,
+                                   retry_backoff, retry_delay_millis,
+                                   max_jobs_per_runner, weight,
+                                   retention_millis, retention_archive,
+                                   max_data_bytes, max_unfinished_jobs,
+                                   queue_weights, deduplicate_data,
+                                   organization, data)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12,
+                     $13, $14, $15, $16)
+             RETURNING id",
+            &[
+                &req.name,
+                &req.heartbeat_expiration_millis,
+                &req.max_runtime_millis,
+                &max_attempts,
+                &retry_backoff,
+                &retry_delay_millis,
+                &req.max_jobs_per_runner,
+                &req.weight.unwrap_or(1),
+                &req.retention_millis,
+                &req.retention_archive,
+                &req.max_data_bytes,
+                &req.max_unfinished_jobs,
+                &serde_json::json!(req.queue_weights),
+                &req.deduplicate_data,
+                &organization_id,
+                &req.data,
+            ],
+        )
+        .instrument_query("add_project")
+        .await?;
+
+    AddProjectResponse {
+        project_id: row.get(0),
+    }
+}
+
+#[throws]
+async fn list_projects(
+    pool: &Pool,
+    req: &ListProjectsRequest,
+) -> ListProjectsResponse {
+    let (cmp, dir) = page_order(&req.page);
+    let after_name: Option<String> = page_cursor(&req.page)?;
+    let query = format!(
+        "SELECT projects.id, projects.name, organizations.name
+         FROM projects
+         LEFT JOIN organizations
+           ON organizations.id = projects.organization
+         WHERE ($1::TEXT IS NULL OR projects.name {} $1)
+           AND ($3::TEXT IS NULL OR organizations.name = $3)
+         ORDER BY projects.name {}
+         LIMIT $2",
+        cmp, dir
+    );
+
+    let conn = pool.get().await?;
+    let rows = conn
+        .query(
+            query.as_str(),
+            &[
+                &after_name,
+                &page_query_limit(&req.page)?,
+                &req.organization_name,
+            ],
+        )
+        .instrument_query("list_projects")
+        .await?;
+
+    let mut projects: Vec<_> = rows
+        .iter()
+        .map(|row| ProjectSummary {
+            id: row.get(0),
+            name: row.get(1),
+            organization_name: row.get(2),
+        })
+        .collect();
+    let next_cursor =
+        finish_page(&mut projects, &req.page, |project| project.name.clone());
+    ListProjectsResponse {
+        projects,
+        next_cursor,
+    }
+}
+
+#[throws]
+async fn get_project_stats(
+    pool: &Pool,
+    req: &GetProjectStatsRequest,
+) -> GetProjectStatsResponse {
+    if req.window_millis <= 0 {
+        throw!(Error::invalid_argument("window_millis", req.window_millis));
+    }
+
+    let conn = pool.get().await?;
+    let rows = conn
+        .query(
+            "SELECT id FROM projects WHERE name = $1",
+            &[&req.project_name],
+        )
+        .instrument_query("get_project_id")
+        .await?;
+    if rows.is_empty() {
+        throw!(Error::NotFound);
+    }
+    let project_id: ProjectId = rows[0].get(0);
+
+    let rows = conn
+        .query(
+            "SELECT state, COUNT(*) FROM jobs WHERE project = $1
+             GROUP BY state",
+            &[&project_id],
+        )
+        .instrument_query("count_jobs_by_state")
+        .await?;
+    let job_counts = rows
+        .iter()
+        .map(|row| -> Result<(JobState, i64), Error> {
+            let state: String = row.get(0);
+            Ok((state.parse()?, row.get(1)))
+        })
+        .collect::<Result<_, _>>()?;
+
+    let rows = conn
+        .query(
+            "SELECT state, COUNT(*) FROM jobs
+             WHERE project = $1
+               AND finished > COALESCE($3, CURRENT_TIMESTAMP) -
+                              $2::BIGINT * INTERVAL '1 millisecond'
+               AND finished <= COALESCE($3, CURRENT_TIMESTAMP)
+             GROUP BY state",
+            &[&project_id, &req.window_millis, &req.window_end],
+        )
+        .instrument_query("count_finished_jobs_by_state")
+        .await?;
+    let finished_counts = rows
+        .iter()
+        .map(|row| -> Result<(JobState, i64), Error> {
+            let state: String = row.get(0);
+            Ok((state.parse()?, row.get(1)))
+        })
+        .collect::<Result<_, _>>()?;
+
+    let row = conn
+        .query_one(
+            include_str!("../../db/query_project_stats.sql"),
+            &[&project_id, &req.window_millis, &req.window_end],
+        )
+        .instrument_query("project_stats")
+        .await?;
+
+    GetProjectStatsResponse {
+        job_counts,
+        finished_counts,
+        avg_queue_wait_millis: row.get(0),
+        avg_run_duration_millis: row.get(1),
+        p50_run_duration_millis: row.get(2),
+        p95_run_duration_millis: row.get(3),
+    }
+}
+
+#[throws]
+async fn get_job(pool: &Pool, req: &GetJobRequest) -> GetJobResponse {
+    let conn = pool.get().await?;
+    let rows = conn
+        .query(
+            "SELECT id, project, state, created, started, finished, priority,
+                    attempts, queue, data, parent
+             FROM jobs
+             WHERE project = (SELECT id FROM projects WHERE name = $1)
+               AND id = $2",
+            &[&req.project_name, &req.job_id],
+        )
+        .instrument_query("get_job")
+        .await?;
+
+    if rows.is_empty() {
+        throw!(Error::NotFound);
+    }
+    let row = &rows[0];
+    let state: String = row.get(2);
+    let job = Job {
+        id: row.get(0),
+        project_name: req.project_name.clone(),
+        project_id: row.get(1),
+        state: state.parse()?,
+        created: row.get(3),
+        started: row.get(4),
+        finished: row.get(5),
+        priority: row.get(6),
+        attempts: row.get(7),
+        queue: row.get(8),
+        data: row.get(9),
+    };
+    let parent_id = row.get(10);
+
+    let steps = conn
+        .query(
+            "SELECT name, state, started, finished
+             FROM job_steps WHERE job = $1
+             ORDER BY position",
+            &[&req.job_id],
+        )
+        .instrument_query("get_job_steps")
+        .await?
+        .iter()
+        .map(|row| -> Result<JobStep, Error> {
+            let state: String = row.get(1);
+            Ok(JobStep {
+                name: row.get(0),
+                state: state.parse()?,
+                started: row.get(2),
+                finished: row.get(3),
+            })
+        })
+        .collect::<Result<Vec<JobStep>, _>>()?;
+
+    let artifacts = conn
+        .query(
+            "SELECT name, url, size, content_type, created
+             FROM job_artifacts WHERE job = $1
+             ORDER BY name",
+            &[&req.job_id],
+        )
+        .instrument_query("get_job_artifacts")
+        .await?
+        .iter()
+        .map(|row| JobArtifact {
+            name: row.get(0),
+            url: row.get(1),
+            size: row.get(2),
+            content_type: row.get(3),
+            created: row.get(4),
+        })
+        .collect();
+
+    let child_ids = conn
+        .query(
+            "SELECT id FROM jobs WHERE parent = $1 ORDER BY id",
+            &[&req.job_id],
+        )
+        .instrument_query("get_child_jobs")
+        .await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+
+    GetJobResponse {
+        job,
+        steps,
+        artifacts,
+        parent_id,
+        child_ids,
+    }
+}
+
+/// SQL conditions for a `JobFilter`, starting with `AND`. They use
+/// the parameters from `JobFilterParams::params`, numbered from
+/// `first`.
+pub(crate) fn job_filter_sql(first: usize) -> String {
+    format!(
+        "AND (${0}::TEXT[] IS NULL OR state = ANY(${0}))
+         AND (${1}::TEXT IS NULL OR runner = ${1})
+         AND requirements @> ${2}::TEXT[]
+         AND (${3}::TIMESTAMPTZ IS NULL OR created >= ${3})
+         AND (${4}::TIMESTAMPTZ IS NULL OR created < ${4})
+         AND (${5}::JSONB IS NULL OR data @> ${5})
+         AND (${6}::TEXT IS NULL OR
+              strpos(lower(data::TEXT), lower(${6})) > 0)",
+        first,
+        first + 1,
+        first + 2,
+        first + 3,
+        first + 4,
+        first + 5,
+        first + 6,
+    )
+}
+
+/// Query parameters for a `JobFilter`.
+pub(crate) struct JobFilterParams<'a> {
+    filter: &'a JobFilter,
+    states: Option<Vec<&'a str>>,
+}
+
+impl<'a> JobFilterParams<'a> {
+    #[throws]
+    pub(crate) fn new(filter: &'a JobFilter) -> JobFilterParams<'a> {
+        if filter.states.contains(&JobState::Unknown) {
+            throw!(Error::invalid_argument("state", JobState::Unknown));
+        }
+        let states = if filter.states.is_empty() {
+            None
+        } else {
+            Some(filter.states.iter().map(AsRef::as_ref).collect())
+        };
+        JobFilterParams { filter, states }
+    }
+
+    /// Parameters in the order used by `job_filter_sql`.
+    pub(crate) fn params(&self) -> Vec<&(dyn ToSql + Sync)> {
+        vec![
+            &self.states,
+            &self.filter.runner,
+            &self.filter.requirements,
+            &self.filter.created_after,
+            &self.filter.created_before,
+            &self.filter.data_filter,
+            &self.filter.data_search,
+        ]
+    }
+}
+
+#[throws]
+async fn get_jobs(pool: &Pool, req: &GetJobsRequest) -> GetJobsResponse {
+    let (cmp, dir) = page_order(&req.page);
+    let after_id: Option<JobId> = page_cursor(&req.page)?;
+    let filter = JobFilterParams::new(&req.filter)?;
+    let data_column = if req.exclude_data {
+        "'null'::JSONB"
+    } else {
+        "data"
+    };
+    let query = format!(
+        "SELECT id, project, state, created, started, finished, priority,
+                attempts, queue, {}
+         FROM jobs
+         WHERE project = (SELECT id FROM projects WHERE name = $1)
+           AND ($2::BIGINT IS NULL OR id {} $2)
+           {}
+         ORDER BY id {}
+         LIMIT $3",
+        data_column,
+        cmp,
+        job_filter_sql(4),
+        dir
+    );
+    let limit = page_query_limit(&req.page)?;
+    let mut params: Vec<&(dyn ToSql + Sync)> =
+        vec![&req.project_name, &after_id, &limit];
+    params.extend(filter.params());
+
+    let conn = pool.get().await?;
+    let rows = conn
+        .query(query.as_str(), &params)
+        .instrument_query("get_jobs")
+        .await?;
+
+    let mut jobs = rows
+        .iter()
+        .map(|row| -> Result<Job, Error> {
+            let state: String = row.get(2);
+            Ok(Job {
+                id: row.get(0),
+                project_name: req.project_name.clone(),
+                project_id: row.get(1),
+                state: state.parse()?,
+                created: row.get(3),
+                started: row.get(4),
+                finished: row.get(5),
+                priority: row.get(6),
+                attempts: row.get(7),
+                queue: row.get(8),
+                data: row.get(9),
+            })
+        })
+        .collect::<Result<Vec<Job>, _>>()?;
+
+    let next_cursor = finish_page(&mut jobs, &req.page, |job| job.id);
+    GetJobsResponse { jobs, next_cursor }
+}
+
+#[throws]
+async fn get_job_history(
+    pool: &Pool,
+    req: &GetJobHistoryRequest,
+) -> GetJobHistoryResponse {
+    let conn = pool.get().await?;
+    let rows = conn
+        .query(
+            "SELECT job_events.old_state, job_events.new_state,
+                    job_events.runner, job_events.created
+             FROM job_events
+             JOIN jobs ON jobs.id = job_events.job
+             WHERE jobs.project = (SELECT id FROM projects WHERE name = $1)
+               AND jobs.id = $2
+             ORDER BY job_events.id",
+            &[&req.project_name, &req.job_id],
+        )
+        .instrument_query("get_job_history")
+        .await?;
+
+    // Every job has at least one event from when it was added
+    if rows.is_empty() {
+        throw!(Error::NotFound);
+    }
+
+    let events = rows
+        .iter()
+        .map(|row| -> Result<JobEvent, Error> {
+            let old_state: Option<String> = row.get(0);
+            let new_state: String = row.get(1);
+            Ok(JobEvent {
+                old_state: old_state.map(|s| s.parse()).transpose()?,
+                new_state: new_state.parse()?,
+                runner: row.get(2),
+                time: row.get(3),
+            })
+        })
+        .collect::<Result<Vec<JobEvent>, _>>()?;
+
+    GetJobHistoryResponse { events }
+}
+
+#[throws]
+async fn get_job_attempts(
+    pool: &Pool,
+    req: &GetJobAttemptsRequest,
+) -> GetJobAttemptsResponse {
+    let conn = pool.get().await?;
+    let job = conn
+        .query_opt(
+            "SELECT 1 FROM jobs
+             WHERE project = (SELECT id FROM projects WHERE name = $1)
+               AND id = $2",
+            &[&req.project_name, &req.job_id],
+        )
+        .instrument_query("get_job_project")
+        .await?;
+    if job.is_none() {
+        throw!(Error::NotFound);
+    }
+
+    let attempts = conn
+        .query(
+            "SELECT attempt, runner, started, finished, outcome
+             FROM job_attempts WHERE job = $1
+             ORDER BY attempt",
+            &[&req.job_id],
+        )
+        .instrument_query("get_job_attempts")
+        .await?
+        .iter()
+        .map(|row| -> Result<JobAttempt, Error> {
+            let outcome: Option<String> = row.get(4);
+            Ok(JobAttempt {
+                attempt: row.get(0),
+                runner: row.get(1),
+                started: row.get(2),
+                finished: row.get(3),
+                outcome: outcome.map(|s| s.parse()).transpose()?,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    GetJobAttemptsResponse { attempts }
+}
+
+/// Maximum size of a job's data as JSON, in bytes, for projects that
+/// don't set their own limit.
+const DEFAULT_MAX_DATA_BYTES: i32 = 1024 * 1024;
+
+/// Check that job data isn't too large for the project.
+#[throws]
+pub(crate) async fn check_data_size(
+    conn: &Client,
+    project_name: &str,
+    data: &serde_json::Value,
+) {
+    let size = serde_json::to_string(data)
+        .map_err(|err| Error::invalid_argument("data", err))?
+        .len();
+    let row = conn
+        .query_opt(
+            "SELECT max_data_bytes FROM projects WHERE name = $1",
+            &[&project_name],
+        )
+        .instrument_query("get_max_data_bytes")
+        .await?;
+    // A missing project is reported by the request itself
+    if let Some(row) = row {
+        let max_bytes = row
+            .get::<_, Option<i32>>(0)
+            .unwrap_or(DEFAULT_MAX_DATA_BYTES);
+        if size > max_bytes as usize {
+            throw!(Error::bad_request(
+                ErrorCode::DataTooLarge,
+                format!(
+                    "data is {} bytes, but the maximum is {}",
+                    size, max_bytes
+                )
+            ));
+        }
+    }
+}
+
+/// Check that a queue name isn't empty.
+#[throws]
+fn check_queue_name(queue: &str) {
+    if queue.is_empty() {
+        throw!(Error::bad_request(
+            ErrorCode::InvalidArgument,
+            "queue names can't be empty".into()
+        ));
+    }
+}
+
+/// Check the fields of a request to add a job.
+#[throws]
+pub(crate) fn check_add_job(req: &AddJobRequest) {
+    check_max_runtime(req.max_runtime_millis)?;
+    check_queue_name(job_queue(req))?;
+    if let Some(millis) = req.expires_after_millis {
+        if millis <= 0 {
+            throw!(Error::invalid_argument("expires_after_millis", millis));
+        }
+    }
+
+    for (i, step) in req.steps.iter().enumerate() {
+        if req.steps[..i].contains(step) {
+            throw!(Error::bad_request(
+                ErrorCode::DuplicateStep,
+                format!("duplicate step: {}", step)
+            ));
+        }
+    }
+}
+
+/// Queue a new job goes in.
+fn job_queue(req: &AddJobRequest) -> &str {
+    req.queue.as_deref().unwrap_or(DEFAULT_QUEUE)
+}
+
+#[throws]
+pub(crate) async fn add_job(
+    pool: &Pool,
+    req: &AddJobRequest,
+) -> AddJobResponse {
+    check_add_job(req)?;
+
+    let mut conn = pool.get().await?;
+    check_data_size(&conn, &req.project_name, &req.data).await?;
+    let txn = conn.transaction().await?;
+    let job_id = add_job_in_transaction(&txn, req, None).await?;
+    txn.commit().await?;
+    AddJobResponse { job_id }
+}
+
+/// Add a job whose request has been checked, or get the existing job
+/// the request matches.
+#[throws]
+pub(crate) async fn add_job_in_transaction(
+    txn: &Transaction<'_>,
+    req: &AddJobRequest,
+    parent: Option<JobId>,
+) -> JobId {
+    match find_duplicate_job(txn, req).await? {
+        Some(job_id) => job_id,
+        None => insert_job(txn, req, parent).await?,
+    }
+}
+
+#[throws]
+async fn add_child_job(
+    pool: &Pool,
+    req: &AddChildJobRequest,
+) -> AddJobResponse {
+    if req.child.project_name != req.project_name {
+        throw!(Error::bad_request(
+            ErrorCode::InvalidArgument,
+            format!(
+                "child job is in project {} but its parent is in project {}",
+                req.child.project_name, req.project_name
+            )
+        ));
+    }
+    check_add_job(&req.child)?;
+
+    let mut conn = pool.get().await?;
+    check_data_size(&conn, &req.project_name, &req.child.data).await?;
+    let txn = conn.transaction().await?;
+    // Lock the parent so that it can't finish while the child is added
+    let row = txn
+        .query_opt(
+            "SELECT id FROM jobs
+             WHERE project = (SELECT id FROM projects WHERE name = $1)
+               AND id = $2
+               AND token = $3
+               AND state IN ('activating', 'running', 'canceling')
+             FOR SHARE",
+            &[&req.project_name, &req.job_id, &req.token],
+        )
+        .instrument_query("lock_parent_job")
+        .await?;
+    if row.is_none() {
+        throw!(Error::NotFound);
+    }
+    let job_id =
+        add_job_in_transaction(&txn, &req.child, Some(req.job_id)).await?;
+    txn.commit().await?;
+    AddJobResponse { job_id }
+}
+
+/// Largest number of jobs a batch can have.
+const MAX_BATCH_JOBS: usize = 1000;
+
+#[throws]
+async fn add_batch(pool: &Pool, req: &AddBatchRequest) -> AddBatchResponse {
+    if req.jobs.is_empty() || req.jobs.len() > MAX_BATCH_JOBS {
+        throw!(Error::bad_request(
+            ErrorCode::InvalidArgument,
+            format!("a batch must have 1 to {} jobs", MAX_BATCH_JOBS)
+        ));
+    }
+    if let Some(url) = &req.webhook_url {
+        check_webhook_url(url)?;
+    }
+    for job in &req.jobs {
+        if job.project_name != req.project_name {
+            throw!(Error::bad_request(
+                ErrorCode::InvalidArgument,
+                format!(
+                    "job is in project {} but the batch is in project {}",
+                    job.project_name, req.project_name
+                )
+            ));
+        }
+        check_add_job(job)?;
+    }
+
+    let mut conn = pool.get().await?;
+    for job in &req.jobs {
+        check_data_size(&conn, &job.project_name, &job.data).await?;
+    }
+    let txn = conn.transaction().await?;
+    let row = txn
+        .query_opt(
+            "INSERT INTO batches (project, webhook_url)
+             SELECT id, $2 FROM projects WHERE name = $1
+             RETURNING id",
+            &[&req.project_name, &req.webhook_url],
+        )
+        .instrument_query("add_batch")
+        .await?;
+    let batch_id: BatchId = match row {
+        Some(row) => row.get(0),
+        None => throw!(Error::NotFound),
+    };
+    let mut job_ids = Vec::with_capacity(req.jobs.len());
+    for job in &req.jobs {
+        job_ids.push(add_job_in_transaction(&txn, job, None).await?);
+    }
+    // The same job can be returned for more than one of the requests
+    txn.execute(
+        "INSERT INTO batch_jobs (batch, job)
+         SELECT $1, unnest($2::BIGINT[])
+         ON CONFLICT DO NOTHING",
+        &[&batch_id, &job_ids],
+    )
+    .instrument_query("add_batch_jobs")
+    .await?;
+    txn.commit().await?;
+
+    AddBatchResponse { batch_id, job_ids }
+}
+
+/// Check that a batch's webhook URL can be sent to.
+#[cfg(feature = "webhooks")]
+#[throws]
+fn check_webhook_url(url: &str) {
+    if !(url.starts_with("http://") || url.starts_with("https://")) {
+        throw!(Error::invalid_argument("webhook_url", url));
+    }
+}
+
+#[cfg(not(feature = "webhooks"))]
+fn check_webhook_url(_url: &str) -> Result<(), Error> {
+    Err(Error::bad_request(
+        ErrorCode::InvalidArgument,
+        "the server was built without webhook support".into(),
+    ))
+}
+
+/// Get a batch's progress, or `None` if the project doesn't have the
+/// batch.
+#[throws]
+pub(crate) async fn load_batch(
+    conn: &Client,
+    project_name: &str,
+    batch_id: BatchId,
+) -> Option<Batch> {
+    let row = conn
+        .query_opt(
+            "SELECT batches.created FROM batches
+             JOIN projects ON projects.id = batches.project
+             WHERE projects.name = $1 AND batches.id = $2",
+            &[&project_name, &batch_id],
+        )
+        .instrument_query("get_batch")
+        .await?;
+    match row {
+        Some(row) => {
+            let rows = conn
+                .query(
+                    "SELECT jobs.state, COUNT(*) FROM batch_jobs
+                     JOIN jobs ON jobs.id = batch_jobs.job
+                     WHERE batch_jobs.batch = $1
+                     GROUP BY jobs.state",
+                    &[&batch_id],
+                )
+                .instrument_query("count_batch_jobs_by_state")
+                .await?;
+            let job_counts = rows
+                .iter()
+                .map(|row| -> Result<(JobState, i64), Error> {
+                    let state: String = row.get(0);
+                    Ok((state.parse()?, row.get(1)))
+                })
+                .collect::<Result<BTreeMap<_, _>, _>>()?;
+            let finished = job_counts.keys().all(JobState::is_terminal);
+            Some(Batch {
+                id: batch_id,
+                project_name: project_name.into(),
+                created: row.get(0),
+                job_counts,
+                finished,
+            })
+        }
+        None => None,
+    }
+}
+
+#[throws]
+async fn get_batch(pool: &Pool, req: &GetBatchRequest) -> GetBatchResponse {
+    let conn = pool.get().await?;
+    match load_batch(&conn, &req.project_name, req.batch_id).await? {
+        Some(batch) => GetBatchResponse { batch },
+        None => throw!(Error::NotFound),
+    }
+}
+
+/// Names of the terminal job states, for SQL parameters.
+pub(crate) fn terminal_states() -> Vec<&'static str> {
+    JobState::TERMINAL.iter().map(AsRef::as_ref).collect()
+}
+
+/// Check that a job can leave its terminal state without there being
+/// two active jobs with its unique key.
+#[throws]
+async fn check_unique_key_free(txn: &Transaction<'_>, job_id: JobId) {
+    let row = txn
+        .query_opt(
+            "SELECT other.id, other.unique_key
+             FROM jobs AS job
+             JOIN jobs AS other ON other.project = job.project
+                               AND other.unique_key = job.unique_key
+             WHERE job.id = $1
+               AND other.id != job.id
+               AND other.state != ALL($2)
+             LIMIT 1",
+            &[&job_id, &terminal_states()],
+        )
+        .instrument_query("get_unique_key_job")
+        .await?;
+    if let Some(row) = row {
+        throw!(Error::bad_request(
+            ErrorCode::InvalidState,
+            format!(
+                "job {} with unique key {} is still active",
+                row.get::<_, JobId>(0),
+                row.get::<_, &str>(1)
+            )
+        ));
+    }
+}
+
+/// Find an available job with the same data as the new job, if its
+/// project deduplicates jobs by their data. This holds a lock on the
+/// data's hash until the transaction ends, so that two requests
+/// adding the same data at once don't both add a job.
+#[throws]
+async fn find_duplicate_job(
+    txn: &Transaction<'_>,
+    req: &AddJobRequest,
+) -> Option<JobId> {
+    let row = txn
+        .query_opt(
+            "SELECT deduplicate_data FROM projects WHERE name = $1",
+            &[&req.project_name],
+        )
+        .instrument_query("get_deduplicate_data")
+        .await?;
+    // A missing project is reported when the job is inserted
+    let deduplicate = row.map(|row| row.get(0)).unwrap_or(false);
+    if deduplicate {
+        txn.execute(
+            "SELECT pg_advisory_xact_lock(
+               hashtext($1), hashtext(md5($2::JSONB::TEXT)))",
+            &[&req.project_name, &req.data],
+        )
+        .instrument_query("lock_job_data_hash")
+        .await?;
+        let row = txn
+            .query_opt(
+                "SELECT id FROM jobs
+                 WHERE project = (SELECT id FROM projects WHERE name = $1)
+                   AND state = 'available'
+                   AND md5(data::TEXT) = md5($2::JSONB::TEXT)
+                 ORDER BY id
+                 LIMIT 1",
+                &[&req.project_name, &req.data],
+            )
+            .instrument_query("get_duplicate_job")
+            .await?;
+        row.map(|row| row.get(0))
+    } else {
+        None
+    }
+}
+
+/// Check that the project doesn't have more unfinished jobs than it
+/// allows. This is called after a job is inserted, so that requests
+/// that get an existing job back aren't rejected.
+#[throws]
+async fn check_unfinished_jobs(txn: &Transaction<'_>, project_name: &str) {
+    let row = txn
+        .query_one(
+            "SELECT max_unfinished_jobs FROM projects WHERE name = $1",
+            &[&project_name],
+        )
+        .instrument_query("get_max_unfinished_jobs")
+        .await?;
+    if let Some(max_jobs) = row.get::<_, Option<i32>>(0) {
+        // Lock the project so that jobs added at the same time are
+        // counted one after the other. This doesn't block adding jobs
+        // to the project, which only needs a key share lock. The count
+        // has to be a separate statement to see jobs committed while
+        // waiting for the lock.
+        txn.execute(
+            "SELECT id FROM projects WHERE name = $1 FOR NO KEY UPDATE",
+            &[&project_name],
+        )
+        .instrument_query("lock_project")
+        .await?;
+        let count: i64 = txn
+            .query_one(
+                "SELECT COUNT(*) FROM jobs
+                 WHERE project = (SELECT id FROM projects WHERE name = $1)
+                   AND state IN
+                     ('available', 'activating', 'running', 'canceling')",
+                &[&project_name],
+            )
+            .instrument_query("count_unfinished_jobs")
+            .await?
+            .get(0);
+        if count > max_jobs as i64 {
+            throw!(Error::bad_request(
+                ErrorCode::QuotaExceeded,
+                format!(
+                    "project {} already has the maximum of {} unfinished \
+                     jobs",
+                    project_name, max_jobs
+                )
+            ));
+        }
+    }
+}
+
+/// Insert a new job and its steps. If a job with the same idempotency
+/// key, or an active job with the same unique key, already exists,
+/// its ID is returned instead.
+#[throws]
+async fn insert_job(
+    txn: &Transaction<'_>,
+    req: &AddJobRequest,
+    parent: Option<JobId>,
+) -> JobId {
+    let rows = txn
+        .query(
+            "INSERT INTO jobs (project, max_runtime_millis, idempotency_key,
+                              requirements, expires_at, data, priority,
+                              queue, unique_key, parent)
+             VALUES ((SELECT id FROM projects WHERE name = $1),
+                     $2, $3, $4,
+                     CURRENT_TIMESTAMP +
+                         $5::BIGINT * INTERVAL '1 millisecond',
+                     $6, $7, $8, $9, $10)
+             ON CONFLICT DO NOTHING
+             RETURNING id",
+            &[
+                &req.project_name,
+                &req.max_runtime_millis,
+                &req.idempotency_key,
+                &req.requirements,
+                &req.expires_after_millis,
+                &req.data,
+                &req.priority,
+                &job_queue(req),
+                &req.unique_key,
+                &parent,
+            ],
+        )
+        .instrument_query("add_job")
+        .await?;
+
+    if rows.is_empty() {
+        // A job with the same idempotency key, or an active job with
+        // the same unique key, already exists
+        let row = txn
+            .query_one(
+                "SELECT id FROM jobs
+                 WHERE project = (SELECT id FROM projects WHERE name = $1)
+                   AND (idempotency_key = $2 OR
+                        (unique_key = $3 AND state != ALL($4)))
+                 ORDER BY idempotency_key = $2 DESC NULLS LAST
+                 LIMIT 1",
+                &[
+                    &req.project_name,
+                    &req.idempotency_key,
+                    &req.unique_key,
+                    &terminal_states(),
+                ],
+            )
+            .instrument_query("get_existing_job")
+            .await?;
+        row.get(0)
+    } else {
+        check_unfinished_jobs(txn, &req.project_name).await?;
+        let job_id = rows[0].get(0);
+        txn.execute(
+            "INSERT INTO job_steps (job, position, name)
+             SELECT $1, position, name
+             FROM unnest($2::TEXT[]) WITH ORDINALITY AS steps (name, position)",
+            &[&job_id, &req.steps],
+        )
+        .instrument_query("add_job_steps")
+        .await?;
+        if !req.depends_on.is_empty() {
+            let found: Vec<JobId> = txn
+                .query(
+                    "INSERT INTO job_dependencies (job, depends_on)
+                     SELECT $1, id FROM jobs
+                     WHERE id = ANY($2)
+                       AND project = (SELECT project FROM jobs WHERE id = $1)
+                     RETURNING depends_on",
+                    &[&job_id, &req.depends_on],
+                )
+                .instrument_query("add_job_dependencies")
+                .await?
+                .iter()
+                .map(|row| row.get(0))
+                .collect();
+            if let Some(missing) =
+                req.depends_on.iter().find(|id| !found.contains(id))
+            {
+                throw!(Error::invalid_argument("depends_on", missing));
+            }
+        }
+        job_id
+    }
+}
+
+/// Longest time a request to take a job can wait for one to become
+/// available.
+const MAX_WAIT_MILLIS: i32 = 60_000;
+
+/// Get the time to stop waiting for a job to become available, if the
+/// request asked to wait.
+#[throws]
+fn get_wait_deadline(wait_millis: Option<i32>) -> Option<Instant> {
+    match wait_millis {
+        Some(millis) if millis <= 0 || millis > MAX_WAIT_MILLIS => {
+            throw!(Error::invalid_argument("wait_millis", millis))
+        }
+        Some(millis) => {
+            Some(Instant::now() + Duration::from_millis(millis as u64))
+        }
+        None => None,
+    }
+}
+
+/// Take ownership of up to `max_jobs` available jobs.
+///
+/// This gets the highest priority jobs with the oldest creation that
+/// are available for this project and marks them as running. The
+/// jobs' runner is set to the input runner, and a unique token is
+/// generated for each job so that the runner can send updates.
+/// (Updates that do not include the correct token are rejected.)
+///
+/// If no jobs are available and the request sets `wait_millis`, this
+/// waits for a job to be added to the project and tries again.
+#[throws]
+async fn take_jobs(
+    pool: &Pool,
+    req: &TakeJobRequest,
+    max_jobs: i32,
+) -> Vec<TakeJobResponseJob> {
+    if max_jobs <= 0 {
+        throw!(Error::invalid_argument("max_jobs", max_jobs));
+    }
+    if let Some(millis) = req.lease_millis {
+        if millis <= 0 {
+            throw!(Error::invalid_argument("lease_millis", millis));
+        }
+    }
+    if let Some(millis) = req.first_heartbeat_millis {
+        if millis <= 0 {
+            throw!(Error::invalid_argument("first_heartbeat_millis", millis));
+        }
+    }
+    check_priority_window(req.min_priority, req.max_priority)?;
+    let deadline = get_wait_deadline(req.wait_millis)?;
+    let project_names = [req.project_name.clone()];
+
+    loop {
+        let mut subscription = notify::subscribe();
+        let jobs = try_take_jobs(pool, req, max_jobs).await?;
+        match deadline {
+            Some(deadline) if jobs.is_empty() && Instant::now() < deadline => {
+                subscription.wait_for_job(&project_names, deadline).await
+            }
+            _ => break jobs,
+        }
+    }
+}
+
+/// Take up to `max_jobs` jobs without waiting.
+#[cfg(feature = "redis")]
+#[throws]
+async fn try_take_jobs(
+    pool: &Pool,
+    req: &TakeJobRequest,
+    max_jobs: i32,
+) -> Vec<TakeJobResponseJob> {
+    match redis_queue::get() {
+        Some(queue) => queue.take_jobs(pool, req, max_jobs).await?,
+        None => take_jobs_from_queues(pool, req, max_jobs).await?,
+    }
+}
+
+/// Take up to `max_jobs` jobs without waiting.
+#[cfg(not(feature = "redis"))]
+#[throws]
+async fn try_take_jobs(
+    pool: &Pool,
+    req: &TakeJobRequest,
+    max_jobs: i32,
+) -> Vec<TakeJobResponseJob> {
+    take_jobs_from_queues(pool, req, max_jobs).await?
+}
+
+/// Take up to `max_jobs` jobs, interleaving the project's queues
+/// according to its queue weights.
+///
+/// Each job is taken from a queue picked at random, with the chance
+/// of a queue being picked proportional to its weight. Only queues
+/// with a job the runner could take are considered. If the project
+/// has no queue weights, jobs are taken by priority regardless of
+/// their queue.
+#[throws]
+pub(crate) async fn take_jobs_from_queues(
+    pool: &Pool,
+    req: &TakeJobRequest,
+    max_jobs: i32,
+) -> Vec<TakeJobResponseJob> {
+    let conn = pool.get().await?;
+    let rows = conn
+        .query(
+            "SELECT DISTINCT jobs.queue,
+                    COALESCE((projects.queue_weights ->> jobs.queue)::INT, 1)
+             FROM jobs
+             JOIN projects ON projects.id = jobs.project
+             WHERE projects.name = $1
+               AND projects.queue_weights != '{}'
+               AND jobs.state = 'available'
+               AND jobs.available_at <= CURRENT_TIMESTAMP
+               AND (jobs.expires_at IS NULL OR
+                    jobs.expires_at > CURRENT_TIMESTAMP)
+               AND jobs.requirements <@ $2::TEXT[]
+               AND ($3::INT IS NULL OR jobs.priority >= $3)
+               AND ($4::INT IS NULL OR jobs.priority <= $4)
+               AND job_dependencies_met(jobs.id)",
+            &[
+                &req.project_name,
+                &req.capabilities,
+                &req.min_priority,
+                &req.max_priority,
+            ],
+        )
+        .instrument_query("get_take_queues")
+        .await?;
+    drop(conn);
+    let mut queues: Vec<(String, i32)> =
+        rows.iter().map(|row| (row.get(0), row.get(1))).collect();
+
+    let mut jobs = Vec::new();
+    if queues.is_empty() {
+        jobs = take_jobs_from_db(pool, req, max_jobs, None, None).await?;
+    }
+    while (jobs.len() as i32) < max_jobs && !queues.is_empty() {
+        let mut taken = false;
+        for queue in weighted_shuffle(queues.clone()) {
+            let job = take_jobs_from_db(pool, req, 1, None, Some(&queue))
+                .await?
+                .pop();
+            if let Some(job) = job {
+                jobs.push(job);
+                taken = true;
+                break;
+            }
+            // Another runner took the queue's last job, or the runner
+            // is at the project's limit
+            queues.retain(|(name, _)| *name != queue);
+        }
+        if !taken {
+            break;
+        }
+    }
+    jobs
+}
+
+/// Take up to `max_jobs` jobs by searching the project's available
+/// jobs in the database. If `job_ids` is set, only those jobs are
+/// considered, and if `queue` is set, only jobs in that queue are.
+///
+/// The jobs are locked with `SKIP LOCKED` and activated in the same
+/// transaction, so runners taking jobs at the same time never get the
+/// same job and don't wait on each other's locks.
+#[throws]
+pub(crate) async fn take_jobs_from_db(
+    pool: &Pool,
+    req: &TakeJobRequest,
+    max_jobs: i32,
+    job_ids: Option<&[JobId]>,
+    queue: Option<&str>,
+) -> Vec<TakeJobResponseJob> {
+    let mut conn = pool.get().await?;
+    let txn = conn.transaction().await?;
+
+    // Requests from the same runner take jobs one after the other, so
+    // that together they can't go over the project's limit on the
+    // runner's concurrent jobs. Other runners aren't held up.
+    txn.execute(
+        "SELECT pg_advisory_xact_lock(hashtext($1), hashtext($2))",
+        &[&req.project_name, &req.runner],
+    )
+    .instrument_query("lock_runner")
+    .await?;
+
+    let taken_ids: Vec<JobId> = txn
+        .query(
+            include_str!("../../db/query_take_job.sql"),
+            &[
+                &req.project_name,
+                &req.runner,
+                &max_jobs,
+                &req.capabilities,
+                &job_ids,
+                &req.min_priority,
+                &req.max_priority,
+                &queue,
+            ],
+        )
+        .instrument_query("take_jobs")
+        .await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    let tokens: Vec<String> =
+        taken_ids.iter().map(|_| make_random_string(16)).collect();
+
+    if !taken_ids.is_empty() {
+        txn.execute(
+            "UPDATE jobs
+             SET state = 'activating',
+                 runner = $3,
+                 started = CURRENT_TIMESTAMP,
+                 heartbeat = CURRENT_TIMESTAMP,
+                 lease_millis = $4,
+                 -- The runner may have asked for longer to send its
+                 -- first heartbeat
+                 lease_expires_at = CURRENT_TIMESTAMP + COALESCE(
+                   $5, $4, projects.heartbeat_expiration_millis
+                 ) * INTERVAL '1 millisecond',
+                 token = taken.token,
+                 attempts = attempts + 1
+             FROM unnest($1::BIGINT[], $2::TEXT[]) AS taken (id, token),
+                  projects
+             WHERE jobs.id = taken.id AND projects.id = jobs.project",
+            &[
+                &taken_ids,
+                &tokens,
+                &req.runner,
+                &req.lease_millis,
+                &req.first_heartbeat_millis,
+            ],
+        )
+        .instrument_query("activate_taken_jobs")
+        .await?;
+    }
+    txn.commit().await?;
+
+    taken_ids
+        .into_iter()
+        .zip(tokens)
+        .map(|(job_id, job_token)| TakeJobResponseJob {
+            project_name: req.project_name.clone(),
+            job_id,
+            job_token,
+        })
+        .collect()
+}
+
+/// Take ownership of an available job. See `take_jobs` for details.
+#[throws]
+async fn take_job(pool: &Pool, req: &TakeJobRequest) -> TakeJobResponse {
+    TakeJobResponse {
+        job: take_jobs(pool, req, 1).await?.pop(),
+    }
+}
+
+/// Randomly order projects or queues such that the chance of one
+/// coming next is proportional to its weight.
+pub(crate) fn weighted_shuffle(mut names: Vec<(String, i32)>) -> Vec<String> {
+    let mut rng = thread_rng();
+    let mut ordered = Vec::with_capacity(names.len());
+    while !names.is_empty() {
+        let weights = names.iter().map(|(_, weight)| *weight);
+        let index = match WeightedIndex::new(weights) {
+            Ok(dist) => dist.sample(&mut rng),
+            // All of the remaining weights are zero
+            Err(_) => 0,
+        };
+        ordered.push(names.remove(index).0);
+    }
+    ordered
+}
+
+/// Take an available job from one of several projects.
+///
+/// Only projects that have a job the runner could take are
+/// considered. These are ordered according to the requested fairness
+/// policy, then each is tried in turn until a job is taken. (Another
+/// runner may have taken the last job in a project in the meantime.)
+///
+/// If no job is available and the request sets `wait_millis`, this
+/// waits for a job to be added to one of the projects and tries again.
+#[throws]
+async fn take_job_from_projects(
+    pool: &Pool,
+    req: &TakeJobFromProjectsRequest,
+) -> TakeJobResponse {
+    check_priority_window(req.min_priority, req.max_priority)?;
+    let deadline = get_wait_deadline(req.wait_millis)?;
+
+    loop {
+        let mut subscription = notify::subscribe();
+        let job = try_take_job_from_projects(pool, req).await?;
+        match deadline {
+            Some(deadline) if job.is_none() && Instant::now() < deadline => {
+                subscription
+                    .wait_for_job(&req.project_names, deadline)
+                    .await
+            }
+            _ => break TakeJobResponse { job },
+        }
+    }
+}
+
+/// Take a job from one of several projects without waiting.
+#[throws]
+async fn try_take_job_from_projects(
+    pool: &Pool,
+    req: &TakeJobFromProjectsRequest,
+) -> Option<TakeJobResponseJob> {
+    let conn = pool.get().await?;
+    let rows = conn
+        .query(
+            "SELECT projects.name, projects.weight
+             FROM projects
+             WHERE projects.name = ANY($1)
+               AND EXISTS (
+                 SELECT 1 FROM jobs
+                 WHERE jobs.project = projects.id
+                   AND jobs.state = 'available'
+                   AND jobs.available_at <= CURRENT_TIMESTAMP
+                   AND (jobs.expires_at IS NULL OR
+                        jobs.expires_at > CURRENT_TIMESTAMP)
+                   AND jobs.requirements <@ $2::TEXT[]
+                   AND ($3::INT IS NULL OR jobs.priority >= $3)
+                   AND ($4::INT IS NULL OR jobs.priority <= $4)
+                   AND job_dependencies_met(jobs.id))
+             ORDER BY (SELECT MAX(started) FROM jobs
+                       WHERE jobs.project = projects.id) NULLS FIRST,
+                      projects.name",
+            &[
+                &req.project_names,
+                &req.capabilities,
+                &req.min_priority,
+                &req.max_priority,
+            ],
+        )
+        .instrument_query("take_job_from_projects")
+        .await?;
+    let candidates: Vec<(String, i32)> =
+        rows.iter().map(|row| (row.get(0), row.get(1))).collect();
+
+    let project_names = match req.fairness {
+        // The query already orders the projects by when they last had
+        // a job started
+        ProjectFairness::RoundRobin => {
+            candidates.into_iter().map(|(name, _)| name).collect()
+        }
+        ProjectFairness::Weighted => weighted_shuffle(candidates),
+    };
+
+    let mut job = None;
+    for project_name in project_names {
+        let take_req = TakeJobRequest {
+            project_name,
+            runner: req.runner.clone(),
+            capabilities: req.capabilities.clone(),
+            lease_millis: req.lease_millis,
+            first_heartbeat_millis: req.first_heartbeat_millis,
+            min_priority: req.min_priority,
+            max_priority: req.max_priority,
+            wait_millis: None,
+        };
+        job = take_jobs(pool, &take_req, 1).await?.pop();
+        if job.is_some() {
+            break;
+        }
+    }
+
+    job
+}
+
+fn stuck_job_from_row(row: &Row) -> StuckJob {
+    StuckJob {
+        project_name: row.get(5),
+        job_id: row.get(0),
+        runner: row.get(6),
+        heartbeat_deadline: row.get(7),
+    }
+}
+
+/// List the jobs that `handle_stuck_jobs` would requeue or fail
+/// because their heartbeat has expired, without modifying them.
+#[throws]
+async fn get_stuck_jobs(pool: &Pool) -> GetStuckJobsResponse {
+    let conn = pool.get().await?;
+    // The query locks the rows it returns, but outside of a
+    // transaction the locks are released as soon as it completes.
+    let rows = conn
+        .query(include_str!("../../db/query_handle_stuck_jobs.sql"), &[])
+        .instrument_query("get_stuck_jobs")
+        .await?;
+    GetStuckJobsResponse {
+        jobs: rows.iter().map(stuck_job_from_row).collect(),
+    }
+}
+
+/// Clean up jobs that are no longer making progress.
+///
+/// Available jobs that have passed their expiration time are marked
+/// as expired. Jobs that have been running for longer than their
+/// maximum runtime are marked as timed out. Jobs whose heartbeat has
+/// expired are also marked as timed out, unless the project's retry
+/// policy allows another attempt, in which case they are moved back
+/// to the available state so that another runner can take them. Jobs
+/// that were being canceled when their runner stopped responding, or
+/// when they ran out of time, are marked as canceled.
+#[throws]
+async fn handle_stuck_jobs(pool: &Pool) -> HandleStuckJobsResponse {
+    let mut conn = pool.get().await?;
+    let txn = conn.transaction().await?;
+
+    let mut resp = HandleStuckJobsResponse {
+        requeued: Vec::new(),
+        timed_out: Vec::new(),
+        canceled: Vec::new(),
+        expired: Vec::new(),
+    };
+
+    let rows = txn
+        .query(include_str!("../../db/query_handle_expired_jobs.sql"), &[])
+        .instrument_query("handle_expired_jobs")
+        .await?;
+    resp.expired = rows
+        .iter()
+        .map(|row| ExpiredJob {
+            job_id: row.get(0),
+            project_name: row.get(1),
+            expires_at: row.get(2),
+        })
+        .collect();
+
+    // Handle jobs past their maximum runtime first so that they are
+    // timed out rather than requeued if their heartbeat has also
+    // expired.
+    let rows = txn
+        .query(
+            include_str!("../../db/query_handle_timed_out_jobs.sql"),
+            &[],
+        )
+        .instrument_query("handle_timed_out_jobs")
+        .await?;
+    for row in rows {
+        let job = StuckJob {
+            job_id: row.get(0),
+            project_name: row.get(1),
+            runner: row.get(2),
+            heartbeat_deadline: row.get(3),
+        };
+        if row.get::<_, &str>(4) == "canceled" {
+            resp.canceled.push(job);
+        } else {
+            resp.timed_out.push(job);
+        }
+    }
+
+    let rows = txn
+        .query(include_str!("../../db/query_handle_stuck_jobs.sql"), &[])
+        .instrument_query("get_stuck_jobs")
+        .await?;
+    for row in rows {
+        let job_id: JobId = row.get(0);
+        let attempts: i32 = row.get(1);
+        let state: &str = row.get(8);
+        let retry_delay = get_retry_policy(&row, 2)?
+            .and_then(|policy| policy.retry_delay_millis(attempts));
+        if state == "canceling" {
+            txn.execute(
+                "UPDATE jobs
+                 SET state = 'canceled',
+                     finished = CURRENT_TIMESTAMP,
+                     token = NULL
+                 WHERE id = $1",
+                &[&job_id],
+            )
+            .instrument_query("cancel_stuck_job")
+            .await?;
+            resp.canceled.push(stuck_job_from_row(&row));
+        } else if let Some(retry_delay) = retry_delay {
+            txn.execute(
+                include_str!("../../db/query_retry_job.sql"),
+                &[&job_id, &retry_delay],
+            )
+            .instrument_query("retry_job")
+            .await?;
+            set_attempt_outcome(&txn, job_id, JobState::TimedOut).await?;
+            resp.requeued.push(stuck_job_from_row(&row));
+        } else {
+            txn.execute(
+                "UPDATE jobs
+                 SET state = 'timed_out',
+                     finished = CURRENT_TIMESTAMP,
+                     token = NULL
+                 WHERE id = $1",
+                &[&job_id],
+            )
+            .instrument_query("time_out_stuck_job")
+            .await?;
+            resp.timed_out.push(stuck_job_from_row(&row));
+        }
+    }
+
+    txn.commit().await?;
+    resp
+}
+
+/// Record how a job's current attempt ended when the job is requeued
+/// for another attempt. Otherwise the attempt is recorded as ending in
+/// `Available`, the state the job moved to.
+#[throws]
+async fn set_attempt_outcome(
+    txn: &Transaction<'_>,
+    job_id: JobId,
+    outcome: JobState,
+) {
+    txn.execute(
+        "UPDATE job_attempts SET outcome = $2
+         WHERE job = $1
+           AND attempt = (SELECT attempts FROM jobs WHERE id = $1)",
+        &[&job_id, &outcome.as_ref()],
+    )
+    .instrument_query("set_attempt_outcome")
+    .await?;
+}
+
+/// States in which a job is held by a runner, which can update it with
+/// the job's token.
+const RUNNER_STATES: &[JobState] =
+    &[JobState::Activating, JobState::Running, JobState::Canceling];
+
+/// Get the delay before a failed job can be retried, or `None` if the
+/// job should not be retried. Jobs that are being canceled are never
+/// retried.
+#[throws]
+async fn get_retry_delay(conn: &Client, req: &UpdateJobRequest) -> Option<i64> {
+    let rows = conn
+        .query(
+            "SELECT jobs.attempts, projects.max_attempts,
+                    projects.retry_backoff, projects.retry_delay_millis,
+                    jobs.state
+             FROM jobs
+             JOIN projects ON projects.id = jobs.project
+             WHERE projects.name = $1 AND jobs.id = $2 AND
+                   jobs.state IN ('activating', 'running', 'canceling') AND
+                   jobs.token = $3",
+            &[&req.project_name, &req.job_id, &req.token],
+        )
+        .instrument_query("get_retry_delay")
+        .await?;
+
+    if rows.is_empty() {
+        throw!(Error::NotFound);
+    }
+    let row = &rows[0];
+    let attempts: i32 = row.get(0);
+    let state: &str = row.get(4);
+    if state == "canceling" {
+        None
+    } else {
+        get_retry_policy(row, 1)?
+            .and_then(|policy| policy.retry_delay_millis(attempts))
+    }
+}
+
+#[throws]
+async fn update_job(pool: &Pool, req: &UpdateJobRequest) -> UpdateJobResponse {
+    let mut conn = pool.get().await?;
+    if let Some(data) = &req.data {
+        check_data_size(&conn, &req.project_name, data).await?;
+    }
+
+    // A failed job is requeued instead if the project's retry policy
+    // allows another attempt.
+    let retry_delay = if req.state == Some(JobState::Failed) {
+        get_retry_delay(&conn, req).await?
+    } else {
+        None
+    };
+
+    let txn = conn.transaction().await?;
+
+    let mut stmt = "UPDATE jobs\n".to_string();
+    let mut inputs: Vec<&(dyn ToSql + Sync)> =
+        vec![&req.project_name, &req.job_id, &req.token, &req.data];
+    let job_state_str;
+
+    // The state the job is moving to, if any, which limits the states
+    // it can be updated from. Runners can keep sending heartbeats to a
+    // job that is being canceled so that they learn about the
+    // cancellation, but a job being canceled can't be put back in the
+    // queue.
+    let mut new_state = None;
+
+    // Coalesce is used when setting the data so that if the data in
+    // the request is null, the existing value in the row is kept.
+    match &req.state {
+        None | Some(JobState::Running) => {
+            // No state is set, so just update the heartbeat time. An
+            // explicitly extended lease is never shortened, but the
+            // first heartbeat ends the wait for the runner to start,
+            // and confirms that it has started the job.
+            stmt += "SET state = CASE WHEN state = 'activating'
+                                 THEN 'running' ELSE state END,
+                         heartbeat = CURRENT_TIMESTAMP,
+                         lease_expires_at = GREATEST(
+                           CASE WHEN state != 'activating'
+                             THEN lease_expires_at END,
+                           CURRENT_TIMESTAMP + COALESCE(
+                             lease_millis, (
+                               SELECT heartbeat_expiration_millis
+                               FROM projects WHERE id = jobs.project)
+                           ) * INTERVAL '1 millisecond'),
+                         data = COALESCE($4, data)";
+        }
+        Some(JobState::Available) => {
+            // The runner has given up on the job for some reason and
+            // is transitioning it from running back to
+            // available. Clear the token so that more updates can't
+            // be sent. Clear the started time as well.
+            stmt += "SET state = 'available',
+                         started = null,
+                         token = null,
+                         data = COALESCE($4, data)";
+            new_state = Some(JobState::Available);
+        }
+        Some(JobState::Failed) if retry_delay.is_some() => {
+            // The job failed but can be retried. Move it back to
+            // available, but don't let it be taken again until the
+            // retry delay has passed.
+            stmt += "SET state = 'available',
+                         runner = null,
+                         started = null,
+                         token = null,
+                         available_at = CURRENT_TIMESTAMP +
+                             $5::BIGINT * INTERVAL '1 millisecond',
+                         data = COALESCE($4, data)";
+            inputs.push(&retry_delay);
+            new_state = Some(JobState::Available);
+        }
+        Some(state @ JobState::Canceled)
+        | Some(state @ JobState::Succeeded)
+        | Some(state @ JobState::Failed) => {
+            // The runner is marking the job as finished. Update the
+            // finished time and clear the token so that more updates
+            // can't be sent.
+            stmt += "SET state = $5,
+                         finished = CURRENT_TIMESTAMP,
+                         token = null,
+                         data = COALESCE($4, data)";
+            job_state_str = state.as_ref();
+            inputs.push(&job_state_str);
+            new_state = Some(*state);
+        }
+        Some(state) => {
+            throw!(Error::bad_request(
+                ErrorCode::InvalidState,
+                format!("invalid state: {}", state.as_ref())
+            ));
+        }
+    }
+
+    let allowed_states: Vec<String> = RUNNER_STATES
+        .iter()
+        .filter(|state| match &new_state {
+            Some(new_state) => state.can_transition_to(new_state),
+            None => true,
+        })
+        .map(|state| format!("'{}'", state.as_ref()))
+        .collect();
+    stmt += &format!(
+        "WHERE id = $2 AND project = (
+             SELECT id FROM projects WHERE name = $1) AND
+           state IN ({}) AND token = $3
+         RETURNING state, attempts,
+           CASE WHEN state IN ('activating', 'running', 'canceling') THEN
+             lease_expires_at
+           END",
+        allowed_states.join(", ")
+    );
+
+    let rows = txn
+        .query(stmt.as_str(), &inputs)
+        .instrument_query("update_job")
+        .await?;
+
+    if rows.is_empty() {
+        throw!(Error::NotFound)
+    }
+    if req.state == Some(JobState::Failed) && retry_delay.is_some() {
+        set_attempt_outcome(&txn, req.job_id, JobState::Failed).await?;
+    }
+
+    for step in &req.steps {
+        // A state from a newer client that this server doesn't know
+        if step.state == StepState::Unknown {
+            throw!(Error::invalid_argument("step state", step.state.as_ref()));
+        }
+        let rows = txn
+            .query(
+                include_str!("../../db/query_update_job_step.sql"),
+                &[&req.job_id, &step.name, &step.state.as_ref()],
+            )
+            .instrument_query("update_job_step")
+            .await?;
+        if rows.is_empty() {
+            throw!(Error::bad_request(
+                ErrorCode::UnknownStep,
+                format!("unknown step: {}", step.name)
+            ));
+        }
+    }
+
+    txn.commit().await?;
+
+    let row = &rows[0];
+    let state: JobState = row.get::<_, &str>(0).parse()?;
+    Span::current().record("new_state", &state.as_ref());
+    UpdateJobResponse {
+        cancel_requested: state == JobState::Canceling,
+        state,
+        attempts: row.get(1),
+        heartbeat_deadline: row.get(2),
+    }
+}
+
+#[throws]
+async fn force_set_job_state(pool: &Pool, req: &ForceSetJobStateRequest) {
+    let mut conn = pool.get().await?;
+    let txn = conn.transaction().await?;
+
+    let rows = txn
+        .query(
+            "SELECT state FROM jobs
+             WHERE project = (SELECT id FROM projects WHERE name = $1)
+               AND id = $2
+             FOR UPDATE",
+            &[&req.project_name, &req.job_id],
+        )
+        .instrument_query("lock_job")
+        .await?;
+    if rows.is_empty() {
+        throw!(Error::NotFound);
+    }
+    let old_state: &str = rows[0].get(0);
+
+    let stmt = match &req.state {
+        JobState::Available => {
+            check_unique_key_free(&txn, req.job_id).await?;
+            "UPDATE jobs
+             SET state = $2,
+                 runner = NULL,
+                 started = NULL,
+                 finished = NULL,
+                 token = NULL,
+                 available_at = CURRENT_TIMESTAMP
+             WHERE id = $1"
+        }
+        // Forcing a job skips the usual transitions, but a job can only
+        // be canceling while a runner holds it
+        JobState::Canceling
+            if old_state == "activating" || old_state == "running" =>
+        {
+            "UPDATE jobs SET state = $2 WHERE id = $1"
+        }
+        state if state.is_terminal() => {
+            "UPDATE jobs
+             SET state = $2,
+                 finished = CURRENT_TIMESTAMP,
+                 token = NULL
+             WHERE id = $1"
+        }
+        state => {
+            throw!(Error::bad_request(
+                ErrorCode::InvalidState,
+                format!(
+                    "cannot force job from {} to {}",
+                    old_state,
+                    state.as_ref()
+                )
+            ));
+        }
+    };
+
+    txn.execute(stmt, &[&req.job_id, &req.state.as_ref()])
+        .instrument_query("force_set_job_state")
+        .await?;
+    txn.commit().await?;
+
+    let span = Span::current();
+    span.record("old_state", &old_state);
+    span.record("new_state", &req.state.as_ref());
+    info!(
+        "forced job {} from {} to {}",
+        req.job_id,
+        old_state,
+        req.state.as_ref()
+    );
+}
+
+/// Cancel an available job, or ask the runner of a running job to
+/// stop.
+#[throws]
+async fn cancel_job(pool: &Pool, req: &CancelJobRequest) -> CancelJobResponse {
+    let mut conn = pool.get().await?;
+    let txn = conn.transaction().await?;
+
+    let row = txn
+        .query_opt(
+            "SELECT state FROM jobs
+             WHERE project = (SELECT id FROM projects WHERE name = $1)
+               AND id = $2
+             FOR UPDATE",
+            &[&req.project_name, &req.job_id],
+        )
+        .instrument_query("lock_job")
+        .await?;
+    let old_state: JobState = match row {
+        Some(row) => row.get::<_, &str>(0).parse()?,
+        None => throw!(Error::NotFound),
+    };
+
+    let (state, stmt) = match old_state {
+        JobState::Available => (
+            JobState::Canceled,
+            Some(
+                "UPDATE jobs
+                 SET state = 'canceled',
+                     finished = CURRENT_TIMESTAMP
+                 WHERE id = $1",
+            ),
+        ),
+        // The runner has already been asked to stop
+        JobState::Canceling => (JobState::Canceling, None),
+        state if state.can_transition_to(&JobState::Canceling) => (
+            JobState::Canceling,
+            Some("UPDATE jobs SET state = 'canceling' WHERE id = $1"),
+        ),
+        state => throw!(Error::bad_request(
+            ErrorCode::InvalidState,
+            format!("cannot cancel a job that is {}", state.as_ref())
+        )),
+    };
+    if let Some(stmt) = stmt {
+        txn.execute(stmt, &[&req.job_id])
+            .instrument_query("cancel_job")
+            .await?;
+    }
+    txn.commit().await?;
+
+    info!("canceled job {} (now {})", req.job_id, state.as_ref());
+    CancelJobResponse { state }
+}
+
+/// Requeue a failed, canceled or timed-out job.
+#[throws]
+async fn retry_job(pool: &Pool, req: &RetryJobRequest) {
+    let mut conn = pool.get().await?;
+    let txn = conn.transaction().await?;
+
+    let row = txn
+        .query_opt(
+            "SELECT state FROM jobs
+             WHERE project = (SELECT id FROM projects WHERE name = $1)
+               AND id = $2
+             FOR UPDATE",
+            &[&req.project_name, &req.job_id],
+        )
+        .instrument_query("lock_job")
+        .await?;
+    let old_state: JobState = match row {
+        Some(row) => row.get::<_, &str>(0).parse()?,
+        None => throw!(Error::NotFound),
+    };
+    if !(old_state.is_terminal()
+        && old_state.can_transition_to(&JobState::Available))
+    {
+        throw!(Error::bad_request(
+            ErrorCode::InvalidState,
+            format!("cannot retry a job that is {}", old_state.as_ref())
+        ));
+    }
+    check_unique_key_free(&txn, req.job_id).await?;
+
+    txn.execute(
+        "UPDATE jobs
+         SET state = 'available',
+             runner = NULL,
+             started = NULL,
+             finished = NULL,
+             heartbeat = NULL,
+             lease_expires_at = NULL,
+             token = NULL,
+             available_at = CURRENT_TIMESTAMP
+         WHERE id = $1",
+        &[&req.job_id],
+    )
+    .instrument_query("retry_job")
+    .await?;
+    txn.execute(
+        "UPDATE job_steps
+         SET state = 'pending', started = NULL, finished = NULL
+         WHERE job = $1",
+        &[&req.job_id],
+    )
+    .instrument_query("reset_job_steps")
+    .await?;
+    txn.commit().await?;
+
+    info!("retrying job {} (was {})", req.job_id, old_state.as_ref());
+}
+
+#[throws]
+async fn extend_lease(
+    pool: &Pool,
+    req: &ExtendLeaseRequest,
+) -> ExtendLeaseResponse {
+    if req.extend_by_millis <= 0 {
+        throw!(Error::invalid_argument(
+            "extend_by_millis",
+            req.extend_by_millis
+        ));
     }
 
     let conn = pool.get().await?;
-    let row = conn
-        .query_one(
-            "INSERT INTO projects (name, heartbeat_expiration_millis, data)
-             VALUES ($1, $2, $3)
-             RETURNING id",
-            &[&req.name, &req.heartbeat_expiration_millis, &req.data],
+    let rows = conn
+        .query(
+            include_str!("../../db/query_extend_lease.sql"),
+            &[
+                &req.project_name,
+                &req.job_id,
+                &req.token,
+                &req.extend_by_millis,
+            ],
         )
+        .instrument_query("extend_lease")
         .await?;
 
-    AddProjectResponse {
-        project_id: row.get(0),
+    if rows.is_empty() {
+        throw!(Error::NotFound);
+    }
+    ExtendLeaseResponse {
+        heartbeat_deadline: rows[0].get(0),
     }
 }
 
 #[throws]
-async fn get_job(pool: &Pool, req: &GetJobRequest) -> GetJobResponse {
+async fn add_job_artifact(pool: &Pool, req: &AddJobArtifactRequest) {
     let conn = pool.get().await?;
     let rows = conn
         .query(
-            "SELECT id, project, state, created, started, finished, priority, data
-             FROM jobs
+            include_str!("../../db/query_add_job_artifact.sql"),
+            &[
+                &req.project_name,
+                &req.job_id,
+                &req.token,
+                &req.name,
+                &req.url,
+                &req.size,
+                &req.content_type,
+            ],
+        )
+        .instrument_query("add_job_artifact")
+        .await?;
+
+    if rows.is_empty() {
+        throw!(Error::NotFound)
+    }
+}
+
+#[throws]
+async fn append_job_log(pool: &Pool, req: &AppendJobLogRequest) {
+    let conn = pool.get().await?;
+    let rows = conn
+        .query(
+            include_str!("../../db/query_append_job_log.sql"),
+            &[&req.project_name, &req.job_id, &req.token, &req.text],
+        )
+        .instrument_query("append_job_log")
+        .await?;
+
+    if rows.is_empty() {
+        throw!(Error::NotFound)
+    }
+}
+
+#[throws]
+async fn get_job_log(pool: &Pool, req: &GetJobLogRequest) -> GetJobLogResponse {
+    let conn = pool.get().await?;
+    let rows = conn
+        .query(
+            "SELECT id FROM jobs
              WHERE project = (SELECT id FROM projects WHERE name = $1)
                AND id = $2",
             &[&req.project_name, &req.job_id],
         )
+        .instrument_query("check_job_exists")
         .await?;
-
     if rows.is_empty() {
         throw!(Error::NotFound);
+    }
+
+    let (cmp, dir) = page_order(&req.page);
+    let after_sequence: Option<i32> = page_cursor(&req.page)?;
+    let query = format!(
+        "SELECT sequence, text, created FROM job_logs
+         WHERE job = $1 AND sequence > $2
+           AND ($3::INT IS NULL OR sequence {} $3)
+         ORDER BY sequence {}
+         LIMIT $4",
+        cmp, dir
+    );
+    let rows = conn
+        .query(
+            query.as_str(),
+            &[
+                &req.job_id,
+                &req.after_sequence.unwrap_or(0),
+                &after_sequence,
+                &page_query_limit(&req.page)?,
+            ],
+        )
+        .instrument_query("get_job_log")
+        .await?;
+
+    let mut chunks: Vec<_> = rows
+        .iter()
+        .map(|row| JobLogChunk {
+            sequence: row.get(0),
+            text: row.get(1),
+            time: row.get(2),
+        })
+        .collect();
+    let next_cursor =
+        finish_page(&mut chunks, &req.page, |chunk| chunk.sequence);
+    GetJobLogResponse {
+        chunks,
+        next_cursor,
+    }
+}
+
+fn state_names(states: &[JobState]) -> Vec<&str> {
+    states.iter().map(|state| state.as_ref()).collect()
+}
+
+#[throws]
+async fn purge_jobs(pool: &Pool, req: &PurgeJobsRequest) -> PurgeJobsResponse {
+    if req.older_than_millis < 0 {
+        throw!(Error::invalid_argument(
+            "older_than_millis",
+            req.older_than_millis
+        ));
+    }
+    if let Some(state) = req.states.iter().find(|state| !state.is_terminal()) {
+        throw!(Error::bad_request(
+            ErrorCode::InvalidState,
+            format!("cannot purge jobs in state: {}", state.as_ref())
+        ));
+    }
+    let states = if req.states.is_empty() {
+        state_names(JobState::TERMINAL)
     } else {
-        let row = &rows[0];
-        let state: String = row.get(2);
-        GetJobResponse {
-            job: Job {
-                id: row.get(0),
-                project_name: req.project_name.clone(),
-                project_id: row.get(1),
-                state: state.parse()?,
-                created: row.get(3),
-                started: row.get(4),
-                finished: row.get(5),
-                priority: row.get(6),
-                data: row.get(7),
-            },
-        }
+        state_names(&req.states)
+    };
+
+    let conn = pool.get().await?;
+    let deleted = conn
+        .execute(
+            "DELETE FROM jobs
+             WHERE project = (SELECT id FROM projects WHERE name = $1)
+               AND state = ANY($2)
+               AND finished < CURRENT_TIMESTAMP -
+                   $3::BIGINT * INTERVAL '1 millisecond'",
+            &[&req.project_name, &states, &req.older_than_millis],
+        )
+        .instrument_query("purge_jobs")
+        .await?;
+
+    PurgeJobsResponse { deleted }
+}
+
+#[throws]
+async fn set_retention(pool: &Pool, req: &SetRetentionRequest) {
+    check_retention_millis(req.retention_millis)?;
+
+    let conn = pool.get().await?;
+    let updated = conn
+        .execute(
+            "UPDATE projects
+             SET retention_millis = $2, retention_archive = $3
+             WHERE name = $1",
+            &[&req.project_name, &req.retention_millis, &req.archive],
+        )
+        .instrument_query("set_retention")
+        .await?;
+    if updated == 0 {
+        throw!(Error::NotFound);
     }
 }
 
 #[throws]
-async fn get_jobs(pool: &Pool, req: &GetJobsRequest) -> GetJobsResponse {
+async fn get_archived_jobs(
+    pool: &Pool,
+    req: &GetArchivedJobsRequest,
+) -> GetArchivedJobsResponse {
+    let (cmp, dir) = page_order(&req.page);
+    let after_id: Option<JobId> = page_cursor(&req.page)?;
+    let query = format!(
+        "SELECT archived_jobs.id, archived_jobs.finished,
+                archived_jobs.archived, archived_jobs.job
+         FROM archived_jobs
+         JOIN projects ON projects.id = archived_jobs.project
+         WHERE projects.name = $1
+           AND ($2::BIGINT IS NULL OR archived_jobs.id {} $2)
+         ORDER BY archived_jobs.id {}
+         LIMIT $3",
+        cmp, dir
+    );
+
     let conn = pool.get().await?;
     let rows = conn
         .query(
-            "SELECT id, project, state, created, started, finished, priority, data
-             FROM jobs
-             WHERE project = (SELECT id FROM projects WHERE name = $1)",
-            &[&req.project_name],
+            query.as_str(),
+            &[&req.project_name, &after_id, &page_query_limit(&req.page)?],
         )
+        .instrument_query("get_archived_jobs")
         .await?;
 
-    let jobs = rows
+    let mut jobs: Vec<_> = rows
         .iter()
-        .map(|row| -> Result<Job, Error> {
-            let state: String = row.get(2);
-            Ok(Job {
-                id: row.get(0),
-                project_name: req.project_name.clone(),
-                project_id: row.get(1),
-                state: state.parse()?,
-                created: row.get(3),
-                started: row.get(4),
-                finished: row.get(5),
-                priority: row.get(6),
-                data: row.get(7),
-            })
+        .map(|row| ArchivedJob {
+            job_id: row.get(0),
+            finished: row.get(1),
+            archived: row.get(2),
+            job: row.get(3),
         })
-        .collect::<Result<Vec<Job>, _>>()?;
-
-    GetJobsResponse { jobs }
+        .collect();
+    let next_cursor = finish_page(&mut jobs, &req.page, |job| job.job_id);
+    GetArchivedJobsResponse { jobs, next_cursor }
 }
 
+/// Remove finished jobs according to each project's retention
+/// policy, archiving them if the project asks. Returns the number of
+/// jobs removed and how many of those were archived.
 #[throws]
-async fn add_job(pool: &Pool, req: &AddJobRequest) -> AddJobResponse {
+async fn apply_retention(pool: &Pool) -> (i64, i64) {
     let conn = pool.get().await?;
     let row = conn
         .query_one(
-            "INSERT INTO jobs (project, data)
-             VALUES ((SELECT id FROM projects WHERE name = $1), $2)
-             RETURNING id",
-            &[&req.project_name, &req.data],
+            include_str!("../../db/query_apply_retention.sql"),
+            &[&state_names(JobState::TERMINAL)],
         )
+        .instrument_query("apply_retention")
         .await?;
+    (row.get(0), row.get(1))
+}
+
+/// Run periodic maintenance: handle stuck jobs, apply project
+/// retention policies and send the webhooks of finished batches. This
+/// is intended to be called regularly from a background task, so
+/// errors are logged rather than returned.
+pub async fn run_maintenance(pool: &Pool) {
+    match handle_stuck_jobs(pool).await {
+        Ok(resp) => {
+            for job in &resp.requeued {
+                info!(
+                    "requeued stuck job {} from runner {}",
+                    job.job_id, job.runner
+                );
+            }
+            for job in &resp.timed_out {
+                info!(
+                    "timed out stuck job {} from runner {}",
+                    job.job_id, job.runner
+                );
+            }
+        }
+        Err(err) => error!("failed to handle stuck jobs: {}", err),
+    }
+    match apply_retention(pool).await {
+        Ok((0, _)) => {}
+        Ok((removed, archived)) => info!(
+            "retention policies removed {} jobs, archiving {} of them",
+            removed, archived
+        ),
+        Err(err) => error!("failed to apply retention policies: {}", err),
+    }
+    #[cfg(feature = "webhooks")]
+    if let Err(err) = webhook::send_batch_webhooks(pool).await {
+        error!("failed to send batch webhooks: {}", err);
+    }
+}
 
-    let job_id: JobId = row.get(0);
+/// Default length of time after a runner's last heartbeat when it is
+/// considered offline.
+pub(crate) const DEFAULT_RUNNER_OFFLINE_MILLIS: i64 = 60 * 1000;
 
-    AddJobResponse { job_id }
+#[throws]
+async fn runner_heartbeat(pool: &Pool, req: &RunnerHeartbeatRequest) {
+    let conn = pool.get().await?;
+    conn.execute(
+        "INSERT INTO runners (name, draining, capabilities)
+         VALUES ($1, $2, $3)
+         ON CONFLICT (name) DO UPDATE
+         SET last_seen = CURRENT_TIMESTAMP,
+             draining = EXCLUDED.draining,
+             capabilities = EXCLUDED.capabilities",
+        &[&req.runner, &req.draining, &req.capabilities],
+    )
+    .instrument_query("runner_heartbeat")
+    .await?;
 }
 
-/// Take ownership of an available job.
-///
-/// This gets the highest priority job with the oldest creation that
-/// is available for this project and marks it as running. The job's
-/// runner is set to the input runner, and a unique token is generated
-/// so that the runner can send updates. (Updates that do not include
-/// the correct token are rejected.)
 #[throws]
-async fn take_job(pool: &Pool, req: &TakeJobRequest) -> TakeJobResponse {
-    let token = make_random_string(16);
+async fn list_runners(
+    pool: &Pool,
+    req: &ListRunnersRequest,
+) -> ListRunnersResponse {
+    let offline_after_millis = req
+        .offline_after_millis
+        .unwrap_or(DEFAULT_RUNNER_OFFLINE_MILLIS);
+
+    let (cmp, dir) = page_order(&req.page);
+    let after_name: Option<String> = page_cursor(&req.page)?;
+    let query = format!(
+        "SELECT name, last_seen, capabilities, draining,
+                last_seen < CURRENT_TIMESTAMP -
+                    $1::BIGINT * INTERVAL '1 millisecond',
+                ARRAY(SELECT id FROM jobs
+                      WHERE jobs.runner = runners.name
+                        AND jobs.state IN ('activating', 'running', 'canceling')
+                      ORDER BY id)
+         FROM runners
+         WHERE $2::TEXT IS NULL OR name {} $2
+         ORDER BY name {}
+         LIMIT $3",
+        cmp, dir
+    );
 
     let conn = pool.get().await?;
-    // TODO: do we need to explictly start a transaction here?
     let rows = conn
         .query(
-            include_str!("../../db/query_take_job.sql"),
-            &[&req.project_name, &req.runner, &token],
+            query.as_str(),
+            &[
+                &offline_after_millis,
+                &after_name,
+                &page_query_limit(&req.page)?,
+            ],
         )
+        .instrument_query("list_runners")
         .await?;
 
-    if rows.is_empty() {
-        TakeJobResponse { job: None }
-    } else {
-        let row = &rows[0];
-        TakeJobResponse {
-            job: Some(TakeJobResponseJob {
-                job_id: row.get(0),
-                job_token: row.get(1),
-            }),
-        }
+    let mut runners: Vec<_> = rows
+        .iter()
+        .map(|row| {
+            let draining: bool = row.get(3);
+            let offline: bool = row.get(4);
+            let state = if offline {
+                RunnerState::Offline
+            } else if draining {
+                RunnerState::Draining
+            } else {
+                RunnerState::Online
+            };
+            Runner {
+                name: row.get(0),
+                state,
+                last_seen: row.get(1),
+                capabilities: row.get(2),
+                jobs: row.get(5),
+            }
+        })
+        .collect();
+
+    let next_cursor =
+        finish_page(&mut runners, &req.page, |runner| runner.name.clone());
+    ListRunnersResponse {
+        runners,
+        next_cursor,
     }
 }
 
+/// How often to check for new output when streaming a job's log if no
+/// notification arrives.
+const LOG_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
 #[throws]
-async fn handle_stuck_jobs(pool: &Pool) {
+async fn is_job_finished(pool: &Pool, req: &GetJobLogRequest) -> bool {
     let conn = pool.get().await?;
-    conn.query(include_str!("../../db/query_handle_stuck_jobs.sql"), &[])
+    let row = conn
+        .query_one(
+            "SELECT finished IS NOT NULL FROM jobs WHERE id = $1",
+            &[&req.job_id],
+        )
+        .instrument_query("is_job_finished")
         .await?;
+    row.get(0)
+}
+
+/// Stream a job's log output.
+///
+/// Each item in the stream is a batch of new chunks. The stream waits
+/// for new output until the job has finished and all of its output
+/// has been sent.
+#[throws]
+pub async fn stream_job_log(
+    pool: Pool,
+    project_name: String,
+    job_id: JobId,
+) -> impl Stream<Item = Result<Vec<JobLogChunk>, Error>> {
+    let req = GetJobLogRequest {
+        project_name,
+        job_id,
+        after_sequence: None,
+        page: Page::default(),
+    };
+    // Fail early if the job doesn't exist
+    get_job_log(&pool, &req).await?;
+
+    stream::unfold(Some((pool, req)), |state| async move {
+        let (pool, mut req) = match state {
+            Some(state) => state,
+            // The previous batch was the last one
+            None => return None,
+        };
+        loop {
+            let mut subscription = notify::subscribe();
+            // Check if the job is finished before reading the log so
+            // that no output written in between is missed
+            let finished = match is_job_finished(&pool, &req).await {
+                Ok(finished) => finished,
+                Err(err) => return Some((Err(err), None)),
+            };
+            let chunks = match get_job_log(&pool, &req).await {
+                Ok(resp) => resp.chunks,
+                Err(err) => return Some((Err(err), None)),
+            };
+            if let Some(chunk) = chunks.last() {
+                req.after_sequence = Some(chunk.sequence);
+            }
+
+            if finished {
+                if chunks.is_empty() {
+                    return None;
+                } else {
+                    return Some((Ok(chunks), None));
+                }
+            } else if !chunks.is_empty() {
+                return Some((Ok(chunks), Some((pool, req))));
+            }
+
+            subscription
+                .wait_for_log(req.job_id, LOG_POLL_INTERVAL)
+                .await;
+        }
+    })
 }
 
+/// How often a project's job event stream checks for changes if no
+/// notification arrives.
+const JOB_EVENT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Get the project's job events with an ID greater than `after_id`.
 #[throws]
-async fn update_job(pool: &Pool, req: &UpdateJobRequest) {
+async fn get_project_job_events(
+    pool: &Pool,
+    project_id: ProjectId,
+    after_id: i64,
+) -> Vec<ProjectJobEvent> {
     let conn = pool.get().await?;
+    let rows = conn
+        .query(
+            "SELECT job_events.id, job_events.job, job_events.old_state,
+                    job_events.new_state, job_events.runner,
+                    job_events.created
+             FROM job_events
+             JOIN jobs ON jobs.id = job_events.job
+             WHERE jobs.project = $1 AND job_events.id > $2
+             ORDER BY job_events.id",
+            &[&project_id, &after_id],
+        )
+        .instrument_query("get_project_job_events")
+        .await?;
+    rows.iter()
+        .map(|row| -> Result<ProjectJobEvent, Error> {
+            let old_state: Option<String> = row.get(2);
+            let new_state: String = row.get(3);
+            Ok(ProjectJobEvent {
+                id: row.get(0),
+                job_id: row.get(1),
+                event: JobEvent {
+                    old_state: old_state.map(|s| s.parse()).transpose()?,
+                    new_state: new_state.parse()?,
+                    runner: row.get(4),
+                    time: row.get(5),
+                },
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?
+}
 
-    let mut stmt = "UPDATE jobs\n".to_string();
-    let mut inputs: Vec<&(dyn ToSql + Sync)> =
-        vec![&req.project_name, &req.job_id, &req.token, &req.data];
-    let job_state_str;
+/// Stream state changes of the project's jobs. Only events after
+/// `after_id` are sent, or if it's `None`, only events that happen
+/// after the stream starts.
+///
+/// Each item in the stream is a batch of new events. The stream
+/// doesn't end on its own.
+#[throws]
+pub async fn stream_job_events(
+    pool: Pool,
+    project_name: String,
+    after_id: Option<i64>,
+) -> impl Stream<Item = Result<Vec<ProjectJobEvent>, Error>> {
+    let conn = pool.get().await?;
+    let rows = conn
+        .query("SELECT id FROM projects WHERE name = $1", &[&project_name])
+        .instrument_query("get_project_id")
+        .await?;
+    if rows.is_empty() {
+        throw!(Error::NotFound);
+    }
+    let project_id: ProjectId = rows[0].get(0);
+    let after_id = match after_id {
+        Some(after_id) => after_id,
+        None => conn
+            .query_one("SELECT COALESCE(MAX(id), 0) FROM job_events", &[])
+            .instrument_query("get_last_job_event_id")
+            .await?
+            .get(0),
+    };
+    drop(conn);
 
-    // Coalesce is used when setting the data so that if the data in
-    // the request is null, the existing value in the row is kept.
-    match &req.state {
-        None => {
-            // No state is set, so just update the heartbeat time
-            stmt += "SET heartbeat = CURRENT_TIMESTAMP,
-                         data = COALESCE($4, data)";
+    stream::unfold(Some((pool, after_id)), move |state| {
+        let project_name = project_name.clone();
+        async move {
+            let (pool, mut after_id) = state?;
+            loop {
+                let mut subscription = notify::subscribe();
+                let events =
+                    match get_project_job_events(&pool, project_id, after_id)
+                        .await
+                    {
+                        Ok(events) => events,
+                        Err(err) => return Some((Err(err), None)),
+                    };
+                if let Some(event) = events.last() {
+                    after_id = event.id;
+                    return Some((Ok(events), Some((pool, after_id))));
+                }
+
+                subscription
+                    .wait_for_job_event(&project_name, JOB_EVENT_POLL_INTERVAL)
+                    .await;
+            }
         }
-        Some(JobState::Available) => {
-            // The runner has given up on the job for some reason and
-            // is transitioning it from running back to
-            // available. Clear the token so that more updates can't
-            // be sent. Clear the started time as well.
-            stmt += "SET state = 'available',
-                         started = null,
-                         token = null,
-                         data = COALESCE($4, data)";
+    })
+}
+
+/// How often a runner's event stream checks for new jobs and
+/// cancellations if no notification arrives.
+const RUNNER_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+struct RunnerEvents {
+    pool: Pool,
+    req: SubscribeRunnerRequest,
+    /// Jobs the runner has already been asked to cancel.
+    canceling: HashSet<JobId>,
+    /// Events found by the last check that haven't been sent yet.
+    pending: VecDeque<RunnerEvent>,
+}
+
+impl RunnerEvents {
+    fn take_request(&self) -> TakeJobFromProjectsRequest {
+        TakeJobFromProjectsRequest {
+            project_names: self.req.project_names.clone(),
+            runner: self.req.runner.clone(),
+            capabilities: self.req.capabilities.clone(),
+            fairness: self.req.fairness,
+            lease_millis: self.req.lease_millis,
+            first_heartbeat_millis: self.req.first_heartbeat_millis,
+            min_priority: self.req.min_priority,
+            max_priority: self.req.max_priority,
+            wait_millis: None,
         }
-        Some(JobState::Canceled)
-        | Some(JobState::Succeeded)
-        | Some(JobState::Failed) => {
-            // The runner is marking the job as finished. Update the
-            // finished time and clear the token so that more updates
-            // can't be sent.
-            stmt += "SET state = $5,
-                         finished = CURRENT_TIMESTAMP,
-                         token = null,
-                         data = COALESCE($4, data)";
-            job_state_str = req.state.as_ref().unwrap().as_ref();
-            inputs.push(&job_state_str);
+    }
+
+    /// Look for newly canceled jobs, and take jobs for the runner if
+    /// it has room for more.
+    #[throws]
+    async fn check(&mut self) {
+        let conn = self.pool.get().await?;
+        let rows = conn
+            .query(
+                "SELECT projects.name, jobs.id, jobs.state = 'canceling'
+                 FROM jobs
+                 JOIN projects ON projects.id = jobs.project
+                 WHERE jobs.runner = $1
+                   AND jobs.state IN ('activating', 'running', 'canceling')",
+                &[&self.req.runner],
+            )
+            .instrument_query("get_runner_jobs")
+            .await?;
+        drop(conn);
+
+        let mut canceling = HashSet::new();
+        for row in &rows {
+            let is_canceling: bool = row.get(2);
+            if is_canceling {
+                let job_id = row.get(1);
+                if !self.canceling.contains(&job_id) {
+                    self.pending.push_back(RunnerEvent::CancelRequested {
+                        project_name: row.get(0),
+                        job_id,
+                    });
+                }
+                canceling.insert(job_id);
+            }
         }
-        Some(state) => {
-            throw!(Error::BadRequest(format!(
-                "invalid state: {}",
-                state.as_ref()
-            )));
+        self.canceling = canceling;
+
+        let take_req = self.take_request();
+        for _ in rows.len() as i32..self.req.max_jobs {
+            match try_take_job_from_projects(&self.pool, &take_req).await? {
+                Some(job) => {
+                    self.pending.push_back(RunnerEvent::JobAssigned(job))
+                }
+                None => break,
+            }
         }
     }
+}
 
-    stmt += "WHERE id = $2 AND project = (
-                 SELECT id FROM projects WHERE name = $1) AND
-               state = 'running' AND token = $3
-             RETURNING id";
-
-    let rows = conn.query(stmt.as_str(), &inputs).await?;
+/// Stream jobs taken for a runner and requests to cancel its jobs.
+///
+/// Whenever the runner holds fewer than `max_jobs` running jobs (in
+/// any project), jobs are taken for it from the requested projects.
+/// The stream only ends if there's an error, so drop it when the
+/// runner disconnects.
+#[throws]
+pub async fn stream_runner_events(
+    pool: Pool,
+    req: SubscribeRunnerRequest,
+    context: RequestContext,
+) -> impl Stream<Item = Result<RunnerEvent, Error>> {
+    if req.max_jobs <= 0 {
+        throw!(Error::invalid_argument("max_jobs", req.max_jobs));
+    }
 
-    if rows.is_empty() {
-        throw!(Error::NotFound)
+    let events = RunnerEvents {
+        pool,
+        req,
+        canceling: HashSet::new(),
+        pending: VecDeque::new(),
+    };
+    if auth::is_required(&context) {
+        // Subscribing takes jobs, so it needs the same access
+        let take_req: Request = events.take_request().into();
+        auth::authorize(&events.pool, &take_req, &context).await?;
     }
+
+    stream::unfold(Some(events), |state| async move {
+        let mut events = match state {
+            Some(events) => events,
+            // The previous item was an error
+            None => return None,
+        };
+        loop {
+            if let Some(event) = events.pending.pop_front() {
+                return Some((Ok(event), Some(events)));
+            }
+
+            let mut subscription = notify::subscribe();
+            if let Err(err) = events.check().await {
+                return Some((Err(err), None));
+            }
+            if events.pending.is_empty() {
+                subscription
+                    .wait_for_runner(
+                        &events.req.project_names,
+                        &events.req.runner,
+                        Instant::now() + RUNNER_POLL_INTERVAL,
+                    )
+                    .await;
+            }
+        }
+    })
 }
 
 #[throws]
@@ -230,39 +2781,286 @@ async fn handle_request_ok(pool: &Pool, req: &Request) -> Response {
         Request::AddProject(req) => {
             Response::AddProject(add_project(pool, req).await?)
         }
+        Request::ListProjects(req) => list_projects(pool, req).await?.into(),
+        Request::GetProjectStats(req) => {
+            get_project_stats(pool, req).await?.into()
+        }
+        Request::AddOrganization(req) => {
+            organization::add_organization(pool, req).await?.into()
+        }
+        Request::ListOrganizations(req) => {
+            organization::list_organizations(pool, req).await?.into()
+        }
+        Request::GetOrganizationStats(req) => {
+            organization::get_organization_stats(pool, req)
+                .await?
+                .into()
+        }
 
         Request::AddJob(req) => add_job(pool, req).await?.into(),
+        Request::AddBatch(req) => add_batch(pool, req).await?.into(),
+        Request::GetBatch(req) => get_batch(pool, req).await?.into(),
+        Request::AddChildJob(req) => add_child_job(pool, req).await?.into(),
+        Request::PutWorkflow(req) => {
+            workflow::put_workflow(pool, req).await?;
+            Response::Empty
+        }
+        Request::StartWorkflow(req) => {
+            workflow::start_workflow(pool, req).await?.into()
+        }
+        Request::GetWorkflowRun(req) => {
+            workflow::get_workflow_run(pool, req).await?.into()
+        }
+        Request::PutJobTemplate(req) => {
+            job_template::put_job_template(pool, req).await?;
+            Response::Empty
+        }
+        Request::AddJobFromTemplate(req) => {
+            job_template::add_job_from_template(pool, req).await?.into()
+        }
         Request::GetJob(req) => get_job(pool, req).await?.into(),
         Request::GetJobs(req) => get_jobs(pool, req).await?.into(),
+        Request::GetJobHistory(req) => get_job_history(pool, req).await?.into(),
+        Request::GetJobAttempts(req) => {
+            get_job_attempts(pool, req).await?.into()
+        }
         Request::TakeJob(req) => take_job(pool, req).await?.into(),
-        Request::UpdateJob(req) => {
-            update_job(pool, req).await?;
+        Request::TakeJobFromProjects(req) => {
+            take_job_from_projects(pool, req).await?.into()
+        }
+        Request::TakeJobs(req) => TakeJobsResponse {
+            jobs: take_jobs(pool, &req.params, req.max_jobs).await?,
+        }
+        .into(),
+        Request::UpdateJob(req) => update_job(pool, req).await?.into(),
+        Request::ExtendLease(req) => extend_lease(pool, req).await?.into(),
+        Request::AppendJobLog(req) => {
+            append_job_log(pool, req).await?;
+            Response::Empty
+        }
+        Request::GetJobLog(req) => get_job_log(pool, req).await?.into(),
+        Request::AddJobArtifact(req) => {
+            add_job_artifact(pool, req).await?;
+            Response::Empty
+        }
+        Request::PurgeJobs(req) => purge_jobs(pool, req).await?.into(),
+        Request::SetRetention(req) => {
+            set_retention(pool, req).await?;
+            Response::Empty
+        }
+        Request::GetArchivedJobs(req) => {
+            get_archived_jobs(pool, req).await?.into()
+        }
+        Request::ForceSetJobState(req) => {
+            force_set_job_state(pool, req).await?;
+            Response::Empty
+        }
+        Request::CancelJob(req) => cancel_job(pool, req).await?.into(),
+        Request::RetryJob(req) => {
+            retry_job(pool, req).await?;
             Response::Empty
         }
-        Request::HandleStuckJobs => {
-            handle_stuck_jobs(pool).await?;
+        Request::RunnerHeartbeat(req) => {
+            runner_heartbeat(pool, req).await?;
             Response::Empty
         }
+        Request::ListRunners(req) => list_runners(pool, req).await?.into(),
+        Request::GetStuckJobs => get_stuck_jobs(pool).await?.into(),
+        Request::HandleStuckJobs => handle_stuck_jobs(pool).await?.into(),
+        Request::GetAuditLog(req) => {
+            audit::get_audit_log(pool, req).await?.into()
+        }
+        // Only reachable if the types crate is newer than the server
+        _ => throw!(Error::bad_request(
+            ErrorCode::InvalidRequest,
+            format!("unsupported request: {}", req.as_ref())
+        )),
     }
 }
 
 fn handle_request_err(err: Error) -> Response {
     match err {
-        Error::BadRequest(s) => Response::BadRequest(s),
+        Error::BadRequest(err) => Response::BadRequest(err),
         Error::NotFound => Response::NotFound,
+        Error::Unauthorized => Response::Unauthorized,
+        Error::Forbidden(s) => Response::Forbidden(s),
+        // The query ran past the pool's statement timeout
+        Error::Db(err) if err.code() == Some(&SqlState::QUERY_CANCELED) => {
+            Response::Timeout
+        }
         Error::Db(_) => Response::InternalError,
         Error::Pool(_) => Response::InternalError,
         Error::Parse(_) => Response::InternalError,
         Error::Template(_) => Response::InternalError,
+        Error::Config(_) => Response::InternalError,
+        Error::SchemaVersion { .. } => Response::InternalError,
+        #[cfg(feature = "redis")]
+        Error::Redis(_) => Response::InternalError,
+        #[cfg(feature = "jwt")]
+        Error::Jwks(_) => Response::InternalError,
     }
 }
 
 pub async fn handle_request(pool: &Pool, req: &Request) -> Response {
+    handle_request_with_context(pool, req, &RequestContext::default()).await
+}
+
+/// Handle a request, checking its API key if the context requires it
+/// and recording where it came from in the audit log if the request
+/// changes anything.
+pub async fn handle_request_with_context(
+    pool: &Pool,
+    req: &Request,
+    context: &RequestContext,
+) -> Response {
     info!("request: {:?}", req);
-    match handle_request_ok(pool, req).await {
-        Ok(resp) => resp,
+    // The state fields are recorded by requests that change a job's
+    // state
+    let span = info_span!(
+        "handle_request",
+        request = req.as_ref(),
+        project_name = field::Empty,
+        job_id = field::Empty,
+        old_state = field::Empty,
+        new_state = field::Empty
+    );
+    if let Some(project_name) = req.project_name() {
+        span.record("project_name", &project_name);
+    }
+    if let Some(job_id) = req.job_id() {
+        span.record("job_id", &job_id);
+    }
+    let start = Instant::now();
+    let handled = check_and_handle_request(pool, req, context, span.clone());
+    let resp = match request_timeout() {
+        Some(timeout) => {
+            time::timeout(timeout, handled).await.unwrap_or_else(|_| {
+                span.in_scope(|| {
+                    warn!("request timed out after {:?}", timeout)
+                });
+                Response::Timeout
+            })
+        }
+        None => handled.await,
+    };
+    let latency = start.elapsed();
+    span.in_scope(|| {
+        tracing::info!(
+            outcome = resp.as_ref(),
+            latency_millis = latency.as_millis() as u64,
+            "request handled"
+        )
+    });
+    metrics::record(req.as_ref(), resp.as_ref(), resp.is_error(), latency);
+    resp
+}
+
+/// Number of times a request is retried if it fails because the
+/// connection to the database was lost.
+const MAX_DB_RETRIES: u32 = 3;
+
+/// Delay before the first retry, which doubles with each retry.
+const DB_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Check if a request that failed with a transient error can be
+/// retried without repeating work the database may already have
+/// done. Failing to open a connection means no statement ran, so any
+/// request can be retried. Otherwise the connection may have been lost
+/// after the request's changes were committed, so only requests that
+/// don't change anything, or that are made safe to repeat with an
+/// idempotency key, are retried.
+fn can_retry(req: &Request, err: &Error) -> bool {
+    if !err.is_transient() {
+        return false;
+    }
+    if matches!(err, Error::Pool(bb8::RunError::User(_))) {
+        return true;
+    }
+    match req {
+        Request::ListProjects(_)
+        | Request::GetProjectStats(_)
+        | Request::ListOrganizations(_)
+        | Request::GetOrganizationStats(_)
+        | Request::GetBatch(_)
+        | Request::GetWorkflowRun(_)
+        | Request::GetJob(_)
+        | Request::GetJobs(_)
+        | Request::GetJobHistory(_)
+        | Request::GetJobAttempts(_)
+        | Request::GetArchivedJobs(_)
+        | Request::GetJobLog(_)
+        | Request::ListRunners(_)
+        | Request::GetStuckJobs
+        | Request::GetAuditLog(_) => true,
+        Request::AddJob(req) => req.idempotency_key.is_some(),
+        Request::AddJobFromTemplate(req) => req.idempotency_key.is_some(),
+        _ => false,
+    }
+}
+
+/// Handle a request if it's allowed by the context's API key and the
+/// rate limits. Requests that fail because the connection to the
+/// database was lost are retried with a backoff if `can_retry` allows
+/// it.
+///
+/// The key is checked before the rate limits, so that requests with
+/// a made up key are rejected without using up anyone's tokens.
+async fn check_and_handle_request(
+    pool: &Pool,
+    req: &Request,
+    context: &RequestContext,
+    span: Span,
+) -> Response {
+    if let Err(wait) = circuit_breaker::check() {
+        info!("request rejected while the database is unreachable");
+        return Response::Unavailable {
+            retry_after_millis: wait.as_millis() as u64,
+        };
+    }
+    if auth::is_required(context) {
+        let auth = auth::authorize(pool, req, context).instrument(span.clone());
+        if let Err(err) = auth.await {
+            circuit_breaker::record_error(&err);
+            info!("request rejected: {}", err);
+            return handle_request_err(err);
+        }
+    }
+    if let Err(wait) = rate_limit::check(req, context) {
+        info!("request rate limited");
+        return Response::TooManyRequests {
+            retry_after_millis: wait.as_millis() as u64,
+        };
+    }
+    let mut retries = 0;
+    let result = loop {
+        // The future for every type of request is large, so keep it on
+        // the heap rather than the caller's stack
+        let resp =
+            Box::pin(handle_request_ok(pool, req)).instrument(span.clone());
+        match resp.await {
+            Err(err) if can_retry(req, &err) && retries < MAX_DB_RETRIES => {
+                let delay = DB_RETRY_DELAY * 2u32.pow(retries);
+                warn!("retrying in {:?} after database error: {}", delay, err);
+                time::delay_for(delay).await;
+                retries += 1;
+            }
+            result => break result,
+        }
+    };
+    match result {
+        Ok(resp) => {
+            circuit_breaker::record_success();
+            if audit::is_audited(req) {
+                if let Err(err) = audit::record(pool, req, &resp, context).await
+                {
+                    error!("failed to record request in audit log: {}", err);
+                }
+            }
+            resp
+        }
         Err(err) => {
             error!("error: {}", err);
+            circuit_breaker::record_error(&err);
             handle_request_err(err)
         }
     }
@@ -0,0 +1,218 @@
+use crate::{Error, Pool, QueryExt};
+use fehler::{throw, throws};
+use jobclerk_types::*;
+
+/// Maximum number of entries returned by `get_audit_log` if the
+/// request doesn't set a limit.
+const DEFAULT_AUDIT_LOG_LIMIT: i64 = 100;
+
+/// Information about where a request came from that isn't part of the
+/// request itself.
+#[derive(Clone, Debug, Default)]
+pub struct RequestContext {
+    /// Address of the client that sent the request.
+    pub remote_addr: Option<String>,
+
+    /// API key sent with the request.
+    pub api_key: Option<String>,
+
+    /// Reject the request unless `api_key` is a valid key that is
+    /// allowed to make it.
+    pub require_auth: bool,
+
+    /// The request was sent with the admin key, so it's allowed to do
+    /// anything. The server checks the key before handling the
+    /// request, since the admin key isn't stored in the database.
+    pub admin: bool,
+
+    /// User logged in to the UI who sent the request from one of its
+    /// forms. The request is authorized with the user's role instead
+    /// of an API key.
+    pub ui_user: Option<String>,
+
+    /// Subject of the client's TLS certificate, if it sent one, or the
+    /// user logged in to the UI. This is recorded as the actor in the
+    /// audit log, since unlike the runner name in the request it can't
+    /// be made up.
+    pub client_identity: Option<String>,
+}
+
+/// Check if a request should be recorded in the audit log.
+///
+/// Read-only requests are not recorded. Neither are log appends,
+/// runner heartbeats, or job updates that only send a heartbeat, since
+/// those are sent constantly and don't change anything an operator
+/// would need to trace back. Lease extensions are recorded even though
+/// they also keep a job alive: runners only send them before long
+/// steps, and they let a job hold its runner past the project's
+/// heartbeat deadline, which is worth being able to explain when a job
+/// looks stuck.
+pub(crate) fn is_audited(req: &Request) -> bool {
+    match req {
+        Request::AddProject(_)
+        | Request::AddOrganization(_)
+        | Request::AddJob(_)
+        | Request::AddBatch(_)
+        | Request::AddChildJob(_)
+        | Request::PutWorkflow(_)
+        | Request::StartWorkflow(_)
+        | Request::PutJobTemplate(_)
+        | Request::AddJobFromTemplate(_)
+        | Request::TakeJob(_)
+        | Request::TakeJobs(_)
+        | Request::TakeJobFromProjects(_)
+        | Request::ExtendLease(_)
+        | Request::AddJobArtifact(_)
+        | Request::PurgeJobs(_)
+        | Request::SetRetention(_)
+        | Request::ForceSetJobState(_)
+        | Request::CancelJob(_)
+        | Request::RetryJob(_)
+        | Request::HandleStuckJobs => true,
+        Request::UpdateJob(req) => {
+            req.state.is_some() || req.data.is_some() || !req.steps.is_empty()
+        }
+        Request::ListProjects(_)
+        | Request::GetProjectStats(_)
+        | Request::ListOrganizations(_)
+        | Request::GetOrganizationStats(_)
+        | Request::GetBatch(_)
+        | Request::GetWorkflowRun(_)
+        | Request::GetJob(_)
+        | Request::GetJobs(_)
+        | Request::GetJobHistory(_)
+        | Request::GetJobAttempts(_)
+        | Request::GetArchivedJobs(_)
+        | Request::AppendJobLog(_)
+        | Request::GetJobLog(_)
+        | Request::RunnerHeartbeat(_)
+        | Request::ListRunners(_)
+        | Request::GetStuckJobs
+        | Request::GetAuditLog(_) => false,
+        // Requests this server doesn't handle are rejected
+        _ => false,
+    }
+}
+
+/// Get the runner that sent the request, if the request says.
+fn get_actor(req: &Request) -> Option<&str> {
+    match req {
+        Request::TakeJob(req) => Some(&req.runner),
+        Request::TakeJobs(req) => Some(&req.params.runner),
+        Request::TakeJobFromProjects(req) => Some(&req.runner),
+        _ => None,
+    }
+}
+
+/// Convert the request to JSON, leaving out job tokens so that the
+/// log can't be used to take over a running job.
+fn summarize(req: &Request) -> serde_json::Value {
+    let mut summary =
+        serde_json::to_value(req).expect("failed to convert request to JSON");
+    // Requests are serialized as {"RequestType": {...}}
+    if let Some(fields) = summary
+        .as_object_mut()
+        .and_then(|summary| summary.values_mut().next())
+        .and_then(|fields| fields.as_object_mut())
+    {
+        fields.remove("token");
+    }
+    summary
+}
+
+/// Record a request that succeeded in the audit log. Only requests
+/// for which `is_audited` returns true should be recorded.
+#[throws]
+pub(crate) async fn record(
+    pool: &Pool,
+    req: &Request,
+    resp: &Response,
+    context: &RequestContext,
+) {
+    // Requests that create or take a job only know its ID afterwards
+    let job_id = req.job_id().or_else(|| match resp {
+        Response::AddJob(resp) => Some(resp.job_id),
+        Response::TakeJob(resp) => resp.job.as_ref().map(|job| job.job_id),
+        _ => None,
+    });
+    let project_name = req.project_name().or_else(|| match resp {
+        Response::TakeJob(resp) => {
+            resp.job.as_ref().map(|job| job.project_name.as_str())
+        }
+        _ => None,
+    });
+
+    let actor = match (&context.client_identity, get_actor(req)) {
+        (Some(identity), _) => Some(identity.as_str()),
+        (None, Some(actor)) => Some(actor),
+        (None, None) if context.admin => Some("admin"),
+        (None, None) => None,
+    };
+
+    let conn = pool.get().await?;
+    conn.execute(
+        "INSERT INTO audit_log
+           (request, actor, remote_addr, project_name, job, summary)
+         VALUES ($1, COALESCE($2, (SELECT runner FROM jobs WHERE id = $5)),
+                 $3, $4, $5, $6)",
+        &[
+            &req.as_ref(),
+            &actor,
+            &context.remote_addr,
+            &project_name,
+            &job_id,
+            &summarize(req),
+        ],
+    )
+    .instrument_query("record_audit_log")
+    .await?;
+}
+
+#[throws]
+pub(crate) async fn get_audit_log(
+    pool: &Pool,
+    req: &GetAuditLogRequest,
+) -> GetAuditLogResponse {
+    if let Some(limit) = req.limit {
+        if limit <= 0 {
+            throw!(Error::invalid_argument("limit", limit));
+        }
+    }
+
+    let conn = pool.get().await?;
+    let rows = conn
+        .query(
+            "SELECT id, created, request, actor, remote_addr,
+                    project_name, job, summary
+             FROM audit_log
+             WHERE ($1::TEXT IS NULL OR project_name = $1)
+               AND ($2::BIGINT IS NULL OR job = $2)
+               AND ($3::BIGINT IS NULL OR id < $3)
+             ORDER BY id DESC
+             LIMIT $4",
+            &[
+                &req.project_name,
+                &req.job_id,
+                &req.before_id,
+                &req.limit.unwrap_or(DEFAULT_AUDIT_LOG_LIMIT),
+            ],
+        )
+        .instrument_query("get_audit_log")
+        .await?;
+
+    GetAuditLogResponse {
+        entries: rows
+            .iter()
+            .map(|row| AuditLogEntry {
+                id: row.get(0),
+                time: row.get(1),
+                request: row.get(2),
+                actor: row.get(3),
+                remote_addr: row.get(4),
+                project_name: row.get(5),
+                job_id: row.get(6),
+                summary: row.get(7),
+            })
+            .collect(),
+    }
+}
@@ -0,0 +1,417 @@
+#[cfg(feature = "jwt")]
+use crate::jwt;
+use crate::{Error, Pool, QueryExt, RequestContext};
+use chrono::{Duration, Utc};
+use fehler::{throw, throws};
+use jobclerk_types::*;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use tokio_postgres::Client;
+
+/// Keys are only stored as a hash, so that reading the database
+/// doesn't give access to the API.
+pub(crate) fn hash_api_key(api_key: &str) -> String {
+    format!("{:x}", Sha256::digest(api_key.as_bytes()))
+}
+
+/// Create an API key and return it. This is the only time the key
+/// itself is available.
+///
+/// The key can be limited to either a project or an organization.
+#[throws]
+pub async fn create_api_key(
+    client: &Client,
+    project_name: Option<&str>,
+    organization_name: Option<&str>,
+    role: ApiKeyRole,
+    description: Option<&str>,
+) -> String {
+    if project_name.is_some() && organization_name.is_some() {
+        throw!(Error::bad_request(
+            ErrorCode::InvalidArgument,
+            "an API key can't be limited to both a project and an \
+             organization"
+                .into()
+        ));
+    }
+    let api_key: String =
+        thread_rng().sample_iter(&Alphanumeric).take(32).collect();
+    client
+        .execute(
+            "INSERT INTO api_keys
+               (key_hash, project_name, organization_name, role,
+                description)
+             VALUES ($1, $2, $3, $4, $5)",
+            &[
+                &hash_api_key(&api_key),
+                &project_name,
+                &organization_name,
+                &role.as_ref(),
+                &description,
+            ],
+        )
+        .await?;
+    api_key
+}
+
+/// How long a UI session lasts before the user has to log in again.
+const UI_SESSION_DURATION_DAYS: i64 = 7;
+
+/// Create a user that can log in to the UI, or change the password,
+/// project and role of an existing one. Like an API key, the user can
+/// be limited to a project, and their role limits the requests they
+/// can make from the UI.
+#[throws]
+pub async fn create_ui_user(
+    client: &Client,
+    name: &str,
+    password: &str,
+    project_name: Option<&str>,
+    role: ApiKeyRole,
+) {
+    if name.is_empty() || password.is_empty() {
+        throw!(Error::bad_request(
+            ErrorCode::InvalidArgument,
+            "the user name and password can't be empty".into()
+        ));
+    }
+    let password_hash = bcrypt::hash(password, bcrypt::DEFAULT_COST)
+        .map_err(|err| Error::Config(format!("bcrypt error: {}", err)))?;
+    client
+        .execute(
+            "INSERT INTO ui_users (name, password_hash, project_name, role)
+             VALUES ($1, $2, $3, $4)
+             ON CONFLICT (name) DO UPDATE
+             SET password_hash = $2, project_name = $3, role = $4",
+            &[&name, &password_hash, &project_name, &role.as_ref()],
+        )
+        .await?;
+}
+
+/// Check a UI user's password and start a session for them. Returns
+/// the session ID to store in the browser's cookie, which like API
+/// keys is only available here.
+#[throws]
+pub async fn create_ui_session(
+    pool: &Pool,
+    name: &str,
+    password: &str,
+) -> String {
+    let conn = pool.get().await?;
+    let row = conn
+        .query_opt(
+            "SELECT password_hash FROM ui_users WHERE name = $1",
+            &[&name],
+        )
+        .instrument_query("get_ui_user")
+        .await?;
+    let valid = match row {
+        Some(row) => bcrypt::verify(password, row.get(0)).unwrap_or(false),
+        None => false,
+    };
+    if !valid {
+        throw!(Error::Unauthorized);
+    }
+
+    let session: String =
+        thread_rng().sample_iter(&Alphanumeric).take(32).collect();
+    let expires = Utc::now() + Duration::days(UI_SESSION_DURATION_DAYS);
+    conn.execute(
+        "INSERT INTO ui_sessions (id_hash, user_name, expires)
+         VALUES ($1, $2, $3)",
+        &[&hash_api_key(&session), &name, &expires],
+    )
+    .instrument_query("create_ui_session")
+    .await?;
+    session
+}
+
+/// Get the user a UI session belongs to, or `None` if the session
+/// doesn't exist or has expired.
+#[throws]
+pub async fn get_ui_session_user(pool: &Pool, session: &str) -> Option<String> {
+    let conn = pool.get().await?;
+    let row = conn
+        .query_opt(
+            "SELECT user_name FROM ui_sessions
+             WHERE id_hash = $1 AND expires > CURRENT_TIMESTAMP",
+            &[&hash_api_key(session)],
+        )
+        .instrument_query("get_ui_session")
+        .await?;
+    row.map(|row| row.get(0))
+}
+
+/// End a UI session, along with any sessions that have expired.
+#[throws]
+pub async fn delete_ui_session(pool: &Pool, session: &str) {
+    let conn = pool.get().await?;
+    conn.execute(
+        "DELETE FROM ui_sessions
+         WHERE id_hash = $1 OR expires <= CURRENT_TIMESTAMP",
+        &[&hash_api_key(session)],
+    )
+    .instrument_query("delete_ui_session")
+    .await?;
+}
+
+/// Check if a request manages projects or organizations rather than
+/// jobs. These can only be made with the admin key, not with API keys.
+pub fn is_project_management(req: &Request) -> bool {
+    matches!(req, Request::AddProject(_) | Request::AddOrganization(_))
+}
+
+/// Check if the API key is the admin key, without leaking how much of
+/// it matched through timing.
+pub fn is_admin_key(api_key: Option<&str>, admin_key: &str) -> bool {
+    let api_key = api_key.unwrap_or_default().as_bytes();
+    let admin_key = admin_key.as_bytes();
+    api_key.len() == admin_key.len()
+        && api_key
+            .iter()
+            .zip(admin_key)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Check if a key with the role can make the request. Requests that
+/// aren't listed here are only allowed for admins.
+fn is_allowed(role: ApiKeyRole, req: &Request) -> bool {
+    use ApiKeyRole::*;
+
+    match req {
+        Request::ListProjects(_)
+        | Request::GetProjectStats(_)
+        | Request::ListOrganizations(_)
+        | Request::GetOrganizationStats(_)
+        | Request::GetBatch(_)
+        | Request::GetWorkflowRun(_)
+        | Request::GetJob(_)
+        | Request::GetJobs(_)
+        | Request::GetJobHistory(_)
+        | Request::GetJobAttempts(_)
+        | Request::GetArchivedJobs(_)
+        | Request::GetJobLog(_)
+        | Request::ListRunners(_)
+        | Request::GetStuckJobs
+        | Request::GetAuditLog(_) => true,
+        Request::AddJob(_)
+        | Request::AddBatch(_)
+        | Request::StartWorkflow(_)
+        | Request::AddJobFromTemplate(_)
+        | Request::CancelJob(_)
+        | Request::RetryJob(_) => matches!(role, Admin | Submitter),
+        Request::TakeJob(_)
+        | Request::TakeJobs(_)
+        | Request::TakeJobFromProjects(_)
+        | Request::UpdateJob(_)
+        | Request::ExtendLease(_)
+        | Request::AppendJobLog(_)
+        | Request::AddJobArtifact(_)
+        | Request::AddChildJob(_)
+        | Request::RunnerHeartbeat(_) => matches!(role, Admin | Runner),
+        _ => role == Admin,
+    }
+}
+
+/// Names of the projects a request acts on, or `None` if it isn't
+/// about particular projects.
+fn request_projects(req: &Request) -> Option<Vec<&str>> {
+    match req {
+        Request::TakeJobFromProjects(req) => {
+            Some(req.project_names.iter().map(String::as_str).collect())
+        }
+        Request::AddChildJob(req) => {
+            Some(vec![&req.project_name, &req.child.project_name])
+        }
+        Request::AddBatch(req) => Some(
+            std::iter::once(req.project_name.as_str())
+                .chain(req.jobs.iter().map(|job| job.project_name.as_str()))
+                .collect(),
+        ),
+        req => req.project_name().map(|name| vec![name]),
+    }
+}
+
+/// Check if a key limited to one project can make the request.
+fn is_in_project(req: &Request, key_project: &str) -> bool {
+    request_projects(req)
+        .is_some_and(|names| names.iter().all(|name| *name == key_project))
+}
+
+/// Check if a key limited to one organization can make the request.
+/// Requests about projects are allowed if all of the projects are in
+/// the organization.
+#[throws]
+async fn is_in_organization(
+    pool: &Pool,
+    req: &Request,
+    key_organization: &str,
+) -> bool {
+    match req {
+        Request::ListProjects(req) => {
+            req.organization_name.as_deref() == Some(key_organization)
+        }
+        Request::GetOrganizationStats(req) => {
+            req.organization_name == key_organization
+        }
+        req => match request_projects(req) {
+            Some(names) => {
+                let names: HashSet<&str> = names.into_iter().collect();
+                let names: Vec<&str> = names.into_iter().collect();
+                let conn = pool.get().await?;
+                let count: i64 = conn
+                    .query_one(
+                        "SELECT COUNT(*) FROM projects
+                         JOIN organizations
+                           ON organizations.id = projects.organization
+                         WHERE projects.name = ANY($1)
+                           AND organizations.name = $2",
+                        &[&names, &key_organization],
+                    )
+                    .instrument_query("count_organization_projects")
+                    .await?
+                    .get(0);
+                count == names.len() as i64
+            }
+            None => false,
+        },
+    }
+}
+
+/// What a bearer token or UI user can be used with.
+struct Grant {
+    /// How errors refer to the token or user.
+    holder: &'static str,
+    /// Project the token is limited to, if any.
+    project_name: Option<String>,
+    /// Organization the token is limited to, if any.
+    organization_name: Option<String>,
+    role: ApiKeyRole,
+}
+
+/// Look up an API key and get the project or organization and role it
+/// can be used with.
+#[throws]
+async fn get_api_key_grant(pool: &Pool, api_key: &str) -> Grant {
+    let conn = pool.get().await?;
+    let row = conn
+        .query_opt(
+            "SELECT project_name, organization_name, role FROM api_keys
+             WHERE key_hash = $1 AND revoked IS NULL",
+            &[&hash_api_key(api_key)],
+        )
+        .instrument_query("get_api_key")
+        .await?;
+    let row = match row {
+        Some(row) => row,
+        None => throw!(Error::Unauthorized),
+    };
+    Grant {
+        holder: "API key",
+        project_name: row.get(0),
+        organization_name: row.get(1),
+        role: row.get::<_, &str>(2).parse()?,
+    }
+}
+
+/// Look up a UI user and get the project and role they can use.
+#[throws]
+async fn get_ui_user_grant(pool: &Pool, user: &str) -> Grant {
+    let conn = pool.get().await?;
+    let row = conn
+        .query_opt(
+            "SELECT project_name, role FROM ui_users WHERE name = $1",
+            &[&user],
+        )
+        .instrument_query("get_ui_user_grant")
+        .await?;
+    let row = match row {
+        Some(row) => row,
+        None => throw!(Error::Unauthorized),
+    };
+    Grant {
+        holder: "UI user",
+        project_name: row.get(0),
+        organization_name: None,
+        role: row.get::<_, &str>(1).parse()?,
+    }
+}
+
+/// Get what a bearer token can be used with. The token is either a JWT
+/// or an API key.
+#[cfg(feature = "jwt")]
+#[throws]
+async fn get_grant(pool: &Pool, token: &str) -> Grant {
+    if jwt::is_jwt(token) {
+        let (project_name, role) = jwt::authenticate(token).await?;
+        Grant {
+            holder: "API key",
+            project_name,
+            organization_name: None,
+            role,
+        }
+    } else {
+        get_api_key_grant(pool, token).await?
+    }
+}
+
+#[cfg(not(feature = "jwt"))]
+#[throws]
+async fn get_grant(pool: &Pool, token: &str) -> Grant {
+    get_api_key_grant(pool, token).await?
+}
+
+/// Check if a request has to be authorized. Requests from UI users
+/// always are, and other requests are unless the server doesn't
+/// require auth or they were sent with the admin key.
+pub(crate) fn is_required(context: &RequestContext) -> bool {
+    context.ui_user.is_some() || (context.require_auth && !context.admin)
+}
+
+/// Check that the request's UI user or API key is valid and allowed to
+/// make the request.
+#[throws]
+pub(crate) async fn authorize(
+    pool: &Pool,
+    req: &Request,
+    context: &RequestContext,
+) {
+    if is_project_management(req) {
+        throw!(Error::Forbidden(
+            "managing projects requires the admin key".into()
+        ));
+    }
+
+    let grant = match (&context.ui_user, &context.api_key) {
+        (Some(user), _) => get_ui_user_grant(pool, user).await?,
+        (None, Some(api_key)) => get_grant(pool, api_key).await?,
+        (None, None) => throw!(Error::Unauthorized),
+    };
+
+    if !is_allowed(grant.role, req) {
+        throw!(Error::Forbidden(format!(
+            "the {} role can't make {} requests",
+            grant.role.as_ref(),
+            req.as_ref()
+        )));
+    }
+    if let Some(key_project) = grant.project_name {
+        if !is_in_project(req, &key_project) {
+            throw!(Error::Forbidden(format!(
+                "the {} can only be used with project {}",
+                grant.holder, key_project
+            )));
+        }
+    }
+    if let Some(key_organization) = grant.organization_name {
+        if !is_in_organization(pool, req, &key_organization).await? {
+            throw!(Error::Forbidden(format!(
+                "the {} can only be used with organization {}",
+                grant.holder, key_organization
+            )));
+        }
+    }
+}
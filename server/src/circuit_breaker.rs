@@ -0,0 +1,117 @@
+//! Circuit breaker around the database.
+//!
+//! While the database is down, each request would wait for the pool's
+//! connection timeout before failing, tying up a worker the whole
+//! time. Instead, once enough requests in a row have failed because
+//! the database couldn't be reached, the circuit opens and requests
+//! are rejected right away with an `Unavailable` response. When the
+//! circuit has been open for a while, one request is let through to
+//! try the database again: if it works the circuit closes, and if not
+//! it stays open for another period.
+
+use crate::Error;
+use fehler::{throw, throws};
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug)]
+pub struct CircuitBreakerSettings {
+    /// Number of requests in a row that fail to reach the database
+    /// before the circuit opens.
+    pub failure_threshold: u32,
+    /// How long requests are rejected for before the database is
+    /// tried again.
+    pub open_duration: Duration,
+}
+
+impl Default for CircuitBreakerSettings {
+    fn default() -> CircuitBreakerSettings {
+        CircuitBreakerSettings {
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Breaker {
+    settings: CircuitBreakerSettings,
+    /// Requests in a row that failed to reach the database.
+    failures: u32,
+    /// Set while the circuit is open. Once this has passed, the next
+    /// request is let through as a trial.
+    open_until: Option<Instant>,
+}
+
+static BREAKER: Lazy<Mutex<Breaker>> = Lazy::new(Default::default);
+
+/// Change the settings, which otherwise have their default values.
+/// This also closes the circuit.
+#[throws]
+pub fn configure(settings: CircuitBreakerSettings) {
+    if settings.failure_threshold == 0 {
+        throw!(Error::Config(
+            "the circuit breaker's failure threshold can't be zero".into()
+        ));
+    }
+    *BREAKER.lock().unwrap() = Breaker {
+        settings,
+        ..Default::default()
+    };
+}
+
+/// Whether the error means the database couldn't be reached, as
+/// opposed to an error in the request.
+fn is_outage(err: &Error) -> bool {
+    err.is_transient() || matches!(err, Error::Pool(bb8::RunError::TimedOut))
+}
+
+/// Check if a request can go ahead. If the circuit is open, returns
+/// how long until the database will be tried again.
+pub(crate) fn check() -> Result<(), Duration> {
+    let mut breaker = BREAKER.lock().unwrap();
+    let now = Instant::now();
+    match breaker.open_until {
+        Some(open_until) if now < open_until => Err(open_until - now),
+        Some(_) => {
+            // Let this request through, and keep rejecting the others
+            // until it finishes
+            breaker.open_until = Some(now + breaker.settings.open_duration);
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+/// Record that a request reached the database.
+pub(crate) fn record_success() {
+    let mut breaker = BREAKER.lock().unwrap();
+    if breaker.open_until.is_some() {
+        info!("database reachable again");
+    }
+    breaker.failures = 0;
+    breaker.open_until = None;
+}
+
+/// Record a request's error. Errors other than failing to reach the
+/// database are ignored, since the request may have failed before
+/// getting that far.
+pub(crate) fn record_error(err: &Error) {
+    if !is_outage(err) {
+        return;
+    }
+    let mut breaker = BREAKER.lock().unwrap();
+    breaker.failures = breaker.failures.saturating_add(1);
+    if breaker.failures >= breaker.settings.failure_threshold {
+        if breaker.open_until.is_none() {
+            warn!(
+                "database unreachable, rejecting requests for {:?}",
+                breaker.settings.open_duration
+            );
+        }
+        breaker.open_until =
+            Some(Instant::now() + breaker.settings.open_duration);
+    }
+}
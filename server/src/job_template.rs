@@ -0,0 +1,182 @@
+//! Job templates are named defaults for jobs, so that submitters
+//! don't have to send the same fields for every job they add.
+
+use crate::api::{add_job, check_add_job};
+use crate::{Error, Pool, QueryExt};
+use fehler::{throw, throws};
+use jobclerk_types::*;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Replace the `{{name}}` placeholders in a string with their
+/// parameters, recording which parameters were used.
+#[throws]
+fn substitute_str(
+    s: &str,
+    params: &BTreeMap<String, String>,
+    used: &mut BTreeSet<String>,
+) -> String {
+    let mut out = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start + 2..].find("}}") {
+            Some(len) => start + 2 + len,
+            None => break,
+        };
+        let name = rest[start + 2..end].trim();
+        let value = params.get(name).ok_or_else(|| {
+            Error::bad_request(
+                ErrorCode::InvalidArgument,
+                format!("missing parameter: {}", name),
+            )
+        })?;
+        used.insert(name.into());
+        out.push_str(&rest[..start]);
+        out.push_str(value);
+        rest = &rest[end + 2..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[throws]
+fn substitute_value(
+    value: &mut Value,
+    params: &BTreeMap<String, String>,
+    used: &mut BTreeSet<String>,
+) {
+    match value {
+        Value::String(s) => *s = substitute_str(s, params, used)?,
+        Value::Array(items) => {
+            for item in items {
+                substitute_value(item, params, used)?;
+            }
+        }
+        Value::Object(fields) => {
+            for field in fields.values_mut() {
+                substitute_value(field, params, used)?;
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Replace the `{{name}}` placeholders in the strings of `values`
+/// with their parameters. Fails if a placeholder has no parameter, or
+/// if a parameter isn't used by any placeholder.
+#[throws]
+pub(crate) fn substitute_params<'a>(
+    values: impl IntoIterator<Item = &'a mut Value>,
+    params: &BTreeMap<String, String>,
+) {
+    let mut used = BTreeSet::new();
+    for value in values {
+        substitute_value(value, params, &mut used)?;
+    }
+    if let Some(name) = params.keys().find(|name| !used.contains(*name)) {
+        throw!(Error::bad_request(
+            ErrorCode::InvalidArgument,
+            format!("unknown parameter: {}", name)
+        ));
+    }
+}
+
+/// Merge `overrides` into `base`. Fields of objects are merged
+/// recursively, and any other value replaces the base value, except
+/// for null which leaves it as it is.
+fn merge_data(base: &mut Value, overrides: &Value) {
+    match (base, overrides) {
+        (_, Value::Null) => {}
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                merge_data(base.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (base, overrides) => *base = overrides.clone(),
+    }
+}
+
+/// Request to add a job from `template`.
+#[throws]
+fn template_job(
+    req: &AddJobFromTemplateRequest,
+    template: JobTemplate,
+) -> AddJobRequest {
+    let mut data = template.data;
+    substitute_params(Some(&mut data), &req.params)?;
+    merge_data(&mut data, &req.data);
+    AddJobRequest {
+        project_name: req.project_name.clone(),
+        max_runtime_millis: req
+            .max_runtime_millis
+            .or(template.max_runtime_millis),
+        idempotency_key: req.idempotency_key.clone(),
+        unique_key: req.unique_key.clone(),
+        requirements: req.requirements.clone().unwrap_or(template.requirements),
+        priority: req.priority.unwrap_or(template.priority),
+        queue: req.queue.clone().or(template.queue),
+        data,
+        ..Default::default()
+    }
+}
+
+#[throws]
+pub(crate) async fn put_job_template(pool: &Pool, req: &PutJobTemplateRequest) {
+    if req.name.is_empty() {
+        throw!(Error::bad_request(
+            ErrorCode::InvalidArgument,
+            "template names can't be empty".into()
+        ));
+    }
+    let template = &req.template;
+    check_add_job(&AddJobRequest {
+        project_name: req.project_name.clone(),
+        max_runtime_millis: template.max_runtime_millis,
+        requirements: template.requirements.clone(),
+        priority: template.priority,
+        queue: template.queue.clone(),
+        data: template.data.clone(),
+        ..Default::default()
+    })?;
+
+    let conn = pool.get().await?;
+    let updated = conn
+        .execute(
+            "INSERT INTO job_templates (project, name, template)
+             SELECT id, $2, $3 FROM projects WHERE name = $1
+             ON CONFLICT (project, name) DO UPDATE
+             SET template = EXCLUDED.template,
+                 updated = CURRENT_TIMESTAMP",
+            &[&req.project_name, &req.name, &serde_json::json!(template)],
+        )
+        .instrument_query("put_job_template")
+        .await?;
+    if updated == 0 {
+        throw!(Error::NotFound);
+    }
+}
+
+#[throws]
+pub(crate) async fn add_job_from_template(
+    pool: &Pool,
+    req: &AddJobFromTemplateRequest,
+) -> AddJobResponse {
+    let row = pool
+        .get()
+        .await?
+        .query_opt(
+            "SELECT job_templates.template FROM job_templates
+             JOIN projects ON projects.id = job_templates.project
+             WHERE projects.name = $1 AND job_templates.name = $2",
+            &[&req.project_name, &req.template_name],
+        )
+        .instrument_query("get_job_template")
+        .await?;
+    let template: JobTemplate = match row {
+        Some(row) => serde_json::from_value(row.get(0)).map_err(|err| {
+            Error::Config(format!("invalid job template: {}", err))
+        })?,
+        None => throw!(Error::NotFound),
+    };
+    add_job(pool, &template_job(req, template)?).await?
+}
@@ -0,0 +1,203 @@
+//! Optional authentication with JWTs from an OpenID Connect identity
+//! provider.
+//!
+//! Once configured, a bearer token that looks like a JWT is checked
+//! against the provider's signing keys instead of the `api_keys`
+//! table. The token's claims give the project and role it can be used
+//! with, which mean the same thing as an API key's. Only RS256 tokens
+//! are accepted.
+
+use crate::Error;
+use fehler::{throw, throws};
+use jobclerk_types::ApiKeyRole;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use log::{info, warn};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+/// Shortest time between fetches of the signing keys, so that tokens
+/// with unknown key IDs can't be used to flood the provider.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Settings for accepting JWTs.
+#[derive(Clone, Debug)]
+pub struct JwtConfig {
+    /// Required value of the `iss` claim.
+    pub issuer: String,
+    /// Required value of the `aud` claim.
+    pub audience: String,
+    /// URL of the provider's JSON Web Key Set.
+    pub jwks_url: String,
+    /// Claim with the name of the project the token can be used with.
+    /// Tokens without it can be used with any project.
+    pub project_claim: String,
+    /// Claim with the token's role. Tokens without it are rejected.
+    pub role_claim: String,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+struct RsaKey {
+    kid: Option<String>,
+    modulus: String,
+    exponent: String,
+}
+
+struct SigningKeys {
+    keys: Vec<RsaKey>,
+    fetched: Instant,
+}
+
+impl SigningKeys {
+    /// Find the key with the ID, or the only key if the token doesn't
+    /// say which one it was signed with.
+    fn find(&self, kid: Option<&str>) -> Option<&RsaKey> {
+        match kid {
+            Some(kid) => {
+                self.keys.iter().find(|key| key.kid.as_deref() == Some(kid))
+            }
+            None if self.keys.len() == 1 => self.keys.first(),
+            None => None,
+        }
+    }
+}
+
+struct Validator {
+    config: JwtConfig,
+    keys: RwLock<SigningKeys>,
+}
+
+static VALIDATOR: OnceCell<Validator> = OnceCell::new();
+
+#[throws]
+async fn fetch_keys(jwks_url: &str) -> SigningKeys {
+    let jwks: Jwks = reqwest::get(jwks_url)
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let keys = jwks
+        .keys
+        .into_iter()
+        .filter(|key| key.kty == "RSA")
+        .filter_map(|key| {
+            Some(RsaKey {
+                kid: key.kid,
+                modulus: key.n?,
+                exponent: key.e?,
+            })
+        })
+        .collect();
+    SigningKeys {
+        keys,
+        fetched: Instant::now(),
+    }
+}
+
+/// Fetch the provider's signing keys and start accepting JWTs. This
+/// should be called once at startup.
+#[throws]
+pub async fn configure(config: JwtConfig) {
+    let keys = fetch_keys(&config.jwks_url).await?;
+    info!("loaded {} JWT signing keys", keys.keys.len());
+    // If there's already a configuration this one is dropped
+    let _ = VALIDATOR.set(Validator {
+        config,
+        keys: RwLock::new(keys),
+    });
+}
+
+/// Check if a bearer token should be handled as a JWT. API keys never
+/// contain dots.
+pub(crate) fn is_jwt(token: &str) -> bool {
+    VALIDATOR.get().is_some() && token.split('.').count() == 3
+}
+
+impl Validator {
+    fn validation(&self) -> Validation {
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.iss = Some(self.config.issuer.clone());
+        validation.set_audience(&[&self.config.audience]);
+        validation
+    }
+
+    /// Check the token's signature and standard claims, and get all of
+    /// its claims. Returns None if there's no key with the ID.
+    async fn decode(
+        &self,
+        token: &str,
+        kid: Option<&str>,
+    ) -> Option<jsonwebtoken::errors::Result<Map<String, Value>>> {
+        let keys = self.keys.read().await;
+        let key = keys.find(kid)?;
+        let key = DecodingKey::from_rsa_components(&key.modulus, &key.exponent);
+        Some(decode(token, &key, &self.validation()).map(|data| data.claims))
+    }
+
+    /// Fetch the keys again, unless they were fetched recently. A key
+    /// ID that isn't known may be for a key the provider just added.
+    async fn refresh(&self) {
+        let mut keys = self.keys.write().await;
+        if keys.fetched.elapsed() < MIN_REFRESH_INTERVAL {
+            return;
+        }
+        match fetch_keys(&self.config.jwks_url).await {
+            Ok(new_keys) => *keys = new_keys,
+            Err(err) => {
+                warn!("failed to fetch JWT signing keys: {}", err);
+                keys.fetched = Instant::now();
+            }
+        }
+    }
+}
+
+/// Validate a JWT and get the project and role from its claims.
+#[throws]
+pub(crate) async fn authenticate(token: &str) -> (Option<String>, ApiKeyRole) {
+    let validator = VALIDATOR.get().ok_or(Error::Unauthorized)?;
+    let kid = decode_header(token).map_err(|_| Error::Unauthorized)?.kid;
+
+    let mut claims = validator.decode(token, kid.as_deref()).await;
+    if claims.is_none() {
+        validator.refresh().await;
+        claims = validator.decode(token, kid.as_deref()).await;
+    }
+    let claims = match claims {
+        Some(Ok(claims)) => claims,
+        Some(Err(err)) => {
+            info!("invalid JWT: {}", err);
+            throw!(Error::Unauthorized);
+        }
+        None => {
+            info!("JWT signed with unknown key: {:?}", kid);
+            throw!(Error::Unauthorized);
+        }
+    };
+
+    let config = &validator.config;
+    let project_name = match claims.get(&config.project_claim) {
+        None | Some(Value::Null) => None,
+        Some(Value::String(project_name)) => Some(project_name.clone()),
+        Some(_) => throw!(Error::Unauthorized),
+    };
+    let role = claims
+        .get(&config.role_claim)
+        .and_then(Value::as_str)
+        .and_then(|role| role.parse().ok())
+        .ok_or(Error::Unauthorized)?;
+    (project_name, role)
+}
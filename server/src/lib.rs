@@ -1,18 +1,59 @@
 pub mod api;
+mod audit;
+mod auth;
+pub mod circuit_breaker;
+mod job_template;
+#[cfg(feature = "jwt")]
+pub mod jwt;
+pub mod metrics;
+mod migrations;
+pub mod notify;
+mod organization;
+pub mod rate_limit;
+#[cfg(feature = "redis")]
+pub mod redis_queue;
 pub mod ui;
+#[cfg(feature = "webhooks")]
+pub mod webhook;
+mod workflow;
+
+pub use audit::RequestContext;
+pub use auth::{
+    create_api_key, create_ui_session, create_ui_user, delete_ui_session,
+    get_ui_session_user, is_admin_key, is_project_management,
+};
+pub use migrations::{
+    check_schema_version, migrate, migration_status, MigrationStatus,
+    SCHEMA_VERSION,
+};
 
 use bb8_postgres::PostgresConnectionManager;
-use fehler::throws;
+use fehler::{throw, throws};
+use futures::future::{BoxFuture, Future};
+use jobclerk_types::{BadRequestError, ErrorCode};
+use log::warn;
+use std::error::Error as _;
+use std::fmt::Display;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio_postgres::error::SqlState;
 use tokio_postgres::NoTls;
+use tracing::info_span;
+use tracing_futures::Instrument;
 
 pub type Pool = bb8::Pool<PostgresConnectionManager<NoTls>>;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
-    #[error("bad request: {0}")]
-    BadRequest(String),
+    #[error("bad request: {}", .0.message)]
+    BadRequest(BadRequestError),
     #[error("not found")]
     NotFound,
+    #[error("unauthorized")]
+    Unauthorized,
+    #[error("forbidden: {0}")]
+    Forbidden(String),
     #[error("db error: {0}")]
     Db(#[from] tokio_postgres::Error),
     #[error("pool error: {0}")]
@@ -21,16 +62,220 @@ pub enum Error {
     Parse(#[from] strum::ParseError),
     #[error("template error: {0}")]
     Template(#[from] askama::Error),
+    #[error("config error: {0}")]
+    Config(String),
+    #[error(
+        "database schema version is {found} but {expected} is required \
+         (run `dbctl migrate`)"
+    )]
+    SchemaVersion { expected: i32, found: i32 },
+    #[cfg(feature = "redis")]
+    #[error("redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+    #[cfg(feature = "jwt")]
+    #[error("JWKS error: {0}")]
+    Jwks(#[from] reqwest::Error),
+}
+
+impl Error {
+    fn bad_request(code: ErrorCode, message: String) -> Error {
+        Error::BadRequest(BadRequestError { code, message })
+    }
+
+    /// Check if the error is from losing the connection to the
+    /// database, such as while it restarts. Postgres rolls back the
+    /// transactions of lost connections, but a connection lost while
+    /// committing may have committed first, so only some requests can
+    /// be retried after these errors (see `api::can_retry`).
+    fn is_transient(&self) -> bool {
+        match self {
+            // The pool couldn't open a connection
+            Error::Pool(bb8::RunError::User(_)) => true,
+            Error::Db(err) => {
+                let shutdown = [
+                    SqlState::ADMIN_SHUTDOWN,
+                    SqlState::CRASH_SHUTDOWN,
+                    SqlState::CANNOT_CONNECT_NOW,
+                ];
+                let io_error =
+                    err.source().is_some_and(|source| source.is::<io::Error>());
+                err.code().is_some_and(|code| shutdown.contains(code))
+                    || io_error
+                    // tokio-postgres doesn't say what kind of error
+                    // it is otherwise
+                    || err.to_string() == "connection closed"
+            }
+            _ => false,
+        }
+    }
+
+    /// Create a `BadRequest` error for a request field with an invalid
+    /// value.
+    fn invalid_argument(field: &str, value: impl Display) -> Error {
+        Error::bad_request(
+            ErrorCode::InvalidArgument,
+            format!("invalid {}: {}", field, value),
+        )
+    }
+}
+
+/// Database used if no URL is given.
+pub const DEFAULT_DATABASE_URL: &str = "postgres://postgres@localhost:5432";
+
+/// Get the database URL from the `DATABASE_URL` environment variable,
+/// falling back to `DEFAULT_DATABASE_URL` if it isn't set.
+pub fn database_url_from_env() -> String {
+    std::env::var("DATABASE_URL")
+        .unwrap_or_else(|_| DEFAULT_DATABASE_URL.to_string())
+}
+
+/// Requests that take longer than this are abandoned by default.
+pub const DEFAULT_REQUEST_TIMEOUT_MILLIS: u64 = 60_000;
+
+static REQUEST_TIMEOUT_MILLIS: AtomicU64 =
+    AtomicU64::new(DEFAULT_REQUEST_TIMEOUT_MILLIS);
+
+/// Give up on API requests that take longer than `millis` to handle,
+/// or let them take as long as they need if `millis` is 0.
+pub fn set_request_timeout_millis(millis: u64) {
+    REQUEST_TIMEOUT_MILLIS.store(millis, Ordering::Relaxed);
+}
+
+fn request_timeout() -> Option<Duration> {
+    match REQUEST_TIMEOUT_MILLIS.load(Ordering::Relaxed) {
+        0 => None,
+        millis => Some(Duration::from_millis(millis)),
+    }
+}
+
+/// Queries that take longer than this are logged by default.
+pub const DEFAULT_SLOW_QUERY_MILLIS: u64 = 1000;
+
+static SLOW_QUERY_MILLIS: AtomicU64 = AtomicU64::new(DEFAULT_SLOW_QUERY_MILLIS);
+
+/// Log a warning for each query that takes longer than `millis`, or
+/// turn off slow query logging if `millis` is 0.
+pub fn set_slow_query_millis(millis: u64) {
+    SLOW_QUERY_MILLIS.store(millis, Ordering::Relaxed);
+}
+
+/// Extension for the futures of database queries.
+trait QueryExt: Future + Send + Sized {
+    /// Run a query in a span with its name, count it in the metrics,
+    /// and log a warning if it is slow. Queries are identified by name
+    /// rather than by their SQL, which is often long.
+    fn instrument_query<'a>(
+        self,
+        name: &'static str,
+    ) -> BoxFuture<'a, Self::Output>
+    where
+        Self: 'a,
+    {
+        let span = info_span!("query", name);
+        let query = async move {
+            metrics::record_query(name);
+            let start = Instant::now();
+            let output = self.await;
+            let millis = start.elapsed().as_millis() as u64;
+            let threshold = SLOW_QUERY_MILLIS.load(Ordering::Relaxed);
+            if threshold != 0 && millis > threshold {
+                warn!("slow query {}: {} ms", name, millis);
+            }
+            output
+        };
+        Box::pin(query.instrument(span))
+    }
 }
 
-pub const DEFAULT_POSTGRES_PORT: u16 = 5432;
+impl<F: Future + Send> QueryExt for F {}
+
+/// Connection pool settings. The defaults are the same as bb8's.
+#[derive(Clone, Debug)]
+pub struct PoolOptions {
+    /// Maximum number of connections the pool opens.
+    pub max_size: u32,
+
+    /// Number of idle connections the pool tries to keep open.
+    pub min_idle: Option<u32>,
+
+    /// How long to wait for a connection before failing.
+    pub connection_timeout: Duration,
+
+    /// Idle connections are closed after this long.
+    pub idle_timeout: Option<Duration>,
+
+    /// Connections are closed after this long, even if in use
+    /// recently.
+    pub max_lifetime: Option<Duration>,
+
+    /// Check that each connection still works before handing it out,
+    /// so that connections broken by a database restart are replaced
+    /// rather than failing requests.
+    pub test_on_check_out: bool,
 
+    /// Have the database cancel any query that takes longer than this.
+    /// Unlike timing out a request, this frees the connection that the
+    /// query was using.
+    pub statement_timeout: Option<Duration>,
+}
+
+impl Default for PoolOptions {
+    fn default() -> PoolOptions {
+        PoolOptions {
+            max_size: 10,
+            min_idle: None,
+            connection_timeout: Duration::from_secs(30),
+            idle_timeout: Some(Duration::from_secs(10 * 60)),
+            max_lifetime: Some(Duration::from_secs(30 * 60)),
+            test_on_check_out: true,
+            statement_timeout: None,
+        }
+    }
+}
+
+/// Create a connection pool with the default options. The database
+/// URL can be either a `postgres://` URL or a key/value connection
+/// string.
 #[throws]
-pub async fn make_pool(port: u16) -> Pool {
-    let db_manager = PostgresConnectionManager::new_from_stringlike(
-        format!("host=localhost user=postgres port={}", port),
-        NoTls,
-    )?;
+pub async fn make_pool(database_url: &str) -> Pool {
+    make_pool_with_options(database_url, &PoolOptions::default()).await?
+}
+
+/// Create a connection pool.
+#[throws]
+pub async fn make_pool_with_options(
+    database_url: &str,
+    options: &PoolOptions,
+) -> Pool {
+    // bb8 panics on these rather than returning an error
+    if options.max_size == 0 {
+        throw!(Error::Config("pool max_size must be positive".into()));
+    }
+    if let Some(min_idle) = options.min_idle {
+        if min_idle > options.max_size {
+            throw!(Error::Config(format!(
+                "pool min_idle ({}) is greater than max_size ({})",
+                min_idle, options.max_size
+            )));
+        }
+    }
+
+    let mut config: tokio_postgres::Config = database_url.parse()?;
+    if let Some(timeout) = options.statement_timeout {
+        let mut pg_options =
+            config.get_options().unwrap_or_default().to_string();
+        pg_options += &format!(" -c statement_timeout={}", timeout.as_millis());
+        config.options(pg_options.trim_start());
+    }
+    let db_manager = PostgresConnectionManager::new(config, NoTls);
 
-    Pool::builder().build(db_manager).await?
+    Pool::builder()
+        .max_size(options.max_size)
+        .min_idle(options.min_idle)
+        .connection_timeout(options.connection_timeout)
+        .idle_timeout(options.idle_timeout)
+        .max_lifetime(options.max_lifetime)
+        .test_on_check_out(options.test_on_check_out)
+        .build(db_manager)
+        .await?
 }
@@ -0,0 +1,195 @@
+//! Metrics for API requests and job queues, in the Prometheus text
+//! format.
+//!
+//! Each type of request gets a histogram of how long it took to
+//! handle, and a count of the requests that got an error response by
+//! the type of error. Database queries are counted by name, which
+//! shows how many queries each type of request costs. The number of
+//! unfinished jobs in each project is counted from the database when
+//! the metrics are rendered.
+
+use crate::{Error, Pool, QueryExt};
+use fehler::throws;
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// States counted by the queue depth gauges. Finished jobs aren't
+/// counted, since they are no longer queued.
+const GAUGE_STATES: &[&str] =
+    &["available", "activating", "running", "canceling"];
+
+/// Upper bounds of the latency histogram's buckets, in seconds.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Latency histogram for one type of request.
+#[derive(Default)]
+struct Histogram {
+    /// Number of requests in each bucket, not including the requests
+    /// in the smaller buckets.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum_seconds: f64,
+}
+
+#[derive(Default)]
+struct Metrics {
+    /// By request type.
+    latency: BTreeMap<String, Histogram>,
+    /// By request type and response type.
+    errors: BTreeMap<(String, String), u64>,
+    /// Number of queries run, by name.
+    queries: BTreeMap<&'static str, u64>,
+}
+
+static METRICS: Lazy<Mutex<Metrics>> = Lazy::new(Default::default);
+
+/// Record a request that was handled, with the names of its request
+/// and response types.
+pub(crate) fn record(
+    request: &str,
+    response: &str,
+    is_error: bool,
+    latency: Duration,
+) {
+    let seconds = latency.as_secs_f64();
+    let mut metrics = METRICS.lock().unwrap();
+    let histogram = metrics.latency.entry(request.into()).or_default();
+    if let Some(index) = LATENCY_BUCKETS.iter().position(|le| seconds <= *le) {
+        histogram.buckets[index] += 1;
+    }
+    histogram.count += 1;
+    histogram.sum_seconds += seconds;
+    if is_error {
+        *metrics
+            .errors
+            .entry((request.into(), response.into()))
+            .or_default() += 1;
+    }
+}
+
+/// Record a database query with the name given to `instrument_query`.
+pub(crate) fn record_query(name: &'static str) {
+    *METRICS.lock().unwrap().queries.entry(name).or_default() += 1;
+}
+
+/// Escape a label value for the Prometheus text format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Get the number of jobs in each project in each of the gauge states,
+/// and how many of those are stuck.
+#[throws]
+async fn render_queue_depth(pool: &Pool, out: &mut String) {
+    let conn = pool.get().await?;
+    let rows = conn
+        .query(
+            "SELECT projects.name, states.state, COUNT(jobs.id),
+                    COUNT(jobs.id) FILTER (
+                      WHERE jobs.state IN ('activating', 'running', 'canceling')
+                        AND jobs.lease_expires_at < CURRENT_TIMESTAMP)
+             FROM projects
+             CROSS JOIN UNNEST($1::TEXT[]) AS states (state)
+             LEFT JOIN jobs ON jobs.project = projects.id
+                           AND jobs.state = states.state
+             GROUP BY projects.name, states.state
+             ORDER BY projects.name, states.state",
+            &[&GAUGE_STATES],
+        )
+        .instrument_query("count_queued_jobs")
+        .await?;
+
+    let mut stuck = BTreeMap::new();
+    *out += "# HELP jobclerk_jobs Number of unfinished jobs.\n";
+    *out += "# TYPE jobclerk_jobs gauge\n";
+    for row in &rows {
+        let project = escape_label(row.get(0));
+        let state: &str = row.get(1);
+        let count: i64 = row.get(2);
+        let _ = writeln!(
+            out,
+            "jobclerk_jobs{{project=\"{}\",state=\"{}\"}} {}",
+            project, state, count
+        );
+        *stuck.entry(project).or_insert(0) += row.get::<_, i64>(3);
+    }
+    *out += "# HELP jobclerk_stuck_jobs \
+             Number of jobs whose runner's lease has expired.\n";
+    *out += "# TYPE jobclerk_stuck_jobs gauge\n";
+    for (project, count) in stuck {
+        let _ = writeln!(
+            out,
+            "jobclerk_stuck_jobs{{project=\"{}\"}} {}",
+            project, count
+        );
+    }
+}
+
+/// Get the metrics in the Prometheus text format.
+#[throws]
+pub async fn render(pool: &Pool) -> String {
+    // Writing to a String can't fail
+    let mut out = String::new();
+    render_queue_depth(pool, &mut out).await?;
+    let metrics = METRICS.lock().unwrap();
+    out += "# HELP jobclerk_request_duration_seconds \
+            Time taken to handle API requests.\n";
+    out += "# TYPE jobclerk_request_duration_seconds histogram\n";
+    for (request, histogram) in &metrics.latency {
+        let mut cumulative = 0;
+        for (le, count) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "jobclerk_request_duration_seconds_bucket\
+                 {{request=\"{}\",le=\"{}\"}} {}",
+                request, le, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "jobclerk_request_duration_seconds_bucket\
+             {{request=\"{}\",le=\"+Inf\"}} {}",
+            request, histogram.count
+        );
+        let _ = writeln!(
+            out,
+            "jobclerk_request_duration_seconds_sum{{request=\"{}\"}} {}",
+            request, histogram.sum_seconds
+        );
+        let _ = writeln!(
+            out,
+            "jobclerk_request_duration_seconds_count{{request=\"{}\"}} {}",
+            request, histogram.count
+        );
+    }
+    out += "# HELP jobclerk_request_errors_total \
+            API requests that got an error response.\n";
+    out += "# TYPE jobclerk_request_errors_total counter\n";
+    for ((request, response), count) in &metrics.errors {
+        let _ = writeln!(
+            out,
+            "jobclerk_request_errors_total\
+             {{request=\"{}\",response=\"{}\"}} {}",
+            request, response, count
+        );
+    }
+    out += "# HELP jobclerk_queries_total Database queries run.\n";
+    out += "# TYPE jobclerk_queries_total counter\n";
+    for (query, count) in &metrics.queries {
+        let _ = writeln!(
+            out,
+            "jobclerk_queries_total{{query=\"{}\"}} {}",
+            query, count
+        );
+    }
+    out
+}
@@ -0,0 +1,248 @@
+//! Embedded schema migrations.
+//!
+//! Each migration is a SQL file in `db/migrations` named
+//! `V<version>__<name>.sql`. Migrations that haven't been applied yet
+//! are run in order of version, and the applied versions are recorded
+//! in the `schema_migrations` table. Never change a migration after
+//! it has been released; add a new one instead.
+//!
+//! The first migration is the schema that `db/init.sql` used to
+//! create. It only creates things that don't already exist, so
+//! databases initialized before migrations were added can be migrated
+//! too.
+
+use crate::{Error, Pool};
+use chrono::{DateTime, Utc};
+use fehler::{throw, throws};
+use log::info;
+use std::collections::HashMap;
+use tokio_postgres::Client;
+
+struct Migration {
+    version: i32,
+    name: &'static str,
+    sql: &'static str,
+}
+
+/// All migrations, in order of version.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("../../db/migrations/V1__initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "notify_runners",
+        sql: include_str!("../../db/migrations/V2__notify_runners.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "api_keys",
+        sql: include_str!("../../db/migrations/V3__api_keys.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "api_key_roles",
+        sql: include_str!("../../db/migrations/V4__api_key_roles.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "max_data_bytes",
+        sql: include_str!("../../db/migrations/V5__max_data_bytes.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "notify_job_events",
+        sql: include_str!("../../db/migrations/V6__notify_job_events.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "ui_users",
+        sql: include_str!("../../db/migrations/V7__ui_users.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "queues",
+        sql: include_str!("../../db/migrations/V8__queues.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "deduplicate_data",
+        sql: include_str!("../../db/migrations/V9__deduplicate_data.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "unique_keys",
+        sql: include_str!("../../db/migrations/V10__unique_keys.sql"),
+    },
+    Migration {
+        version: 11,
+        name: "batches",
+        sql: include_str!("../../db/migrations/V11__batches.sql"),
+    },
+    Migration {
+        version: 12,
+        name: "child_jobs",
+        sql: include_str!("../../db/migrations/V12__child_jobs.sql"),
+    },
+    Migration {
+        version: 13,
+        name: "workflows",
+        sql: include_str!("../../db/migrations/V13__workflows.sql"),
+    },
+    Migration {
+        version: 14,
+        name: "job_templates",
+        sql: include_str!("../../db/migrations/V14__job_templates.sql"),
+    },
+    Migration {
+        version: 15,
+        name: "job_attempts",
+        sql: include_str!("../../db/migrations/V15__job_attempts.sql"),
+    },
+    Migration {
+        version: 16,
+        name: "organizations",
+        sql: include_str!("../../db/migrations/V16__organizations.sql"),
+    },
+    Migration {
+        version: 17,
+        name: "max_unfinished_jobs",
+        sql: include_str!("../../db/migrations/V17__max_unfinished_jobs.sql"),
+    },
+    Migration {
+        version: 18,
+        name: "archived_jobs",
+        sql: include_str!("../../db/migrations/V18__archived_jobs.sql"),
+    },
+    Migration {
+        version: 19,
+        name: "cancel_blocked_jobs",
+        sql: include_str!("../../db/migrations/V19__cancel_blocked_jobs.sql"),
+    },
+    Migration {
+        version: 20,
+        name: "ui_user_roles",
+        sql: include_str!("../../db/migrations/V20__ui_user_roles.sql"),
+    },
+];
+
+/// Schema version this code expects, which is the version of the last
+/// migration. A database with no migrations applied is version 0.
+pub const SCHEMA_VERSION: i32 = MIGRATIONS[MIGRATIONS.len() - 1].version;
+
+/// Arbitrary key for the advisory lock that keeps two servers from
+/// migrating at the same time.
+const MIGRATION_LOCK_KEY: i64 = 0x6a6f_6263_6c65_726b;
+
+/// Apply all pending migrations and return the versions that were
+/// applied.
+///
+/// The migrations are applied in a single transaction, so if one
+/// fails the database is left as it was.
+#[throws]
+pub async fn migrate(client: &mut Client) -> Vec<i32> {
+    let txn = client.transaction().await?;
+    txn.execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK_KEY])
+        .await?;
+    txn.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+           version INT PRIMARY KEY,
+           name TEXT NOT NULL,
+           applied TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+         )",
+    )
+    .await?;
+    let row = txn
+        .query_one("SELECT MAX(version) FROM schema_migrations", &[])
+        .await?;
+    let current: Option<i32> = row.get(0);
+
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS {
+        if Some(migration.version) <= current {
+            continue;
+        }
+        info!(
+            "applying migration {}: {}",
+            migration.version, migration.name
+        );
+        txn.batch_execute(migration.sql).await?;
+        txn.execute(
+            "INSERT INTO schema_migrations (version, name) VALUES ($1, $2)",
+            &[&migration.version, &migration.name],
+        )
+        .await?;
+        applied.push(migration.version);
+    }
+
+    txn.commit().await?;
+    applied
+}
+
+/// Whether a migration has been applied to a database.
+#[derive(Debug, Eq, PartialEq)]
+pub struct MigrationStatus {
+    pub version: i32,
+    pub name: &'static str,
+    /// When the migration was applied, or `None` if it's pending.
+    pub applied: Option<DateTime<Utc>>,
+}
+
+/// Get the status of every migration, in order of version.
+#[throws]
+pub async fn migration_status(client: &Client) -> Vec<MigrationStatus> {
+    let row = client
+        .query_one("SELECT to_regclass('schema_migrations') IS NOT NULL", &[])
+        .await?;
+    let mut applied = HashMap::new();
+    if row.get(0) {
+        let rows = client
+            .query("SELECT version, applied FROM schema_migrations", &[])
+            .await?;
+        for row in rows {
+            let version: i32 = row.get(0);
+            applied.insert(version, row.get(1));
+        }
+    }
+    MIGRATIONS
+        .iter()
+        .map(|migration| MigrationStatus {
+            version: migration.version,
+            name: migration.name,
+            applied: applied.get(&migration.version).copied(),
+        })
+        .collect()
+}
+
+/// Check that the database schema is the version this code expects.
+///
+/// Run this at startup so that a database that hasn't been migrated
+/// (or has been migrated by newer code) is caught right away, rather
+/// than when a query hits a missing column.
+#[throws]
+pub async fn check_schema_version(pool: &Pool) {
+    let conn = pool.get().await?;
+    let row = conn
+        .query_one("SELECT to_regclass('schema_migrations') IS NOT NULL", &[])
+        .await?;
+    let has_migrations: bool = row.get(0);
+    let found = if has_migrations {
+        let row = conn
+            .query_one(
+                "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+                &[],
+            )
+            .await?;
+        row.get(0)
+    } else {
+        0
+    };
+    if found != SCHEMA_VERSION {
+        throw!(Error::SchemaVersion {
+            expected: SCHEMA_VERSION,
+            found,
+        });
+    }
+}
@@ -0,0 +1,220 @@
+//! Wake up requests that are waiting on the database.
+//!
+//! Triggers in the database send a notification when a job becomes
+//! available, a job's log changes, a runner's job is asked to cancel
+//! or finishes, or any job changes state. If `listen` has been called
+//! these are used to wake long-polling runners and event streams
+//! right away.
+//! Otherwise, or if a notification is missed, waiters fall back to
+//! checking again periodically.
+
+use crate::Error;
+use fehler::throws;
+use futures::{stream, StreamExt};
+use jobclerk_types::JobId;
+use log::{error, info};
+use once_cell::sync::OnceCell;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::{self, Instant};
+use tokio_postgres::{AsyncMessage, Client, NoTls};
+
+/// Channel notified with the project name when a job becomes
+/// available.
+const JOBS_CHANNEL: &str = "jobclerk_jobs";
+
+/// Channel notified with the job ID when a job's log has new output
+/// or the job finishes.
+const JOB_LOGS_CHANNEL: &str = "jobclerk_job_logs";
+
+/// Channel notified with the runner name when one of the runner's
+/// jobs is asked to cancel or finishes.
+const RUNNERS_CHANNEL: &str = "jobclerk_runners";
+
+/// Channel notified with the project name when one of the project's
+/// jobs changes state.
+const JOB_EVENTS_CHANNEL: &str = "jobclerk_job_events";
+
+/// Number of notifications that can be queued for a slow waiter before
+/// it starts missing them.
+const NOTIFICATION_CAPACITY: usize = 1024;
+
+/// Longest time a waiter goes without checking the database, in case
+/// it missed a notification (or there's no listener).
+const MAX_WAIT_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug)]
+struct Notification {
+    channel: String,
+    payload: String,
+}
+
+struct Listener {
+    // The connection closes if the client is dropped
+    _client: Client,
+    sender: broadcast::Sender<Notification>,
+}
+
+static LISTENER: OnceCell<Listener> = OnceCell::new();
+
+/// Open a dedicated database connection and listen for notifications.
+/// This should be called once at startup.
+#[throws]
+pub async fn listen(database_url: &str) {
+    let (client, mut connection) =
+        tokio_postgres::connect(database_url, NoTls).await?;
+    let (sender, _) = broadcast::channel(NOTIFICATION_CAPACITY);
+    let task_sender = sender.clone();
+    let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+    tokio::spawn(async move {
+        while let Some(message) = messages.next().await {
+            match message {
+                Ok(AsyncMessage::Notification(notification)) => {
+                    // This only fails if nothing is waiting
+                    let _ = task_sender.send(Notification {
+                        channel: notification.channel().to_string(),
+                        payload: notification.payload().to_string(),
+                    });
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    error!("notification listener failed: {}", err);
+                    break;
+                }
+            }
+        }
+        info!("notification listener stopped");
+    });
+
+    client
+        .batch_execute(&format!(
+            "LISTEN {}; LISTEN {}; LISTEN {}; LISTEN {};",
+            JOBS_CHANNEL, JOB_LOGS_CHANNEL, RUNNERS_CHANNEL, JOB_EVENTS_CHANNEL
+        ))
+        .await?;
+
+    // If there's already a listener this one is dropped, which closes
+    // its connection
+    let _ = LISTENER.set(Listener {
+        _client: client,
+        sender,
+    });
+}
+
+/// Notifications received from the time of subscribing. Subscribe
+/// before checking the database so that a change made between the
+/// check and the wait isn't missed.
+pub(crate) struct Subscription(Option<broadcast::Receiver<Notification>>);
+
+pub(crate) fn subscribe() -> Subscription {
+    Subscription(LISTENER.get().map(|listener| listener.sender.subscribe()))
+}
+
+impl Subscription {
+    /// Wait until a matching notification arrives, or until
+    /// `deadline`. `matches` is called with each notification's
+    /// channel and payload. May return early, so the caller must check
+    /// the database again either way.
+    async fn wait<F>(&mut self, matches: F, deadline: Instant)
+    where
+        F: Fn(&str, &str) -> bool,
+    {
+        let deadline = deadline.min(Instant::now() + MAX_WAIT_INTERVAL);
+        let receiver = match &mut self.0 {
+            Some(receiver) => receiver,
+            None => {
+                time::delay_until(deadline).await;
+                return;
+            }
+        };
+        let notified = async {
+            loop {
+                match receiver.recv().await {
+                    Ok(notification) => {
+                        if matches(&notification.channel, &notification.payload)
+                        {
+                            break;
+                        }
+                    }
+                    // A matching notification may have been dropped
+                    Err(broadcast::RecvError::Lagged(_)) => break,
+                    // The listener stopped, so just poll
+                    Err(broadcast::RecvError::Closed) => {
+                        time::delay_until(deadline).await;
+                        break;
+                    }
+                }
+            }
+        };
+        let _ = time::timeout_at(deadline, notified).await;
+    }
+
+    /// Wait until a job may have become available in one of the
+    /// projects.
+    pub(crate) async fn wait_for_job(
+        &mut self,
+        project_names: &[String],
+        deadline: Instant,
+    ) {
+        self.wait(
+            |channel, name| {
+                channel == JOBS_CHANNEL
+                    && project_names.iter().any(|project| project == name)
+            },
+            deadline,
+        )
+        .await
+    }
+
+    /// Wait until a job may have become available in one of the
+    /// projects, or one of the runner's jobs may have been asked to
+    /// cancel or finished.
+    pub(crate) async fn wait_for_runner(
+        &mut self,
+        project_names: &[String],
+        runner: &str,
+        deadline: Instant,
+    ) {
+        self.wait(
+            |channel, payload| match channel {
+                JOBS_CHANNEL => {
+                    project_names.iter().any(|project| project == payload)
+                }
+                RUNNERS_CHANNEL => payload == runner,
+                _ => false,
+            },
+            deadline,
+        )
+        .await
+    }
+
+    /// Wait until a job's log may have new output or the job may have
+    /// finished.
+    pub(crate) async fn wait_for_log(
+        &mut self,
+        job_id: JobId,
+        timeout: Duration,
+    ) {
+        let job_id = job_id.to_string();
+        self.wait(
+            |channel, payload| channel == JOB_LOGS_CHANNEL && payload == job_id,
+            Instant::now() + timeout,
+        )
+        .await
+    }
+
+    /// Wait until one of the project's jobs may have changed state.
+    pub(crate) async fn wait_for_job_event(
+        &mut self,
+        project_name: &str,
+        timeout: Duration,
+    ) {
+        self.wait(
+            |channel, payload| {
+                channel == JOB_EVENTS_CHANNEL && payload == project_name
+            },
+            Instant::now() + timeout,
+        )
+        .await
+    }
+}
@@ -0,0 +1,150 @@
+//! Organizations group projects, so that one server can be shared by
+//! several teams. API keys limited to an organization can only be
+//! used with its projects.
+
+use crate::api::{finish_page, page_cursor, page_order, page_query_limit};
+use crate::{Error, Pool, QueryExt};
+use fehler::{throw, throws};
+use jobclerk_types::*;
+use std::collections::BTreeMap;
+
+#[throws]
+pub(crate) async fn add_organization(
+    pool: &Pool,
+    req: &AddOrganizationRequest,
+) -> AddOrganizationResponse {
+    if req.name.is_empty() {
+        throw!(Error::bad_request(
+            ErrorCode::InvalidArgument,
+            "organization names can't be empty".into()
+        ));
+    }
+
+    let conn = pool.get().await?;
+    let row = conn
+        .query_one(
+            "INSERT INTO organizations (name) VALUES ($1) RETURNING id",
+            &[&req.name],
+        )
+        .instrument_query("add_organization")
+        .await?;
+
+    AddOrganizationResponse {
+        organization_id: row.get(0),
+    }
+}
+
+#[throws]
+pub(crate) async fn list_organizations(
+    pool: &Pool,
+    req: &ListOrganizationsRequest,
+) -> ListOrganizationsResponse {
+    let (cmp, dir) = page_order(&req.page);
+    let after_name: Option<String> = page_cursor(&req.page)?;
+    let query = format!(
+        "SELECT organizations.id, organizations.name, organizations.created,
+                COUNT(projects.id)
+         FROM organizations
+         LEFT JOIN projects ON projects.organization = organizations.id
+         WHERE $1::TEXT IS NULL OR organizations.name {} $1
+         GROUP BY organizations.id
+         ORDER BY organizations.name {}
+         LIMIT $2",
+        cmp, dir
+    );
+
+    let conn = pool.get().await?;
+    let rows = conn
+        .query(
+            query.as_str(),
+            &[&after_name, &page_query_limit(&req.page)?],
+        )
+        .instrument_query("list_organizations")
+        .await?;
+
+    let mut organizations: Vec<_> = rows
+        .iter()
+        .map(|row| OrganizationSummary {
+            id: row.get(0),
+            name: row.get(1),
+            created: row.get(2),
+            project_count: row.get(3),
+        })
+        .collect();
+    let next_cursor =
+        finish_page(&mut organizations, &req.page, |org| org.name.clone());
+    ListOrganizationsResponse {
+        organizations,
+        next_cursor,
+    }
+}
+
+#[throws]
+pub(crate) async fn get_organization_stats(
+    pool: &Pool,
+    req: &GetOrganizationStatsRequest,
+) -> GetOrganizationStatsResponse {
+    if req.window_millis <= 0 {
+        throw!(Error::invalid_argument("window_millis", req.window_millis));
+    }
+
+    let conn = pool.get().await?;
+    let row = conn
+        .query_opt(
+            "SELECT id FROM organizations WHERE name = $1",
+            &[&req.organization_name],
+        )
+        .instrument_query("get_organization_id")
+        .await?;
+    let organization_id: OrganizationId = match row {
+        Some(row) => row.get(0),
+        None => throw!(Error::NotFound),
+    };
+
+    // Projects without any jobs have a single row with a NULL state
+    let rows = conn
+        .query(
+            "SELECT projects.name, jobs.state, COUNT(jobs.id)
+             FROM projects
+             LEFT JOIN jobs ON jobs.project = projects.id
+             WHERE projects.organization = $1
+             GROUP BY projects.name, jobs.state",
+            &[&organization_id],
+        )
+        .instrument_query("count_organization_jobs_by_state")
+        .await?;
+    let mut project_job_counts = BTreeMap::new();
+    for row in &rows {
+        let counts: &mut BTreeMap<JobState, i64> =
+            project_job_counts.entry(row.get(0)).or_default();
+        if let Some(state) = row.get::<_, Option<&str>>(1) {
+            counts.insert(state.parse()?, row.get(2));
+        }
+    }
+
+    let rows = conn
+        .query(
+            "SELECT jobs.state, COUNT(*) FROM jobs
+             JOIN projects ON projects.id = jobs.project
+             WHERE projects.organization = $1
+               AND finished > COALESCE($3, CURRENT_TIMESTAMP) -
+                              $2::BIGINT * INTERVAL '1 millisecond'
+               AND finished <= COALESCE($3, CURRENT_TIMESTAMP)
+             GROUP BY jobs.state",
+            &[&organization_id, &req.window_millis, &req.window_end],
+        )
+        .instrument_query("count_organization_finished_jobs_by_state")
+        .await?;
+    let finished_counts = rows
+        .iter()
+        .map(|row| -> Result<(JobState, i64), Error> {
+            let state: String = row.get(0);
+            Ok((state.parse()?, row.get(1)))
+        })
+        .collect::<Result<_, _>>()?;
+
+    GetOrganizationStatsResponse {
+        project_job_counts,
+        finished_counts,
+    }
+}
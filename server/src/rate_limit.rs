@@ -0,0 +1,168 @@
+//! Optional limits on how often clients can make requests.
+//!
+//! Each client and each project gets a token bucket. A request takes
+//! a token from its client's bucket and its project's bucket, and is
+//! rejected if either is empty. Buckets refill at a steady rate up to
+//! their burst size, so short bursts are allowed but a client stuck in
+//! a loop is slowed down to the rate.
+//!
+//! Clients are identified by their client certificate, API key, or
+//! address, in that order. The API key is only used once it has been
+//! checked, and only by its hash, so the buckets never hold a key and
+//! a client can't get a fresh bucket by making up keys. Requests
+//! without any of these, and requests that aren't about a single
+//! project, are only limited by whichever bucket applies.
+
+use crate::auth::hash_api_key;
+use crate::{Error, RequestContext};
+use fehler::{throw, throws};
+use jobclerk_types::Request;
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Once there are this many buckets, full ones are dropped, since
+/// they're the same as a new bucket.
+const MAX_BUCKETS: usize = 10_000;
+
+/// A request rate with an allowance for bursts.
+#[derive(Clone, Copy, Debug)]
+pub struct Rate {
+    /// Requests allowed per second, on average.
+    pub per_second: f64,
+    /// Requests allowed at once after a quiet period.
+    pub burst: u32,
+}
+
+/// Limits for each client and each project. `None` means no limit.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RateLimits {
+    pub per_client: Option<Rate>,
+    pub per_project: Option<Rate>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * rate.per_second).min(rate.burst as f64);
+        self.updated = now;
+    }
+}
+
+/// Buckets for one kind of limit, by key.
+struct Buckets {
+    rate: Rate,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl Buckets {
+    fn new(rate: Rate) -> Buckets {
+        Buckets {
+            rate,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token from the key's bucket. If it's empty, returns how
+    /// long until it has a token again.
+    fn take(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let rate = self.rate;
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            buckets.retain(|_, bucket| {
+                bucket.refill(rate, now);
+                bucket.tokens < rate.burst as f64
+            });
+        }
+        let bucket = buckets.entry(key.into()).or_insert(Bucket {
+            tokens: rate.burst as f64,
+            updated: now,
+        });
+        bucket.refill(rate, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / rate.per_second;
+            Err(Duration::from_secs_f64(wait))
+        }
+    }
+}
+
+struct Limiter {
+    per_client: Option<Buckets>,
+    per_project: Option<Buckets>,
+}
+
+static LIMITER: OnceCell<Limiter> = OnceCell::new();
+
+/// Start limiting requests. This should be called once at startup.
+#[throws]
+pub fn configure(limits: RateLimits) {
+    for rate in limits.per_client.iter().chain(&limits.per_project) {
+        if !rate.per_second.is_finite()
+            || rate.per_second <= 0.0
+            || rate.burst == 0
+        {
+            throw!(Error::Config(format!("invalid rate limit: {:?}", rate)));
+        }
+    }
+
+    // If there's already a limiter this one is dropped
+    let _ = LIMITER.set(Limiter {
+        per_client: limits.per_client.map(Buckets::new),
+        per_project: limits.per_project.map(Buckets::new),
+    });
+}
+
+/// Get the key of the client's bucket. This is called after the
+/// request has been authorized, so if auth is required, or the key is
+/// the admin key, then the API key is known to be valid.
+fn client_key(context: &RequestContext) -> Option<String> {
+    let key_checked = context.require_auth || context.admin;
+    if let Some(identity) = &context.client_identity {
+        Some(format!("cert:{}", identity))
+    } else if let (Some(api_key), true) = (&context.api_key, key_checked) {
+        Some(format!("key:{}", hash_api_key(api_key)))
+    } else {
+        context
+            .remote_addr
+            .as_ref()
+            .map(|addr| format!("addr:{}", addr))
+    }
+}
+
+/// Check if the request is within the limits. If not, returns how
+/// long the client should wait before trying again. This must only be
+/// called once the request's API key has been checked.
+///
+/// A request that's rejected by the project's limit has still used up
+/// one of the client's tokens, so retrying immediately doesn't help.
+pub(crate) fn check(
+    req: &Request,
+    context: &RequestContext,
+) -> Result<(), Duration> {
+    let limiter = match LIMITER.get() {
+        Some(limiter) => limiter,
+        None => return Ok(()),
+    };
+    let now = Instant::now();
+    if let (Some(buckets), Some(key)) =
+        (&limiter.per_client, client_key(context))
+    {
+        buckets.take(&key, now)?;
+    }
+    if let (Some(buckets), Some(project_name)) =
+        (&limiter.per_project, req.project_name())
+    {
+        buckets.take(project_name, now)?;
+    }
+    Ok(())
+}
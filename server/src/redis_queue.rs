@@ -0,0 +1,273 @@
+//! Optional Redis cache of each project's available jobs.
+//!
+//! Without it, every request to take a job searches the project's
+//! available jobs in Postgres. With it, the IDs of available jobs are
+//! kept in a Redis list per project. Take requests pop candidate IDs
+//! from the list and claim those jobs in Postgres by ID with a single
+//! query, and only search Postgres when the list runs out. Candidates
+//! the runner can't take, because it lacks their requirements for
+//! example, are pushed back to the front of the list for other
+//! runners. Projects with `max_jobs_per_runner` still take each job in
+//! a transaction that locks the runner, so that its jobs can be
+//! counted.
+//!
+//! Postgres is still the record of each job's state and token, so an
+//! ID in the list that is no longer available is just dropped, and
+//! losing the Redis data loses nothing. The list is refilled in
+//! batches, so jobs added after a refill are taken after the jobs
+//! already in the list, even if they have a higher priority. For
+//! projects with queue weights, each batch interleaves the queues
+//! according to their weights.
+
+use crate::api::{
+    make_random_string, take_jobs_from_db, take_jobs_from_queues,
+    weighted_shuffle,
+};
+use crate::{Error, Pool, QueryExt};
+use fehler::throws;
+use jobclerk_types::{JobId, TakeJobRequest, TakeJobResponseJob};
+use once_cell::sync::OnceCell;
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// Number of available jobs added to a project's list when it's empty.
+const REFILL_BATCH_SIZE: i64 = 100;
+
+pub(crate) struct RedisQueue {
+    conn: MultiplexedConnection,
+}
+
+static QUEUE: OnceCell<RedisQueue> = OnceCell::new();
+
+/// Connect to Redis and use it to find available jobs. This should be
+/// called once at startup.
+#[throws]
+pub async fn connect(redis_url: &str) {
+    let client = redis::Client::open(redis_url)?;
+    let conn = client.get_multiplexed_tokio_connection().await?;
+    // If there's already a connection this one is dropped
+    let _ = QUEUE.set(RedisQueue { conn });
+}
+
+pub(crate) fn get() -> Option<&'static RedisQueue> {
+    QUEUE.get()
+}
+
+fn list_key(project_name: &str) -> String {
+    format!("jobclerk:available:{}", project_name)
+}
+
+/// Order the jobs of a project's queues so that each job comes from a
+/// queue picked at random, with the chance of a queue being picked
+/// proportional to its weight. Each queue's jobs keep their order.
+fn interleave_queues(
+    mut queues: BTreeMap<String, (i32, VecDeque<JobId>)>,
+) -> Vec<JobId> {
+    let mut ids = Vec::new();
+    while (ids.len() as i64) < REFILL_BATCH_SIZE {
+        let weights = queues
+            .iter()
+            .map(|(name, (weight, _))| (name.clone(), *weight))
+            .collect();
+        let name = match weighted_shuffle(weights).into_iter().next() {
+            Some(name) => name,
+            None => break,
+        };
+        let (_, queue) = queues.get_mut(&name).unwrap();
+        ids.extend(queue.pop_front());
+        if queue.is_empty() {
+            queues.remove(&name);
+        }
+    }
+    ids
+}
+
+impl RedisQueue {
+    /// Pop up to `count` job IDs from the list.
+    #[throws]
+    async fn pop(&self, key: &str, count: i32) -> Vec<JobId> {
+        let mut conn = self.conn.clone();
+        let mut pipe = redis::pipe();
+        for _ in 0..count {
+            pipe.lpop(key);
+        }
+        let ids: Vec<Option<JobId>> = pipe.query_async(&mut conn).await?;
+        ids.into_iter().flatten().collect()
+    }
+
+    /// Push job IDs back to the front of the list, keeping their
+    /// order.
+    #[throws]
+    async fn push_back(&self, key: &str, ids: &[JobId]) {
+        if !ids.is_empty() {
+            let mut conn = self.conn.clone();
+            // Each ID is pushed in front of the one pushed before it
+            let ids: Vec<JobId> = ids.iter().rev().copied().collect();
+            let _: () = conn.lpush(key, ids.as_slice()).await?;
+        }
+    }
+
+    /// Add the project's next batch of available jobs to its list.
+    /// Returns false if the project has no available jobs.
+    ///
+    /// If the project has queue weights, the batch has up to a batch's
+    /// worth of jobs from each queue, interleaved by weight.
+    ///
+    /// If several requests refill the list at once some jobs will be
+    /// in it more than once, which is harmless.
+    #[throws]
+    async fn refill(&self, pool: &Pool, project_name: &str) -> bool {
+        let conn = pool.get().await?;
+        let rows = conn
+            .query(
+                "SELECT id, queue, weight, weighted FROM (
+                   SELECT jobs.id, jobs.queue, jobs.priority, jobs.created,
+                          COALESCE(
+                            (projects.queue_weights ->> jobs.queue)::INT, 1
+                          ) AS weight,
+                          projects.queue_weights != '{}' AS weighted,
+                          ROW_NUMBER() OVER (
+                            PARTITION BY CASE
+                              WHEN projects.queue_weights != '{}'
+                              THEN jobs.queue
+                            END
+                            ORDER BY jobs.priority, jobs.created
+                          ) AS n
+                   FROM jobs
+                   JOIN projects ON projects.id = jobs.project
+                   WHERE projects.name = $1
+                     AND jobs.state = 'available'
+                     AND jobs.available_at <= CURRENT_TIMESTAMP
+                     AND (jobs.expires_at IS NULL
+                          OR jobs.expires_at > CURRENT_TIMESTAMP)
+                     AND job_dependencies_met(jobs.id)
+                 ) AS available
+                 WHERE n <= $2
+                 ORDER BY priority, created",
+                &[&project_name, &REFILL_BATCH_SIZE],
+            )
+            .instrument_query("refill_redis_queue")
+            .await?;
+        let weighted = rows.first().is_some_and(|row| row.get(3));
+        let ids: Vec<JobId> = if weighted {
+            let mut queues = BTreeMap::new();
+            for row in &rows {
+                queues
+                    .entry(row.get(1))
+                    .or_insert_with(|| (row.get(2), VecDeque::new()))
+                    .1
+                    .push_back(row.get(0));
+            }
+            interleave_queues(queues)
+        } else {
+            rows.iter().map(|row| row.get(0)).collect()
+        };
+
+        if !ids.is_empty() {
+            let mut conn = self.conn.clone();
+            let _: () =
+                conn.rpush(list_key(project_name), ids.as_slice()).await?;
+        }
+        !ids.is_empty()
+    }
+
+    /// Claim the candidates the runner can take with a single query.
+    /// Returns the jobs that were taken, the candidates that are still
+    /// available but weren't taken, and whether the project limits
+    /// each runner's jobs, in which case none are taken.
+    #[throws]
+    async fn claim(
+        pool: &Pool,
+        req: &TakeJobRequest,
+        candidates: &[JobId],
+    ) -> (Vec<TakeJobResponseJob>, Vec<JobId>, bool) {
+        let tokens: Vec<String> =
+            candidates.iter().map(|_| make_random_string(16)).collect();
+        let conn = pool.get().await?;
+        let rows = conn
+            .query(
+                include_str!("../../db/query_claim_listed_jobs.sql"),
+                &[
+                    &req.project_name,
+                    &req.runner,
+                    &candidates,
+                    &req.capabilities,
+                    &req.min_priority,
+                    &req.max_priority,
+                    &tokens,
+                    &req.lease_millis,
+                    &req.first_heartbeat_millis,
+                ],
+            )
+            .instrument_query("claim_listed_jobs")
+            .await?;
+        let limited = rows.first().is_some_and(|row| row.get(2));
+        let mut listed: HashMap<JobId, Option<String>> =
+            rows.iter().map(|row| (row.get(0), row.get(1))).collect();
+
+        // Keep the list's order. An ID can be in the list more than
+        // once, but only the first is used.
+        let mut jobs = Vec::new();
+        let mut untaken = Vec::new();
+        for job_id in candidates {
+            match listed.remove(job_id) {
+                Some(Some(job_token)) => jobs.push(TakeJobResponseJob {
+                    project_name: req.project_name.clone(),
+                    job_id: *job_id,
+                    job_token,
+                }),
+                Some(None) => untaken.push(*job_id),
+                None => {}
+            }
+        }
+        (jobs, untaken, limited)
+    }
+
+    /// Take up to `max_jobs` jobs, using the list to find them.
+    #[throws]
+    pub(crate) async fn take_jobs(
+        &self,
+        pool: &Pool,
+        req: &TakeJobRequest,
+        max_jobs: i32,
+    ) -> Vec<TakeJobResponseJob> {
+        let key = list_key(&req.project_name);
+        let mut candidates = self.pop(&key, max_jobs).await?;
+        if candidates.is_empty() && self.refill(pool, &req.project_name).await?
+        {
+            candidates = self.pop(&key, max_jobs).await?;
+        }
+
+        let mut jobs = Vec::new();
+        if !candidates.is_empty() {
+            let (claimed, mut untaken, limited) =
+                Self::claim(pool, req, &candidates).await?;
+            jobs = claimed;
+            if limited && !untaken.is_empty() {
+                // The runner's jobs have to be counted with the runner
+                // locked
+                jobs = take_jobs_from_db(
+                    pool,
+                    req,
+                    max_jobs,
+                    Some(&untaken),
+                    None,
+                )
+                .await?;
+                untaken.retain(|id| !jobs.iter().any(|job| job.job_id == *id));
+            }
+            // Leave the rest for runners that can take them
+            self.push_back(&key, &untaken).await?;
+
+            // Some of the candidates may have been taken already or
+            // need capabilities the runner doesn't have, so search
+            // for the rest
+            let remaining = max_jobs - jobs.len() as i32;
+            if remaining > 0 {
+                jobs.extend(take_jobs_from_queues(pool, req, remaining).await?);
+            }
+        }
+        jobs
+    }
+}
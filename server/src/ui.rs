@@ -1,8 +1,84 @@
-use crate::{Error, Pool};
+use crate::api::{
+    job_filter_sql, JobFilterParams, DEFAULT_RUNNER_OFFLINE_MILLIS,
+};
+use crate::{Error, Pool, QueryExt};
 use askama::Template;
-use chrono::{DateTime, Utc};
-use fehler::throws;
+use chrono::{DateTime, NaiveDate, Utc};
+use fehler::{throw, throws};
+use jobclerk_types::{data_preview, JobFilter, JobId};
 use log::error;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::Client;
+
+/// Number of jobs shown in each section of the project page.
+const PAGE_SIZE: i64 = 10;
+
+/// Create a token for a browser to send back with the UI's forms.
+pub fn new_csrf_token() -> String {
+    thread_rng().sample_iter(&Alphanumeric).take(32).collect()
+}
+
+/// What pages with forms need from the browser's session.
+#[derive(Clone)]
+pub struct FormContext {
+    /// Sent back with each form to show that the form came from this
+    /// site.
+    pub csrf_token: String,
+    /// Message to show at the top of the page, such as the result of
+    /// a form submitted from the previous page.
+    pub flash: Option<String>,
+}
+
+/// A file served under `/static`, such as the UI's stylesheet. These
+/// are built into the server so that it doesn't need to be run from
+/// the source directory.
+pub struct StaticFile {
+    pub name: &'static str,
+    pub content_type: &'static str,
+    pub contents: &'static [u8],
+}
+
+impl StaticFile {
+    /// Get an ETag for the file, which changes whenever the file's
+    /// contents do.
+    pub fn etag(&self) -> String {
+        let hash = format!("{:x}", Sha256::digest(self.contents));
+        format!("\"{}\"", &hash[..16])
+    }
+}
+
+const STATIC_FILES: &[StaticFile] = &[
+    StaticFile {
+        name: "favicon.svg",
+        content_type: "image/svg+xml",
+        contents: include_bytes!("../static/favicon.svg"),
+    },
+    StaticFile {
+        name: "project.js",
+        content_type: "text/javascript; charset=utf-8",
+        contents: include_bytes!("../static/project.js"),
+    },
+    StaticFile {
+        name: "style.css",
+        content_type: "text/css; charset=utf-8",
+        contents: include_bytes!("../static/style.css"),
+    },
+    StaticFile {
+        name: "ui.js",
+        content_type: "text/javascript; charset=utf-8",
+        contents: include_bytes!("../static/ui.js"),
+    },
+];
+
+/// Get one of the files served under `/static`.
+pub fn get_static_file(name: &str) -> Option<&'static StaticFile> {
+    STATIC_FILES.iter().find(|file| file.name == name)
+}
 
 #[derive(Template)]
 #[template(path = "internal_error.html")]
@@ -23,124 +99,804 @@ pub fn internal_error() -> String {
 #[template(path = "projects.html")]
 struct ProjectsTemplate {
     projects: Vec<String>,
+    /// Logged in user, if the UI requires logging in.
+    user: Option<String>,
 }
 
 #[throws]
-pub async fn list_projects(pool: &Pool) -> String {
+pub async fn list_projects(pool: &Pool, user: Option<&str>) -> String {
     let conn = pool.get().await?;
-    let rows = conn.query("SELECT id, name FROM projects", &[]).await?;
+    let rows = conn
+        .query("SELECT id, name FROM projects", &[])
+        .instrument_query("list_projects")
+        .await?;
 
     let template = ProjectsTemplate {
         projects: rows.iter().map(|row| row.get(1)).collect(),
+        user: user.map(String::from),
     };
     template.render()?
 }
 
+#[derive(Template)]
+#[template(path = "login.html")]
+struct LoginTemplate<'a> {
+    form: &'a FormContext,
+}
+
+#[throws]
+pub fn login_page(form: &FormContext) -> String {
+    let template = LoginTemplate { form };
+    template.render()?
+}
+
 #[derive(Default)]
 struct JobSummary {
     job_id: i64,
     duration: String,
-    data: serde_json::Value,
+    data: String,
     runner: String,
     state: String,
 }
 
+/// One page of a list of jobs, with links to the pages around it.
+struct JobSection {
+    jobs: Vec<JobSummary>,
+    prev_url: Option<String>,
+    next_url: Option<String>,
+}
+
+impl JobSection {
+    /// Create a section from a page of jobs fetched with a limit of
+    /// one more than `PAGE_SIZE`, so that the extra job shows whether
+    /// there's a next page.
+    fn new(
+        mut jobs: Vec<JobSummary>,
+        offset: i64,
+        page_url: impl Fn(i64) -> String,
+    ) -> JobSection {
+        let has_next = jobs.len() as i64 > PAGE_SIZE;
+        jobs.truncate(PAGE_SIZE as usize);
+        JobSection {
+            jobs,
+            prev_url: if offset > 0 {
+                Some(page_url((offset - PAGE_SIZE).max(0)))
+            } else {
+                None
+            },
+            next_url: if has_next {
+                Some(page_url(offset + PAGE_SIZE))
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// Query parameters of the project page. Each section is paged
+/// separately. The filters apply to every section, and are left empty
+/// to not filter.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ProjectPageQuery {
+    pub pending_offset: i64,
+    pub running_offset: i64,
+    pub recent_offset: i64,
+
+    pub state: String,
+    pub runner: String,
+    /// Only show jobs created on or after this date (YYYY-MM-DD).
+    pub created_after: String,
+    /// Only show jobs created on or before this date (YYYY-MM-DD).
+    pub created_before: String,
+    /// Only show jobs whose data contains this. A JSON object or array
+    /// matches jobs whose data contains it as JSON (so `{"order":
+    /// 12345}` matches data with that field), and anything else
+    /// matches jobs whose data contains it as text, ignoring case.
+    pub search: String,
+    /// Only show jobs in this queue.
+    pub queue: String,
+}
+
+impl ProjectPageQuery {
+    fn url(&self, project_name: &str) -> String {
+        format!(
+            "/projects/{}?{}",
+            project_name,
+            serde_urlencoded::to_string(self).expect("failed to encode query")
+        )
+    }
+
+    /// Convert the filter form's fields to a `JobFilter`. Dates are in
+    /// UTC, and the end date is included.
+    #[throws]
+    fn job_filter(&self) -> JobFilter {
+        let start_of_day =
+            |date: NaiveDate| DateTime::from_utc(date.and_hms(0, 0, 0), Utc);
+        let (data_filter, data_search) = match non_empty(&self.search) {
+            Some(search) => match serde_json::from_str(search) {
+                Ok(json @ serde_json::Value::Object(_))
+                | Ok(json @ serde_json::Value::Array(_)) => (Some(json), None),
+                _ => (None, Some(search.to_string())),
+            },
+            None => (None, None),
+        };
+        JobFilter {
+            states: match non_empty(&self.state) {
+                Some(state) => vec![state.parse()?],
+                None => Vec::new(),
+            },
+            runner: non_empty(&self.runner).map(String::from),
+            created_after: parse_date(&self.created_after)?.map(start_of_day),
+            created_before: parse_date(&self.created_before)?
+                .map(|date| start_of_day(date.succ())),
+            data_filter,
+            data_search,
+            ..JobFilter::default()
+        }
+    }
+}
+
+/// Number of rows queried for each section of the project page, one
+/// more than is shown to find out if there's a next page.
+const PAGE_QUERY_LIMIT: i64 = PAGE_SIZE + 1;
+
+/// SQL conditions for the filter and queue of a section of the project
+/// page, starting with `AND`.
+fn section_filter_sql() -> String {
+    format!(
+        "{} AND ($11::TEXT IS NULL OR queue = $11)",
+        job_filter_sql(4)
+    )
+}
+
+/// Parameters for a query of one section of the project page: the
+/// project, the limit and offset, then the filter starting at $4 and
+/// the queue at $11.
+fn section_params<'a>(
+    project_id: &'a i64,
+    offset: &'a i64,
+    filter: &'a JobFilterParams,
+    queue: &'a Option<&str>,
+) -> Vec<&'a (dyn ToSql + Sync)> {
+    let mut params: Vec<&(dyn ToSql + Sync)> =
+        vec![project_id, &PAGE_QUERY_LIMIT, offset];
+    params.extend(filter.params());
+    params.push(queue);
+    params
+}
+
+/// A tab of the project page that shows one of its queues, or all of
+/// them if `name` is empty.
+struct QueueTab {
+    name: String,
+    /// Jobs in the queue that haven't finished.
+    unfinished: i64,
+    url: String,
+    selected: bool,
+}
+
+/// Get the project page's queue tabs, starting with the tab for all
+/// queues. Returns no tabs if all the project's jobs are in one queue
+/// and no queue is selected, since then the tabs would all be the same.
+#[throws]
+async fn get_queue_tabs(
+    conn: &Client,
+    project_id: i64,
+    project_name: &str,
+    query: &ProjectPageQuery,
+) -> Vec<QueueTab> {
+    let rows = conn
+        .query(
+            "SELECT queue, COUNT(*) FILTER (
+                      WHERE state IN
+                        ('available', 'activating', 'running', 'canceling'))
+             FROM jobs
+             WHERE project = $1
+             GROUP BY queue
+             ORDER BY queue",
+            &[&project_id],
+        )
+        .instrument_query("get_project_queues")
+        .await?;
+    if rows.len() <= 1 && query.queue.is_empty() {
+        Vec::new()
+    } else {
+        let all_unfinished = rows.iter().map(|row| row.get::<_, i64>(1)).sum();
+        let queues = rows.iter().map(|row| (row.get(0), row.get(1)));
+        std::iter::once((String::new(), all_unfinished))
+            .chain(queues)
+            .map(|(name, unfinished)| {
+                // Start each tab's sections from their first page
+                let url = ProjectPageQuery {
+                    queue: name.clone(),
+                    pending_offset: 0,
+                    running_offset: 0,
+                    recent_offset: 0,
+                    ..query.clone()
+                }
+                .url(project_name);
+                QueueTab {
+                    selected: name == query.queue,
+                    name,
+                    unfinished,
+                    url,
+                }
+            })
+            .collect()
+    }
+}
+
+/// States that can be picked in the project page's state filter.
+const JOB_STATES: &[&str] = &[
+    "available",
+    "activating",
+    "running",
+    "canceling",
+    "canceled",
+    "succeeded",
+    "failed",
+    "expired",
+    "timed_out",
+];
+
+fn non_empty(s: &str) -> Option<&str> {
+    if s.is_empty() {
+        None
+    } else {
+        Some(s)
+    }
+}
+
+#[throws]
+fn parse_date(s: &str) -> Option<NaiveDate> {
+    match non_empty(s) {
+        Some(s) => Some(
+            NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .map_err(|_| Error::invalid_argument("date", s))?,
+        ),
+        None => None,
+    }
+}
+
+/// A bar in a `BarChart`, in SVG coordinates.
+struct Bar {
+    x: i64,
+    y: i64,
+    width: i64,
+    height: i64,
+    /// Shown when hovering over the bar.
+    title: String,
+}
+
+/// A bar chart drawn as inline SVG.
+struct BarChart {
+    bars: Vec<Bar>,
+    /// Value of the tallest bar.
+    max: i64,
+    first_label: String,
+    last_label: String,
+}
+
+impl BarChart {
+    const WIDTH: i64 = 480;
+    const HEIGHT: i64 = 80;
+
+    /// Create a chart from labeled values, in order from left to
+    /// right.
+    fn new(values: &[(String, i64)]) -> BarChart {
+        let max = values.iter().map(|(_, value)| *value).max().unwrap_or(0);
+        let slot = Self::WIDTH / (values.len() as i64).max(1);
+        let bars = values
+            .iter()
+            .enumerate()
+            .map(|(i, (label, value))| {
+                let height = if max > 0 {
+                    value * Self::HEIGHT / max
+                } else {
+                    0
+                };
+                Bar {
+                    x: i as i64 * slot,
+                    y: Self::HEIGHT - height,
+                    width: (slot - 2).max(1),
+                    height,
+                    title: format!("{}: {}", label, value),
+                }
+            })
+            .collect();
+        let label = |index: Option<usize>| {
+            index
+                .and_then(|index| values.get(index))
+                .map(|(label, _)| label.clone())
+                .unwrap_or_default()
+        };
+        BarChart {
+            bars,
+            max,
+            first_label: label(Some(0)),
+            last_label: label(values.len().checked_sub(1)),
+        }
+    }
+}
+
+/// Aggregate statistics shown at the top of the project page.
+struct ProjectStats {
+    completed_per_hour: BarChart,
+    completed_per_day: BarChart,
+    /// Run time percentiles of recently succeeded jobs, as (percentile,
+    /// duration) pairs. Empty if no jobs succeeded recently.
+    durations: Vec<(&'static str, String)>,
+}
+
+/// Count the project's jobs that succeeded or failed in each of the
+/// last `count` periods of length `unit`, which is a unit that
+/// `date_trunc` accepts such as "hour". The last period is the current
+/// one.
+#[throws]
+async fn get_completed_jobs(
+    conn: &Client,
+    project_id: i64,
+    unit: &str,
+    count: i32,
+    label_format: &str,
+) -> Vec<(String, i64)> {
+    let rows = conn
+        .query(
+            "SELECT periods.start, COUNT(jobs.id)
+             FROM generate_series(
+                    date_trunc($2, CURRENT_TIMESTAMP) -
+                        ($3 - 1) * ('1 ' || $2)::INTERVAL,
+                    date_trunc($2, CURRENT_TIMESTAMP),
+                    ('1 ' || $2)::INTERVAL) AS periods(start)
+             LEFT JOIN jobs
+               ON jobs.project = $1
+              AND jobs.state IN ('succeeded', 'failed')
+              AND jobs.finished >= periods.start
+              AND jobs.finished < periods.start + ('1 ' || $2)::INTERVAL
+             GROUP BY periods.start
+             ORDER BY periods.start",
+            &[&project_id, &unit, &count],
+        )
+        .instrument_query("get_completed_jobs")
+        .await?;
+    rows.iter()
+        .map(|row| {
+            let start: DateTime<Utc> = row.get(0);
+            (start.format(label_format).to_string(), row.get(1))
+        })
+        .collect()
+}
+
+#[throws]
+async fn get_project_stats(conn: &Client, project_id: i64) -> ProjectStats {
+    let per_hour =
+        get_completed_jobs(conn, project_id, "hour", 24, "%H:00").await?;
+    let per_day =
+        get_completed_jobs(conn, project_id, "day", 14, "%b %d").await?;
+
+    const PERCENTILES: &[&str] = &["p50", "p90", "p99"];
+    let row = conn
+        .query_one(
+            "SELECT percentile_cont(ARRAY[0.5, 0.9, 0.99])
+                      WITHIN GROUP (ORDER BY
+                        EXTRACT(EPOCH FROM finished - started))
+             FROM jobs
+             WHERE project = $1
+               AND state = 'succeeded'
+               AND finished > CURRENT_TIMESTAMP - INTERVAL '7 days'",
+            &[&project_id],
+        )
+        .instrument_query("get_duration_percentiles")
+        .await?;
+    let durations: Option<Vec<f64>> = row.get(0);
+    let durations = durations
+        .unwrap_or_default()
+        .into_iter()
+        .zip(PERCENTILES)
+        .map(|(secs, name)| {
+            let duration = chrono::Duration::seconds(secs as i64);
+            (*name, jobclerk_types::format_duration(duration))
+        })
+        .collect();
+
+    ProjectStats {
+        completed_per_hour: BarChart::new(&per_hour),
+        completed_per_day: BarChart::new(&per_day),
+        durations,
+    }
+}
+
 #[derive(Template)]
 #[template(path = "project.html")]
 struct ProjectTemplate {
     name: String,
-    recent_jobs: Vec<JobSummary>,
-    pending_jobs: Vec<JobSummary>,
-    running_jobs: Vec<JobSummary>,
+    query: ProjectPageQuery,
+    form: FormContext,
+    stats: ProjectStats,
+    states: &'static [&'static str],
+    queue_tabs: Vec<QueueTab>,
+    recent_jobs: JobSection,
+    pending_jobs: JobSection,
+    running_jobs: JobSection,
+}
+
+impl ProjectTemplate {
+    fn is_state_selected(&self, state: &str) -> bool {
+        self.query.state == state
+    }
+
+    fn url(&self) -> String {
+        self.query.url(&self.name)
+    }
 }
 
 fn format_duration(start: DateTime<Utc>, end: DateTime<Utc>) -> String {
-    let duration = if let Ok(duration) = (end - start).to_std() {
-        // Round trip the number of seconds to clear out the subsecond
-        // fields
-        std::time::Duration::from_secs(duration.as_secs())
-    } else {
+    if end < start {
         error!("invalid duration: start={}, end={}", start, end);
-        std::time::Duration::default()
-    };
-    humantime::format_duration(duration).to_string()
+    }
+    jobclerk_types::format_duration(end - start)
 }
 
 #[throws]
-pub async fn get_project(pool: &Pool, project_name: &str) -> String {
+pub async fn get_project(
+    pool: &Pool,
+    project_name: &str,
+    query: &ProjectPageQuery,
+    form: &FormContext,
+) -> String {
     let conn = pool.get().await?;
 
+    let project_id: i64 = match conn
+        .query_opt("SELECT id FROM projects WHERE name = $1", &[&project_name])
+        .instrument_query("get_project_id")
+        .await?
+    {
+        Some(row) => row.get(0),
+        None => throw!(Error::NotFound),
+    };
+    let offset = |offset: i64| offset.max(0);
+
+    let filter = query.job_filter()?;
+    let filter = JobFilterParams::new(&filter)?;
+    let filter_sql = section_filter_sql();
+    let queue = non_empty(&query.queue);
+
     let rows = conn
         .query(
-            "SELECT id, data
-             FROM jobs WHERE state = 'available'
-             ORDER BY priority, created
-             LIMIT 10",
-            &[],
+            format!(
+                "SELECT id, data
+                 FROM jobs WHERE project = $1 AND state = 'available' {}
+                 ORDER BY priority, created, id
+                 LIMIT $2 OFFSET $3",
+                filter_sql
+            )
+            .as_str(),
+            &section_params(
+                &project_id,
+                &offset(query.pending_offset),
+                &filter,
+                &queue,
+            ),
         )
+        .instrument_query("get_pending_jobs")
         .await?;
-    let pending_jobs = rows
-        .iter()
-        .map(|row| JobSummary {
-            job_id: row.get(0),
-            data: row.get(1),
-            ..JobSummary::default()
-        })
-        .collect();
+    let pending_jobs = JobSection::new(
+        rows.iter()
+            .map(|row| JobSummary {
+                job_id: row.get(0),
+                data: data_preview(&row.get(1)),
+                ..JobSummary::default()
+            })
+            .collect(),
+        offset(query.pending_offset),
+        |pending_offset| {
+            ProjectPageQuery {
+                pending_offset,
+                ..query.clone()
+            }
+            .url(project_name)
+        },
+    );
 
     let rows = conn
         .query(
-            "SELECT id, data, runner, started, CURRENT_TIMESTAMP
-             FROM jobs WHERE state = 'running'
-             ORDER BY priority, created
-             LIMIT 10",
-            &[],
+            format!(
+                "SELECT id, data, runner, started, CURRENT_TIMESTAMP
+                 FROM jobs
+                 WHERE project = $1 AND state IN ('activating', 'running') {}
+                 ORDER BY priority, created, id
+                 LIMIT $2 OFFSET $3",
+                filter_sql
+            )
+            .as_str(),
+            &section_params(
+                &project_id,
+                &offset(query.running_offset),
+                &filter,
+                &queue,
+            ),
+        )
+        .instrument_query("get_running_jobs")
+        .await?;
+    let running_jobs = JobSection::new(
+        rows.iter()
+            .map(|row| {
+                let started: DateTime<Utc> = row.get(3);
+                let now: DateTime<Utc> = row.get(4);
+                JobSummary {
+                    job_id: row.get(0),
+                    data: data_preview(&row.get(1)),
+                    runner: row.get(2),
+                    duration: format_duration(started, now),
+                    ..JobSummary::default()
+                }
+            })
+            .collect(),
+        offset(query.running_offset),
+        |running_offset| {
+            ProjectPageQuery {
+                running_offset,
+                ..query.clone()
+            }
+            .url(project_name)
+        },
+    );
+
+    let rows = conn
+        .query(
+            format!(
+                "SELECT id, data, runner, started,
+                        COALESCE(finished, CURRENT_TIMESTAMP), state
+                 FROM jobs
+                 WHERE project = $1
+                   AND state NOT IN ('available', 'activating', 'running') {}
+                 ORDER BY created DESC, id DESC
+                 LIMIT $2 OFFSET $3",
+                filter_sql
+            )
+            .as_str(),
+            &section_params(
+                &project_id,
+                &offset(query.recent_offset),
+                &filter,
+                &queue,
+            ),
         )
+        .instrument_query("get_recent_jobs")
         .await?;
-    let running_jobs = rows
+    let recent_jobs = JobSection::new(
+        rows.iter()
+            .map(|row| {
+                // Jobs that never ran, such as expired jobs, have no
+                // start time
+                let started: Option<DateTime<Utc>> = row.get(3);
+                let finished: DateTime<Utc> = row.get(4);
+                JobSummary {
+                    job_id: row.get(0),
+                    data: data_preview(&row.get(1)),
+                    runner: row.get::<_, Option<String>>(2).unwrap_or_default(),
+                    duration: started
+                        .map(|started| format_duration(started, finished))
+                        .unwrap_or_default(),
+                    state: row.get(5),
+                }
+            })
+            .collect(),
+        offset(query.recent_offset),
+        |recent_offset| {
+            ProjectPageQuery {
+                recent_offset,
+                ..query.clone()
+            }
+            .url(project_name)
+        },
+    );
+
+    let stats = get_project_stats(&conn, project_id).await?;
+    let queue_tabs =
+        get_queue_tabs(&conn, project_id, project_name, query).await?;
+
+    let template = ProjectTemplate {
+        name: project_name.into(),
+        stats,
+        query: query.clone(),
+        form: form.clone(),
+        states: JOB_STATES,
+        queue_tabs,
+        pending_jobs,
+        running_jobs,
+        recent_jobs,
+    };
+    template.render()?
+}
+
+struct StepSummary {
+    name: String,
+    state: String,
+    duration: String,
+}
+
+#[derive(Template)]
+#[template(path = "job.html")]
+struct JobTemplate {
+    project_name: String,
+    job_id: JobId,
+    form: FormContext,
+    state: String,
+    runner: Option<String>,
+    duration: String,
+    data: serde_json::Value,
+    steps: Vec<StepSummary>,
+    parent_id: Option<JobId>,
+    child_ids: Vec<JobId>,
+}
+
+#[throws]
+pub async fn get_job(
+    pool: &Pool,
+    project_name: &str,
+    job_id: JobId,
+    form: &FormContext,
+) -> String {
+    let conn = pool.get().await?;
+
+    let rows = conn
+        .query(
+            "SELECT state, runner, started, finished, CURRENT_TIMESTAMP, data,
+                    parent
+             FROM jobs
+             WHERE project = (SELECT id FROM projects WHERE name = $1)
+               AND id = $2",
+            &[&project_name, &job_id],
+        )
+        .instrument_query("get_job")
+        .await?;
+    if rows.is_empty() {
+        throw!(Error::NotFound);
+    }
+    let row = &rows[0];
+    let started: Option<DateTime<Utc>> = row.get(2);
+    let finished: Option<DateTime<Utc>> = row.get(3);
+    let now: DateTime<Utc> = row.get(4);
+    let duration = started
+        .map(|started| format_duration(started, finished.unwrap_or(now)))
+        .unwrap_or_default();
+
+    let steps = conn
+        .query(
+            "SELECT name, state, started, finished
+             FROM job_steps WHERE job = $1
+             ORDER BY position",
+            &[&job_id],
+        )
+        .instrument_query("get_job_steps")
+        .await?
         .iter()
-        .map(|row| {
-            let started: DateTime<Utc> = row.get(3);
-            let now: DateTime<Utc> = row.get(4);
-            JobSummary {
-                job_id: row.get(0),
-                data: row.get(1),
-                runner: row.get(2),
-                duration: format_duration(started, now),
-                ..JobSummary::default()
+        .map(|step| {
+            let started: Option<DateTime<Utc>> = step.get(2);
+            let finished: Option<DateTime<Utc>> = step.get(3);
+            StepSummary {
+                name: step.get(0),
+                state: step.get(1),
+                duration: started
+                    .map(|started| {
+                        format_duration(started, finished.unwrap_or(now))
+                    })
+                    .unwrap_or_default(),
             }
         })
         .collect();
 
+    let child_ids = conn
+        .query(
+            "SELECT id FROM jobs WHERE parent = $1 ORDER BY id",
+            &[&job_id],
+        )
+        .instrument_query("get_child_jobs")
+        .await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+
+    let template = JobTemplate {
+        project_name: project_name.into(),
+        job_id,
+        form: form.clone(),
+        state: row.get(0),
+        runner: row.get(1),
+        duration,
+        data: row.get(5),
+        steps,
+        parent_id: row.get(6),
+        child_ids,
+    };
+    template.render()?
+}
+
+/// A job shown on the runners page, with its project so that it can be
+/// linked to.
+struct RunnerJob {
+    project_name: String,
+    job_id: JobId,
+}
+
+struct RunnerSummary {
+    name: String,
+    /// Either online, draining or offline.
+    state: String,
+    /// How long ago the last heartbeat was.
+    last_seen: String,
+    capabilities: Vec<String>,
+    jobs: Vec<RunnerJob>,
+}
+
+#[derive(Template)]
+#[template(path = "runners.html")]
+struct RunnersTemplate {
+    runners: Vec<RunnerSummary>,
+}
+
+#[throws]
+pub async fn list_runners(pool: &Pool) -> String {
+    let conn = pool.get().await?;
+
     let rows = conn
         .query(
-            "SELECT id, data, runner, started, finished, state
-             FROM jobs WHERE state != 'available' AND state != 'running'
-             ORDER BY priority, created
-             LIMIT 10",
+            "SELECT jobs.runner, projects.name, jobs.id
+             FROM jobs JOIN projects ON projects.id = jobs.project
+             WHERE jobs.state IN ('activating', 'running', 'canceling')
+               AND jobs.runner IS NOT NULL
+             ORDER BY jobs.id",
             &[],
         )
+        .instrument_query("get_runner_jobs")
         .await?;
-    let recent_jobs = rows
+    let mut jobs: HashMap<String, Vec<RunnerJob>> = HashMap::new();
+    for row in rows {
+        jobs.entry(row.get(0)).or_default().push(RunnerJob {
+            project_name: row.get(1),
+            job_id: row.get(2),
+        });
+    }
+
+    let rows = conn
+        .query(
+            "SELECT name, last_seen, capabilities, draining,
+                    last_seen < CURRENT_TIMESTAMP -
+                        $1::BIGINT * INTERVAL '1 millisecond',
+                    CURRENT_TIMESTAMP
+             FROM runners
+             ORDER BY name",
+            &[&DEFAULT_RUNNER_OFFLINE_MILLIS],
+        )
+        .instrument_query("list_runners")
+        .await?;
+    let runners = rows
         .iter()
         .map(|row| {
-            let started: DateTime<Utc> = row.get(3);
-            let now: DateTime<Utc> = row.get(4);
-            JobSummary {
-                job_id: row.get(0),
-                data: row.get(1),
-                runner: row.get(2),
-                duration: format_duration(started, now),
-                state: row.get(5),
+            let name: String = row.get(0);
+            let last_seen: DateTime<Utc> = row.get(1);
+            let draining: bool = row.get(3);
+            let offline: bool = row.get(4);
+            let now: DateTime<Utc> = row.get(5);
+            RunnerSummary {
+                jobs: jobs.remove(&name).unwrap_or_default(),
+                name,
+                state: if offline {
+                    "offline"
+                } else if draining {
+                    "draining"
+                } else {
+                    "online"
+                }
+                .into(),
+                last_seen: format_duration(last_seen, now),
+                capabilities: row.get(2),
             }
         })
         .collect();
 
-    let template = ProjectTemplate {
-        name: project_name.into(),
-        pending_jobs,
-        running_jobs,
-        recent_jobs,
-    };
+    let template = RunnersTemplate { runners };
     template.render()?
 }
@@ -0,0 +1,73 @@
+//! Webhooks sent when all of a batch's jobs have finished.
+
+use crate::api::{load_batch, terminal_states};
+use crate::{Error, Pool, QueryExt};
+use fehler::throws;
+use jobclerk_types::BatchId;
+use log::{info, warn};
+use std::time::Duration;
+
+/// Number of times sending a batch's webhook is tried before giving
+/// up.
+const MAX_WEBHOOK_ATTEMPTS: i32 = 5;
+
+/// How long to wait for the webhook's URL to respond.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Send the webhooks of batches whose jobs have all finished. A
+/// webhook that fails is tried again the next time this is called,
+/// so a webhook may be sent more than once.
+#[throws]
+pub async fn send_batch_webhooks(pool: &Pool) {
+    let conn = pool.get().await?;
+    let rows = conn
+        .query(
+            "SELECT batches.id, projects.name, batches.webhook_url
+             FROM batches
+             JOIN projects ON projects.id = batches.project
+             WHERE batches.webhook_url IS NOT NULL
+               AND batches.webhook_sent IS NULL
+               AND batches.webhook_attempts < $1
+               AND NOT EXISTS (
+                 SELECT 1 FROM batch_jobs
+                 JOIN jobs ON jobs.id = batch_jobs.job
+                 WHERE batch_jobs.batch = batches.id
+                   AND jobs.state != ALL($2))",
+            &[&MAX_WEBHOOK_ATTEMPTS, &terminal_states()],
+        )
+        .instrument_query("get_finished_batches")
+        .await?;
+
+    let client = reqwest::Client::new();
+    for row in &rows {
+        let batch_id: BatchId = row.get(0);
+        let project_name: &str = row.get(1);
+        let url: &str = row.get(2);
+        let batch = match load_batch(&conn, project_name, batch_id).await? {
+            Some(batch) => batch,
+            None => continue,
+        };
+        let result = client
+            .post(url)
+            .timeout(WEBHOOK_TIMEOUT)
+            .json(&batch)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status());
+        let stmt = match result {
+            Ok(_) => {
+                info!("sent webhook for batch {}", batch_id);
+                "UPDATE batches SET webhook_sent = CURRENT_TIMESTAMP
+                 WHERE id = $1"
+            }
+            Err(err) => {
+                warn!("failed to send webhook for batch {}: {}", batch_id, err);
+                "UPDATE batches SET webhook_attempts = webhook_attempts + 1
+                 WHERE id = $1"
+            }
+        };
+        conn.execute(stmt, &[&batch_id])
+            .instrument_query("update_batch_webhook")
+            .await?;
+    }
+}
@@ -0,0 +1,250 @@
+//! Workflows are named templates of jobs with dependencies between
+//! them. Starting a workflow adds a job for each of its nodes.
+
+use crate::api::{add_job_in_transaction, check_add_job, check_data_size};
+use crate::job_template::substitute_params;
+use crate::{Error, Pool, QueryExt};
+use fehler::{throw, throws};
+use jobclerk_types::*;
+use std::collections::{BTreeMap, HashSet};
+
+/// Order a workflow's nodes so that each node comes after the nodes it
+/// depends on. Fails if a node's name is empty or repeated, if a node
+/// depends on one that doesn't exist, or if the dependencies form a
+/// cycle.
+#[throws]
+fn node_order(nodes: &[WorkflowNode]) -> Vec<&WorkflowNode> {
+    let mut names = HashSet::new();
+    for node in nodes {
+        if node.name.is_empty() {
+            throw!(Error::bad_request(
+                ErrorCode::InvalidArgument,
+                "node names can't be empty".into()
+            ));
+        }
+        if !names.insert(node.name.as_str()) {
+            throw!(Error::bad_request(
+                ErrorCode::InvalidArgument,
+                format!("duplicate node: {}", node.name)
+            ));
+        }
+    }
+    for node in nodes {
+        for dep in &node.depends_on {
+            if !names.contains(dep.as_str()) {
+                throw!(Error::bad_request(
+                    ErrorCode::InvalidArgument,
+                    format!(
+                        "node {} depends on unknown node {}",
+                        node.name, dep
+                    )
+                ));
+            }
+        }
+    }
+
+    let mut ordered = Vec::with_capacity(nodes.len());
+    let mut added = HashSet::new();
+    while ordered.len() < nodes.len() {
+        let ready: Vec<&WorkflowNode> = nodes
+            .iter()
+            .filter(|node| {
+                !added.contains(node.name.as_str())
+                    && node
+                        .depends_on
+                        .iter()
+                        .all(|dep| added.contains(dep.as_str()))
+            })
+            .collect();
+        // Every node left depends on another node that's left
+        if ready.is_empty() {
+            throw!(Error::bad_request(
+                ErrorCode::InvalidArgument,
+                "the workflow's dependencies form a cycle".into()
+            ));
+        }
+        for node in ready {
+            added.insert(node.name.as_str());
+            ordered.push(node);
+        }
+    }
+    ordered
+}
+
+/// Request to add the job for a node.
+fn node_job(
+    project_name: &str,
+    node: &WorkflowNode,
+    depends_on: Vec<JobId>,
+) -> AddJobRequest {
+    AddJobRequest {
+        project_name: project_name.into(),
+        max_runtime_millis: node.max_runtime_millis,
+        requirements: node.requirements.clone(),
+        priority: node.priority,
+        queue: node.queue.clone(),
+        depends_on,
+        data: node.data.clone(),
+        ..Default::default()
+    }
+}
+
+#[throws]
+pub(crate) async fn put_workflow(pool: &Pool, req: &PutWorkflowRequest) {
+    if req.name.is_empty() {
+        throw!(Error::bad_request(
+            ErrorCode::InvalidArgument,
+            "workflow names can't be empty".into()
+        ));
+    }
+    if req.nodes.is_empty() {
+        throw!(Error::bad_request(
+            ErrorCode::InvalidArgument,
+            "a workflow needs at least one node".into()
+        ));
+    }
+    node_order(&req.nodes)?;
+    for node in &req.nodes {
+        check_add_job(&node_job(&req.project_name, node, Vec::new()))?;
+    }
+
+    let conn = pool.get().await?;
+    let updated = conn
+        .execute(
+            "INSERT INTO workflows (project, name, nodes)
+             SELECT id, $2, $3 FROM projects WHERE name = $1
+             ON CONFLICT (project, name) DO UPDATE
+             SET nodes = EXCLUDED.nodes,
+                 updated = CURRENT_TIMESTAMP",
+            &[&req.project_name, &req.name, &serde_json::json!(req.nodes)],
+        )
+        .instrument_query("put_workflow")
+        .await?;
+    if updated == 0 {
+        throw!(Error::NotFound);
+    }
+}
+
+#[throws]
+pub(crate) async fn start_workflow(
+    pool: &Pool,
+    req: &StartWorkflowRequest,
+) -> StartWorkflowResponse {
+    let mut conn = pool.get().await?;
+    let row = conn
+        .query_opt(
+            "SELECT workflows.id, workflows.nodes FROM workflows
+             JOIN projects ON projects.id = workflows.project
+             WHERE projects.name = $1 AND workflows.name = $2",
+            &[&req.project_name, &req.name],
+        )
+        .instrument_query("get_workflow")
+        .await?;
+    let (workflow_id, mut nodes): (i64, Vec<WorkflowNode>) = match row {
+        Some(row) => (
+            row.get(0),
+            serde_json::from_value(row.get(1)).map_err(|err| {
+                Error::Config(format!("invalid workflow nodes: {}", err))
+            })?,
+        ),
+        None => throw!(Error::NotFound),
+    };
+    substitute_params(
+        nodes.iter_mut().map(|node| &mut node.data),
+        &req.params,
+    )?;
+    for node in &nodes {
+        check_data_size(&conn, &req.project_name, &node.data).await?;
+    }
+
+    let txn = conn.transaction().await?;
+    let run_id: WorkflowRunId = txn
+        .query_one(
+            "INSERT INTO workflow_runs (workflow) VALUES ($1) RETURNING id",
+            &[&workflow_id],
+        )
+        .instrument_query("add_workflow_run")
+        .await?
+        .get(0);
+    let mut job_ids = BTreeMap::new();
+    for node in node_order(&nodes)? {
+        let depends_on =
+            node.depends_on.iter().map(|dep| job_ids[dep]).collect();
+        let job = node_job(&req.project_name, node, depends_on);
+        let job_id = add_job_in_transaction(&txn, &job, None).await?;
+        txn.execute(
+            "INSERT INTO workflow_run_jobs (run, node, job)
+             VALUES ($1, $2, $3)",
+            &[&run_id, &node.name, &job_id],
+        )
+        .instrument_query("add_workflow_run_job")
+        .await?;
+        job_ids.insert(node.name.clone(), job_id);
+    }
+    txn.commit().await?;
+
+    StartWorkflowResponse { run_id, job_ids }
+}
+
+#[throws]
+pub(crate) async fn get_workflow_run(
+    pool: &Pool,
+    req: &GetWorkflowRunRequest,
+) -> GetWorkflowRunResponse {
+    let conn = pool.get().await?;
+    let row = conn
+        .query_opt(
+            "SELECT workflows.name, workflow_runs.created
+             FROM workflow_runs
+             JOIN workflows ON workflows.id = workflow_runs.workflow
+             JOIN projects ON projects.id = workflows.project
+             WHERE projects.name = $1 AND workflow_runs.id = $2",
+            &[&req.project_name, &req.run_id],
+        )
+        .instrument_query("get_workflow_run")
+        .await?;
+    let row = match row {
+        Some(row) => row,
+        None => throw!(Error::NotFound),
+    };
+
+    let jobs = conn
+        .query(
+            "SELECT workflow_run_jobs.node, jobs.id, jobs.state
+             FROM workflow_run_jobs
+             JOIN jobs ON jobs.id = workflow_run_jobs.job
+             WHERE workflow_run_jobs.run = $1
+             ORDER BY workflow_run_jobs.node",
+            &[&req.run_id],
+        )
+        .instrument_query("get_workflow_run_jobs")
+        .await?
+        .iter()
+        .map(|row| -> Result<WorkflowRunJob, Error> {
+            Ok(WorkflowRunJob {
+                node: row.get(0),
+                job_id: row.get(1),
+                state: row.get::<_, &str>(2).parse()?,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let state = if jobs
+        .iter()
+        .any(|job| job.state.is_terminal() && job.state != JobState::Succeeded)
+    {
+        WorkflowRunState::Failed
+    } else if jobs.iter().all(|job| job.state == JobState::Succeeded) {
+        WorkflowRunState::Succeeded
+    } else {
+        WorkflowRunState::Running
+    };
+
+    GetWorkflowRunResponse {
+        run_id: req.run_id,
+        workflow_name: row.get(0),
+        created: row.get(1),
+        state,
+        jobs,
+    }
+}
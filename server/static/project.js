@@ -0,0 +1,23 @@
+// Reload the project page's job lists when a job changes state.
+// Changes that arrive close together are handled with one reload.
+document.addEventListener("DOMContentLoaded", () => {
+  const events = new EventSource(window.location.pathname + "/events");
+  let reloading = false;
+  events.onmessage = () => {
+    if (reloading) {
+      return;
+    }
+    reloading = true;
+    setTimeout(async () => {
+      try {
+        const resp = await fetch(window.location.href);
+        const html = new DOMParser().parseFromString(
+          await resp.text(), "text/html");
+        document.getElementById("jobs").replaceWith(
+          html.getElementById("jobs"));
+      } finally {
+        reloading = false;
+      }
+    }, 500);
+  };
+});
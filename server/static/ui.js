@@ -0,0 +1,10 @@
+// Helpers used by all of the UI's pages
+
+// Ask before submitting forms with a data-confirm attribute, such as
+// forms that cancel a job
+document.addEventListener("submit", (event) => {
+  const message = event.target.dataset.confirm;
+  if (message && !confirm(message)) {
+    event.preventDefault();
+  }
+});
@@ -1,13 +1,39 @@
 use chrono::{Duration, Utc};
 use env_logger::Env;
-use jobclerk_server::api::handle_request;
-use jobclerk_server::{make_pool, Pool};
+use futures::StreamExt;
+use jobclerk_server::api::{self, handle_request, handle_request_with_context};
+use jobclerk_server::circuit_breaker::{self, CircuitBreakerSettings};
+use jobclerk_server::rate_limit::{self, Rate, RateLimits};
+#[cfg(feature = "redis")]
+use jobclerk_server::redis_queue;
+use jobclerk_server::ui;
+#[cfg(feature = "webhooks")]
+use jobclerk_server::webhook;
+use jobclerk_server::{
+    check_schema_version, create_api_key, create_ui_session, create_ui_user,
+    delete_ui_session, get_ui_session_user, make_pool, make_pool_with_options,
+    metrics, migrate, migration_status, notify, set_request_timeout_millis,
+    Error, Pool, PoolOptions, RequestContext, DEFAULT_REQUEST_TIMEOUT_MILLIS,
+    SCHEMA_VERSION,
+};
 use jobclerk_types::*;
+use serde::Deserialize;
 use serde_json::json;
+use std::collections::BTreeMap;
+#[cfg(feature = "webhooks")]
+use std::io::{BufRead, BufReader, Read, Write};
+#[cfg(feature = "webhooks")]
+use std::net::TcpListener;
 use std::process::Command;
+#[cfg(feature = "webhooks")]
+use std::thread;
 
 const POSTGRES_CONTAINER_NAME: &str = "jobclerk-test-postgres";
 const POSTGRES_PORT: u16 = 5433;
+#[cfg(feature = "redis")]
+const REDIS_CONTAINER_NAME: &str = "jobclerk-test-redis";
+#[cfg(feature = "redis")]
+const REDIS_PORT: u16 = 6380;
 
 fn cmd_str(cmd: &Command) -> String {
     format!("{:?}", cmd).replace('"', "")
@@ -46,7 +72,7 @@ impl Drop for RunOnDrop {
 
 fn get_postgres_cmd(action: &str) -> Command {
     let mut cmd = Command::new("docker");
-    cmd.args(&[action, POSTGRES_CONTAINER_NAME]);
+    cmd.args([action, POSTGRES_CONTAINER_NAME]);
     cmd
 }
 
@@ -54,7 +80,7 @@ fn run_postgres() {
     // Stop the container if it already exists
     run_cmd_no_check(&mut get_postgres_cmd("stop"));
 
-    run_cmd(Command::new("docker").args(&[
+    run_cmd(Command::new("docker").args([
         "run",
         "--rm",
         "--name",
@@ -70,22 +96,86 @@ fn run_postgres() {
     ]));
 }
 
+#[cfg(feature = "redis")]
+fn get_redis_cmd(action: &str) -> Command {
+    let mut cmd = Command::new("docker");
+    cmd.args([action, REDIS_CONTAINER_NAME]);
+    cmd
+}
+
+#[cfg(feature = "redis")]
+fn run_redis() {
+    // Stop the container if it already exists
+    run_cmd_no_check(&mut get_redis_cmd("stop"));
+
+    run_cmd(Command::new("docker").args([
+        "run",
+        "--rm",
+        "--name",
+        REDIS_CONTAINER_NAME,
+        "--publish",
+        &format!("{}:{}", REDIS_PORT, 6379),
+        "-d",
+        "redis:alpine",
+    ]));
+}
+
+/// Get how many times the query has run from the rendered metrics.
+#[cfg(feature = "redis")]
+fn query_count(metrics: &str, query: &str) -> u64 {
+    let prefix = format!("jobclerk_queries_total{{query=\"{}\"}} ", query);
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(&prefix))
+        .map_or(0, |count| count.parse().unwrap())
+}
+
+/// Accept one HTTP request and respond with an empty 200 response,
+/// returning the request's body.
+#[cfg(feature = "webhooks")]
+fn receive_webhook(listener: TcpListener) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end().to_lowercase();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(len) = line.strip_prefix("content-length:") {
+                content_length = len.trim().parse().unwrap();
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+            .unwrap();
+        String::from_utf8(body).unwrap()
+    })
+}
+
 struct CheckRequest {
     pool: Pool,
     req: Request,
     expected_response: Option<Response>,
     check_error: bool,
+    context: RequestContext,
 }
 
 impl CheckRequest {
     async fn call(&self) -> Response {
-        let resp = handle_request(&self.pool, &self.req).await;
+        let resp =
+            handle_request_with_context(&self.pool, &self.req, &self.context)
+                .await;
         if let Some(expected_response) = &self.expected_response {
             assert_eq!(&resp, expected_response);
-        } else if self.check_error {
-            if resp.is_error() {
-                panic!("call failed with: {:?}", resp);
-            }
+        } else if self.check_error && resp.is_error() {
+            panic!("call failed with: {:?}", resp);
         }
         resp
     }
@@ -98,13 +188,33 @@ async fn integration_test() {
     // Run and initialize the database
     run_postgres();
     let _stop_postgres = RunOnDrop::new(get_postgres_cmd("kill"));
-    let pool = make_pool(POSTGRES_PORT).await.unwrap();
+    let database_url =
+        format!("postgres://postgres@localhost:{}", POSTGRES_PORT);
+    let pool = make_pool(&database_url).await.unwrap();
+    // The server can't run against a database with an old schema
+    match check_schema_version(&pool).await {
+        Err(Error::SchemaVersion { expected, found }) => {
+            assert_eq!(expected, SCHEMA_VERSION);
+            assert_eq!(found, 0);
+        }
+        result => panic!("unexpected schema check result: {:?}", result),
+    }
     {
-        let conn = pool.get().await.unwrap();
-        conn.batch_execute(include_str!("../../db/init.sql"))
-            .await
-            .unwrap();
+        let mut conn = pool.get().await.unwrap();
+        let status = migration_status(&conn).await.unwrap();
+        assert_eq!(status.len(), 20);
+        assert!(status.iter().all(|migration| migration.applied.is_none()));
+        assert_eq!(
+            migrate(&mut conn).await.unwrap(),
+            (1..=20).collect::<Vec<_>>()
+        );
+        // Nothing is left to apply
+        assert!(migrate(&mut conn).await.unwrap().is_empty());
+        let status = migration_status(&conn).await.unwrap();
+        assert!(status.iter().all(|migration| migration.applied.is_some()));
     }
+    check_schema_version(&pool).await.unwrap();
+    notify::listen(&database_url).await.unwrap();
 
     // Create a project
     let mut check = CheckRequest {
@@ -113,10 +223,12 @@ async fn integration_test() {
             name: "testproj".into(),
             heartbeat_expiration_millis: 250, // 0.25 seconds
             data: json!({}),
+            ..Default::default()
         }
         .into(),
         expected_response: Some(AddProjectResponse { project_id: 1 }.into()),
         check_error: true,
+        context: RequestContext::default(),
     };
     check.call().await;
 
@@ -126,6 +238,7 @@ async fn integration_test() {
         data: json!({
             "hello": "world",
         }),
+        ..Default::default()
     }
     .into();
     check.expected_response = Some(AddJobResponse { job_id: 1 }.into());
@@ -134,6 +247,7 @@ async fn integration_test() {
     // List jobs
     check.req = GetJobsRequest {
         project_name: "testproj".into(),
+        ..Default::default()
     }
     .into();
     check.expected_response = None;
@@ -155,16 +269,32 @@ async fn integration_test() {
             started: None,
             finished: None,
             priority: 0,
+            attempts: 0,
+            queue: "default".into(),
             data: json!({
                 "hello": "world",
             })
         }
     );
 
+    // List jobs without their data
+    check.req = GetJobsRequest {
+        project_name: "testproj".into(),
+        exclude_data: true,
+        ..Default::default()
+    }
+    .into();
+    let resp = check.call().await.into_get_jobs().unwrap();
+    assert_eq!(resp.jobs.len(), 1);
+    assert_eq!(resp.jobs[0].id, 1);
+    assert_eq!(resp.jobs[0].state, JobState::Available);
+    assert_eq!(resp.jobs[0].data, json!(null));
+
     // Take a job
     check.req = TakeJobRequest {
         project_name: "testproj".into(),
         runner: "testrunner".into(),
+        ..Default::default()
     }
     .into();
     let job = check.call().await.into_take_job().unwrap().job.unwrap();
@@ -176,17 +306,31 @@ async fn integration_test() {
     check.expected_response = Some(TakeJobResponse { job: None }.into());
     check.call().await;
 
-    // Send a heartbeat update
+    // The job is activating until the runner sends an update
+    check.req = GetJobRequest {
+        project_name: "testproj".into(),
+        job_id: 1,
+    }
+    .into();
+    check.expected_response = None;
+    let resp = check.call().await.into_get_job().unwrap();
+    assert_eq!(resp.job.state, JobState::Activating);
+
+    // Send a heartbeat update, which moves the job to running
     check.req = UpdateJobRequest {
         project_name: "testproj".into(),
         job_id: 1,
         token: token.clone(),
         state: None,
         data: None,
+        steps: Vec::new(),
     }
     .into();
-    check.expected_response = Some(Response::Empty);
-    check.call().await;
+    check.expected_response = None;
+    let resp = check.call().await.into_update_job().unwrap();
+    assert_eq!(resp.state, JobState::Running);
+    assert_eq!(resp.attempts, 1);
+    assert!(resp.heartbeat_deadline.is_some());
 
     // Verify that the job's JSON data was not changed
     check.req = GetJobRequest {
@@ -205,10 +349,14 @@ async fn integration_test() {
         token: token.clone(),
         state: None,
         data: Some(json!({"hello": "test"})),
+        steps: Vec::new(),
     }
     .into();
-    check.expected_response = Some(Response::Empty);
-    check.call().await;
+    check.expected_response = None;
+    let resp = check.call().await.into_update_job().unwrap();
+    assert_eq!(resp.state, JobState::Running);
+    assert_eq!(resp.attempts, 1);
+    assert!(resp.heartbeat_deadline.is_some());
 
     // Verify that the job's JSON data was changed
     check.req = GetJobRequest {
@@ -220,6 +368,24 @@ async fn integration_test() {
     let resp = check.call().await.into_get_job().unwrap();
     assert_eq!(resp.job.data, json!({"hello": "test"}));
 
+    // The data can be converted to a matching type
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Hello {
+        hello: String,
+    }
+    let job = resp.job.with_data::<Hello>().unwrap();
+    assert_eq!(job.data.hello, "test");
+    assert_eq!(job.to_string(), "testproj/1 (running)");
+    let job = check.call().await.into_get_job().unwrap().job;
+    assert!(job.with_data::<Vec<Hello>>().is_err());
+
+    // Summaries for the CLI and UI
+    assert_eq!(format_duration(Duration::seconds(3723)), "1h2m3s");
+    assert_eq!(format_duration(Duration::seconds(-5)), "0s");
+    let preview = data_preview(&json!({ "log": "x".repeat(100) }));
+    assert_eq!(preview.chars().count(), DATA_PREVIEW_LEN);
+    assert!(preview.ends_with('…'));
+
     // Mark the job as finished
     check.req = UpdateJobRequest {
         project_name: "testproj".into(),
@@ -227,15 +393,20 @@ async fn integration_test() {
         token,
         state: Some(JobState::Succeeded),
         data: None,
+        steps: Vec::new(),
     }
     .into();
-    check.expected_response = Some(Response::Empty);
-    check.call().await;
+    check.expected_response = None;
+    let resp = check.call().await.into_update_job().unwrap();
+    assert_eq!(resp.state, JobState::Succeeded);
+    assert_eq!(resp.attempts, 1);
+    assert!(resp.heartbeat_deadline.is_none());
 
     // Create a second job
     check.req = AddJobRequest {
         project_name: "testproj".into(),
         data: json!({}),
+        ..Default::default()
     }
     .into();
     check.expected_response = Some(AddJobResponse { job_id: 2 }.into());
@@ -245,6 +416,7 @@ async fn integration_test() {
     check.req = TakeJobRequest {
         project_name: "testproj".into(),
         runner: "testrunner".into(),
+        ..Default::default()
     }
     .into();
     check.expected_response = None;
@@ -258,6 +430,23 @@ async fn integration_test() {
 
     // Poke the server to check for stuck jobs
     check.req = Request::HandleStuckJobs;
+    check.expected_response = None;
+    let resp = check.call().await.into_handle_stuck_jobs().unwrap();
+    let timed_out: Vec<_> = resp
+        .timed_out
+        .iter()
+        .map(|job| (job.job_id, job.runner.as_str()))
+        .collect();
+    assert_eq!(timed_out, [(2, "testrunner")]);
+    assert!(resp.requeued.is_empty());
+
+    // The project has no retry policy, so the job has to be retried
+    // by hand
+    check.req = RetryJobRequest {
+        project_name: "testproj".into(),
+        job_id: 2,
+    }
+    .into();
     check.expected_response = Some(Response::Empty);
     check.call().await;
 
@@ -265,10 +454,2932 @@ async fn integration_test() {
     check.req = TakeJobRequest {
         project_name: "testproj".into(),
         runner: "testrunner".into(),
+        ..Default::default()
     }
     .into();
     check.expected_response = None;
     let job = check.call().await.into_take_job().unwrap().job.unwrap();
     assert_eq!(job.job_id, 2);
     assert_ne!(job.job_token, token);
+
+    // Create a job with a short maximum runtime
+    check.req = AddJobRequest {
+        project_name: "testproj".into(),
+        max_runtime_millis: Some(100),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = Some(AddJobResponse { job_id: 3 }.into());
+    check.call().await;
+
+    // Take the job
+    check.req = TakeJobRequest {
+        project_name: "testproj".into(),
+        runner: "testrunner".into(),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = None;
+    let job = check.call().await.into_take_job().unwrap().job.unwrap();
+    assert_eq!(job.job_id, 3);
+
+    // Sleep past the maximum runtime and check that the job gets
+    // timed out, along with job 2 whose heartbeat expired again
+    tokio::time::delay_for(tokio::time::Duration::from_millis(500)).await;
+    check.req = Request::HandleStuckJobs;
+    check.expected_response = None;
+    let resp = check.call().await.into_handle_stuck_jobs().unwrap();
+    let timed_out: Vec<_> = resp
+        .timed_out
+        .iter()
+        .map(|job| (job.job_id, job.runner.as_str()))
+        .collect();
+    assert_eq!(timed_out, [(3, "testrunner"), (2, "testrunner")]);
+    assert!(resp.requeued.is_empty());
+
+    check.req = GetJobRequest {
+        project_name: "testproj".into(),
+        job_id: 3,
+    }
+    .into();
+    check.expected_response = None;
+    let resp = check.call().await.into_get_job().unwrap();
+    assert_eq!(resp.job.state, JobState::TimedOut);
+    assert!(resp.job.finished.is_some());
+
+    // Create a project that retries failed jobs
+    check.req = AddProjectRequest {
+        name: "retryproj".into(),
+        heartbeat_expiration_millis: 1000,
+        retry_policy: Some(RetryPolicy {
+            max_attempts: 2,
+            backoff: RetryBackoff::Fixed,
+            delay_millis: 250,
+        }),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = Some(AddProjectResponse { project_id: 2 }.into());
+    check.call().await;
+
+    check.req = AddJobRequest {
+        project_name: "retryproj".into(),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = Some(AddJobResponse { job_id: 4 }.into());
+    check.call().await;
+
+    let take_retry_job = || TakeJobRequest {
+        project_name: "retryproj".into(),
+        runner: "testrunner".into(),
+        ..Default::default()
+    };
+    let fail_retry_job = |token| UpdateJobRequest {
+        project_name: "retryproj".into(),
+        job_id: 4,
+        token,
+        state: Some(JobState::Failed),
+        data: None,
+        steps: Vec::new(),
+    };
+
+    // Take the job and fail it
+    check.req = take_retry_job().into();
+    check.expected_response = None;
+    let job = check.call().await.into_take_job().unwrap().job.unwrap();
+    assert_eq!(job.job_id, 4);
+    check.req = fail_retry_job(job.job_token).into();
+    check.expected_response = None;
+    let resp = check.call().await.into_update_job().unwrap();
+    assert_eq!(resp.state, JobState::Available);
+    assert_eq!(resp.attempts, 1);
+    assert!(resp.heartbeat_deadline.is_none());
+
+    // The job should be requeued, but can't be taken until the retry
+    // delay has passed
+    check.req = GetJobRequest {
+        project_name: "retryproj".into(),
+        job_id: 4,
+    }
+    .into();
+    check.expected_response = None;
+    let resp = check.call().await.into_get_job().unwrap();
+    assert_eq!(resp.job.state, JobState::Available);
+    assert_eq!(resp.job.attempts, 1);
+    check.req = take_retry_job().into();
+    check.expected_response = Some(TakeJobResponse { job: None }.into());
+    check.call().await;
+
+    // Wait for the retry delay, then take the job and fail it again
+    tokio::time::delay_for(tokio::time::Duration::from_millis(500)).await;
+    check.expected_response = None;
+    let job = check.call().await.into_take_job().unwrap().job.unwrap();
+    assert_eq!(job.job_id, 4);
+    check.req = fail_retry_job(job.job_token).into();
+    check.expected_response = None;
+    let resp = check.call().await.into_update_job().unwrap();
+    assert_eq!(resp.state, JobState::Failed);
+    assert_eq!(resp.attempts, 2);
+    assert!(resp.heartbeat_deadline.is_none());
+
+    // The job is out of attempts so it should now be failed
+    check.req = GetJobRequest {
+        project_name: "retryproj".into(),
+        job_id: 4,
+    }
+    .into();
+    check.expected_response = None;
+    let resp = check.call().await.into_get_job().unwrap();
+    assert_eq!(resp.job.state, JobState::Failed);
+    assert_eq!(resp.job.attempts, 2);
+
+    // Add a job with an idempotency key, then add it again and check
+    // that the original job ID is returned
+    for _ in 0..2 {
+        check.req = AddJobRequest {
+            project_name: "testproj".into(),
+            idempotency_key: Some("testkey".into()),
+            data: json!({}),
+            ..Default::default()
+        }
+        .into();
+        check.expected_response = Some(AddJobResponse { job_id: 5 }.into());
+        check.call().await;
+    }
+
+    // Search for jobs by their data. The filter's fields are at the
+    // top level of the request.
+    check.req = serde_json::from_value(json!({"GetJobs": {
+        "project_name": "testproj",
+        "data_filter": {"hello": "test"},
+    }}))
+    .unwrap();
+    check.expected_response = None;
+    let resp = check.call().await.into_get_jobs().unwrap();
+    assert_eq!(resp.jobs.len(), 1);
+    assert_eq!(resp.jobs[0].id, 1);
+
+    // Filter on the other fields
+    let get_filtered_ids = |filter| -> Request {
+        GetJobsRequest {
+            project_name: "testproj".into(),
+            filter,
+            ..Default::default()
+        }
+        .into()
+    };
+    for filter in [
+        JobFilter {
+            states: vec![JobState::Succeeded, JobState::Available],
+            runner: Some("testrunner".into()),
+            ..Default::default()
+        },
+        JobFilter {
+            created_before: Some(Utc::now()),
+            data_search: Some("TEST".into()),
+            ..Default::default()
+        },
+    ] {
+        check.req = get_filtered_ids(filter);
+        let resp = check.call().await.into_get_jobs().unwrap();
+        let ids: Vec<_> = resp.jobs.iter().map(|job| job.id).collect();
+        assert_eq!(ids, [1]);
+    }
+    check.req = get_filtered_ids(JobFilter {
+        states: vec![JobState::TimedOut],
+        created_after: Some(Utc::now()),
+        ..Default::default()
+    });
+    assert!(check.call().await.into_get_jobs().unwrap().jobs.is_empty());
+    check.req = get_filtered_ids(JobFilter {
+        requirements: vec!["gpu".into()],
+        ..Default::default()
+    });
+    assert!(check.call().await.into_get_jobs().unwrap().jobs.is_empty());
+
+    // Get the job counts for the project
+    check.req = GetProjectStatsRequest {
+        project_name: "testproj".into(),
+        window_millis: 60 * 60 * 1000,
+        window_end: None,
+    }
+    .into();
+    check.expected_response = None;
+    let resp = check.call().await.into_get_project_stats().unwrap();
+    let expected_counts: BTreeMap<JobState, i64> = vec![
+        (JobState::Available, 1),
+        (JobState::Succeeded, 1),
+        (JobState::TimedOut, 2),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(resp.job_counts, expected_counts);
+    assert!(resp.avg_queue_wait_millis.is_some());
+    assert!(resp.avg_run_duration_millis.is_some());
+    let expected_counts: BTreeMap<JobState, i64> =
+        vec![(JobState::Succeeded, 1), (JobState::TimedOut, 2)]
+            .into_iter()
+            .collect();
+    assert_eq!(resp.finished_counts, expected_counts);
+    assert_eq!(resp.success_rate(), Some(1.0 / 3.0));
+    assert_eq!(resp.failure_rate(), Some(2.0 / 3.0));
+    assert!(resp.p50_run_duration_millis <= resp.p95_run_duration_millis);
+    assert!(resp.p50_run_duration_millis.is_some());
+    // Nothing finished in the hour before the jobs were added
+    check.req = GetProjectStatsRequest {
+        project_name: "testproj".into(),
+        window_millis: 60 * 60 * 1000,
+        window_end: Some(Utc::now() - Duration::hours(1)),
+    }
+    .into();
+    let resp = check.call().await.into_get_project_stats().unwrap();
+    assert!(resp.finished_counts.is_empty());
+    assert_eq!(resp.success_rate(), None);
+    assert_eq!(resp.p95_run_duration_millis, None);
+
+    // Check the state history of the retried job
+    check.req = GetJobHistoryRequest {
+        project_name: "retryproj".into(),
+        job_id: 4,
+    }
+    .into();
+    let resp = check.call().await.into_get_job_history().unwrap();
+    let transitions: Vec<_> = resp
+        .events
+        .into_iter()
+        .map(|event| (event.old_state, event.new_state))
+        .collect();
+    assert_eq!(
+        transitions,
+        vec![
+            (None, JobState::Available),
+            (Some(JobState::Available), JobState::Activating),
+            (Some(JobState::Activating), JobState::Available),
+            (Some(JobState::Available), JobState::Activating),
+            (Some(JobState::Activating), JobState::Failed),
+        ]
+    );
+
+    // Each time the job was taken is recorded as an attempt, with how
+    // it ended even if the job was requeued afterwards
+    check.req = GetJobAttemptsRequest {
+        project_name: "retryproj".into(),
+        job_id: 4,
+    }
+    .into();
+    let resp = check.call().await.into_get_job_attempts().unwrap();
+    let outcomes: Vec<_> = resp
+        .attempts
+        .iter()
+        .map(|attempt| (attempt.attempt, attempt.outcome))
+        .collect();
+    assert_eq!(
+        outcomes,
+        vec![(1, Some(JobState::Failed)), (2, Some(JobState::Failed))]
+    );
+    assert!(resp
+        .attempts
+        .iter()
+        .all(|attempt| attempt.finished.is_some()));
+    check.req = GetJobAttemptsRequest {
+        project_name: "testproj".into(),
+        job_id: 4,
+    }
+    .into();
+    check.expected_response = Some(Response::NotFound);
+    check.call().await;
+    check.expected_response = None;
+
+    // Stream the project's job events from the start, which includes
+    // the retried job's history
+    let mut events = Box::pin(
+        api::stream_job_events(check.pool.clone(), "retryproj".into(), Some(0))
+            .await
+            .unwrap(),
+    );
+    let batch = events.next().await.unwrap().unwrap();
+    assert_eq!(batch.iter().filter(|event| event.job_id == 4).count(), 5);
+
+    assert!(matches!(
+        api::stream_job_events(check.pool.clone(), "nope".into(), None).await,
+        Err(Error::NotFound)
+    ));
+
+    // Take a job and append to its log
+    check.req = TakeJobRequest {
+        project_name: "testproj".into(),
+        runner: "testrunner".into(),
+        ..Default::default()
+    }
+    .into();
+    let job = check.call().await.into_take_job().unwrap().job.unwrap();
+    for text in &["hello\n", "world\n"] {
+        check.req = AppendJobLogRequest {
+            project_name: "testproj".into(),
+            job_id: job.job_id,
+            token: job.job_token.clone(),
+            text: text.to_string(),
+        }
+        .into();
+        check.expected_response = Some(Response::Empty);
+        check.call().await;
+    }
+
+    // Appending with the wrong token should fail
+    check.req = AppendJobLogRequest {
+        project_name: "testproj".into(),
+        job_id: job.job_id,
+        token: "badtoken".into(),
+        text: "bad".into(),
+    }
+    .into();
+    check.expected_response = Some(Response::NotFound);
+    check.call().await;
+
+    // Read the whole log, then just the new output
+    check.req = GetJobLogRequest {
+        project_name: "testproj".into(),
+        job_id: job.job_id,
+        after_sequence: None,
+        page: Page::default(),
+    }
+    .into();
+    check.expected_response = None;
+    let resp = check.call().await.into_get_job_log().unwrap();
+    let chunks: Vec<_> = resp
+        .chunks
+        .iter()
+        .map(|chunk| (chunk.sequence, chunk.text.as_str()))
+        .collect();
+    assert_eq!(chunks, vec![(1, "hello\n"), (2, "world\n")]);
+
+    check.req = GetJobLogRequest {
+        project_name: "testproj".into(),
+        job_id: job.job_id,
+        after_sequence: Some(1),
+        page: Page::default(),
+    }
+    .into();
+    let resp = check.call().await.into_get_job_log().unwrap();
+    assert_eq!(resp.chunks.len(), 1);
+    assert_eq!(resp.chunks[0].text, "world\n");
+
+    // Page through the log backwards
+    let mut page = Page {
+        limit: Some(1),
+        order: SortOrder::Descending,
+        ..Default::default()
+    };
+    let mut sequences = Vec::new();
+    loop {
+        check.req = GetJobLogRequest {
+            project_name: "testproj".into(),
+            job_id: job.job_id,
+            after_sequence: None,
+            page: page.clone(),
+        }
+        .into();
+        let resp = check.call().await.into_get_job_log().unwrap();
+        sequences.extend(resp.chunks.iter().map(|chunk| chunk.sequence));
+        match resp.next_cursor {
+            Some(cursor) => page.cursor = Some(cursor),
+            None => break,
+        }
+    }
+    assert_eq!(sequences, [2, 1]);
+
+    // Add an artifact to the job and check that it's listed
+    check.req = AddJobArtifactRequest {
+        project_name: "testproj".into(),
+        job_id: job.job_id,
+        token: job.job_token.clone(),
+        name: "output.tar.gz".into(),
+        url: "s3://bucket/output.tar.gz".into(),
+        size: Some(1024),
+        content_type: Some("application/gzip".into()),
+    }
+    .into();
+    check.expected_response = Some(Response::Empty);
+    check.call().await;
+
+    check.req = GetJobRequest {
+        project_name: "testproj".into(),
+        job_id: job.job_id,
+    }
+    .into();
+    check.expected_response = None;
+    let resp = check.call().await.into_get_job().unwrap();
+    assert_eq!(resp.artifacts.len(), 1);
+    let artifact = &resp.artifacts[0];
+    assert_eq!(artifact.name, "output.tar.gz");
+    assert_eq!(artifact.url, "s3://bucket/output.tar.gz");
+    assert_eq!(artifact.size, Some(1024));
+    assert_eq!(artifact.content_type.as_deref(), Some("application/gzip"));
+
+    // Create a project with a job that requires a GPU
+    check.req = AddProjectRequest {
+        name: "gpuproj".into(),
+        heartbeat_expiration_millis: 1000,
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = Some(AddProjectResponse { project_id: 3 }.into());
+    check.call().await;
+
+    check.req = AddJobRequest {
+        project_name: "gpuproj".into(),
+        requirements: vec!["gpu".into()],
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = None;
+    let gpu_job_id = check.call().await.into_add_job().unwrap().job_id;
+
+    // A runner without a GPU can't take the job
+    check.req = TakeJobRequest {
+        project_name: "gpuproj".into(),
+        runner: "cpurunner".into(),
+        capabilities: vec!["cpu".into()],
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = Some(TakeJobResponse { job: None }.into());
+    check.call().await;
+
+    // A runner with a GPU can
+    check.req = TakeJobRequest {
+        project_name: "gpuproj".into(),
+        runner: "gpurunner".into(),
+        capabilities: vec!["cpu".into(), "gpu".into()],
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = None;
+    let job = check.call().await.into_take_job().unwrap().job.unwrap();
+    assert_eq!(job.job_id, gpu_job_id);
+
+    // Add a few jobs and take them all at once
+    let mut job_ids = Vec::new();
+    for _ in 0..3 {
+        check.req = AddJobRequest {
+            project_name: "gpuproj".into(),
+            data: json!({}),
+            ..Default::default()
+        }
+        .into();
+        job_ids.push(check.call().await.into_add_job().unwrap().job_id);
+    }
+    check.req = TakeJobsRequest {
+        params: TakeJobRequest {
+            project_name: "gpuproj".into(),
+            runner: "testrunner".into(),
+            ..Default::default()
+        },
+        max_jobs: 5,
+    }
+    .into();
+    let resp = check.call().await.into_take_jobs().unwrap();
+    let mut taken_ids: Vec<_> =
+        resp.jobs.iter().map(|job| job.job_id).collect();
+    taken_ids.sort();
+    assert_eq!(taken_ids, job_ids);
+    // Each job should have its own token
+    assert_ne!(resp.jobs[0].job_token, resp.jobs[1].job_token);
+
+    // Register a runner and check that it's listed along with the
+    // jobs it's running
+    check.req = RunnerHeartbeatRequest {
+        runner: "testrunner".into(),
+        capabilities: vec!["cpu".into()],
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = Some(Response::Empty);
+    check.call().await;
+
+    check.req = ListRunnersRequest::default().into();
+    check.expected_response = None;
+    let resp = check.call().await.into_list_runners().unwrap();
+    assert_eq!(resp.runners.len(), 1);
+    let runner = &resp.runners[0];
+    assert_eq!(runner.name, "testrunner");
+    assert_eq!(runner.state, RunnerState::Online);
+    assert_eq!(runner.capabilities, vec!["cpu".to_string()]);
+    assert!(taken_ids.iter().all(|id| runner.jobs.contains(id)));
+
+    // The runner is offline if its heartbeat is too old
+    tokio::time::delay_for(tokio::time::Duration::from_millis(100)).await;
+    check.req = ListRunnersRequest {
+        offline_after_millis: Some(50),
+        ..Default::default()
+    }
+    .into();
+    let resp = check.call().await.into_list_runners().unwrap();
+    assert_eq!(resp.runners[0].state, RunnerState::Offline);
+
+    // Create a project that limits runners to one job at a time
+    check.req = AddProjectRequest {
+        name: "limitproj".into(),
+        heartbeat_expiration_millis: 1000,
+        max_jobs_per_runner: Some(1),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = Some(AddProjectResponse { project_id: 4 }.into());
+    check.call().await;
+    for _ in 0..2 {
+        check.req = AddJobRequest {
+            project_name: "limitproj".into(),
+            data: json!({}),
+            ..Default::default()
+        }
+        .into();
+        check.expected_response = None;
+        check.call().await;
+    }
+
+    // Only one job can be taken even though more are requested
+    let take_limited_jobs = |runner: &str| TakeJobsRequest {
+        params: TakeJobRequest {
+            project_name: "limitproj".into(),
+            runner: runner.into(),
+            ..Default::default()
+        },
+        max_jobs: 2,
+    };
+    check.req = take_limited_jobs("testrunner").into();
+    let resp = check.call().await.into_take_jobs().unwrap();
+    assert_eq!(resp.jobs.len(), 1);
+    check.req = take_limited_jobs("testrunner").into();
+    check.expected_response = Some(TakeJobsResponse { jobs: vec![] }.into());
+    check.call().await;
+
+    // A different runner can take the other job
+    check.req = take_limited_jobs("otherrunner").into();
+    check.expected_response = None;
+    let resp = check.call().await.into_take_jobs().unwrap();
+    assert_eq!(resp.jobs.len(), 1);
+
+    // Create two projects with one job each
+    for (project_id, name) in &[(5, "fairproj1"), (6, "fairproj2")] {
+        check.req = AddProjectRequest {
+            name: name.to_string(),
+            heartbeat_expiration_millis: 1000,
+            data: json!({}),
+            ..Default::default()
+        }
+        .into();
+        check.expected_response = Some(
+            AddProjectResponse {
+                project_id: *project_id,
+            }
+            .into(),
+        );
+        check.call().await;
+
+        check.req = AddJobRequest {
+            project_name: name.to_string(),
+            data: json!({}),
+            ..Default::default()
+        }
+        .into();
+        check.expected_response = None;
+        check.call().await;
+    }
+
+    // Taking from both projects should alternate between them
+    let mut project_names = Vec::new();
+    for _ in 0..2 {
+        check.req = TakeJobFromProjectsRequest {
+            project_names: vec!["fairproj1".into(), "fairproj2".into()],
+            runner: "testrunner".into(),
+            ..Default::default()
+        }
+        .into();
+        let job = check.call().await.into_take_job().unwrap().job.unwrap();
+        project_names.push(job.project_name);
+    }
+    project_names.sort();
+    assert_eq!(project_names, vec!["fairproj1", "fairproj2"]);
+
+    // Add a job that expires quickly
+    check.req = AddJobRequest {
+        project_name: "fairproj1".into(),
+        expires_after_millis: Some(100),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    let expiring_job_id = check.call().await.into_add_job().unwrap().job_id;
+    tokio::time::delay_for(tokio::time::Duration::from_millis(300)).await;
+
+    // The job can't be taken after it expires, even before the stuck
+    // jobs are handled
+    check.req = TakeJobRequest {
+        project_name: "fairproj1".into(),
+        runner: "testrunner".into(),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = Some(TakeJobResponse { job: None }.into());
+    check.call().await;
+
+    check.req = Request::HandleStuckJobs;
+    check.expected_response = None;
+    let resp = check.call().await.into_handle_stuck_jobs().unwrap();
+    let expired: Vec<_> = resp
+        .expired
+        .iter()
+        .map(|job| (job.project_name.as_str(), job.job_id))
+        .collect();
+    assert_eq!(expired, [("fairproj1", expiring_job_id)]);
+
+    check.req = GetJobRequest {
+        project_name: "fairproj1".into(),
+        job_id: expiring_job_id,
+    }
+    .into();
+    check.expected_response = None;
+    let resp = check.call().await.into_get_job().unwrap();
+    assert_eq!(resp.job.state, JobState::Expired);
+
+    // Purging running jobs isn't allowed
+    check.req = PurgeJobsRequest {
+        project_name: "fairproj1".into(),
+        older_than_millis: 0,
+        states: vec![JobState::Running],
+    }
+    .into();
+    check.expected_response = Some(Response::BadRequest(BadRequestError::new(
+        ErrorCode::InvalidState,
+        "cannot purge jobs in state: running",
+    )));
+    check.call().await;
+
+    // Purge the expired job
+    check.req = PurgeJobsRequest {
+        project_name: "fairproj1".into(),
+        older_than_millis: 0,
+        states: vec![JobState::Expired],
+    }
+    .into();
+    check.expected_response = Some(PurgeJobsResponse { deleted: 1 }.into());
+    check.call().await;
+
+    check.req = GetJobRequest {
+        project_name: "fairproj1".into(),
+        job_id: expiring_job_id,
+    }
+    .into();
+    check.expected_response = Some(Response::NotFound);
+    check.call().await;
+
+    // Take a job and then start canceling it
+    check.req = AddJobRequest {
+        project_name: "fairproj1".into(),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = None;
+    check.call().await;
+    check.req = TakeJobRequest {
+        project_name: "fairproj1".into(),
+        runner: "cancelrunner".into(),
+        ..Default::default()
+    }
+    .into();
+    let job = check.call().await.into_take_job().unwrap().job.unwrap();
+    check
+        .pool
+        .get()
+        .await
+        .unwrap()
+        .execute(
+            "UPDATE jobs SET state = 'canceling' WHERE id = $1",
+            &[&job.job_id],
+        )
+        .await
+        .unwrap();
+
+    // The runner learns about the cancellation from its next heartbeat
+    let update_canceling_job = |state| UpdateJobRequest {
+        project_name: "fairproj1".into(),
+        job_id: job.job_id,
+        token: job.job_token.clone(),
+        state,
+        data: None,
+        steps: Vec::new(),
+    };
+    check.req = update_canceling_job(None).into();
+    check.expected_response = None;
+    let resp = check.call().await.into_update_job().unwrap();
+    assert_eq!(resp.state, JobState::Canceling);
+    assert_eq!(resp.attempts, 1);
+    assert!(resp.heartbeat_deadline.is_some());
+    assert!(resp.cancel_requested);
+
+    // The job can't be put back in the queue while it's being canceled
+    check.req = update_canceling_job(Some(JobState::Available)).into();
+    check.expected_response = Some(Response::NotFound);
+    check.call().await;
+
+    // The runner stops and marks the job canceled
+    check.req = update_canceling_job(Some(JobState::Canceled)).into();
+    check.expected_response = None;
+    let resp = check.call().await.into_update_job().unwrap();
+    assert_eq!(resp.state, JobState::Canceled);
+    assert_eq!(resp.attempts, 1);
+    assert!(resp.heartbeat_deadline.is_none());
+
+    // Create a project for testing job steps
+    check.req = AddProjectRequest {
+        name: "stepproj".into(),
+        heartbeat_expiration_millis: 1000,
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = Some(AddProjectResponse { project_id: 7 }.into());
+    check.call().await;
+
+    // Step names must be unique
+    check.req = AddJobRequest {
+        project_name: "stepproj".into(),
+        steps: vec!["build".into(), "build".into()],
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = Some(Response::BadRequest(BadRequestError::new(
+        ErrorCode::DuplicateStep,
+        "duplicate step: build",
+    )));
+    check.call().await;
+
+    // Add a job with steps and take it
+    check.req = AddJobRequest {
+        project_name: "stepproj".into(),
+        steps: vec!["build".into(), "test".into()],
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = None;
+    let step_job_id = check.call().await.into_add_job().unwrap().job_id;
+    check.req = TakeJobRequest {
+        project_name: "stepproj".into(),
+        runner: "testrunner".into(),
+        ..Default::default()
+    }
+    .into();
+    let job = check.call().await.into_take_job().unwrap().job.unwrap();
+    assert_eq!(job.job_id, step_job_id);
+
+    let update_steps = |steps| UpdateJobRequest {
+        project_name: "stepproj".into(),
+        job_id: step_job_id,
+        token: job.job_token.clone(),
+        state: None,
+        data: None,
+        steps,
+    };
+
+    // Finish the first step and start the second
+    check.req = update_steps(vec![
+        UpdateJobStep {
+            name: "build".into(),
+            state: StepState::Succeeded,
+        },
+        UpdateJobStep {
+            name: "test".into(),
+            state: StepState::Running,
+        },
+    ])
+    .into();
+    check.call().await;
+
+    // Updating a step that wasn't declared fails
+    check.req = update_steps(vec![UpdateJobStep {
+        name: "deploy".into(),
+        state: StepState::Running,
+    }])
+    .into();
+    check.expected_response = Some(Response::BadRequest(BadRequestError::new(
+        ErrorCode::UnknownStep,
+        "unknown step: deploy",
+    )));
+    check.call().await;
+
+    // States from newer versions are deserialized as unknown, and the
+    // server rejects them
+    let step: UpdateJobStep =
+        serde_json::from_value(json!({"name": "test", "state": "paused"}))
+            .unwrap();
+    assert_eq!(step.state, StepState::Unknown);
+    check.req = update_steps(vec![step]).into();
+    check.expected_response = Some(Response::BadRequest(BadRequestError::new(
+        ErrorCode::InvalidArgument,
+        "invalid step state: unknown",
+    )));
+    check.call().await;
+    let resp: Response =
+        serde_json::from_value(json!({"NewThing": {"a": 1}})).unwrap();
+    assert_eq!(resp, Response::Unknown(json!({"NewThing": {"a": 1}})));
+    assert!(resp.is_error());
+
+    check.req = GetJobRequest {
+        project_name: "stepproj".into(),
+        job_id: step_job_id,
+    }
+    .into();
+    check.expected_response = None;
+    let resp = check.call().await.into_get_job().unwrap();
+    let steps: Vec<_> = resp
+        .steps
+        .iter()
+        .map(|step| (step.name.as_str(), step.state))
+        .collect();
+    assert_eq!(
+        steps,
+        [
+            ("build", StepState::Succeeded),
+            ("test", StepState::Running)
+        ]
+    );
+    assert!(resp.steps[0].finished.is_some());
+    assert!(resp.steps[1].started.is_some());
+    assert!(resp.steps[1].finished.is_none());
+
+    // Create a project with a long grace period for testing leases,
+    // which requeues jobs whose lease expires
+    check.req = AddProjectRequest {
+        name: "leaseproj".into(),
+        heartbeat_expiration_millis: 60 * 60 * 1000,
+        retry_policy: Some(RetryPolicy {
+            max_attempts: 10,
+            backoff: RetryBackoff::Fixed,
+            delay_millis: 0,
+        }),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = Some(AddProjectResponse { project_id: 8 }.into());
+    check.call().await;
+    check.req = AddJobRequest {
+        project_name: "leaseproj".into(),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = None;
+    let lease_job_id = check.call().await.into_add_job().unwrap().job_id;
+
+    // Take the job with a short lease and let it expire
+    let take_lease_job = |lease_millis| TakeJobRequest {
+        project_name: "leaseproj".into(),
+        runner: "testrunner".into(),
+        lease_millis,
+        ..Default::default()
+    };
+    check.req = take_lease_job(Some(100)).into();
+    let job = check.call().await.into_take_job().unwrap().job.unwrap();
+    assert_eq!(job.job_id, lease_job_id);
+    tokio::time::delay_for(tokio::time::Duration::from_millis(300)).await;
+    check.req = Request::HandleStuckJobs;
+    check.expected_response = None;
+    let resp = check.call().await.into_handle_stuck_jobs().unwrap();
+    assert!(resp.requeued.iter().any(|job| job.job_id == lease_job_id));
+    check.req = GetJobRequest {
+        project_name: "leaseproj".into(),
+        job_id: lease_job_id,
+    }
+    .into();
+    check.expected_response = None;
+    let resp = check.call().await.into_get_job().unwrap();
+    assert_eq!(resp.job.state, JobState::Available);
+
+    // Take it again with the project's grace period and extend the
+    // lease
+    check.req = take_lease_job(None).into();
+    let job = check.call().await.into_take_job().unwrap().job.unwrap();
+    check.req = ExtendLeaseRequest {
+        project_name: "leaseproj".into(),
+        job_id: lease_job_id,
+        token: job.job_token.clone(),
+        extend_by_millis: 2 * 60 * 60 * 1000,
+    }
+    .into();
+    let deadline = check
+        .call()
+        .await
+        .into_extend_lease()
+        .unwrap()
+        .heartbeat_deadline;
+    assert!(deadline > Utc::now() + Duration::hours(2));
+
+    // A heartbeat doesn't shorten the extended lease
+    check.req = UpdateJobRequest {
+        project_name: "leaseproj".into(),
+        job_id: lease_job_id,
+        token: job.job_token,
+        state: None,
+        data: None,
+        steps: Vec::new(),
+    }
+    .into();
+    let resp = check.call().await.into_update_job().unwrap();
+    assert_eq!(resp.heartbeat_deadline, Some(deadline));
+    let lease_job_update = check.req;
+
+    // Jobs can't be forced into the running state
+    let force_lease_job = |state| ForceSetJobStateRequest {
+        project_name: "leaseproj".into(),
+        job_id: lease_job_id,
+        state,
+    };
+    check.req = force_lease_job(JobState::Running).into();
+    check.expected_response = Some(Response::BadRequest(BadRequestError::new(
+        ErrorCode::InvalidState,
+        "cannot force job from running to running",
+    )));
+    check.call().await;
+
+    // Force the job to fail even though its lease hasn't expired
+    check.req = force_lease_job(JobState::Failed).into();
+    check.expected_response = Some(Response::Empty);
+    check.call().await;
+    check.req = GetJobRequest {
+        project_name: "leaseproj".into(),
+        job_id: lease_job_id,
+    }
+    .into();
+    check.expected_response = None;
+    let resp = check.call().await.into_get_job().unwrap();
+    assert_eq!(resp.job.state, JobState::Failed);
+    assert!(resp.job.finished.is_some());
+
+    // The runner's token is no longer valid
+    check.req = lease_job_update;
+    check.expected_response = Some(Response::NotFound);
+    check.call().await;
+
+    // A runner that's slow to start can ask for longer to send its
+    // first heartbeat, after which the lease applies
+    check.req = AddJobRequest {
+        project_name: "leaseproj".into(),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = None;
+    let slow_job_id = check.call().await.into_add_job().unwrap().job_id;
+    check.req = TakeJobRequest {
+        first_heartbeat_millis: Some(60 * 1000),
+        ..take_lease_job(Some(100))
+    }
+    .into();
+    let job = check.call().await.into_take_job().unwrap().job.unwrap();
+    assert_eq!(job.job_id, slow_job_id);
+    tokio::time::delay_for(tokio::time::Duration::from_millis(200)).await;
+    check.req = Request::GetStuckJobs;
+    let resp = check.call().await.into_get_stuck_jobs().unwrap();
+    assert!(resp.jobs.iter().all(|job| job.job_id != slow_job_id));
+    let update_slow_job = |state| UpdateJobRequest {
+        project_name: "leaseproj".into(),
+        job_id: slow_job_id,
+        token: job.job_token.clone(),
+        state,
+        data: None,
+        steps: Vec::new(),
+    };
+    check.req = update_slow_job(None).into();
+    let resp = check.call().await.into_update_job().unwrap();
+    assert_eq!(resp.state, JobState::Running);
+    assert!(
+        resp.heartbeat_deadline.unwrap() < Utc::now() + Duration::seconds(1)
+    );
+    check.req = update_slow_job(Some(JobState::Succeeded)).into();
+    check.call().await;
+
+    // Let a job's lease expire and check that it's listed as stuck
+    check.req = AddJobRequest {
+        project_name: "leaseproj".into(),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = None;
+    let stuck_job_id = check.call().await.into_add_job().unwrap().job_id;
+    check.req = take_lease_job(Some(50)).into();
+    check.call().await;
+    tokio::time::delay_for(tokio::time::Duration::from_millis(200)).await;
+    let get_stuck_lease_jobs = |resp: Response| -> Vec<(JobId, String)> {
+        resp.into_get_stuck_jobs()
+            .unwrap()
+            .jobs
+            .into_iter()
+            .filter(|job| job.project_name == "leaseproj")
+            .map(|job| (job.job_id, job.runner))
+            .collect()
+    };
+    check.req = Request::GetStuckJobs;
+    let stuck = get_stuck_lease_jobs(check.call().await);
+    assert_eq!(stuck, [(stuck_job_id, "testrunner".to_string())]);
+
+    // Listing stuck jobs doesn't change them
+    check.req = GetJobRequest {
+        project_name: "leaseproj".into(),
+        job_id: stuck_job_id,
+    }
+    .into();
+    let resp = check.call().await.into_get_job().unwrap();
+    assert_eq!(resp.job.state, JobState::Activating);
+
+    check.req = Request::HandleStuckJobs;
+    let resp = check.call().await.into_handle_stuck_jobs().unwrap();
+    assert!(resp.requeued.iter().any(|job| job.job_id == stuck_job_id));
+    check.req = Request::GetStuckJobs;
+    let stuck = get_stuck_lease_jobs(check.call().await);
+    assert!(stuck.is_empty());
+    check.req = GetJobAttemptsRequest {
+        project_name: "leaseproj".into(),
+        job_id: stuck_job_id,
+    }
+    .into();
+    let attempts = check.call().await.into_get_job_attempts().unwrap().attempts;
+    assert_eq!(attempts.len(), 1);
+    assert_eq!(attempts[0].outcome, Some(JobState::TimedOut));
+
+    // A job whose runner stops responding while it's being canceled is
+    // canceled rather than left waiting for the runner
+    check.req = AddJobRequest {
+        project_name: "leaseproj".into(),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.call().await.into_add_job().unwrap();
+    check.req = take_lease_job(Some(50)).into();
+    let canceling_job_id = check
+        .call()
+        .await
+        .into_take_job()
+        .unwrap()
+        .job
+        .unwrap()
+        .job_id;
+    check.req = CancelJobRequest {
+        project_name: "leaseproj".into(),
+        job_id: canceling_job_id,
+    }
+    .into();
+    assert_eq!(
+        check.call().await.into_cancel_job().unwrap().state,
+        JobState::Canceling
+    );
+    tokio::time::delay_for(tokio::time::Duration::from_millis(200)).await;
+    check.req = Request::HandleStuckJobs;
+    let resp = check.call().await.into_handle_stuck_jobs().unwrap();
+    assert!(resp
+        .canceled
+        .iter()
+        .any(|job| job.job_id == canceling_job_id));
+    assert!(!resp
+        .requeued
+        .iter()
+        .any(|job| job.job_id == canceling_job_id));
+    check.req = GetJobRequest {
+        project_name: "leaseproj".into(),
+        job_id: canceling_job_id,
+    }
+    .into();
+    let job = check.call().await.into_get_job().unwrap().job;
+    assert_eq!(job.state, JobState::Canceled);
+    assert!(job.finished.is_some());
+
+    // Check the audit log for the lease test job
+    check.req = GetAuditLogRequest {
+        project_name: Some("leaseproj".into()),
+        job_id: Some(lease_job_id),
+        ..Default::default()
+    }
+    .into();
+    let entries = check.call().await.into_get_audit_log().unwrap().entries;
+    let requests: Vec<_> =
+        entries.iter().map(|entry| entry.request.as_str()).collect();
+    assert_eq!(
+        requests,
+        [
+            "ForceSetJobState",
+            "ExtendLease",
+            "TakeJob",
+            "TakeJob",
+            "AddJob"
+        ]
+    );
+    // The runner is found from the job, and the token is left out
+    let extend_lease = &entries[1];
+    assert_eq!(extend_lease.actor.as_deref(), Some("testrunner"));
+    assert_eq!(
+        extend_lease.summary,
+        json!({"ExtendLease": {
+            "project_name": "leaseproj",
+            "job_id": lease_job_id,
+            "extend_by_millis": 2 * 60 * 60 * 1000,
+        }})
+    );
+
+    // Page through the log
+    check.req = GetAuditLogRequest {
+        project_name: Some("leaseproj".into()),
+        job_id: Some(lease_job_id),
+        before_id: Some(entries[1].id),
+        limit: Some(1),
+    }
+    .into();
+    let page = check.call().await.into_get_audit_log().unwrap().entries;
+    assert_eq!(page, entries[2..3]);
+
+    // Create a project for testing waiting for jobs
+    check.req = AddProjectRequest {
+        name: "waitproj".into(),
+        heartbeat_expiration_millis: 1000,
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = Some(AddProjectResponse { project_id: 9 }.into());
+    check.call().await;
+
+    let take_wait_job = |wait_millis| TakeJobRequest {
+        project_name: "waitproj".into(),
+        runner: "testrunner".into(),
+        wait_millis: Some(wait_millis),
+        ..Default::default()
+    };
+    check.req = take_wait_job(0).into();
+    check.expected_response = Some(Response::BadRequest(BadRequestError::new(
+        ErrorCode::InvalidArgument,
+        "invalid wait_millis: 0",
+    )));
+    check.call().await;
+
+    // Nothing is added, so the request times out
+    check.req = take_wait_job(100).into();
+    check.expected_response = Some(TakeJobResponse { job: None }.into());
+    check.call().await;
+
+    // A waiting runner is woken up as soon as a job is added, well
+    // before it would have polled again
+    check.req = take_wait_job(30_000).into();
+    check.expected_response = None;
+    let add_job = async {
+        tokio::time::delay_for(tokio::time::Duration::from_millis(200)).await;
+        let req = AddJobRequest {
+            project_name: "waitproj".into(),
+            data: json!({}),
+            ..Default::default()
+        };
+        handle_request(&check.pool, &req.into())
+            .await
+            .into_add_job()
+            .unwrap()
+            .job_id
+    };
+    let start = std::time::Instant::now();
+    let (resp, wait_job_id) =
+        futures::future::join(check.call(), add_job).await;
+    assert!(start.elapsed() < std::time::Duration::from_secs(2));
+    let job = resp.into_take_job().unwrap().job.unwrap();
+    assert_eq!(job.job_id, wait_job_id);
+
+    // Create a project for testing pushing jobs to runners
+    check.req = AddProjectRequest {
+        name: "pushproj".into(),
+        heartbeat_expiration_millis: 60 * 1000,
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response =
+        Some(AddProjectResponse { project_id: 10 }.into());
+    check.call().await;
+
+    let mut events = Box::pin(
+        api::stream_runner_events(
+            check.pool.clone(),
+            SubscribeRunnerRequest {
+                runner: "pushrunner".into(),
+                project_names: vec!["pushproj".into()],
+                max_jobs: 1,
+                ..Default::default()
+            },
+            RequestContext::default(),
+        )
+        .await
+        .unwrap(),
+    );
+    let add_push_job = || AddJobRequest {
+        project_name: "pushproj".into(),
+        data: json!({}),
+        ..Default::default()
+    };
+    let next_pushed_job =
+        |event: Option<Result<RunnerEvent, Error>>| match event
+            .unwrap()
+            .unwrap()
+        {
+            RunnerEvent::JobAssigned(job) => job,
+            event => panic!("unexpected event: {:?}", event),
+        };
+
+    // A new job is pushed to the runner
+    check.req = add_push_job().into();
+    check.expected_response = None;
+    let push_job_id = check.call().await.into_add_job().unwrap().job_id;
+    let push_job = next_pushed_job(events.next().await);
+    assert_eq!(push_job.job_id, push_job_id);
+
+    // The runner is told when the job is canceled
+    check.req = CancelJobRequest {
+        project_name: "pushproj".into(),
+        job_id: push_job_id,
+    }
+    .into();
+    check.expected_response = Some(
+        CancelJobResponse {
+            state: JobState::Canceling,
+        }
+        .into(),
+    );
+    check.call().await;
+    assert_eq!(
+        events.next().await.unwrap().unwrap(),
+        RunnerEvent::CancelRequested {
+            project_name: "pushproj".into(),
+            job_id: push_job_id,
+        }
+    );
+
+    // The runner already holds as many jobs as it asked for, so the
+    // next job isn't pushed until the canceled job finishes
+    check.req = add_push_job().into();
+    check.expected_response = None;
+    let next_push_job_id = check.call().await.into_add_job().unwrap().job_id;
+    check.req = UpdateJobRequest {
+        project_name: "pushproj".into(),
+        job_id: push_job_id,
+        token: push_job.job_token,
+        state: Some(JobState::Canceled),
+        data: None,
+        steps: Vec::new(),
+    }
+    .into();
+    check.call().await;
+    let push_job = next_pushed_job(events.next().await);
+    assert_eq!(push_job.job_id, next_push_job_id);
+
+    // Create keys for the push project
+    let (viewer_key, runner_key, submitter_key) = {
+        let conn = check.pool.get().await.unwrap();
+        let viewer_key = create_api_key(
+            &conn,
+            Some("pushproj"),
+            None,
+            ApiKeyRole::Viewer,
+            None,
+        )
+        .await
+        .unwrap();
+        let runner_key = create_api_key(
+            &conn,
+            Some("pushproj"),
+            None,
+            ApiKeyRole::Runner,
+            None,
+        )
+        .await
+        .unwrap();
+        let submitter_key = create_api_key(
+            &conn,
+            Some("pushproj"),
+            None,
+            ApiKeyRole::Submitter,
+            Some("test key"),
+        )
+        .await
+        .unwrap();
+        (viewer_key, runner_key, submitter_key)
+    };
+
+    // Requests need a valid API key when auth is required
+    check.context.require_auth = true;
+    check.req = GetJobsRequest {
+        project_name: "pushproj".into(),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = Some(Response::Unauthorized);
+    check.call().await;
+    check.context.api_key = Some("notakey".into());
+    check.call().await;
+
+    // A viewer key can get jobs but not add them
+    check.context.api_key = Some(viewer_key);
+    check.expected_response = None;
+    check.call().await.into_get_jobs().unwrap();
+    check.req = add_push_job().into();
+    check.expected_response = Some(Response::Forbidden(
+        "the viewer role can't make AddJob requests".into(),
+    ));
+    check.call().await;
+
+    // A runner key can take jobs but not add them
+    check.context.api_key = Some(runner_key);
+    check.expected_response = Some(Response::Forbidden(
+        "the runner role can't make AddJob requests".into(),
+    ));
+    check.call().await;
+    check.req = TakeJobRequest {
+        runner: "pushrunner".into(),
+        project_name: "pushproj".into(),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = None;
+    check.call().await.into_take_job().unwrap();
+
+    // A key for one project can't be used with another
+    check.context.api_key = Some(submitter_key);
+    check.req = add_push_job().into();
+    check.expected_response = None;
+    let push_job_id = check.call().await.into_add_job().unwrap().job_id;
+
+    // Forcing a job's state is left to admins
+    check.req = ForceSetJobStateRequest {
+        project_name: "pushproj".into(),
+        job_id: push_job_id,
+        state: JobState::Succeeded,
+    }
+    .into();
+    check.expected_response = Some(Response::Forbidden(
+        "the submitter role can't make ForceSetJobState requests".into(),
+    ));
+    check.call().await;
+
+    check.req = AddJobRequest {
+        project_name: "testproj".into(),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = Some(Response::Forbidden(
+        "the API key can only be used with project pushproj".into(),
+    ));
+    check.call().await;
+
+    // Only the server's admin key can add projects, not API keys
+    // with the admin role
+    check.context.api_key = Some({
+        let conn = check.pool.get().await.unwrap();
+        create_api_key(&conn, None, None, ApiKeyRole::Admin, None)
+            .await
+            .unwrap()
+    });
+    check.req = AddProjectRequest {
+        name: "adminproj".into(),
+        heartbeat_expiration_millis: 60 * 1000,
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = Some(Response::Forbidden(
+        "managing projects requires the admin key".into(),
+    ));
+    check.call().await;
+    check.context.admin = true;
+    check.expected_response =
+        Some(AddProjectResponse { project_id: 11 }.into());
+    check.call().await;
+
+    // The client certificate's subject is recorded as the actor
+    check.context.client_identity = Some("CN=certrunner".into());
+    check.req = AddJobRequest {
+        project_name: "adminproj".into(),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = None;
+    check.call().await.into_add_job().unwrap();
+    check.req = GetAuditLogRequest {
+        project_name: Some("adminproj".into()),
+        limit: Some(1),
+        ..Default::default()
+    }
+    .into();
+    let entries = check.call().await.into_get_audit_log().unwrap().entries;
+    assert_eq!(entries[0].request, "AddJob");
+    assert_eq!(entries[0].actor.as_deref(), Some("CN=certrunner"));
+    check.context = RequestContext::default();
+
+    // Log in to the UI
+    {
+        let conn = check.pool.get().await.unwrap();
+        create_ui_user(&conn, "uiuser", "secret", None, ApiKeyRole::Viewer)
+            .await
+            .unwrap();
+        create_ui_user(
+            &conn,
+            "uisubmitter",
+            "secret",
+            Some("adminproj"),
+            ApiKeyRole::Submitter,
+        )
+        .await
+        .unwrap();
+    }
+    assert!(matches!(
+        create_ui_session(&check.pool, "uiuser", "wrong").await,
+        Err(Error::Unauthorized)
+    ));
+    assert!(matches!(
+        create_ui_session(&check.pool, "nobody", "secret").await,
+        Err(Error::Unauthorized)
+    ));
+    let session = create_ui_session(&check.pool, "uiuser", "secret")
+        .await
+        .unwrap();
+    assert_eq!(
+        get_ui_session_user(&check.pool, &session).await.unwrap(),
+        Some("uiuser".into())
+    );
+    assert_eq!(
+        get_ui_session_user(&check.pool, "invalid").await.unwrap(),
+        None
+    );
+    delete_ui_session(&check.pool, &session).await.unwrap();
+    assert_eq!(
+        get_ui_session_user(&check.pool, &session).await.unwrap(),
+        None
+    );
+
+    // Requests from the UI's forms are authorized with the user's role
+    // and project
+    check.context.ui_user = Some("uiuser".into());
+    check.req = AddJobRequest {
+        project_name: "adminproj".into(),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = Some(Response::Forbidden(
+        "the viewer role can't make AddJob requests".into(),
+    ));
+    check.call().await;
+    check.context.ui_user = Some("uisubmitter".into());
+    check.expected_response = None;
+    check.call().await.into_add_job().unwrap();
+    check.req = AddJobRequest {
+        project_name: "testproj".into(),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = Some(Response::Forbidden(
+        "the UI user can only be used with project adminproj".into(),
+    ));
+    check.call().await;
+    check.context.ui_user = Some("nobody".into());
+    check.expected_response = Some(Response::Unauthorized);
+    check.call().await;
+    check.context = RequestContext::default();
+    check.expected_response = None;
+
+    // Job data can't be larger than the project's limit
+    check.req = AddProjectRequest {
+        name: "smallproj".into(),
+        heartbeat_expiration_millis: 60 * 1000,
+        max_data_bytes: Some(16),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response =
+        Some(AddProjectResponse { project_id: 12 }.into());
+    check.call().await;
+    check.req = AddJobRequest {
+        project_name: "smallproj".into(),
+        data: json!({"a": "0123456789abcdef"}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = Some(Response::BadRequest(BadRequestError::new(
+        ErrorCode::DataTooLarge,
+        "data is 24 bytes, but the maximum is 16",
+    )));
+    check.call().await;
+    check.req = AddJobRequest {
+        project_name: "smallproj".into(),
+        data: json!({"a": 1}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = None;
+    let small_job_id = check.call().await.into_add_job().unwrap().job_id;
+
+    // A job that hasn't been taken is canceled right away, and can't
+    // be canceled again
+    check.req = CancelJobRequest {
+        project_name: "smallproj".into(),
+        job_id: small_job_id,
+    }
+    .into();
+    check.expected_response = Some(
+        CancelJobResponse {
+            state: JobState::Canceled,
+        }
+        .into(),
+    );
+    check.call().await;
+    check.expected_response = Some(Response::BadRequest(BadRequestError::new(
+        ErrorCode::InvalidState,
+        "cannot cancel a job that is canceled",
+    )));
+    check.call().await;
+
+    // The canceled job can be retried, which makes it available again
+    check.req = RetryJobRequest {
+        project_name: "smallproj".into(),
+        job_id: small_job_id,
+    }
+    .into();
+    check.expected_response = Some(Response::Empty);
+    check.call().await;
+    check.expected_response = Some(Response::BadRequest(BadRequestError::new(
+        ErrorCode::InvalidState,
+        "cannot retry a job that is available",
+    )));
+    check.call().await;
+    check.req = GetJobRequest {
+        project_name: "smallproj".into(),
+        job_id: small_job_id,
+    }
+    .into();
+    check.expected_response = None;
+    let job = check.call().await.into_get_job().unwrap().job;
+    assert_eq!(job.state, JobState::Available);
+    assert_eq!(job.finished, None);
+
+    // The project page only shows the project's own jobs; the job
+    // before this one is in adminproj
+    let form = ui::FormContext {
+        csrf_token: ui::new_csrf_token(),
+        flash: None,
+    };
+    let query = ui::ProjectPageQuery::default();
+    let page = ui::get_project(&check.pool, "smallproj", &query, &form)
+        .await
+        .unwrap();
+    assert!(page.contains(&format!("/jobs/{}\"", small_job_id)));
+    assert!(!page.contains(&format!("/jobs/{}\"", small_job_id - 1)));
+    assert!(matches!(
+        ui::get_project(&check.pool, "nope", &query, &form).await,
+        Err(Error::NotFound)
+    ));
+
+    // Once the project has more than one queue, the page has a tab for
+    // each, which only shows the queue's jobs
+    assert!(!page.contains("queue-tabs"));
+    check.req = AddJobRequest {
+        project_name: "smallproj".into(),
+        queue: Some("bulk".into()),
+        data: json!({"b": 2}),
+        ..Default::default()
+    }
+    .into();
+    let bulk_job_id = check.call().await.into_add_job().unwrap().job_id;
+    let page = ui::get_project(&check.pool, "smallproj", &query, &form)
+        .await
+        .unwrap();
+    assert!(page.contains("all queues (2)"));
+    assert!(page.contains("queue=bulk\">bulk (1)"));
+    assert!(page.contains("queue=default\">default (1)"));
+    let query = ui::ProjectPageQuery {
+        queue: "bulk".into(),
+        ..Default::default()
+    };
+    let page = ui::get_project(&check.pool, "smallproj", &query, &form)
+        .await
+        .unwrap();
+    assert!(page.contains(&format!("/jobs/{}\"", bulk_job_id)));
+    assert!(!page.contains(&format!("/jobs/{}\"", small_job_id)));
+
+    // Paging through the projects gets the same list as getting them
+    // all at once
+    check.req = ListProjectsRequest::default().into();
+    let all_projects = check.call().await.into_list_projects().unwrap();
+    assert_eq!(all_projects.next_cursor, None);
+    let names: Vec<_> = all_projects
+        .projects
+        .iter()
+        .map(|p| p.name.as_str())
+        .collect();
+    let mut sorted_names = names.clone();
+    sorted_names.sort();
+    assert_eq!(names, sorted_names);
+    let mut page = Page {
+        limit: Some(5),
+        ..Default::default()
+    };
+    let mut paged_projects = Vec::new();
+    loop {
+        check.req = ListProjectsRequest {
+            page: page.clone(),
+            ..Default::default()
+        }
+        .into();
+        let resp = check.call().await.into_list_projects().unwrap();
+        assert!(resp.projects.len() <= 5);
+        paged_projects.extend(resp.projects);
+        match resp.next_cursor {
+            Some(cursor) => page.cursor = Some(cursor),
+            None => break,
+        }
+    }
+    assert_eq!(paged_projects, all_projects.projects);
+
+    // Stream a project's job events without an event ID, which only
+    // gets new events
+    let mut events = Box::pin(
+        api::stream_job_events(check.pool.clone(), "retryproj".into(), None)
+            .await
+            .unwrap(),
+    );
+    check.req = AddJobRequest {
+        project_name: "retryproj".into(),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = None;
+    let job_id = check.call().await.into_add_job().unwrap().job_id;
+    let batch = events.next().await.unwrap().unwrap();
+    assert_eq!(batch.len(), 1);
+    assert_eq!(batch[0].job_id, job_id);
+    assert_eq!(batch[0].event.old_state, None);
+    assert_eq!(batch[0].event.new_state, JobState::Available);
+
+    // Runners can limit the priorities of the jobs they take
+    check.req = AddProjectRequest {
+        name: "prioproj".into(),
+        heartbeat_expiration_millis: 1000,
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.call().await.into_add_project().unwrap();
+    let mut prio_job_ids = Vec::new();
+    for priority in &[5, -1] {
+        check.req = AddJobRequest {
+            project_name: "prioproj".into(),
+            priority: *priority,
+            data: json!({}),
+            ..Default::default()
+        }
+        .into();
+        prio_job_ids.push(check.call().await.into_add_job().unwrap().job_id);
+    }
+    let take_prio_job = |min_priority, max_priority| -> Request {
+        TakeJobRequest {
+            project_name: "prioproj".into(),
+            runner: "testrunner".into(),
+            min_priority,
+            max_priority,
+            ..Default::default()
+        }
+        .into()
+    };
+    check.req = take_prio_job(None, Some(0));
+    let job = check.call().await.into_take_job().unwrap().job.unwrap();
+    assert_eq!(job.job_id, prio_job_ids[1]);
+    check.expected_response = Some(TakeJobResponse { job: None }.into());
+    check.call().await;
+    check.req = take_prio_job(Some(10), None);
+    check.call().await;
+    check.req = take_prio_job(Some(1), Some(0));
+    check.expected_response = Some(Response::BadRequest(BadRequestError::new(
+        ErrorCode::InvalidArgument,
+        "min_priority 1 is above max_priority 0",
+    )));
+    check.call().await;
+    check.req = take_prio_job(Some(0), Some(5));
+    check.expected_response = None;
+    let job = check.call().await.into_take_job().unwrap().job.unwrap();
+    assert_eq!(job.job_id, prio_job_ids[0]);
+
+    // Queue weights keep a flood of jobs in one queue from starving
+    // the others. A zero weight means the queue's jobs are only taken
+    // once the other queues are empty.
+    check.req = AddProjectRequest {
+        name: "queueproj".into(),
+        heartbeat_expiration_millis: 1000,
+        queue_weights: [("bulk".to_string(), 0)].iter().cloned().collect(),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.call().await.into_add_project().unwrap();
+    let mut bulk_job_ids = Vec::new();
+    for _ in 0..3 {
+        check.req = AddJobRequest {
+            project_name: "queueproj".into(),
+            queue: Some("bulk".into()),
+            data: json!({}),
+            ..Default::default()
+        }
+        .into();
+        bulk_job_ids.push(check.call().await.into_add_job().unwrap().job_id);
+    }
+    check.req = AddJobRequest {
+        project_name: "queueproj".into(),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    let default_job_id = check.call().await.into_add_job().unwrap().job_id;
+    check.req = GetJobRequest {
+        project_name: "queueproj".into(),
+        job_id: bulk_job_ids[0],
+    }
+    .into();
+    let resp = check.call().await.into_get_job().unwrap();
+    assert_eq!(resp.job.queue, "bulk");
+    check.req = TakeJobsRequest {
+        params: TakeJobRequest {
+            project_name: "queueproj".into(),
+            runner: "testrunner".into(),
+            ..Default::default()
+        },
+        max_jobs: 2,
+    }
+    .into();
+    let resp = check.call().await.into_take_jobs().unwrap();
+    let taken: Vec<_> = resp.jobs.iter().map(|job| job.job_id).collect();
+    assert_eq!(taken, [default_job_id, bulk_job_ids[0]]);
+    check.req = AddJobRequest {
+        project_name: "queueproj".into(),
+        queue: Some("".into()),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = Some(Response::BadRequest(BadRequestError::new(
+        ErrorCode::InvalidArgument,
+        "queue names can't be empty",
+    )));
+    check.call().await;
+    check.expected_response = None;
+
+    // Projects can deduplicate available jobs by their data. Key
+    // order doesn't matter, and once the job is taken the same data
+    // adds a new job.
+    check.req = AddProjectRequest {
+        name: "dedupeproj".into(),
+        heartbeat_expiration_millis: 1000,
+        deduplicate_data: true,
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.call().await.into_add_project().unwrap();
+    let add_dedupe_job = |data| -> Request {
+        AddJobRequest {
+            project_name: "dedupeproj".into(),
+            data,
+            ..Default::default()
+        }
+        .into()
+    };
+    check.req = add_dedupe_job(json!({"event": "push", "sha": "abc"}));
+    let dedupe_job_id = check.call().await.into_add_job().unwrap().job_id;
+    check.req = add_dedupe_job(json!({"sha": "abc", "event": "push"}));
+    check.expected_response = Some(
+        AddJobResponse {
+            job_id: dedupe_job_id,
+        }
+        .into(),
+    );
+    check.call().await;
+    check.req = add_dedupe_job(json!({"event": "push", "sha": "def"}));
+    check.expected_response = None;
+    let other_job_id = check.call().await.into_add_job().unwrap().job_id;
+    assert_ne!(other_job_id, dedupe_job_id);
+    check.req = TakeJobRequest {
+        project_name: "dedupeproj".into(),
+        runner: "testrunner".into(),
+        ..Default::default()
+    }
+    .into();
+    let job = check.call().await.into_take_job().unwrap().job.unwrap();
+    assert_eq!(job.job_id, dedupe_job_id);
+    check.req = add_dedupe_job(json!({"event": "push", "sha": "abc"}));
+    let new_job_id = check.call().await.into_add_job().unwrap().job_id;
+    assert!(new_job_id > other_job_id);
+
+    // Only one active job per unique key, whoever adds it
+    let add_unique_job = |idempotency_key: &str| -> Request {
+        AddJobRequest {
+            project_name: "dedupeproj".into(),
+            idempotency_key: Some(idempotency_key.into()),
+            unique_key: Some("deploy-main".into()),
+            data: json!({"submitter": idempotency_key}),
+            ..Default::default()
+        }
+        .into()
+    };
+    check.req = add_unique_job("submitter1");
+    let unique_job_id = check.call().await.into_add_job().unwrap().job_id;
+    check.req = add_unique_job("submitter2");
+    check.expected_response = Some(
+        AddJobResponse {
+            job_id: unique_job_id,
+        }
+        .into(),
+    );
+    check.call().await;
+    check.req = CancelJobRequest {
+        project_name: "dedupeproj".into(),
+        job_id: unique_job_id,
+    }
+    .into();
+    check.expected_response = None;
+    check.call().await.into_cancel_job().unwrap();
+    check.req = add_unique_job("submitter3");
+    let next_unique_job_id = check.call().await.into_add_job().unwrap().job_id;
+    assert_ne!(next_unique_job_id, unique_job_id);
+    // The canceled job can't be retried while the new one is active
+    check.req = RetryJobRequest {
+        project_name: "dedupeproj".into(),
+        job_id: unique_job_id,
+    }
+    .into();
+    check.expected_response = Some(Response::BadRequest(BadRequestError::new(
+        ErrorCode::InvalidState,
+        &format!(
+            "job {} with unique key deploy-main is still active",
+            next_unique_job_id
+        ),
+    )));
+    check.call().await;
+    check.expected_response = None;
+
+    // Jobs added as a batch are tracked together, and the batch's
+    // webhook is sent once they have all finished
+    check.req = AddProjectRequest {
+        name: "batchproj".into(),
+        heartbeat_expiration_millis: 1000,
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.call().await.into_add_project().unwrap();
+    #[cfg(feature = "webhooks")]
+    let webhook_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    #[cfg(feature = "webhooks")]
+    let webhook_url = Some(format!(
+        "http://{}/",
+        webhook_listener.local_addr().unwrap()
+    ));
+    #[cfg(not(feature = "webhooks"))]
+    let webhook_url = None;
+    let add_batch_job = |project_name: &str, n| AddJobRequest {
+        project_name: project_name.into(),
+        data: json!({ "n": n }),
+        ..Default::default()
+    };
+    check.req = AddBatchRequest {
+        project_name: "batchproj".into(),
+        jobs: vec![add_batch_job("batchproj", 1), add_batch_job("testproj", 2)],
+        webhook_url: webhook_url.clone(),
+    }
+    .into();
+    check.expected_response = Some(Response::BadRequest(BadRequestError::new(
+        ErrorCode::InvalidArgument,
+        "job is in project testproj but the batch is in project batchproj",
+    )));
+    check.call().await;
+    check.req = AddBatchRequest {
+        project_name: "batchproj".into(),
+        jobs: vec![
+            add_batch_job("batchproj", 1),
+            add_batch_job("batchproj", 2),
+        ],
+        webhook_url,
+    }
+    .into();
+    check.expected_response = None;
+    let resp = check.call().await.into_add_batch().unwrap();
+    assert_eq!(resp.job_ids.len(), 2);
+    let batch_id = resp.batch_id;
+    check.req = GetBatchRequest {
+        project_name: "batchproj".into(),
+        batch_id,
+    }
+    .into();
+    let batch = check.call().await.into_get_batch().unwrap().batch;
+    assert_eq!(
+        batch.job_counts,
+        [(JobState::Available, 2)].iter().cloned().collect()
+    );
+    assert!(!batch.finished);
+    check.req = TakeJobsRequest {
+        params: TakeJobRequest {
+            project_name: "batchproj".into(),
+            runner: "testrunner".into(),
+            ..Default::default()
+        },
+        max_jobs: 2,
+    }
+    .into();
+    let taken = check.call().await.into_take_jobs().unwrap().jobs;
+    assert_eq!(taken.len(), 2);
+    for job in &taken {
+        check.req = UpdateJobRequest {
+            project_name: "batchproj".into(),
+            job_id: job.job_id,
+            token: job.job_token.clone(),
+            state: Some(JobState::Succeeded),
+            data: None,
+            steps: Vec::new(),
+        }
+        .into();
+        check.call().await.into_update_job().unwrap();
+    }
+    check.req = GetBatchRequest {
+        project_name: "batchproj".into(),
+        batch_id,
+    }
+    .into();
+    let batch = check.call().await.into_get_batch().unwrap().batch;
+    assert_eq!(
+        batch.job_counts,
+        [(JobState::Succeeded, 2)].iter().cloned().collect()
+    );
+    assert!(batch.finished);
+    #[cfg(feature = "webhooks")]
+    {
+        let received = receive_webhook(webhook_listener);
+        webhook::send_batch_webhooks(&check.pool).await.unwrap();
+        let sent: Batch =
+            serde_json::from_str(&received.join().unwrap()).unwrap();
+        assert_eq!(sent, batch);
+        // The webhook is only sent once
+        webhook::send_batch_webhooks(&check.pool).await.unwrap();
+    }
+
+    // A running job can add child jobs with its token
+    check.req = AddJobRequest {
+        project_name: "batchproj".into(),
+        data: json!({"fan_out": true}),
+        ..Default::default()
+    }
+    .into();
+    let parent_id = check.call().await.into_add_job().unwrap().job_id;
+    check.req = TakeJobRequest {
+        project_name: "batchproj".into(),
+        runner: "testrunner".into(),
+        ..Default::default()
+    }
+    .into();
+    let parent = check.call().await.into_take_job().unwrap().job.unwrap();
+    assert_eq!(parent.job_id, parent_id);
+    let add_child_job = |token: &str| -> Request {
+        AddChildJobRequest {
+            project_name: "batchproj".into(),
+            job_id: parent_id,
+            token: token.into(),
+            child: AddJobRequest {
+                project_name: "batchproj".into(),
+                data: json!({"part": 1}),
+                ..Default::default()
+            },
+        }
+        .into()
+    };
+    check.req = add_child_job("badtoken");
+    check.expected_response = Some(Response::NotFound);
+    check.call().await;
+    check.req = add_child_job(&parent.job_token);
+    check.expected_response = None;
+    let child_id = check.call().await.into_add_job().unwrap().job_id;
+    check.req = GetJobRequest {
+        project_name: "batchproj".into(),
+        job_id: parent_id,
+    }
+    .into();
+    let resp = check.call().await.into_get_job().unwrap();
+    assert_eq!(resp.parent_id, None);
+    assert_eq!(resp.child_ids, [child_id]);
+    check.req = GetJobRequest {
+        project_name: "batchproj".into(),
+        job_id: child_id,
+    }
+    .into();
+    let resp = check.call().await.into_get_job().unwrap();
+    assert_eq!(resp.parent_id, Some(parent_id));
+    assert!(resp.child_ids.is_empty());
+    let page = ui::get_job(&check.pool, "batchproj", child_id, &form)
+        .await
+        .unwrap();
+    assert!(page.contains(&format!(
+        "parent=<a href=\"/projects/batchproj/jobs/{0}\">{0}</a>",
+        parent_id
+    )));
+
+    // A job with dependencies can't be taken until they have
+    // succeeded, and workflows add a set of such jobs together
+    check.req = AddProjectRequest {
+        name: "workflowproj".into(),
+        heartbeat_expiration_millis: 1000,
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.call().await.into_add_project().unwrap();
+    check.req = AddJobRequest {
+        project_name: "workflowproj".into(),
+        depends_on: vec![parent_id],
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.expected_response = Some(Response::BadRequest(BadRequestError::new(
+        ErrorCode::InvalidArgument,
+        &format!("invalid depends_on: {}", parent_id),
+    )));
+    check.call().await;
+    let node = |name: &str, depends_on: &[&str]| WorkflowNode {
+        name: name.into(),
+        depends_on: depends_on.iter().map(|dep| dep.to_string()).collect(),
+        data: json!({ "node": name }),
+        ..Default::default()
+    };
+    check.req = PutWorkflowRequest {
+        project_name: "workflowproj".into(),
+        name: "build".into(),
+        nodes: vec![node("compile", &["test"]), node("test", &["compile"])],
+    }
+    .into();
+    check.expected_response = Some(Response::BadRequest(BadRequestError::new(
+        ErrorCode::InvalidArgument,
+        "the workflow's dependencies form a cycle",
+    )));
+    check.call().await;
+    check.req = PutWorkflowRequest {
+        project_name: "workflowproj".into(),
+        name: "build".into(),
+        nodes: vec![node("test", &["compile"])],
+    }
+    .into();
+    check.expected_response = Some(Response::BadRequest(BadRequestError::new(
+        ErrorCode::InvalidArgument,
+        "node test depends on unknown node compile",
+    )));
+    check.call().await;
+    check.req = PutWorkflowRequest {
+        project_name: "workflowproj".into(),
+        name: "build".into(),
+        nodes: vec![node("test", &["compile"]), node("compile", &[])],
+    }
+    .into();
+    check.expected_response = Some(Response::Empty);
+    check.call().await;
+    check.req = StartWorkflowRequest {
+        project_name: "workflowproj".into(),
+        name: "build".into(),
+        params: BTreeMap::new(),
+    }
+    .into();
+    check.expected_response = None;
+    let run = check.call().await.into_start_workflow().unwrap();
+    let get_run = || -> Request {
+        GetWorkflowRunRequest {
+            project_name: "workflowproj".into(),
+            run_id: run.run_id,
+        }
+        .into()
+    };
+    for node in &["compile", "test"] {
+        check.req = TakeJobRequest {
+            project_name: "workflowproj".into(),
+            runner: "testrunner".into(),
+            ..Default::default()
+        }
+        .into();
+        let job = check.call().await.into_take_job().unwrap().job.unwrap();
+        assert_eq!(job.job_id, run.job_ids[*node]);
+        // The next node's job isn't available yet
+        assert!(check.call().await.into_take_job().unwrap().job.is_none());
+        check.req = get_run();
+        let resp = check.call().await.into_get_workflow_run().unwrap();
+        assert_eq!(resp.workflow_name, "build");
+        assert_eq!(resp.state, WorkflowRunState::Running);
+        check.req = UpdateJobRequest {
+            project_name: "workflowproj".into(),
+            job_id: job.job_id,
+            token: job.job_token,
+            state: Some(JobState::Succeeded),
+            data: None,
+            steps: Vec::new(),
+        }
+        .into();
+        check.call().await.into_update_job().unwrap();
+    }
+    check.req = get_run();
+    let resp = check.call().await.into_get_workflow_run().unwrap();
+    assert_eq!(resp.state, WorkflowRunState::Succeeded);
+    assert_eq!(
+        resp.jobs.iter().map(|job| job.state).collect::<Vec<_>>(),
+        [JobState::Succeeded, JobState::Succeeded]
+    );
+    // If a dependency fails, the jobs that depend on it can never be
+    // taken, so they are canceled
+    check.req = StartWorkflowRequest {
+        project_name: "workflowproj".into(),
+        name: "build".into(),
+        params: BTreeMap::new(),
+    }
+    .into();
+    let failed_run = check.call().await.into_start_workflow().unwrap();
+    check.req = TakeJobRequest {
+        project_name: "workflowproj".into(),
+        runner: "testrunner".into(),
+        ..Default::default()
+    }
+    .into();
+    let job = check.call().await.into_take_job().unwrap().job.unwrap();
+    assert_eq!(job.job_id, failed_run.job_ids["compile"]);
+    check.req = UpdateJobRequest {
+        project_name: "workflowproj".into(),
+        job_id: job.job_id,
+        token: job.job_token,
+        state: Some(JobState::Failed),
+        data: None,
+        steps: Vec::new(),
+    }
+    .into();
+    check.call().await.into_update_job().unwrap();
+    check.req = GetJobRequest {
+        project_name: "workflowproj".into(),
+        job_id: failed_run.job_ids["test"],
+    }
+    .into();
+    let job = check.call().await.into_get_job().unwrap().job;
+    assert_eq!(job.state, JobState::Canceled);
+    assert!(job.finished.is_some());
+    // A job added with a dependency that already failed is canceled
+    // right away
+    check.req = AddJobRequest {
+        project_name: "workflowproj".into(),
+        depends_on: vec![failed_run.job_ids["compile"]],
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    let job_id = check.call().await.into_add_job().unwrap().job_id;
+    check.req = GetJobRequest {
+        project_name: "workflowproj".into(),
+        job_id,
+    }
+    .into();
+    let job = check.call().await.into_get_job().unwrap().job;
+    assert_eq!(job.state, JobState::Canceled);
+    check.req = GetWorkflowRunRequest {
+        project_name: "workflowproj".into(),
+        run_id: failed_run.run_id,
+    }
+    .into();
+    let resp = check.call().await.into_get_workflow_run().unwrap();
+    assert_eq!(resp.state, WorkflowRunState::Failed);
+
+    // Jobs added from a template get the template's fields, with the
+    // request's data merged into the template's and its parameters
+    // substituted into the template's placeholders
+    let add_from_template =
+        |template_name: &str, params: &[(&str, &str)]| -> Request {
+            AddJobFromTemplateRequest {
+                project_name: "workflowproj".into(),
+                template_name: template_name.into(),
+                params: params
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect(),
+                priority: Some(3),
+                data: json!({"build": {"branch": "feature"}, "extra": true}),
+                ..Default::default()
+            }
+            .into()
+        };
+    check.req = add_from_template("nightly", &[]);
+    check.expected_response = Some(Response::NotFound);
+    check.call().await;
+    check.req = PutJobTemplateRequest {
+        project_name: "workflowproj".into(),
+        name: "nightly".into(),
+        template: JobTemplate {
+            requirements: vec!["linux".into()],
+            priority: 1,
+            queue: Some("nightly".into()),
+            max_runtime_millis: None,
+            data: json!({
+                "build": {"branch": "main", "target": "{{ target }}-{{os}}"},
+            }),
+        },
+    }
+    .into();
+    check.expected_response = Some(Response::Empty);
+    check.call().await;
+    check.req = add_from_template("nightly", &[("target", "all")]);
+    check.expected_response = Some(Response::BadRequest(BadRequestError::new(
+        ErrorCode::InvalidArgument,
+        "missing parameter: os",
+    )));
+    check.call().await;
+    check.req = add_from_template(
+        "nightly",
+        &[("target", "all"), ("os", "linux"), ("arch", "x86")],
+    );
+    check.expected_response = Some(Response::BadRequest(BadRequestError::new(
+        ErrorCode::InvalidArgument,
+        "unknown parameter: arch",
+    )));
+    check.call().await;
+    check.req =
+        add_from_template("nightly", &[("target", "all"), ("os", "linux")]);
+    check.expected_response = None;
+    let job_id = check.call().await.into_add_job().unwrap().job_id;
+    check.req = GetJobRequest {
+        project_name: "workflowproj".into(),
+        job_id,
+    }
+    .into();
+    let job = check.call().await.into_get_job().unwrap().job;
+    assert_eq!(job.priority, 3);
+    assert_eq!(job.queue, "nightly");
+    assert_eq!(
+        job.data,
+        json!({
+            "build": {"branch": "feature", "target": "all-linux"},
+            "extra": true,
+        })
+    );
+    // Only a runner with the template's requirements can take it
+    let take_template_job = |capabilities: Vec<String>| -> Request {
+        TakeJobRequest {
+            project_name: "workflowproj".into(),
+            runner: "testrunner".into(),
+            capabilities,
+            ..Default::default()
+        }
+        .into()
+    };
+    check.req = take_template_job(Vec::new());
+    assert!(check.call().await.into_take_job().unwrap().job.is_none());
+    check.req = take_template_job(vec!["linux".into()]);
+    let taken = check.call().await.into_take_job().unwrap().job.unwrap();
+    assert_eq!(taken.job_id, job_id);
+
+    // Requests still work after the database drops the pool's
+    // connections, as it does when it restarts
+    {
+        let conn = check.pool.get().await.unwrap();
+        let killed: i64 = conn
+            .query_one(
+                "SELECT COUNT(pg_terminate_backend(pid)) FROM pg_stat_activity
+                 WHERE pid != pg_backend_pid()
+                   AND datname = current_database()
+                   AND query NOT LIKE 'LISTEN%'",
+                &[],
+            )
+            .await
+            .unwrap()
+            .get(0);
+        assert!(killed > 0);
+    }
+    check.req = GetJobsRequest {
+        project_name: "workflowproj".into(),
+        ..Default::default()
+    }
+    .into();
+    for _ in 0..10 {
+        check.call().await.into_get_jobs().unwrap();
+    }
+
+    // Each request so far is counted in the metrics, along with the
+    // ones that got an error, and each project's unfinished jobs are
+    // counted by state
+    let metrics = metrics::render(&check.pool).await.unwrap();
+    assert!(metrics.contains(
+        "jobclerk_jobs{project=\"workflowproj\",state=\"available\"} 0\n"
+    ));
+    assert!(metrics.contains(
+        "jobclerk_jobs{project=\"workflowproj\",state=\"activating\"} 1\n"
+    ));
+    assert!(metrics.contains("jobclerk_stuck_jobs{project=\"workflowproj\"} "));
+    assert!(metrics.contains(
+        "jobclerk_request_duration_seconds_count{request=\"StartWorkflow\"} 2\n"
+    ));
+    assert!(metrics.contains(
+        "jobclerk_request_errors_total\
+         {request=\"AddJobFromTemplate\",response=\"NotFound\"} 1\n"
+    ));
+
+    // Projects can be added to an organization, which must exist
+    check.req = AddOrganizationRequest {
+        name: "teamorg".into(),
+    }
+    .into();
+    check.call().await.into_add_organization().unwrap();
+    let add_team_project =
+        |name: &str, organization_name: &str| AddProjectRequest {
+            name: name.into(),
+            heartbeat_expiration_millis: 60 * 1000,
+            organization_name: Some(organization_name.into()),
+            data: json!({}),
+            ..Default::default()
+        };
+    check.req = add_team_project("teamproj", "teamorg").into();
+    check.call().await.into_add_project().unwrap();
+    check.req = add_team_project("strayproj", "noorg").into();
+    check.expected_response = Some(Response::BadRequest(BadRequestError::new(
+        ErrorCode::InvalidArgument,
+        "invalid organization_name: noorg",
+    )));
+    check.call().await;
+    check.expected_response = None;
+    check.req = AddJobRequest {
+        project_name: "teamproj".into(),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.call().await.into_add_job().unwrap();
+
+    // Projects can be listed by organization, and an organization's
+    // stats cover all of its projects
+    check.req = ListProjectsRequest {
+        organization_name: Some("teamorg".into()),
+        ..Default::default()
+    }
+    .into();
+    let projects = check.call().await.into_list_projects().unwrap().projects;
+    assert_eq!(projects.len(), 1);
+    assert_eq!(projects[0].name, "teamproj");
+    assert_eq!(projects[0].organization_name.as_deref(), Some("teamorg"));
+    check.req = ListOrganizationsRequest::default().into();
+    let organizations = check
+        .call()
+        .await
+        .into_list_organizations()
+        .unwrap()
+        .organizations;
+    assert_eq!(organizations.len(), 1);
+    assert_eq!(organizations[0].name, "teamorg");
+    assert_eq!(organizations[0].project_count, 1);
+    check.req = GetOrganizationStatsRequest {
+        organization_name: "teamorg".into(),
+        window_millis: 60 * 60 * 1000,
+        window_end: None,
+    }
+    .into();
+    let stats = check.call().await.into_get_organization_stats().unwrap();
+    assert_eq!(
+        stats.project_job_counts,
+        vec![(
+            "teamproj".to_string(),
+            vec![(JobState::Available, 1)].into_iter().collect()
+        )]
+        .into_iter()
+        .collect()
+    );
+    assert_eq!(stats.job_counts()[&JobState::Available], 1);
+    assert_eq!(stats.success_rate(), None);
+
+    // A key limited to an organization can be used with its projects
+    // and nothing else
+    let org_key = {
+        let conn = check.pool.get().await.unwrap();
+        assert!(matches!(
+            create_api_key(
+                &conn,
+                Some("teamproj"),
+                Some("teamorg"),
+                ApiKeyRole::Viewer,
+                None
+            )
+            .await,
+            Err(Error::BadRequest(_))
+        ));
+        create_api_key(&conn, None, Some("teamorg"), ApiKeyRole::Viewer, None)
+            .await
+            .unwrap()
+    };
+    check.context.require_auth = true;
+    check.context.api_key = Some(org_key);
+    check.call().await.into_get_organization_stats().unwrap();
+    check.req = ListProjectsRequest {
+        organization_name: Some("teamorg".into()),
+        ..Default::default()
+    }
+    .into();
+    check.call().await.into_list_projects().unwrap();
+    check.req = GetJobsRequest {
+        project_name: "teamproj".into(),
+        ..Default::default()
+    }
+    .into();
+    check.call().await.into_get_jobs().unwrap();
+    check.expected_response = Some(Response::Forbidden(
+        "the API key can only be used with organization teamorg".into(),
+    ));
+    check.req = GetJobsRequest {
+        project_name: "pushproj".into(),
+        ..Default::default()
+    }
+    .into();
+    check.call().await;
+    check.req = ListProjectsRequest::default().into();
+    check.call().await;
+    check.expected_response = None;
+    check.context = RequestContext::default();
+
+    // A project can limit how many unfinished jobs it has. Requests
+    // that get an existing job back still work at the limit, and
+    // finishing a job makes room for another.
+    check.req = AddProjectRequest {
+        name: "quotaproj".into(),
+        heartbeat_expiration_millis: 60 * 1000,
+        max_unfinished_jobs: Some(2),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.call().await.into_add_project().unwrap();
+    let add_quota_job = |idempotency_key: &str| AddJobRequest {
+        project_name: "quotaproj".into(),
+        idempotency_key: Some(idempotency_key.into()),
+        data: json!({}),
+        ..Default::default()
+    };
+    check.req = add_quota_job("quota1").into();
+    let first_job_id = check.call().await.into_add_job().unwrap().job_id;
+    check.req = add_quota_job("quota2").into();
+    check.call().await.into_add_job().unwrap();
+    check.req = add_quota_job("quota3").into();
+    check.expected_response = Some(Response::BadRequest(BadRequestError::new(
+        ErrorCode::QuotaExceeded,
+        "project quotaproj already has the maximum of 2 unfinished jobs",
+    )));
+    check.call().await;
+    check.expected_response = None;
+    check.req = add_quota_job("quota1").into();
+    assert_eq!(
+        check.call().await.into_add_job().unwrap().job_id,
+        first_job_id
+    );
+    check.req = CancelJobRequest {
+        project_name: "quotaproj".into(),
+        job_id: first_job_id,
+    }
+    .into();
+    check.call().await.into_cancel_job().unwrap();
+    check.req = add_quota_job("quota3").into();
+    check.call().await.into_add_job().unwrap();
+
+    // A project's retention policy can archive its finished jobs
+    // rather than deleting them
+    check.req = AddProjectRequest {
+        name: "archiveproj".into(),
+        heartbeat_expiration_millis: 60 * 1000,
+        retention_millis: Some(0),
+        retention_archive: true,
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.call().await.into_add_project().unwrap();
+    async fn add_and_cancel_archive_job(check: &mut CheckRequest) -> JobId {
+        check.req = AddJobRequest {
+            project_name: "archiveproj".into(),
+            data: json!({"archive": true}),
+            ..Default::default()
+        }
+        .into();
+        {
+            let job_id = check.call().await.into_add_job().unwrap().job_id;
+            check.req = CancelJobRequest {
+                project_name: "archiveproj".into(),
+                job_id,
+            }
+            .into();
+            check.call().await.into_cancel_job().unwrap();
+            check.req = GetJobRequest {
+                project_name: "archiveproj".into(),
+                job_id,
+            }
+            .into();
+            job_id
+        }
+    }
+    let job_id = add_and_cancel_archive_job(&mut check).await;
+    api::run_maintenance(&check.pool).await;
+    check.expected_response = Some(Response::NotFound);
+    check.call().await;
+    check.expected_response = None;
+    check.req = GetArchivedJobsRequest {
+        project_name: "archiveproj".into(),
+        ..Default::default()
+    }
+    .into();
+    let archived = check.call().await.into_get_archived_jobs().unwrap().jobs;
+    assert_eq!(archived.len(), 1);
+    assert_eq!(archived[0].job_id, job_id);
+    assert!(archived[0].finished.is_some());
+    assert_eq!(archived[0].job["state"], "canceled");
+    assert_eq!(archived[0].job["data"], json!({"archive": true}));
+    assert!(archived[0].job.get("token").is_none());
+
+    // Turning off retention keeps finished jobs
+    check.req = SetRetentionRequest {
+        project_name: "archiveproj".into(),
+        retention_millis: None,
+        archive: false,
+    }
+    .into();
+    check.expected_response = Some(Response::Empty);
+    check.call().await;
+    check.expected_response = None;
+    add_and_cancel_archive_job(&mut check).await;
+    api::run_maintenance(&check.pool).await;
+    check.call().await.into_get_job().unwrap();
+
+    // Many runners taking jobs at the same time each get different
+    // jobs, and between them take every job
+    check.req = AddProjectRequest {
+        name: "stressproj".into(),
+        heartbeat_expiration_millis: 60 * 1000,
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.call().await.into_add_project().unwrap();
+    for n in 0..200 {
+        check.req = AddJobRequest {
+            project_name: "stressproj".into(),
+            data: json!({ "n": n }),
+            ..Default::default()
+        }
+        .into();
+        check.call().await.into_add_job().unwrap();
+    }
+    let runners = (0..50).map(|runner| {
+        let pool = check.pool.clone();
+        async move {
+            let req = TakeJobRequest {
+                project_name: "stressproj".into(),
+                runner: format!("stressrunner{}", runner),
+                ..Default::default()
+            }
+            .into();
+            let mut job_ids = Vec::new();
+            while let Some(job) = handle_request(&pool, &req)
+                .await
+                .into_take_job()
+                .unwrap()
+                .job
+            {
+                job_ids.push(job.job_id);
+            }
+            job_ids
+        }
+    });
+    let mut job_ids: Vec<JobId> = futures::future::join_all(runners)
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+    assert_eq!(job_ids.len(), 200);
+    job_ids.sort_unstable();
+    job_ids.dedup();
+    assert_eq!(job_ids.len(), 200);
+
+    // Requests from one runner at the same time can't go over the
+    // project's limit on the runner's jobs
+    check.req = AddProjectRequest {
+        name: "stresslimitproj".into(),
+        heartbeat_expiration_millis: 60 * 1000,
+        max_jobs_per_runner: Some(2),
+        data: json!({}),
+        ..Default::default()
+    }
+    .into();
+    check.call().await.into_add_project().unwrap();
+    for n in 0..20 {
+        check.req = AddJobRequest {
+            project_name: "stresslimitproj".into(),
+            data: json!({ "n": n }),
+            ..Default::default()
+        }
+        .into();
+        check.call().await.into_add_job().unwrap();
+    }
+    let requests = (0..10).map(|_| {
+        let pool = check.pool.clone();
+        async move {
+            let req = TakeJobRequest {
+                project_name: "stresslimitproj".into(),
+                runner: "stressrunner".into(),
+                ..Default::default()
+            }
+            .into();
+            handle_request(&pool, &req)
+                .await
+                .into_take_job()
+                .unwrap()
+                .job
+        }
+    });
+    let taken = futures::future::join_all(requests)
+        .await
+        .into_iter()
+        .flatten()
+        .count();
+    assert_eq!(taken, 2);
+
+    // Once requests fail to reach the database twice in a row, the
+    // rest are rejected right away until the database is tried again
+    circuit_breaker::configure(CircuitBreakerSettings {
+        failure_threshold: 2,
+        open_duration: std::time::Duration::from_millis(300),
+    })
+    .unwrap();
+    check.req = GetJobsRequest {
+        project_name: "teamproj".into(),
+        ..Default::default()
+    }
+    .into();
+    let good_pool = check.pool.clone();
+    check.pool = make_pool_with_options(
+        "postgres://postgres@localhost:1/postgres",
+        &PoolOptions {
+            connection_timeout: std::time::Duration::from_millis(100),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    check.check_error = false;
+    for _ in 0..2 {
+        assert_eq!(check.call().await, Response::InternalError);
+    }
+    match check.call().await {
+        Response::Unavailable { retry_after_millis } => {
+            assert!(retry_after_millis > 0 && retry_after_millis <= 300)
+        }
+        resp => panic!("unexpected response: {:?}", resp),
+    }
+    // The circuit stays open even though the database works again
+    check.pool = good_pool;
+    assert!(matches!(check.call().await, Response::Unavailable { .. }));
+    check.check_error = true;
+    tokio::time::delay_for(tokio::time::Duration::from_millis(300)).await;
+    check.call().await.into_get_jobs().unwrap();
+    check.call().await.into_get_jobs().unwrap();
+    circuit_breaker::configure(Default::default()).unwrap();
+
+    // Requests that are stuck waiting on a lock time out, either in
+    // the server or in the database
+    let good_pool = check.pool.clone();
+    let mut conn = good_pool.get().await.unwrap();
+    let txn = conn.transaction().await.unwrap();
+    txn.execute("LOCK TABLE jobs IN ACCESS EXCLUSIVE MODE", &[])
+        .await
+        .unwrap();
+    set_request_timeout_millis(200);
+    check.check_error = false;
+    assert_eq!(check.call().await, Response::Timeout);
+    set_request_timeout_millis(DEFAULT_REQUEST_TIMEOUT_MILLIS);
+    check.pool = make_pool_with_options(
+        &database_url,
+        &PoolOptions {
+            statement_timeout: Some(std::time::Duration::from_millis(200)),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(check.call().await, Response::Timeout);
+    check.check_error = true;
+    txn.rollback().await.unwrap();
+    check.call().await.into_get_jobs().unwrap();
+    drop(conn);
+    check.pool = good_pool;
+
+    // With Redis, jobs are found through a list of each project's
+    // available jobs. This applies to every take request from here on.
+    #[cfg(feature = "redis")]
+    {
+        run_redis();
+        let _stop_redis = RunOnDrop::new(get_redis_cmd("kill"));
+        let redis_url = format!("redis://localhost:{}", REDIS_PORT);
+        redis_queue::connect(&redis_url).await.unwrap();
+        check.req = AddProjectRequest {
+            name: "redisproj".into(),
+            heartbeat_expiration_millis: 60 * 1000,
+            data: json!({}),
+            ..Default::default()
+        }
+        .into();
+        check.call().await.into_add_project().unwrap();
+        let mut redis_job_ids = Vec::new();
+        for requirements in [vec!["gpu".to_string()], Vec::new(), Vec::new()] {
+            check.req = AddJobRequest {
+                project_name: "redisproj".into(),
+                requirements,
+                data: json!({}),
+                ..Default::default()
+            }
+            .into();
+            redis_job_ids
+                .push(check.call().await.into_add_job().unwrap().job_id);
+        }
+        let take_redis_job = |capabilities: &[&str]| -> Request {
+            TakeJobRequest {
+                project_name: "redisproj".into(),
+                runner: "redisrunner".into(),
+                capabilities: capabilities
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect(),
+                ..Default::default()
+            }
+            .into()
+        };
+        // The first job in the list needs a GPU, so this runner gets
+        // the next one, and the GPU job is left for another runner
+        check.req = take_redis_job(&[]);
+        let job = check.call().await.into_take_job().unwrap().job.unwrap();
+        assert_eq!(job.job_id, redis_job_ids[1]);
+        // Taking a job from the list is a single query
+        let before = metrics::render(&check.pool).await.unwrap();
+        check.req = take_redis_job(&["gpu"]);
+        let job = check.call().await.into_take_job().unwrap().job.unwrap();
+        assert_eq!(job.job_id, redis_job_ids[0]);
+        let after = metrics::render(&check.pool).await.unwrap();
+        for query in &["claim_listed_jobs", "take_jobs", "refill_redis_queue"] {
+            let expected = if *query == "claim_listed_jobs" { 1 } else { 0 };
+            assert_eq!(
+                query_count(&after, query) - query_count(&before, query),
+                expected
+            );
+        }
+        check.req = take_redis_job(&[]);
+        let job = check.call().await.into_take_job().unwrap().job.unwrap();
+        assert_eq!(job.job_id, redis_job_ids[2]);
+        assert!(check.call().await.into_take_job().unwrap().job.is_none());
+
+        // The list interleaves the project's queues by weight
+        check.req = AddProjectRequest {
+            name: "redisqueueproj".into(),
+            heartbeat_expiration_millis: 60 * 1000,
+            queue_weights: [("bulk".to_string(), 0)].iter().cloned().collect(),
+            data: json!({}),
+            ..Default::default()
+        }
+        .into();
+        check.call().await.into_add_project().unwrap();
+        let mut queue_job_ids = Vec::new();
+        for queue in &["bulk", "bulk", "default"] {
+            check.req = AddJobRequest {
+                project_name: "redisqueueproj".into(),
+                queue: Some(queue.to_string()),
+                data: json!({}),
+                ..Default::default()
+            }
+            .into();
+            queue_job_ids
+                .push(check.call().await.into_add_job().unwrap().job_id);
+        }
+        check.req = TakeJobsRequest {
+            params: TakeJobRequest {
+                project_name: "redisqueueproj".into(),
+                runner: "redisrunner".into(),
+                ..Default::default()
+            },
+            max_jobs: 2,
+        }
+        .into();
+        let resp = check.call().await.into_take_jobs().unwrap();
+        let taken: Vec<_> = resp.jobs.iter().map(|job| job.job_id).collect();
+        assert_eq!(taken, [queue_job_ids[2], queue_job_ids[0]]);
+
+        // Projects that limit each runner's jobs still keep to the
+        // limit
+        check.req = AddProjectRequest {
+            name: "redislimitproj".into(),
+            heartbeat_expiration_millis: 60 * 1000,
+            max_jobs_per_runner: Some(1),
+            data: json!({}),
+            ..Default::default()
+        }
+        .into();
+        check.call().await.into_add_project().unwrap();
+        for _ in 0..2 {
+            check.req = AddJobRequest {
+                project_name: "redislimitproj".into(),
+                data: json!({}),
+                ..Default::default()
+            }
+            .into();
+            check.call().await.into_add_job().unwrap();
+        }
+        check.req = TakeJobsRequest {
+            params: TakeJobRequest {
+                project_name: "redislimitproj".into(),
+                runner: "redisrunner".into(),
+                ..Default::default()
+            },
+            max_jobs: 2,
+        }
+        .into();
+        let resp = check.call().await.into_take_jobs().unwrap();
+        assert_eq!(resp.jobs.len(), 1);
+        assert!(check.call().await.into_take_jobs().unwrap().jobs.is_empty());
+    }
+
+    // Limit each client to a burst of two requests. This applies to
+    // every request from here on.
+    rate_limit::configure(RateLimits {
+        per_client: Some(Rate {
+            per_second: 0.01,
+            burst: 2,
+        }),
+        per_project: None,
+    })
+    .unwrap();
+    check.context.remote_addr = Some("10.0.0.1".into());
+    check.req = GetJobsRequest {
+        project_name: "adminproj".into(),
+        ..Default::default()
+    }
+    .into();
+    check.call().await.into_get_jobs().unwrap();
+    check.call().await.into_get_jobs().unwrap();
+    check.check_error = false;
+    match check.call().await {
+        Response::TooManyRequests { retry_after_millis } => {
+            assert!(retry_after_millis > 0)
+        }
+        resp => panic!("unexpected response: {:?}", resp),
+    }
+    check.check_error = true;
+    // Other clients aren't affected
+    check.context.remote_addr = Some("10.0.0.2".into());
+    check.call().await.into_get_jobs().unwrap();
+    // Requests with an invalid key are rejected before they use up any
+    // tokens
+    check.context.require_auth = true;
+    check.context.api_key = Some("notakey".into());
+    check.context.remote_addr = Some("10.0.0.3".into());
+    check.expected_response = Some(Response::Unauthorized);
+    for _ in 0..3 {
+        check.call().await;
+    }
+    // Clients with a valid key are limited by the key rather than
+    // their address
+    check.context.api_key = Some({
+        let conn = check.pool.get().await.unwrap();
+        create_api_key(&conn, Some("adminproj"), None, ApiKeyRole::Viewer, None)
+            .await
+            .unwrap()
+    });
+    check.expected_response = None;
+    check.call().await.into_get_jobs().unwrap();
+    check.context.remote_addr = Some("10.0.0.4".into());
+    check.call().await.into_get_jobs().unwrap();
+    check.check_error = false;
+    assert!(matches!(
+        check.call().await,
+        Response::TooManyRequests { .. }
+    ));
 }
@@ -1,5 +1,19 @@
 use argh::FromArgs;
+use chrono::{DateTime, Utc};
+use jobclerk_client::{Client, JobContext, Runner};
 use jobclerk_types::*;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::path::PathBuf;
+use std::process::{self, Stdio};
+use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
 
 /// Create a project.
 #[derive(FromArgs)]
@@ -12,11 +26,155 @@ struct AddProject {
     #[argh(option, default = "30")]
     grace_period: i32,
 
+    /// default length of time in seconds before running jobs are
+    /// marked as timed out
+    #[argh(option)]
+    max_runtime: Option<i32>,
+
+    /// maximum number of times a job can be attempted (enables
+    /// retrying failed jobs)
+    #[argh(option)]
+    max_attempts: Option<i32>,
+
+    /// how the delay between retries grows (fixed or exponential)
+    #[argh(option, default = "RetryBackoff::Fixed")]
+    retry_backoff: RetryBackoff,
+
+    /// length of time in seconds before a failed job is retried
+    #[argh(option, default = "0")]
+    retry_delay: i32,
+
+    /// length of time in seconds after finishing before jobs are
+    /// deleted
+    #[argh(option)]
+    retention: Option<i64>,
+
+    /// move jobs removed by --retention to the project's archive
+    /// instead of deleting them
+    #[argh(switch)]
+    retention_archive: bool,
+
+    /// maximum number of jobs a single runner can be running at once
+    #[argh(option)]
+    max_jobs_per_runner: Option<i32>,
+
+    /// relative share of jobs the project gets when runners take
+    /// jobs from several projects (defaults to 1)
+    #[argh(option)]
+    weight: Option<i32>,
+
+    /// maximum size of a job's data as JSON, in bytes (defaults to
+    /// 1 MiB)
+    #[argh(option)]
+    max_data_bytes: Option<i32>,
+
+    /// maximum number of unfinished jobs the project can have (no
+    /// limit by default)
+    #[argh(option)]
+    max_unfinished_jobs: Option<i32>,
+
+    /// relative share of taken jobs a queue gets, formatted as
+    /// QUEUE=WEIGHT (can be repeated)
+    #[argh(option, from_str_fn(parse_queue_weight))]
+    queue_weight: Vec<(String, i32)>,
+
+    /// adding a job with the same data as an available job returns
+    /// the existing job instead
+    #[argh(switch)]
+    deduplicate_data: bool,
+
+    /// organization to add the project to
+    #[argh(option)]
+    organization: Option<String>,
+
     /// set the project data
     #[argh(option, default = "serde_json::json!({})")]
     data: serde_json::Value,
 }
 
+fn parse_queue_weight(value: &str) -> Result<(String, i32), String> {
+    let mut parts = value.splitn(2, '=');
+    let queue = parts.next().unwrap_or_default();
+    let weight = parts
+        .next()
+        .ok_or_else(|| format!("expected QUEUE=WEIGHT: {}", value))?;
+    Ok((
+        queue.into(),
+        weight
+            .parse()
+            .map_err(|_| format!("invalid weight: {}", weight))?,
+    ))
+}
+
+fn parse_param(value: &str) -> Result<(String, String), String> {
+    let mut parts = value.splitn(2, '=');
+    let name = parts.next().unwrap_or_default();
+    let value = parts
+        .next()
+        .ok_or_else(|| format!("expected NAME=VALUE: {}", value))?;
+    Ok((name.into(), value.into()))
+}
+
+/// Get a project's job counts, along with success rates and durations
+/// for a window of time.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "get-project-stats")]
+struct GetProjectStats {
+    #[argh(positional)]
+    project_name: String,
+
+    /// length of the window in seconds (defaults to a week)
+    #[argh(option, default = "7 * 24 * 60 * 60")]
+    window: i64,
+
+    /// end of the window (RFC 3339), defaults to now
+    #[argh(option)]
+    window_end: Option<DateTime<Utc>>,
+}
+
+/// Create an organization, which projects can be added to.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "add-organization")]
+struct AddOrganization {
+    #[argh(positional)]
+    name: String,
+}
+
+/// List organizations by name.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "list-organizations")]
+struct ListOrganizations {
+    /// maximum number of organizations to list
+    #[argh(option)]
+    limit: Option<i64>,
+
+    /// continue from the cursor printed with a previous page
+    #[argh(option)]
+    cursor: Option<String>,
+
+    /// list in descending order
+    #[argh(switch)]
+    descending: bool,
+}
+
+/// Get the job counts of each of an organization's projects, along
+/// with how many of its jobs finished in each state within a window
+/// of time.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "get-organization-stats")]
+struct GetOrganizationStats {
+    #[argh(positional)]
+    organization_name: String,
+
+    /// length of the window in seconds (defaults to a week)
+    #[argh(option, default = "7 * 24 * 60 * 60")]
+    window: i64,
+
+    /// end of the window (RFC 3339), defaults to now
+    #[argh(option)]
+    window_end: Option<DateTime<Utc>>,
+}
+
 /// Create a job within a project.
 #[derive(FromArgs)]
 #[argh(subcommand, name = "add-job")]
@@ -26,6 +184,294 @@ struct AddJob {
 
     #[argh(positional)]
     data: serde_json::Value,
+
+    /// length of time in seconds before the job is marked as timed out
+    #[argh(option)]
+    max_runtime: Option<i32>,
+
+    /// unique key used to avoid adding the same job twice
+    #[argh(option)]
+    idempotency_key: Option<String>,
+
+    /// key that only one unfinished job in the project can have at
+    /// once
+    #[argh(option)]
+    unique_key: Option<String>,
+
+    /// length of time in seconds after which the job expires if it
+    /// hasn't been taken
+    #[argh(option)]
+    expires_after: Option<i64>,
+
+    /// capability a runner must have to take the job (can be
+    /// repeated)
+    #[argh(option)]
+    requirement: Vec<String>,
+
+    /// name of a step the job runs (can be repeated, in order)
+    #[argh(option)]
+    step: Vec<String>,
+
+    /// jobs with a lower priority are taken first (defaults to 0)
+    #[argh(option, default = "0")]
+    priority: i32,
+
+    /// queue within the project to add the job to (defaults to
+    /// "default")
+    #[argh(option)]
+    queue: Option<String>,
+
+    /// ID of a job that has to succeed before this job can be taken
+    /// (can be repeated)
+    #[argh(option)]
+    depends_on: Vec<JobId>,
+}
+
+/// Add or replace a job template.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "put-job-template")]
+struct PutJobTemplate {
+    #[argh(positional)]
+    project_name: String,
+
+    #[argh(positional)]
+    name: String,
+
+    #[argh(positional)]
+    data: serde_json::Value,
+
+    /// length of time in seconds before the job is marked as timed out
+    #[argh(option)]
+    max_runtime: Option<i32>,
+
+    /// capability a runner must have to take the job (can be
+    /// repeated)
+    #[argh(option)]
+    requirement: Vec<String>,
+
+    /// jobs with a lower priority are taken first (defaults to 0)
+    #[argh(option, default = "0")]
+    priority: i32,
+
+    /// queue within the project to add the job to (defaults to
+    /// "default")
+    #[argh(option)]
+    queue: Option<String>,
+}
+
+/// Create a job from a template, merging the data into the template's.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "add-job-from-template")]
+struct AddJobFromTemplate {
+    #[argh(positional)]
+    project_name: String,
+
+    #[argh(positional)]
+    template_name: String,
+
+    #[argh(positional, default = "serde_json::Value::Null")]
+    data: serde_json::Value,
+
+    /// value for a placeholder in the template, formatted as
+    /// NAME=VALUE (can be repeated)
+    #[argh(option, from_str_fn(parse_param))]
+    param: Vec<(String, String)>,
+
+    /// unique key used to avoid adding the same job twice
+    #[argh(option)]
+    idempotency_key: Option<String>,
+
+    /// key that only one unfinished job in the project can have at
+    /// once
+    #[argh(option)]
+    unique_key: Option<String>,
+
+    /// overrides the template's priority
+    #[argh(option)]
+    priority: Option<i32>,
+
+    /// overrides the template's queue
+    #[argh(option)]
+    queue: Option<String>,
+}
+
+/// Add a job as a child of a running job.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "add-child-job")]
+struct AddChildJob {
+    #[argh(positional)]
+    project_name: String,
+
+    /// ID of the parent job
+    #[argh(positional)]
+    job_id: JobId,
+
+    /// token of the parent job
+    #[argh(positional)]
+    token: JobToken,
+
+    #[argh(positional)]
+    data: serde_json::Value,
+}
+
+/// Add or replace a workflow.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "put-workflow")]
+struct PutWorkflow {
+    #[argh(positional)]
+    project_name: String,
+
+    #[argh(positional)]
+    name: String,
+
+    /// JSON file with the workflow's nodes as an array
+    #[argh(option)]
+    nodes: String,
+}
+
+/// Start a workflow, adding a job for each of its nodes.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "start-workflow")]
+struct StartWorkflow {
+    #[argh(positional)]
+    project_name: String,
+
+    #[argh(positional)]
+    name: String,
+
+    /// value for a placeholder in the workflow, formatted as
+    /// NAME=VALUE (can be repeated)
+    #[argh(option, from_str_fn(parse_param))]
+    param: Vec<(String, String)>,
+}
+
+/// Get the state of a workflow run and its jobs.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "get-workflow-run")]
+struct GetWorkflowRun {
+    #[argh(positional)]
+    project_name: String,
+
+    #[argh(positional)]
+    run_id: WorkflowRunId,
+}
+
+/// Add several jobs to a project as a batch.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "add-batch")]
+struct AddBatch {
+    #[argh(positional)]
+    project_name: String,
+
+    /// data of a job in the batch (can be repeated)
+    #[argh(option)]
+    job: Vec<serde_json::Value>,
+
+    /// URL to POST the batch's status to once all of its jobs finish
+    #[argh(option)]
+    webhook_url: Option<String>,
+}
+
+/// Get the number of a batch's jobs in each state.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "get-batch")]
+struct GetBatch {
+    #[argh(positional)]
+    project_name: String,
+
+    #[argh(positional)]
+    batch_id: BatchId,
+}
+
+/// List the times a job was taken and how each attempt ended.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "get-job-attempts")]
+struct GetJobAttempts {
+    #[argh(positional)]
+    project_name: String,
+
+    #[argh(positional)]
+    job_id: JobId,
+}
+
+/// List jobs within a project.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "get-jobs")]
+struct GetJobs {
+    /// defaults to the profile's project
+    #[argh(positional)]
+    project_name: Option<String>,
+
+    /// only list jobs in this state (can be repeated)
+    #[argh(option)]
+    state: Vec<JobState>,
+
+    /// only list jobs taken by this runner
+    #[argh(option)]
+    runner: Option<String>,
+
+    /// only list jobs with this requirement (can be repeated)
+    #[argh(option)]
+    requirement: Vec<String>,
+
+    /// only list jobs created at or after this time (RFC 3339)
+    #[argh(option)]
+    created_after: Option<DateTime<Utc>>,
+
+    /// only list jobs created before this time (RFC 3339)
+    #[argh(option)]
+    created_before: Option<DateTime<Utc>>,
+
+    /// only list jobs whose data contains this JSON value
+    #[argh(option)]
+    data_filter: Option<serde_json::Value>,
+
+    /// only list jobs whose data contains this text, ignoring case
+    #[argh(option)]
+    data_search: Option<String>,
+
+    /// maximum number of jobs to list
+    #[argh(option)]
+    limit: Option<i64>,
+
+    /// continue from the cursor printed with a previous page
+    #[argh(option)]
+    cursor: Option<String>,
+
+    /// list in descending order
+    #[argh(switch)]
+    descending: bool,
+
+    /// leave out the jobs' data
+    #[argh(switch)]
+    exclude_data: bool,
+
+    /// keep refreshing the list, highlighting jobs that changed
+    #[argh(switch)]
+    watch: bool,
+
+    /// seconds between refreshes with --watch
+    #[argh(option, default = "2")]
+    interval: u64,
+}
+
+/// Print a job's log output.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "get-job-log")]
+struct GetJobLog {
+    #[argh(positional)]
+    project_name: String,
+
+    #[argh(positional)]
+    job_id: JobId,
+
+    /// only get chunks after this sequence number
+    #[argh(option)]
+    after: Option<i32>,
+
+    /// keep printing new output until the job finishes
+    #[argh(switch)]
+    follow: bool,
 }
 
 /// Start running an available job.
@@ -37,6 +483,58 @@ struct TakeJob {
 
     #[argh(positional)]
     runner: String,
+
+    /// capability the runner has (can be repeated)
+    #[argh(option)]
+    capability: Vec<String>,
+
+    /// take up to this many jobs at once
+    #[argh(option)]
+    max_jobs: Option<i32>,
+
+    /// length of time in seconds before the jobs are considered stuck
+    /// if no heartbeat is received (defaults to the project's grace
+    /// period)
+    #[argh(option)]
+    lease: Option<i32>,
+
+    /// length of time in seconds to wait for the first heartbeat, for
+    /// runners that are slow to start
+    #[argh(option)]
+    first_heartbeat: Option<i32>,
+
+    /// only take jobs with at least this priority
+    #[argh(option)]
+    min_priority: Option<i32>,
+
+    /// only take jobs with at most this priority
+    #[argh(option)]
+    max_priority: Option<i32>,
+
+    /// if no job is available, wait up to this many seconds for one
+    #[argh(option)]
+    wait: Option<i32>,
+}
+
+/// Take jobs one at a time and run a shell command for each.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "worker")]
+struct Worker {
+    #[argh(positional)]
+    project_name: String,
+
+    #[argh(positional)]
+    runner: String,
+
+    /// shell command to run for each job. `{field}` is replaced with
+    /// that field of the job's data, quoted for the shell, and all of
+    /// the data is in $JOBCLERK_JOB_DATA.
+    #[argh(positional)]
+    command: String,
+
+    /// capability the runner has (can be repeated)
+    #[argh(option)]
+    capability: Vec<String>,
 }
 
 /// Update a running job.
@@ -59,62 +557,1183 @@ struct UpdateJob {
     /// set the job data
     #[argh(option)]
     data: Option<serde_json::Value>,
+
+    /// set a step's state, formatted as NAME=STATE (can be repeated)
+    #[argh(option, from_str_fn(parse_step_update))]
+    step: Vec<UpdateJobStep>,
+}
+
+fn parse_step_update(value: &str) -> Result<UpdateJobStep, String> {
+    let mut parts = value.splitn(2, '=');
+    let name = parts.next().unwrap_or_default();
+    let state = parts
+        .next()
+        .ok_or_else(|| format!("expected NAME=STATE: {}", value))?;
+    Ok(UpdateJobStep {
+        name: name.into(),
+        state: state
+            .parse()
+            .map_err(|_| format!("invalid state: {}", state))?,
+    })
+}
+
+/// Push a running job's heartbeat deadline further out.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "extend-lease")]
+struct ExtendLease {
+    #[argh(positional)]
+    project_name: String,
+
+    #[argh(positional)]
+    job_id: JobId,
+
+    #[argh(positional)]
+    token: JobToken,
+
+    /// number of seconds to add to the deadline
+    #[argh(positional)]
+    extend_by: i64,
+}
+
+/// Move a job to a new state without its token.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "force-job-state")]
+struct ForceJobState {
+    #[argh(positional)]
+    project_name: String,
+
+    #[argh(positional)]
+    job_id: JobId,
+
+    #[argh(positional)]
+    state: JobState,
+}
+
+/// Delete finished jobs.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "purge-jobs")]
+struct PurgeJobs {
+    /// defaults to the profile's project
+    #[argh(positional)]
+    project_name: Option<String>,
+
+    /// only delete jobs that finished at least this many seconds ago
+    #[argh(option, default = "0")]
+    older_than: i64,
+
+    /// only delete jobs in this state (can be repeated)
+    #[argh(option)]
+    state: Vec<JobState>,
+}
+
+/// Change how long a project's finished jobs are kept.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "set-retention")]
+struct SetRetention {
+    /// defaults to the profile's project
+    #[argh(positional)]
+    project_name: Option<String>,
+
+    /// length of time in seconds after finishing before jobs are
+    /// removed (jobs are kept until they're purged if not set)
+    #[argh(option)]
+    retention: Option<i64>,
+
+    /// move removed jobs to the project's archive instead of deleting
+    /// them
+    #[argh(switch)]
+    archive: bool,
+}
+
+/// List the jobs a project's retention policy has archived.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "get-archived-jobs")]
+struct GetArchivedJobs {
+    /// defaults to the profile's project
+    #[argh(positional)]
+    project_name: Option<String>,
+
+    /// maximum number of jobs to list
+    #[argh(option)]
+    limit: Option<i64>,
+
+    /// continue from the cursor printed with a previous page
+    #[argh(option)]
+    cursor: Option<String>,
+
+    /// list in descending order
+    #[argh(switch)]
+    descending: bool,
+}
+
+/// List runners and their state.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "list-runners")]
+struct ListRunners {
+    /// length of time in seconds since a runner's last heartbeat
+    /// before it is considered offline
+    #[argh(option)]
+    offline_after: Option<i64>,
+
+    /// maximum number of runners to list
+    #[argh(option)]
+    limit: Option<i64>,
+
+    /// continue from the cursor printed with a previous page
+    #[argh(option)]
+    cursor: Option<String>,
+
+    /// list in descending order
+    #[argh(switch)]
+    descending: bool,
 }
 
+/// List projects by name.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "list-projects")]
+struct ListProjects {
+    /// maximum number of projects to list
+    #[argh(option)]
+    limit: Option<i64>,
+
+    /// only list the projects in this organization
+    #[argh(option)]
+    organization: Option<String>,
+
+    /// continue from the cursor printed with a previous page
+    #[argh(option)]
+    cursor: Option<String>,
+
+    /// list in descending order
+    #[argh(switch)]
+    descending: bool,
+}
+
+fn make_page(
+    limit: Option<i64>,
+    cursor: Option<String>,
+    descending: bool,
+) -> Page {
+    Page {
+        cursor: cursor.map(Cursor),
+        limit,
+        order: if descending {
+            SortOrder::Descending
+        } else {
+            SortOrder::Ascending
+        },
+    }
+}
+
+/// List recorded API mutations, newest first.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "get-audit-log")]
+struct GetAuditLog {
+    /// only list entries for this project
+    #[argh(option)]
+    project: Option<String>,
+
+    /// only list entries for this job
+    #[argh(option)]
+    job: Option<JobId>,
+
+    /// only list entries older than this entry ID
+    #[argh(option)]
+    before: Option<i64>,
+
+    /// maximum number of entries to list
+    #[argh(option)]
+    limit: Option<i64>,
+}
+
+/// List running jobs whose heartbeat has expired.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "get-stuck-jobs")]
+struct GetStuckJobs {
+    /// keep refreshing the list, highlighting jobs that changed
+    #[argh(switch)]
+    watch: bool,
+
+    /// seconds between refreshes with --watch
+    #[argh(option, default = "2")]
+    interval: u64,
+}
+
+/// Cancel a job, or ask its runner to stop if it's running.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "cancel-job")]
+struct CancelJob {
+    #[argh(positional)]
+    project_name: String,
+
+    #[argh(positional)]
+    job_id: JobId,
+}
+
+/// Fail jobs that have run too long, and requeue or fail jobs whose
+/// heartbeat has expired.
+#[derive(FromArgs)]
+#[argh(subcommand, name = "handle-stuck-jobs")]
+struct HandleStuckJobs {}
+
 #[derive(FromArgs)]
 #[argh(subcommand)]
 enum Command {
     AddProject(AddProject),
+    ListProjects(ListProjects),
+    GetProjectStats(GetProjectStats),
+
+    AddOrganization(AddOrganization),
+    ListOrganizations(ListOrganizations),
+    GetOrganizationStats(GetOrganizationStats),
 
     AddJob(AddJob),
+    AddChildJob(AddChildJob),
+    AddBatch(AddBatch),
+    GetBatch(GetBatch),
+    GetJobAttempts(GetJobAttempts),
+    PutJobTemplate(PutJobTemplate),
+    AddJobFromTemplate(AddJobFromTemplate),
+    PutWorkflow(PutWorkflow),
+    StartWorkflow(StartWorkflow),
+    GetWorkflowRun(GetWorkflowRun),
+    GetJobs(GetJobs),
+    GetJobLog(GetJobLog),
     TakeJob(TakeJob),
+    Worker(Worker),
     UpdateJob(UpdateJob),
+    ExtendLease(ExtendLease),
+    ForceJobState(ForceJobState),
+    PurgeJobs(PurgeJobs),
+    SetRetention(SetRetention),
+    GetArchivedJobs(GetArchivedJobs),
+    CancelJob(CancelJob),
+    GetStuckJobs(GetStuckJobs),
+    HandleStuckJobs(HandleStuckJobs),
+    GetAuditLog(GetAuditLog),
+
+    ListRunners(ListRunners),
 }
 
 /// Send a request to the server and print the response.
 #[derive(FromArgs)]
 struct Opt {
-    /// base URL of the server (including scheme)
-    #[argh(option, default = "\"http://localhost:8000\".into()")]
-    base_url: String,
+    /// profile to load from the config file (defaults to
+    /// $JOBCLERK_PROFILE, or the "default" profile if there is one)
+    #[argh(option)]
+    profile: Option<String>,
+
+    /// base URL of the server, including scheme (defaults to the
+    /// profile's base URL, or http://localhost:8000)
+    #[argh(option)]
+    base_url: Option<String>,
+
+    /// API key to send with the request (defaults to
+    /// $JOBCLERK_API_KEY, or the profile's API key)
+    #[argh(option)]
+    api_key: Option<String>,
+
+    /// how to print the response: json (the default), yaml, or table
+    #[argh(option, default = "OutputFormat::Json")]
+    output: OutputFormat,
 
     #[argh(subcommand)]
     command: Command,
 }
 
+/// Server settings from a profile in the config file, which is
+/// `$XDG_CONFIG_HOME/jobclerk/config.toml` (or
+/// `~/.config/jobclerk/config.toml`). For example:
+///
+/// ```toml
+/// [profiles.default]
+/// base_url = "https://jobclerk.example.com"
+/// api_key = "..."
+/// project = "myproj"
+/// ```
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Profile {
+    base_url: Option<String>,
+    api_key: Option<String>,
+    /// Project to use when a command's project is left out.
+    project: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+}
+
+fn config_path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("jobclerk/config.toml"))
+}
+
+/// Load a profile from the config file. If no name is given, the
+/// "default" profile is used if there is one. Exits if the config file
+/// is invalid or a named profile doesn't exist.
+fn load_profile(name: Option<&str>) -> Profile {
+    let path = config_path();
+    let mut config = match path.as_ref().map(fs::read_to_string) {
+        Some(Ok(contents)) => match toml::from_str(&contents) {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("invalid config file: {}", err);
+                std::process::exit(1);
+            }
+        },
+        _ => Config::default(),
+    };
+    match name {
+        Some(name) => config.profiles.remove(name).unwrap_or_else(|| {
+            eprintln!("profile not found: {}", name);
+            std::process::exit(1);
+        }),
+        None => config.profiles.remove("default").unwrap_or_default(),
+    }
+}
+
+/// Get the project for a command, falling back to the profile's
+/// project.
+fn project_or_default(
+    project_name: Option<String>,
+    profile: &Profile,
+) -> String {
+    project_name
+        .or_else(|| profile.project.clone())
+        .unwrap_or_else(|| {
+            eprintln!("no project given, and the profile doesn't have one");
+            std::process::exit(1);
+        })
+}
+
+/// Print a job's log output as it arrives from the server's event
+/// stream.
+fn follow_job_log(base_url: &str, project_name: &str, job_id: JobId) {
+    let url = format!(
+        "{}/projects/{}/jobs/{}/log/stream",
+        base_url, project_name, job_id
+    );
+    let resp = ureq::get(&url).call();
+    if !resp.ok() {
+        eprintln!("failed to stream log: {}", resp.status_line());
+        std::process::exit(1);
+    }
+
+    let reader = BufReader::new(resp.into_reader());
+    for line in reader.lines() {
+        let line = line.expect("failed to read log stream");
+        if let Some(data) = line.strip_prefix("data: ") {
+            let chunk: JobLogChunk =
+                serde_json::from_str(data).expect("invalid log chunk");
+            print!("{}", chunk.text);
+        }
+    }
+}
+
+/// Send a request to the server and get the JSON response, along with
+/// the ID the server gave the request.
+fn send_request(
+    url: &str,
+    api_key: Option<&str>,
+    req: Request,
+) -> (Value, Option<String>) {
+    let mut http_req = ureq::post(url);
+    if let Some(api_key) = api_key {
+        http_req.set("Authorization", &format!("Bearer {}", api_key));
+    }
+    let resp = http_req.send_json(
+        serde_json::to_value(req).expect("failed to convert request to JSON"),
+    );
+    let request_id = resp.header("X-Request-Id").map(String::from);
+    (resp.into_json().expect("response is not json"), request_id)
+}
+
+/// Describe an error response, along with the exit code for it so
+/// that scripts can tell errors apart. Returns `None` if the response
+/// isn't an error.
+fn describe_error(resp: &Response) -> Option<(i32, String)> {
+    let error = match resp {
+        Response::BadRequest(err) => {
+            (2, format!("bad request: {}", err.message))
+        }
+        Response::NotFound => (3, "not found".into()),
+        Response::Unauthorized => (4, "a valid API key is required".into()),
+        Response::Forbidden(msg) => (5, format!("forbidden: {}", msg)),
+        Response::TooManyRequests { retry_after_millis } => (
+            6,
+            format!("too many requests, retry in {}ms", retry_after_millis),
+        ),
+        Response::InternalError => (7, "internal server error".into()),
+        Response::Unavailable { retry_after_millis } => (
+            8,
+            format!("server unavailable, retry in {}ms", retry_after_millis),
+        ),
+        Response::Timeout => (9, "the request timed out".into()),
+        _ => return None,
+    };
+    Some(error)
+}
+
+/// Print the error and exit if the response is an error. The request
+/// ID, if the server sent one, helps find the request in its logs.
+fn exit_if_error(resp: Option<&Response>, request_id: Option<&str>) {
+    if let Some((code, msg)) = resp.and_then(describe_error) {
+        match request_id {
+            Some(request_id) => {
+                eprintln!("error: {} (request ID {})", msg, request_id)
+            }
+            None => eprintln!("error: {}", msg),
+        }
+        process::exit(code);
+    }
+}
+
+/// How to print responses.
+enum OutputFormat {
+    Json,
+    Yaml,
+    Table,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "yaml" => Ok(OutputFormat::Yaml),
+            "table" => Ok(OutputFormat::Table),
+            _ => Err(format!("invalid output format: {}", s)),
+        }
+    }
+}
+
+/// A row of a `Table`.
+struct Row {
+    /// Identifies the row between refreshes in watch mode.
+    key: String,
+    /// The parts of the row that watch mode highlights changes to.
+    /// This leaves out cells like durations that change on every
+    /// refresh.
+    change_key: String,
+    cells: Vec<String>,
+}
+
+/// A response shown as rows and columns.
+struct Table {
+    header: Vec<&'static str>,
+    rows: Vec<Row>,
+}
+
+impl Table {
+    fn new(header: Vec<&'static str>) -> Table {
+        Table {
+            header,
+            rows: Vec::new(),
+        }
+    }
+
+    /// Add a row whose cells don't change unless the thing it shows
+    /// does, so any change to them is highlighted in watch mode.
+    fn add_row(&mut self, key: impl ToString, cells: Vec<String>) {
+        let change_key = cells.join("\t");
+        self.add_row_with_change_key(key, change_key, cells);
+    }
+
+    /// Add a row where only changes to `change_key` are highlighted in
+    /// watch mode.
+    fn add_row_with_change_key(
+        &mut self,
+        key: impl ToString,
+        change_key: impl ToString,
+        cells: Vec<String>,
+    ) {
+        self.rows.push(Row {
+            key: key.to_string(),
+            change_key: change_key.to_string(),
+            cells,
+        });
+    }
+
+    /// Print the table with each column as wide as its widest cell.
+    /// Rows for which `highlight` returns true are printed in bold.
+    fn print(&self, highlight: impl Fn(&Row) -> bool) {
+        let mut widths: Vec<usize> =
+            self.header.iter().map(|name| name.len()).collect();
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(&row.cells) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        let format_row = |cells: &[&str]| {
+            let line: Vec<String> = cells
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:<1$}", cell, width))
+                .collect();
+            line.join("  ").trim_end().to_string()
+        };
+
+        println!("{}", format_row(&self.header));
+        for row in &self.rows {
+            let cell_refs: Vec<&str> =
+                row.cells.iter().map(String::as_str).collect();
+            let line = format_row(&cell_refs);
+            if highlight(row) {
+                println!("\x1b[1;33m{}\x1b[0m", line);
+            } else {
+                println!("{}", line);
+            }
+        }
+    }
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn optional(value: Option<impl ToString>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn jobs_table<'a>(jobs: impl Iterator<Item = &'a Job>) -> Table {
+    let mut table = Table::new(vec![
+        "ID", "PROJECT", "QUEUE", "STATE", "ATTEMPTS", "CREATED", "WAITED",
+        "RAN", "DATA",
+    ]);
+    let now = Utc::now();
+    for job in jobs {
+        // The durations change on every refresh
+        table.add_row_with_change_key(
+            job.id,
+            job.state,
+            vec![
+                job.id.to_string(),
+                job.project_name.clone(),
+                job.queue.clone(),
+                job.state.to_string(),
+                job.attempts.to_string(),
+                format_time(job.created),
+                format_duration(job.wait_duration(now)),
+                optional(job.run_duration(now).map(format_duration)),
+                job.data_preview(),
+            ],
+        );
+    }
+    table
+}
+
+fn stuck_jobs_table(jobs: &[(&str, &StuckJob)]) -> Table {
+    let mut table =
+        Table::new(vec!["PROJECT", "JOB", "RUNNER", "DEADLINE", "STATUS"]);
+    for (status, job) in jobs {
+        table.add_row(
+            format!("{}/{}", job.project_name, job.job_id),
+            vec![
+                job.project_name.clone(),
+                job.job_id.to_string(),
+                job.runner.clone(),
+                format_time(job.heartbeat_deadline),
+                status.to_string(),
+            ],
+        );
+    }
+    table
+}
+
+/// Get a table for responses that list things. Other responses don't
+/// have a table form.
+fn make_table(resp: &Response) -> Option<Table> {
+    let table = match resp {
+        Response::ListProjects(resp) => {
+            let mut table = Table::new(vec!["ID", "NAME", "ORGANIZATION"]);
+            for project in &resp.projects {
+                table.add_row(
+                    project.id,
+                    vec![
+                        project.id.to_string(),
+                        project.name.clone(),
+                        project.organization_name.clone().unwrap_or_default(),
+                    ],
+                );
+            }
+            table
+        }
+        Response::ListOrganizations(resp) => {
+            let mut table = Table::new(vec!["ID", "NAME", "PROJECTS"]);
+            for org in &resp.organizations {
+                table.add_row(
+                    &org.name,
+                    vec![
+                        org.id.to_string(),
+                        org.name.clone(),
+                        org.project_count.to_string(),
+                    ],
+                );
+            }
+            table
+        }
+        Response::GetJob(resp) => jobs_table(std::iter::once(&resp.job)),
+        Response::GetJobs(resp) => jobs_table(resp.jobs.iter()),
+        Response::ListRunners(resp) => {
+            let mut table =
+                Table::new(vec!["NAME", "STATE", "LAST SEEN", "JOBS"]);
+            for runner in &resp.runners {
+                let jobs: Vec<String> =
+                    runner.jobs.iter().map(ToString::to_string).collect();
+                // Runners are seen again with each heartbeat
+                table.add_row_with_change_key(
+                    &runner.name,
+                    format!("{}\t{}", runner.state.as_ref(), jobs.join(",")),
+                    vec![
+                        runner.name.clone(),
+                        runner.state.as_ref().into(),
+                        format_time(runner.last_seen),
+                        jobs.join(","),
+                    ],
+                );
+            }
+            table
+        }
+        Response::GetStuckJobs(resp) => {
+            let jobs: Vec<_> =
+                resp.jobs.iter().map(|job| ("stuck", job)).collect();
+            stuck_jobs_table(&jobs)
+        }
+        Response::HandleStuckJobs(resp) => {
+            let requeued = resp.requeued.iter().map(|job| ("requeued", job));
+            let timed_out = resp.timed_out.iter().map(|job| ("timed_out", job));
+            let canceled = resp.canceled.iter().map(|job| ("canceled", job));
+            let jobs: Vec<_> =
+                requeued.chain(timed_out).chain(canceled).collect();
+            let mut table = stuck_jobs_table(&jobs);
+            for job in &resp.expired {
+                table.add_row(
+                    format!("{}/{}", job.project_name, job.job_id),
+                    vec![
+                        job.project_name.clone(),
+                        job.job_id.to_string(),
+                        String::new(),
+                        format_time(job.expires_at),
+                        "expired".into(),
+                    ],
+                );
+            }
+            table
+        }
+        Response::GetAuditLog(resp) => {
+            let mut table = Table::new(vec![
+                "ID", "TIME", "REQUEST", "ACTOR", "PROJECT", "JOB",
+            ]);
+            for entry in &resp.entries {
+                table.add_row(
+                    entry.id,
+                    vec![
+                        entry.id.to_string(),
+                        format_time(entry.time),
+                        entry.request.clone(),
+                        optional(entry.actor.as_ref()),
+                        optional(entry.project_name.as_ref()),
+                        optional(entry.job_id),
+                    ],
+                );
+            }
+            table
+        }
+        _ => return None,
+    };
+    Some(table)
+}
+
+/// Print a JSON response from the server in the requested format.
+/// Responses without a table form are printed as YAML when a table is
+/// requested.
+fn print_response(value: &Value, format: &OutputFormat) {
+    match format {
+        OutputFormat::Json => println!("{}", value),
+        OutputFormat::Table => {
+            let table = serde_json::from_value(value.clone())
+                .ok()
+                .and_then(|resp| make_table(&resp));
+            match table {
+                Some(table) => table.print(|_| false),
+                None => print_response(value, &OutputFormat::Yaml),
+            }
+        }
+        OutputFormat::Yaml => print!(
+            "{}",
+            serde_yaml::to_string(value).expect("failed to convert to YAML")
+        ),
+    }
+}
+
+/// Send a request every `interval` seconds and print the response as
+/// a table, highlighting rows that are new or changed since the
+/// previous refresh.
+fn watch(
+    url: &str,
+    api_key: Option<&str>,
+    interval: u64,
+    make_req: impl Fn() -> Request,
+) {
+    let mut prev: Option<HashMap<String, String>> = None;
+    loop {
+        let (value, request_id) = send_request(url, api_key, make_req());
+        let resp: Option<Response> = serde_json::from_value(value.clone()).ok();
+        exit_if_error(resp.as_ref(), request_id.as_deref());
+        let table = match resp.as_ref().and_then(make_table) {
+            Some(table) => table,
+            None => {
+                eprintln!("{}", value);
+                std::process::exit(1);
+            }
+        };
+
+        // Clear the screen and move the cursor to the top left
+        print!("\x1b[2J\x1b[H");
+        table.print(|row| match &prev {
+            Some(prev) => prev.get(&row.key) != Some(&row.change_key),
+            None => false,
+        });
+
+        prev = Some(
+            table
+                .rows
+                .into_iter()
+                .map(|row| (row.key, row.change_key))
+                .collect(),
+        );
+        thread::sleep(Duration::from_secs(interval));
+    }
+}
+
+/// Quote a string so that the shell treats it as a single word.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Replace `{field}` in a worker's command with that field of the
+/// job's data. Placeholders for fields that aren't in the data are left
+/// as they are.
+fn render_command(template: &str, data: &Value) -> String {
+    let mut command = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        command.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let field = rest.find('}').and_then(|end| {
+            let value = data.get(&rest[1..end])?;
+            Some((end, value))
+        });
+        match field {
+            Some((end, value)) => {
+                let value = match value {
+                    Value::String(s) => s.clone(),
+                    value => value.to_string(),
+                };
+                command.push_str(&shell_quote(&value));
+                rest = &rest[end + 1..];
+            }
+            None => {
+                command.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    command.push_str(rest);
+    command
+}
+
+/// Run a worker's command for a job, adding its output to the job's
+/// log as it runs. The command is killed if the job is canceled.
+fn run_worker_job(context: &JobContext, template: &str) -> Result<(), String> {
+    let job = context.job();
+    eprintln!("running job {}", job);
+    let mut child = process::Command::new("sh")
+        .arg("-c")
+        .arg(render_command(template, &job.data))
+        .env("JOBCLERK_JOB_ID", job.id.to_string())
+        .env("JOBCLERK_JOB_DATA", job.data.to_string())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("failed to run command: {}", err))?;
+
+    let (sender, receiver) = mpsc::channel();
+    let stdout = child
+        .stdout
+        .take()
+        .map(|r| Box::new(r) as Box<dyn Read + Send>);
+    let stderr = child
+        .stderr
+        .take()
+        .map(|r| Box::new(r) as Box<dyn Read + Send>);
+    for reader in stdout.into_iter().chain(stderr) {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(reader).lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(sender);
+
+    loop {
+        match receiver.recv_timeout(Duration::from_secs(1)) {
+            Ok(line) => {
+                // Send whatever output has built up as one chunk
+                let mut text = line + "\n";
+                for line in receiver.try_iter() {
+                    text.push_str(&line);
+                    text.push('\n');
+                }
+                if let Err(err) = context.append_log(&text) {
+                    eprintln!("failed to append to job log: {}", err);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if context.is_cancel_requested() {
+            // Don't wait for the output to end, since anything the
+            // command started could still be holding it open
+            let _ = child.kill();
+            break;
+        }
+    }
+
+    let status = child
+        .wait()
+        .map_err(|err| format!("failed to wait for command: {}", err))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("command failed: {}", status))
+    }
+}
+
+fn run_worker(opt: Worker, base_url: &str, api_key: Option<&str>) -> ! {
+    let mut client = Client::new(base_url);
+    if let Some(api_key) = api_key {
+        client = client.with_api_key(api_key);
+    }
+    let command = opt.command;
+    let err = Runner::new(client, &opt.project_name, &opt.runner)
+        .with_capabilities(opt.capability)
+        .run(|context| run_worker_job(context, &command));
+    eprintln!("worker stopped: {}", err);
+    process::exit(1);
+}
+
 fn main() {
     let opt: Opt = argh::from_env();
-    let url = format!("{}/api", opt.base_url);
+    let profile = load_profile(
+        opt.profile
+            .or_else(|| env::var("JOBCLERK_PROFILE").ok())
+            .as_deref(),
+    );
+    let base_url = opt
+        .base_url
+        .or_else(|| profile.base_url.clone())
+        .unwrap_or_else(|| "http://localhost:8000".into());
+    let url = format!("{}/api/v{}", base_url, API_VERSION);
+    let api_key = opt
+        .api_key
+        .or_else(|| env::var("JOBCLERK_API_KEY").ok())
+        .or_else(|| profile.api_key.clone());
 
     let req: Request = match opt.command {
         Command::AddProject(opt) => AddProjectRequest {
+            // The closure borrows all of `opt`, so this has to come
+            // before any of its fields are moved
+            retry_policy: opt.max_attempts.map(|max_attempts| RetryPolicy {
+                max_attempts,
+                backoff: opt.retry_backoff,
+                delay_millis: opt.retry_delay * 1000,
+            }),
             name: opt.name,
             data: opt.data,
             heartbeat_expiration_millis: opt.grace_period * 1000,
+            max_runtime_millis: opt.max_runtime.map(|secs| secs * 1000),
+            max_jobs_per_runner: opt.max_jobs_per_runner,
+            retention_millis: opt.retention.map(|secs| secs * 1000),
+            retention_archive: opt.retention_archive,
+            weight: opt.weight,
+            max_data_bytes: opt.max_data_bytes,
+            max_unfinished_jobs: opt.max_unfinished_jobs,
+            queue_weights: opt.queue_weight.into_iter().collect(),
+            deduplicate_data: opt.deduplicate_data,
+            organization_name: opt.organization,
         }
         .into(),
         Command::AddJob(opt) => AddJobRequest {
             project_name: opt.project_name,
+            max_runtime_millis: opt.max_runtime.map(|secs| secs * 1000),
+            idempotency_key: opt.idempotency_key,
+            unique_key: opt.unique_key,
+            requirements: opt.requirement,
+            steps: opt.step,
+            expires_after_millis: opt.expires_after.map(|secs| secs * 1000),
+            priority: opt.priority,
+            queue: opt.queue,
+            depends_on: opt.depends_on,
             data: opt.data,
         }
         .into(),
-        Command::TakeJob(opt) => TakeJobRequest {
+        Command::GetJobs(opt) => {
+            let project_name =
+                project_or_default(opt.project_name.clone(), &profile);
+            let filter = JobFilter {
+                states: opt.state.clone(),
+                runner: opt.runner.clone(),
+                requirements: opt.requirement.clone(),
+                created_after: opt.created_after,
+                created_before: opt.created_before,
+                data_filter: opt.data_filter.clone(),
+                data_search: opt.data_search.clone(),
+            };
+            let make_req = || -> Request {
+                GetJobsRequest {
+                    project_name: project_name.clone(),
+                    filter: filter.clone(),
+                    page: make_page(
+                        opt.limit,
+                        opt.cursor.clone(),
+                        opt.descending,
+                    ),
+                    exclude_data: opt.exclude_data,
+                }
+                .into()
+            };
+            if opt.watch {
+                watch(&url, api_key.as_deref(), opt.interval, make_req);
+                return;
+            }
+            make_req()
+        }
+        Command::GetJobLog(log_opt) => {
+            if log_opt.follow {
+                follow_job_log(
+                    &base_url,
+                    &log_opt.project_name,
+                    log_opt.job_id,
+                );
+                return;
+            }
+            GetJobLogRequest {
+                project_name: log_opt.project_name,
+                job_id: log_opt.job_id,
+                after_sequence: log_opt.after,
+                page: Page::default(),
+            }
+            .into()
+        }
+        Command::TakeJob(opt) => {
+            let params = TakeJobRequest {
+                project_name: opt.project_name,
+                runner: opt.runner,
+                capabilities: opt.capability,
+                lease_millis: opt.lease.map(|secs| secs * 1000),
+                first_heartbeat_millis: opt
+                    .first_heartbeat
+                    .map(|secs| secs * 1000),
+                min_priority: opt.min_priority,
+                max_priority: opt.max_priority,
+                wait_millis: opt.wait.map(|secs| secs * 1000),
+            };
+            if let Some(max_jobs) = opt.max_jobs {
+                TakeJobsRequest { params, max_jobs }.into()
+            } else {
+                params.into()
+            }
+        }
+        Command::Worker(opt) => run_worker(opt, &base_url, api_key.as_deref()),
+        Command::UpdateJob(opt) => UpdateJobRequest {
             project_name: opt.project_name,
-            runner: opt.runner,
+            job_id: opt.job_id,
+            state: opt.state,
+            data: opt.data,
+            steps: opt.step,
+            token: opt.token,
         }
         .into(),
-        Command::UpdateJob(opt) => UpdateJobRequest {
+        Command::ExtendLease(opt) => ExtendLeaseRequest {
+            project_name: opt.project_name,
+            job_id: opt.job_id,
+            token: opt.token,
+            extend_by_millis: opt.extend_by * 1000,
+        }
+        .into(),
+        Command::ForceJobState(opt) => ForceSetJobStateRequest {
             project_name: opt.project_name,
             job_id: opt.job_id,
             state: opt.state,
+        }
+        .into(),
+        Command::PurgeJobs(opt) => PurgeJobsRequest {
+            project_name: project_or_default(opt.project_name, &profile),
+            older_than_millis: opt.older_than * 1000,
+            states: opt.state,
+        }
+        .into(),
+        Command::SetRetention(opt) => SetRetentionRequest {
+            project_name: project_or_default(opt.project_name, &profile),
+            retention_millis: opt.retention.map(|secs| secs * 1000),
+            archive: opt.archive,
+        }
+        .into(),
+        Command::GetArchivedJobs(opt) => GetArchivedJobsRequest {
+            project_name: project_or_default(opt.project_name, &profile),
+            page: make_page(opt.limit, opt.cursor, opt.descending),
+        }
+        .into(),
+        Command::PutJobTemplate(opt) => PutJobTemplateRequest {
+            project_name: opt.project_name,
+            name: opt.name,
+            template: JobTemplate {
+                requirements: opt.requirement,
+                priority: opt.priority,
+                queue: opt.queue,
+                max_runtime_millis: opt.max_runtime.map(|secs| secs * 1000),
+                data: opt.data,
+            },
+        }
+        .into(),
+        Command::AddJobFromTemplate(opt) => AddJobFromTemplateRequest {
+            project_name: opt.project_name,
+            template_name: opt.template_name,
+            params: opt.param.into_iter().collect(),
+            idempotency_key: opt.idempotency_key,
+            unique_key: opt.unique_key,
+            priority: opt.priority,
+            queue: opt.queue,
             data: opt.data,
+            ..Default::default()
+        }
+        .into(),
+        Command::PutWorkflow(opt) => PutWorkflowRequest {
+            nodes: serde_json::from_str(
+                &fs::read_to_string(&opt.nodes)
+                    .expect("failed to read nodes file"),
+            )
+            .expect("invalid workflow nodes"),
+            project_name: opt.project_name,
+            name: opt.name,
+        }
+        .into(),
+        Command::StartWorkflow(opt) => StartWorkflowRequest {
+            project_name: opt.project_name,
+            name: opt.name,
+            params: opt.param.into_iter().collect(),
+        }
+        .into(),
+        Command::GetWorkflowRun(opt) => GetWorkflowRunRequest {
+            project_name: opt.project_name,
+            run_id: opt.run_id,
+        }
+        .into(),
+        Command::AddChildJob(opt) => AddChildJobRequest {
+            child: AddJobRequest {
+                project_name: opt.project_name.clone(),
+                data: opt.data,
+                ..Default::default()
+            },
+            project_name: opt.project_name,
+            job_id: opt.job_id,
             token: opt.token,
         }
         .into(),
+        Command::AddBatch(opt) => {
+            let project_name = opt.project_name;
+            AddBatchRequest {
+                jobs: opt
+                    .job
+                    .into_iter()
+                    .map(|data| AddJobRequest {
+                        project_name: project_name.clone(),
+                        data,
+                        ..Default::default()
+                    })
+                    .collect(),
+                project_name,
+                webhook_url: opt.webhook_url,
+            }
+            .into()
+        }
+        Command::GetBatch(opt) => GetBatchRequest {
+            project_name: opt.project_name,
+            batch_id: opt.batch_id,
+        }
+        .into(),
+        Command::GetJobAttempts(opt) => GetJobAttemptsRequest {
+            project_name: opt.project_name,
+            job_id: opt.job_id,
+        }
+        .into(),
+        Command::CancelJob(opt) => CancelJobRequest {
+            project_name: opt.project_name,
+            job_id: opt.job_id,
+        }
+        .into(),
+        Command::GetStuckJobs(opt) => {
+            if opt.watch {
+                watch(&url, api_key.as_deref(), opt.interval, || {
+                    Request::GetStuckJobs
+                });
+                return;
+            }
+            Request::GetStuckJobs
+        }
+        Command::HandleStuckJobs(_) => Request::HandleStuckJobs,
+        Command::GetAuditLog(opt) => GetAuditLogRequest {
+            project_name: opt.project,
+            job_id: opt.job,
+            before_id: opt.before,
+            limit: opt.limit,
+        }
+        .into(),
+        Command::ListRunners(opt) => ListRunnersRequest {
+            offline_after_millis: opt.offline_after.map(|secs| secs * 1000),
+            page: make_page(opt.limit, opt.cursor, opt.descending),
+        }
+        .into(),
+        Command::ListProjects(opt) => ListProjectsRequest {
+            page: make_page(opt.limit, opt.cursor, opt.descending),
+            organization_name: opt.organization,
+        }
+        .into(),
+        Command::AddOrganization(opt) => {
+            AddOrganizationRequest { name: opt.name }.into()
+        }
+        Command::ListOrganizations(opt) => ListOrganizationsRequest {
+            page: make_page(opt.limit, opt.cursor, opt.descending),
+        }
+        .into(),
+        Command::GetOrganizationStats(opt) => GetOrganizationStatsRequest {
+            organization_name: opt.organization_name,
+            window_millis: opt.window * 1000,
+            window_end: opt.window_end,
+        }
+        .into(),
+        Command::GetProjectStats(opt) => GetProjectStatsRequest {
+            project_name: opt.project_name,
+            window_millis: opt.window * 1000,
+            window_end: opt.window_end,
+        }
+        .into(),
     };
 
-    let resp = ureq::post(&url).send_json(
-        serde_json::to_value(req).expect("failed to convert request to JSON"),
-    );
-    println!("{}", resp.into_json().expect("response is not json"));
+    let (value, request_id) = send_request(&url, api_key.as_deref(), req);
+    let resp: Option<Response> = serde_json::from_value(value.clone()).ok();
+    exit_if_error(resp.as_ref(), request_id.as_deref());
+    print_response(&value, &opt.output);
 }
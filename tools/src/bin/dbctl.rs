@@ -1,22 +1,106 @@
 use anyhow::Error;
 use argh::FromArgs;
 use fehler::{throw, throws};
+use jobclerk_server::{
+    create_api_key, create_ui_user, migrate, migration_status,
+};
+use jobclerk_types::ApiKeyRole;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
 use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
 use std::str::FromStr;
-use tokio_postgres::NoTls;
+use tokio_postgres::{Client, Config, NoTls};
 
 /// Database control.
 #[derive(FromArgs)]
 struct Opt {
+    /// postgres URL to connect to (defaults to $DATABASE_URL, or the
+    /// local database if that isn't set)
+    #[argh(option)]
+    database_url: Option<String>,
+
+    /// database host to connect to instead of using a URL (defaults
+    /// to localhost)
+    #[argh(option)]
+    host: Option<String>,
+
+    /// database port to connect to instead of using a URL (defaults
+    /// to 5432)
+    #[argh(option)]
+    port: Option<u16>,
+
+    /// database user to connect as instead of using a URL (defaults
+    /// to postgres)
+    #[argh(option)]
+    user: Option<String>,
+
+    /// password to connect with instead of using a URL (defaults to
+    /// $PGPASSWORD)
+    #[argh(option)]
+    password: Option<String>,
+
+    /// database to connect to instead of using a URL (defaults to the
+    /// user name)
+    #[argh(option)]
+    dbname: Option<String>,
+
+    /// project the new API key or UI user can be used with, or the
+    /// project to dump (add-api-key, add-ui-user and dump only;
+    /// defaults to all projects)
+    #[argh(option)]
+    project: Option<String>,
+
+    /// organization the new API key can be used with, instead of a
+    /// single project (add-api-key only)
+    #[argh(option)]
+    organization: Option<String>,
+
+    /// file to write the dump to (dump only; defaults to stdout)
+    #[argh(option)]
+    out: Option<String>,
+
+    /// file to load a dump from (load only; defaults to stdin)
+    #[argh(option)]
+    file: Option<String>,
+
+    /// role of the new API key or UI user: admin, submitter, runner,
+    /// or viewer (add-api-key and add-ui-user only)
+    #[argh(option, default = "ApiKeyRole::Viewer")]
+    role: ApiKeyRole,
+
+    /// description of the new API key (add-api-key only)
+    #[argh(option)]
+    description: Option<String>,
+
+    /// name of the UI user to add (add-ui-user only); the password is
+    /// read from $JOBCLERK_UI_PASSWORD, or from stdin if that isn't
+    /// set
+    #[argh(option)]
+    ui_user: Option<String>,
+
+    /// show which migrations have been applied instead of applying
+    /// them (migrate only)
+    #[argh(switch)]
+    status: bool,
+
     #[argh(positional)]
     command: Command,
 }
 
 #[derive(Debug, PartialEq)]
 enum Command {
-    Init,
+    Migrate,
     Clean,
     Test,
+    Seed,
+    AddApiKey,
+    AddUiUser,
+    Dump,
+    Load,
 }
 
 impl FromStr for Command {
@@ -24,12 +108,22 @@ impl FromStr for Command {
 
     #[throws(Self::Err)]
     fn from_str(s: &str) -> Self {
-        if s == "init" {
-            Self::Init
+        if s == "migrate" {
+            Self::Migrate
         } else if s == "clean" {
             Self::Clean
         } else if s == "test" {
             Self::Test
+        } else if s == "seed" {
+            Self::Seed
+        } else if s == "add-api-key" {
+            Self::AddApiKey
+        } else if s == "add-ui-user" {
+            Self::AddUiUser
+        } else if s == "dump" {
+            Self::Dump
+        } else if s == "load" {
+            Self::Load
         } else {
             throw!("invalid command")
         }
@@ -40,19 +134,155 @@ impl fmt::Display for Command {
     #[throws(fmt::Error)]
     fn fmt(&self, f: &mut fmt::Formatter) {
         let s = match self {
-            Self::Init => "init",
+            Self::Migrate => "migrate",
             Self::Clean => "clean",
             Self::Test => "test",
+            Self::Seed => "seed",
+            Self::AddApiKey => "add-api-key",
+            Self::AddUiUser => "add-ui-user",
+            Self::Dump => "dump",
+            Self::Load => "load",
         };
         write!(f, "{}", s)?
     }
 }
 
+impl Opt {
+    fn has_connection_options(&self) -> bool {
+        self.host.is_some()
+            || self.port.is_some()
+            || self.user.is_some()
+            || self.password.is_some()
+            || self.dbname.is_some()
+    }
+
+    /// Get the database connection settings, either from the
+    /// connection options or from a URL. The two can't be mixed.
+    #[throws]
+    fn connection_config(&self) -> Config {
+        if !self.has_connection_options() {
+            let database_url = self.database_url.clone().unwrap_or_else(|| {
+                env::var("DATABASE_URL")
+                    .unwrap_or_else(|_| "postgres://postgres@localhost".into())
+            });
+            database_url.parse()?
+        } else if self.database_url.is_some() {
+            throw!(anyhow::anyhow!(
+                "--database-url can't be used with other connection options"
+            ));
+        } else {
+            let mut config = Config::new();
+            config
+                .host(self.host.as_deref().unwrap_or("localhost"))
+                .user(self.user.as_deref().unwrap_or("postgres"));
+            if let Some(port) = self.port {
+                config.port(port);
+            }
+            if let Some(password) = self
+                .password
+                .clone()
+                .or_else(|| env::var("PGPASSWORD").ok())
+            {
+                config.password(password);
+            }
+            if let Some(dbname) = &self.dbname {
+                config.dbname(dbname);
+            }
+            config
+        }
+    }
+}
+
+/// Projects and jobs serialized by the dump command. Each row is the
+/// table's columns as JSON, minus the ID, so that loading it into
+/// another database assigns new IDs. Jobs refer to their project by
+/// name rather than ID.
+#[derive(Deserialize, Serialize)]
+struct Dump {
+    projects: Vec<Value>,
+    jobs: Vec<Value>,
+}
+
+#[throws]
+async fn dump(client: &Client, project_name: Option<&str>) -> Dump {
+    let projects = client
+        .query(
+            "SELECT to_jsonb(p) - 'id' FROM projects p
+             WHERE $1::TEXT IS NULL OR name = $1
+             ORDER BY id",
+            &[&project_name],
+        )
+        .await?;
+    let jobs = client
+        .query(
+            "SELECT to_jsonb(j) - 'id' - 'project'
+                    || jsonb_build_object('project_name', p.name)
+             FROM jobs j JOIN projects p ON p.id = j.project
+             WHERE $1::TEXT IS NULL OR p.name = $1
+             ORDER BY j.id",
+            &[&project_name],
+        )
+        .await?;
+    Dump {
+        projects: projects.iter().map(|row| row.get(0)).collect(),
+        jobs: jobs.iter().map(|row| row.get(0)).collect(),
+    }
+}
+
+/// Add the projects and jobs from a dump. Nothing is added if any
+/// project already exists.
+#[throws]
+async fn load(client: &mut Client, dump: Dump) {
+    let transaction = client.transaction().await?;
+    let mut project_ids = HashMap::new();
+    for project in dump.projects {
+        let name = project["name"].as_str().unwrap_or_default().to_string();
+        let row = transaction
+            .query_one(
+                "INSERT INTO projects
+                 SELECT * FROM jsonb_populate_record(
+                   NULL::projects,
+                   $1 || jsonb_build_object('id', nextval('projects_id_seq')))
+                 RETURNING id",
+                &[&project],
+            )
+            .await?;
+        project_ids.insert(name, row.get::<_, i64>(0));
+    }
+    for mut job in dump.jobs {
+        let project_id = match job["project_name"]
+            .as_str()
+            .and_then(|name| project_ids.get(name))
+        {
+            Some(id) => *id,
+            None => throw!(anyhow::anyhow!(
+                "job refers to a project that isn't in the dump: {}",
+                job["project_name"]
+            )),
+        };
+        if let Some(job) = job.as_object_mut() {
+            job.remove("project_name");
+        }
+        transaction
+            .execute(
+                "INSERT INTO jobs
+                 SELECT * FROM jsonb_populate_record(
+                   NULL::jobs,
+                   $1 || jsonb_build_object(
+                     'id', nextval('jobs_id_seq'), 'project', $2::BIGINT))",
+                &[&job, &project_id],
+            )
+            .await?;
+    }
+    transaction.commit().await?;
+}
+
 #[throws]
 #[tokio::main]
 async fn main() {
-    let (client, connection) =
-        tokio_postgres::connect("host=localhost user=postgres", NoTls).await?;
+    let opt: Opt = argh::from_env();
+    let (mut client, connection) =
+        opt.connection_config()?.connect(NoTls).await?;
 
     tokio::spawn(async move {
         if let Err(e) = connection.await {
@@ -60,13 +290,27 @@ async fn main() {
         }
     });
 
-    let opt: Opt = argh::from_env();
-
     match opt.command {
-        Command::Init => {
-            client
-                .batch_execute(include_str!("../../../db/init.sql"))
-                .await?;
+        Command::Migrate if opt.status => {
+            for migration in migration_status(&client).await? {
+                let applied = match migration.applied {
+                    Some(time) => format!("applied {}", time),
+                    None => "pending".into(),
+                };
+                println!(
+                    "{:>4}  {:<20}  {}",
+                    migration.version, migration.name, applied
+                );
+            }
+        }
+        Command::Migrate => {
+            let applied = migrate(&mut client).await?;
+            if applied.is_empty() {
+                println!("database is up to date");
+            }
+            for version in applied {
+                println!("applied migration {}", version);
+            }
         }
         Command::Clean => {
             client
@@ -78,5 +322,64 @@ async fn main() {
                 .batch_execute(include_str!("../../../db/test.sql"))
                 .await?;
         }
+        Command::Seed => {
+            client
+                .batch_execute(include_str!("../../../db/seed.sql"))
+                .await?;
+        }
+        Command::AddApiKey => {
+            let api_key = create_api_key(
+                &client,
+                opt.project.as_deref(),
+                opt.organization.as_deref(),
+                opt.role,
+                opt.description.as_deref(),
+            )
+            .await?;
+            println!("{}", api_key);
+        }
+        Command::AddUiUser => {
+            let name = match &opt.ui_user {
+                Some(name) => name,
+                None => {
+                    throw!(anyhow::anyhow!("add-ui-user requires --ui-user"))
+                }
+            };
+            let password = match env::var("JOBCLERK_UI_PASSWORD") {
+                Ok(password) => password,
+                Err(_) => {
+                    let mut line = String::new();
+                    io::stdin().lock().read_line(&mut line)?;
+                    line.trim_end_matches(&['\r', '\n'][..]).to_string()
+                }
+            };
+            create_ui_user(
+                &client,
+                name,
+                &password,
+                opt.project.as_deref(),
+                opt.role,
+            )
+            .await?;
+        }
+        Command::Dump => {
+            let dump = dump(&client, opt.project.as_deref()).await?;
+            let out: Box<dyn Write> = match &opt.out {
+                Some(path) => Box::new(File::create(path)?),
+                None => Box::new(io::stdout()),
+            };
+            let mut out = BufWriter::new(out);
+            serde_json::to_writer_pretty(&mut out, &dump)?;
+            writeln!(out)?;
+        }
+        Command::Load => {
+            let dump = match &opt.file {
+                Some(path) => {
+                    serde_json::from_reader(BufReader::new(File::open(path)?))?
+                }
+                None => serde_json::from_reader(io::stdin())?,
+            };
+            load(&mut client, dump).await?;
+        }
     }
 }
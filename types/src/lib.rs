@@ -1,11 +1,51 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
 use paste::paste;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
 use strum_macros::{AsRefStr, EnumString};
 
 pub type JobId = i64;
 pub type JobToken = String;
 pub type ProjectId = i64;
+pub type OrganizationId = i64;
+pub type BatchId = i64;
+pub type WorkflowRunId = i64;
+
+/// Queue that jobs are added to if they don't name one.
+pub const DEFAULT_QUEUE: &str = "default";
+
+/// Maximum number of characters in a data preview.
+pub const DATA_PREVIEW_LEN: usize = 60;
+
+/// Format a duration as e.g. "1h2m3s". Negative durations are shown
+/// as zero.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.num_seconds().max(0);
+    let (hours, mins, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{}h{}m{}s", hours, mins, secs)
+    } else if mins > 0 {
+        format!("{}m{}s", mins, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// Get a one-line preview of job data, cut off with "…" if it's
+/// longer than `DATA_PREVIEW_LEN` characters.
+pub fn data_preview(data: &serde_json::Value) -> String {
+    let data = data.to_string();
+    if data.chars().count() > DATA_PREVIEW_LEN {
+        let mut preview: String =
+            data.chars().take(DATA_PREVIEW_LEN - 1).collect();
+        preview.push('…');
+        preview
+    } else {
+        data
+    }
+}
 
 macro_rules! request_from {
     ($name:ident) => {
@@ -31,45 +71,345 @@ macro_rules! response_from {
     };
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Version of the API described by this crate. The server accepts
+/// requests at `/api/v{API_VERSION}` (and at `/api`, which is the
+/// current version).
+///
+/// New request and response types can be added without changing the
+/// version, so `Request` and `Response` are non-exhaustive. A server
+/// that receives a request type it doesn't know responds with
+/// `Response::BadRequest`, and a client that receives a response type
+/// it doesn't know should treat it as an error (it's deserialized as
+/// `Response::Unknown`). Likewise, states added to `JobState` and
+/// `StepState` are deserialized as `Unknown` by older clients.
+/// Changing or removing an existing type requires a new version.
+///
+/// Version 2 changed `Response::BadRequest` from a message to a
+/// `BadRequestError`.
+pub const API_VERSION: u32 = 2;
+
+/// Position in a list to continue from. Cursors come from the
+/// `next_cursor` of a previous response, and their contents should be
+/// treated as opaque.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Cursor(pub String);
+
+#[derive(
+    Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize, Serialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Ascending,
+    Descending,
+}
+
+/// Which part of a list to get. The default is the whole list in
+/// ascending order.
+///
+/// If `limit` is set and there are more items, the response's
+/// `next_cursor` is set. Getting the next page is done by repeating
+/// the request with that cursor and the same order.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Page {
+    /// Start after the last item of a previous page.
+    pub cursor: Option<Cursor>,
+    /// Maximum number of items to return.
+    pub limit: Option<i64>,
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+#[derive(Debug, Deserialize, Serialize, AsRefStr)]
+#[non_exhaustive]
 pub enum Request {
     AddProject(AddProjectRequest),
+    ListProjects(ListProjectsRequest),
+    GetProjectStats(GetProjectStatsRequest),
+
+    AddOrganization(AddOrganizationRequest),
+    ListOrganizations(ListOrganizationsRequest),
+    GetOrganizationStats(GetOrganizationStatsRequest),
 
     AddJob(AddJobRequest),
+    AddBatch(AddBatchRequest),
+    GetBatch(GetBatchRequest),
+    AddChildJob(AddChildJobRequest),
+    PutWorkflow(PutWorkflowRequest),
+    StartWorkflow(StartWorkflowRequest),
+    GetWorkflowRun(GetWorkflowRunRequest),
+    PutJobTemplate(PutJobTemplateRequest),
+    AddJobFromTemplate(AddJobFromTemplateRequest),
     GetJob(GetJobRequest),
     GetJobs(GetJobsRequest),
+    GetJobHistory(GetJobHistoryRequest),
+    GetJobAttempts(GetJobAttemptsRequest),
     TakeJob(TakeJobRequest),
+    TakeJobs(TakeJobsRequest),
+    TakeJobFromProjects(TakeJobFromProjectsRequest),
     UpdateJob(UpdateJobRequest),
+    ExtendLease(ExtendLeaseRequest),
+    AppendJobLog(AppendJobLogRequest),
+    GetJobLog(GetJobLogRequest),
+    AddJobArtifact(AddJobArtifactRequest),
+    PurgeJobs(PurgeJobsRequest),
+    SetRetention(SetRetentionRequest),
+    GetArchivedJobs(GetArchivedJobsRequest),
+    ForceSetJobState(ForceSetJobStateRequest),
+    CancelJob(CancelJobRequest),
+    RetryJob(RetryJobRequest),
+
+    RunnerHeartbeat(RunnerHeartbeatRequest),
+    ListRunners(ListRunnersRequest),
 
+    GetStuckJobs,
     HandleStuckJobs,
+
+    GetAuditLog(GetAuditLogRequest),
+}
+
+impl Request {
+    /// Name of the project the request acts on, if it acts on a
+    /// single project.
+    pub fn project_name(&self) -> Option<&str> {
+        match self {
+            Request::AddProject(req) => Some(&req.name),
+            Request::GetProjectStats(req) => Some(&req.project_name),
+            Request::AddJob(req) => Some(&req.project_name),
+            Request::AddBatch(req) => Some(&req.project_name),
+            Request::GetBatch(req) => Some(&req.project_name),
+            Request::AddChildJob(req) => Some(&req.project_name),
+            Request::PutWorkflow(req) => Some(&req.project_name),
+            Request::StartWorkflow(req) => Some(&req.project_name),
+            Request::GetWorkflowRun(req) => Some(&req.project_name),
+            Request::PutJobTemplate(req) => Some(&req.project_name),
+            Request::AddJobFromTemplate(req) => Some(&req.project_name),
+            Request::GetJob(req) => Some(&req.project_name),
+            Request::GetJobs(req) => Some(&req.project_name),
+            Request::GetJobHistory(req) => Some(&req.project_name),
+            Request::GetJobAttempts(req) => Some(&req.project_name),
+            Request::TakeJob(req) => Some(&req.project_name),
+            Request::TakeJobs(req) => Some(&req.params.project_name),
+            Request::UpdateJob(req) => Some(&req.project_name),
+            Request::ExtendLease(req) => Some(&req.project_name),
+            Request::AppendJobLog(req) => Some(&req.project_name),
+            Request::GetJobLog(req) => Some(&req.project_name),
+            Request::AddJobArtifact(req) => Some(&req.project_name),
+            Request::PurgeJobs(req) => Some(&req.project_name),
+            Request::SetRetention(req) => Some(&req.project_name),
+            Request::GetArchivedJobs(req) => Some(&req.project_name),
+            Request::ForceSetJobState(req) => Some(&req.project_name),
+            Request::CancelJob(req) => Some(&req.project_name),
+            Request::RetryJob(req) => Some(&req.project_name),
+            Request::ListProjects(_)
+            | Request::AddOrganization(_)
+            | Request::ListOrganizations(_)
+            | Request::GetOrganizationStats(_)
+            | Request::TakeJobFromProjects(_)
+            | Request::RunnerHeartbeat(_)
+            | Request::ListRunners(_)
+            | Request::GetStuckJobs
+            | Request::HandleStuckJobs
+            | Request::GetAuditLog(_) => None,
+        }
+    }
+
+    /// ID of the job the request acts on, if it acts on an existing
+    /// job.
+    pub fn job_id(&self) -> Option<JobId> {
+        match self {
+            Request::AddChildJob(req) => Some(req.job_id),
+            Request::GetJob(req) => Some(req.job_id),
+            Request::GetJobHistory(req) => Some(req.job_id),
+            Request::GetJobAttempts(req) => Some(req.job_id),
+            Request::UpdateJob(req) => Some(req.job_id),
+            Request::ExtendLease(req) => Some(req.job_id),
+            Request::AppendJobLog(req) => Some(req.job_id),
+            Request::GetJobLog(req) => Some(req.job_id),
+            Request::AddJobArtifact(req) => Some(req.job_id),
+            Request::ForceSetJobState(req) => Some(req.job_id),
+            Request::CancelJob(req) => Some(req.job_id),
+            Request::RetryJob(req) => Some(req.job_id),
+            _ => None,
+        }
+    }
 }
 
 request_from!(AddProject);
+request_from!(ListProjects);
+request_from!(GetProjectStats);
+request_from!(AddOrganization);
+request_from!(ListOrganizations);
+request_from!(GetOrganizationStats);
 request_from!(AddJob);
+request_from!(AddBatch);
+request_from!(GetBatch);
+request_from!(AddChildJob);
+request_from!(PutWorkflow);
+request_from!(StartWorkflow);
+request_from!(GetWorkflowRun);
+request_from!(PutJobTemplate);
+request_from!(AddJobFromTemplate);
 request_from!(GetJob);
 request_from!(GetJobs);
+request_from!(GetJobHistory);
+request_from!(GetJobAttempts);
 request_from!(TakeJob);
+request_from!(TakeJobs);
+request_from!(TakeJobFromProjects);
 request_from!(UpdateJob);
+request_from!(ExtendLease);
+request_from!(AppendJobLog);
+request_from!(GetJobLog);
+request_from!(AddJobArtifact);
+request_from!(PurgeJobs);
+request_from!(SetRetention);
+request_from!(GetArchivedJobs);
+request_from!(ForceSetJobState);
+request_from!(CancelJob);
+request_from!(RetryJob);
+request_from!(RunnerHeartbeat);
+request_from!(ListRunners);
+request_from!(GetAuditLog);
 
-#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+/// Why a request was rejected with `Response::BadRequest`, so that
+/// clients can handle some errors differently without matching on the
+/// message.
+#[derive(
+    Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize, AsRefStr,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+#[non_exhaustive]
+pub enum ErrorCode {
+    /// The request couldn't be parsed, or the server doesn't support
+    /// its type.
+    InvalidRequest,
+    /// One of the request's fields has an invalid value, such as a
+    /// negative duration.
+    InvalidArgument,
+    /// The request's `cursor` didn't come from a previous response.
+    InvalidCursor,
+    /// The job's data is larger than the project allows.
+    DataTooLarge,
+    /// The project already has as many unfinished jobs as it allows.
+    QuotaExceeded,
+    /// The job has the same step more than once.
+    DuplicateStep,
+    /// The update is for a step the job doesn't have.
+    UnknownStep,
+    /// The job can't be changed from its current state that way, such
+    /// as canceling a job that has already succeeded.
+    InvalidState,
+    /// An error code added in a newer version of the server.
+    #[serde(other)]
+    Other,
+}
+
+/// Details of a `Response::BadRequest`.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct BadRequestError {
+    pub code: ErrorCode,
+    /// Description of the error for people rather than programs.
+    pub message: String,
+}
+
+impl BadRequestError {
+    pub fn new(code: ErrorCode, message: &str) -> BadRequestError {
+        BadRequestError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize, AsRefStr)]
+#[non_exhaustive]
 pub enum Response {
     AddProject(AddProjectResponse),
+    ListProjects(ListProjectsResponse),
+    GetProjectStats(GetProjectStatsResponse),
+    AddOrganization(AddOrganizationResponse),
+    ListOrganizations(ListOrganizationsResponse),
+    GetOrganizationStats(GetOrganizationStatsResponse),
     AddJob(AddJobResponse),
+    AddBatch(AddBatchResponse),
+    GetBatch(GetBatchResponse),
+    StartWorkflow(StartWorkflowResponse),
+    GetWorkflowRun(GetWorkflowRunResponse),
     GetJob(GetJobResponse),
     GetJobs(GetJobsResponse),
+    GetJobHistory(GetJobHistoryResponse),
+    GetJobAttempts(GetJobAttemptsResponse),
     TakeJob(TakeJobResponse),
+    TakeJobs(TakeJobsResponse),
+    UpdateJob(UpdateJobResponse),
+    ExtendLease(ExtendLeaseResponse),
+    GetJobLog(GetJobLogResponse),
+    ListRunners(ListRunnersResponse),
+    PurgeJobs(PurgeJobsResponse),
+    GetArchivedJobs(GetArchivedJobsResponse),
+    CancelJob(CancelJobResponse),
+    GetStuckJobs(GetStuckJobsResponse),
+    HandleStuckJobs(HandleStuckJobsResponse),
+    GetAuditLog(GetAuditLogResponse),
     Empty,
 
-    BadRequest(String),
+    BadRequest(BadRequestError),
     NotFound,
     InternalError,
+    /// The request needs an API key and didn't have a valid one.
+    Unauthorized,
+    /// The request's API key isn't allowed to make the request.
+    Forbidden(String),
+    /// The client or project has made too many requests recently. Try
+    /// again after waiting.
+    TooManyRequests {
+        retry_after_millis: u64,
+    },
+    /// The server can't reach its database. Try again after waiting.
+    Unavailable {
+        retry_after_millis: u64,
+    },
+    /// The server gave up on the request because it took too long.
+    /// Requests that change several things may have been partly
+    /// applied.
+    Timeout,
+
+    /// A response type added in a newer version of the server, as it
+    /// was sent. Clients should treat this as an error.
+    #[serde(untagged)]
+    Unknown(serde_json::Value),
 }
 
 response_from!(AddProject);
+response_from!(ListProjects);
+response_from!(GetProjectStats);
+response_from!(AddOrganization);
+response_from!(ListOrganizations);
+response_from!(GetOrganizationStats);
 response_from!(AddJob);
+response_from!(AddBatch);
+response_from!(GetBatch);
+response_from!(StartWorkflow);
+response_from!(GetWorkflowRun);
 response_from!(GetJob);
 response_from!(GetJobs);
+response_from!(GetJobHistory);
+response_from!(GetJobAttempts);
 response_from!(TakeJob);
+response_from!(TakeJobs);
+response_from!(UpdateJob);
+response_from!(ExtendLease);
+response_from!(GetJobLog);
+response_from!(ListRunners);
+response_from!(PurgeJobs);
+response_from!(GetArchivedJobs);
+response_from!(CancelJob);
+response_from!(GetStuckJobs);
+response_from!(HandleStuckJobs);
+response_from!(GetAuditLog);
 
 macro_rules! response_into {
     ($name:ident, $ret:ty, $resptype:path) => {
@@ -87,21 +427,141 @@ macro_rules! response_into {
 
 impl Response {
     pub fn is_error(&self) -> bool {
-        matches!(self, Response::BadRequest(_) | Response::NotFound |
-                 Response::InternalError)
+        matches!(
+            self,
+            Response::BadRequest(_)
+                | Response::NotFound
+                | Response::InternalError
+                | Response::Unauthorized
+                | Response::Forbidden(_)
+                | Response::TooManyRequests { .. }
+                | Response::Unavailable { .. }
+                | Response::Timeout
+                | Response::Unknown(_)
+        )
     }
 
     response_into!(add_project, AddProjectResponse, Response::AddProject);
+    response_into!(list_projects, ListProjectsResponse, Response::ListProjects);
+    response_into!(
+        get_project_stats,
+        GetProjectStatsResponse,
+        Response::GetProjectStats
+    );
+    response_into!(
+        add_organization,
+        AddOrganizationResponse,
+        Response::AddOrganization
+    );
+    response_into!(
+        list_organizations,
+        ListOrganizationsResponse,
+        Response::ListOrganizations
+    );
+    response_into!(
+        get_organization_stats,
+        GetOrganizationStatsResponse,
+        Response::GetOrganizationStats
+    );
     response_into!(add_job, AddJobResponse, Response::AddJob);
+    response_into!(add_batch, AddBatchResponse, Response::AddBatch);
+    response_into!(get_batch, GetBatchResponse, Response::GetBatch);
+    response_into!(
+        start_workflow,
+        StartWorkflowResponse,
+        Response::StartWorkflow
+    );
+    response_into!(
+        get_workflow_run,
+        GetWorkflowRunResponse,
+        Response::GetWorkflowRun
+    );
     response_into!(get_job, GetJobResponse, Response::GetJob);
     response_into!(get_jobs, GetJobsResponse, Response::GetJobs);
+    response_into!(
+        get_job_history,
+        GetJobHistoryResponse,
+        Response::GetJobHistory
+    );
+    response_into!(
+        get_job_attempts,
+        GetJobAttemptsResponse,
+        Response::GetJobAttempts
+    );
     response_into!(take_job, TakeJobResponse, Response::TakeJob);
+    response_into!(take_jobs, TakeJobsResponse, Response::TakeJobs);
+    response_into!(update_job, UpdateJobResponse, Response::UpdateJob);
+    response_into!(extend_lease, ExtendLeaseResponse, Response::ExtendLease);
+    response_into!(get_job_log, GetJobLogResponse, Response::GetJobLog);
+    response_into!(list_runners, ListRunnersResponse, Response::ListRunners);
+    response_into!(purge_jobs, PurgeJobsResponse, Response::PurgeJobs);
+    response_into!(
+        get_archived_jobs,
+        GetArchivedJobsResponse,
+        Response::GetArchivedJobs
+    );
+    response_into!(cancel_job, CancelJobResponse, Response::CancelJob);
+    response_into!(
+        get_stuck_jobs,
+        GetStuckJobsResponse,
+        Response::GetStuckJobs
+    );
+    response_into!(
+        handle_stuck_jobs,
+        HandleStuckJobsResponse,
+        Response::HandleStuckJobs
+    );
+    response_into!(get_audit_log, GetAuditLogResponse, Response::GetAuditLog);
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct AddProjectRequest {
     pub name: String,
     pub heartbeat_expiration_millis: i32,
+    /// Default maximum runtime for the project's jobs. If a job runs
+    /// longer than this it is marked as timed out.
+    pub max_runtime_millis: Option<i32>,
+    /// How to retry failed jobs and jobs whose heartbeat expires. If
+    /// not set, jobs that fail are not retried and jobs whose
+    /// heartbeat expires are marked as timed out.
+    pub retry_policy: Option<RetryPolicy>,
+    /// Maximum number of the project's jobs a single runner can be
+    /// running at once.
+    pub max_jobs_per_runner: Option<i32>,
+    /// Finished jobs are deleted this long after they finish. If not
+    /// set, jobs are kept until they are purged.
+    pub retention_millis: Option<i64>,
+    /// If true, jobs removed by `retention_millis` are moved to the
+    /// project's archive instead of being deleted.
+    #[serde(default)]
+    pub retention_archive: bool,
+    /// Relative share of jobs this project gets when a runner takes
+    /// jobs from several projects with weighted fairness. Defaults to
+    /// 1.
+    pub weight: Option<i32>,
+    /// Maximum size of a job's data as JSON, in bytes. Adding or
+    /// updating a job with larger data fails. Defaults to 1 MiB.
+    pub max_data_bytes: Option<i32>,
+    /// Maximum number of the project's jobs that can be unfinished at
+    /// once. Adding a job beyond this fails, so that a runaway
+    /// submitter can't fill the database. If not set, there is no
+    /// limit.
+    #[serde(default)]
+    pub max_unfinished_jobs: Option<i32>,
+    /// Relative share of taken jobs each of the project's queues
+    /// gets, so that a flood of jobs in one queue doesn't starve the
+    /// others. Queues that aren't listed have a weight of 1. If
+    /// empty, jobs are taken by priority regardless of their queue.
+    #[serde(default)]
+    pub queue_weights: BTreeMap<String, i32>,
+    /// If true, adding a job with the same data as one of the
+    /// project's available jobs returns the existing job's ID instead
+    /// of adding a new job.
+    #[serde(default)]
+    pub deduplicate_data: bool,
+    /// Organization the project belongs to, which must already exist.
+    #[serde(default)]
+    pub organization_name: Option<String>,
     pub data: serde_json::Value,
 }
 
@@ -110,20 +570,330 @@ pub struct AddProjectResponse {
     pub project_id: ProjectId,
 }
 
-#[derive(Debug, Eq, PartialEq, Deserialize, Serialize, AsRefStr, EnumString)]
+/// List projects, sorted by name.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ListProjectsRequest {
+    #[serde(default)]
+    pub page: Page,
+    /// Only list the projects in this organization.
+    #[serde(default)]
+    pub organization_name: Option<String>,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ProjectSummary {
+    pub id: ProjectId,
+    pub name: String,
+    #[serde(default)]
+    pub organization_name: Option<String>,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ListProjectsResponse {
+    pub projects: Vec<ProjectSummary>,
+    pub next_cursor: Option<Cursor>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GetProjectStatsRequest {
+    pub project_name: String,
+    /// Only jobs that started or finished within this many
+    /// milliseconds of the window's end are included in the averages.
+    pub window_millis: i64,
+    /// End of the window, or now if not set.
+    #[serde(default)]
+    pub window_end: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct GetProjectStatsResponse {
+    /// Number of jobs in each state. States with no jobs are omitted.
+    pub job_counts: BTreeMap<JobState, i64>,
+    /// Number of jobs that finished within the window, by the state
+    /// they finished in.
+    #[serde(default)]
+    pub finished_counts: BTreeMap<JobState, i64>,
+    /// Average time between a job being created and started, or
+    /// `None` if no jobs started within the window.
+    pub avg_queue_wait_millis: Option<i64>,
+    /// Average time between a job being started and finished, or
+    /// `None` if no jobs finished within the window.
+    pub avg_run_duration_millis: Option<i64>,
+    /// Median time between a job being started and finished, for jobs
+    /// that finished within the window.
+    pub p50_run_duration_millis: Option<i64>,
+    /// 95th percentile of the time between a job being started and
+    /// finished, for jobs that finished within the window.
+    pub p95_run_duration_millis: Option<i64>,
+}
+
+/// Fraction of the finished jobs that ended in one of the states, or
+/// `None` if no jobs finished.
+fn finished_rate(
+    finished_counts: &BTreeMap<JobState, i64>,
+    states: &[JobState],
+) -> Option<f64> {
+    let total: i64 = finished_counts.values().sum();
+    if total == 0 {
+        return None;
+    }
+    let count: i64 = states
+        .iter()
+        .filter_map(|state| finished_counts.get(state))
+        .sum();
+    Some(count as f64 / total as f64)
+}
+
+impl GetProjectStatsResponse {
+    /// Fraction of the jobs that finished within the window that
+    /// succeeded, or `None` if no jobs finished.
+    pub fn success_rate(&self) -> Option<f64> {
+        finished_rate(&self.finished_counts, &[JobState::Succeeded])
+    }
+
+    /// Fraction of the jobs that finished within the window that
+    /// failed or timed out, or `None` if no jobs finished.
+    pub fn failure_rate(&self) -> Option<f64> {
+        finished_rate(
+            &self.finished_counts,
+            &[JobState::Failed, JobState::TimedOut],
+        )
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct AddOrganizationRequest {
+    pub name: String,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct AddOrganizationResponse {
+    pub organization_id: OrganizationId,
+}
+
+/// List organizations, sorted by name.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ListOrganizationsRequest {
+    #[serde(default)]
+    pub page: Page,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct OrganizationSummary {
+    pub id: OrganizationId,
+    pub name: String,
+    pub created: DateTime<Utc>,
+    /// Number of projects in the organization.
+    pub project_count: i64,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ListOrganizationsResponse {
+    pub organizations: Vec<OrganizationSummary>,
+    pub next_cursor: Option<Cursor>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GetOrganizationStatsRequest {
+    pub organization_name: String,
+    /// Only jobs that finished within this many milliseconds of the
+    /// window's end are included in `finished_counts`.
+    pub window_millis: i64,
+    /// End of the window, or now if not set.
+    #[serde(default)]
+    pub window_end: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct GetOrganizationStatsResponse {
+    /// Number of jobs in each state, by project. Every project in the
+    /// organization is listed, and states with no jobs are omitted.
+    pub project_job_counts: BTreeMap<String, BTreeMap<JobState, i64>>,
+    /// Number of jobs in any of the organization's projects that
+    /// finished within the window, by the state they finished in.
+    pub finished_counts: BTreeMap<JobState, i64>,
+}
+
+impl GetOrganizationStatsResponse {
+    /// Number of jobs in each state across all of the organization's
+    /// projects.
+    pub fn job_counts(&self) -> BTreeMap<JobState, i64> {
+        let mut job_counts = BTreeMap::new();
+        for counts in self.project_job_counts.values() {
+            for (state, count) in counts {
+                *job_counts.entry(*state).or_insert(0) += count;
+            }
+        }
+        job_counts
+    }
+
+    /// Fraction of the jobs that finished within the window that
+    /// succeeded, or `None` if no jobs finished.
+    pub fn success_rate(&self) -> Option<f64> {
+        finished_rate(&self.finished_counts, &[JobState::Succeeded])
+    }
+
+    /// Fraction of the jobs that finished within the window that
+    /// failed or timed out, or `None` if no jobs finished.
+    pub fn failure_rate(&self) -> Option<f64> {
+        finished_rate(
+            &self.finished_counts,
+            &[JobState::Failed, JobState::TimedOut],
+        )
+    }
+}
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Deserialize,
+    Serialize,
+    AsRefStr,
+    EnumString,
+)]
 #[serde(rename_all = "snake_case")]
 #[strum(serialize_all = "snake_case")]
+#[non_exhaustive]
 pub enum JobState {
     Available,
+    /// The job has been taken, but the runner hasn't sent an update
+    /// yet to say that it has started.
+    Activating,
     Running,
     Canceling,
     Canceled,
     Succeeded,
     Failed,
+    /// The job was not taken before its expiration time.
+    Expired,
+    /// The job's heartbeat expired or it ran longer than its maximum
+    /// runtime.
+    TimedOut,
+    /// A state added in a newer version of the server. The server
+    /// never sends this, and rejects requests that use it.
+    #[serde(other)]
+    Unknown,
+}
+
+impl JobState {
+    /// States where no runner is working on the job and it won't
+    /// change on its own. Jobs that failed, timed out or were canceled
+    /// can still be retried, which makes them `Available` again.
+    pub const TERMINAL: &'static [JobState] = &[
+        JobState::Canceled,
+        JobState::Succeeded,
+        JobState::Failed,
+        JobState::Expired,
+        JobState::TimedOut,
+    ];
+
+    pub fn is_terminal(&self) -> bool {
+        Self::TERMINAL.contains(self)
+    }
+
+    /// Check if a job can move from this state to `to`. The server
+    /// rejects any other transition, except when an admin forces a
+    /// job's state.
+    ///
+    /// Finished jobs can only go back to `Available`, and only if
+    /// they didn't succeed or expire. A job being canceled always
+    /// finishes: if its runner stops responding, the server marks it
+    /// as `Canceled`.
+    pub fn can_transition_to(&self, to: &JobState) -> bool {
+        use JobState::*;
+
+        matches!(
+            (self, to),
+            (Available, Activating)
+                | (Available, Canceled)
+                | (Available, Expired)
+                | (Activating, Running)
+                | (Activating, Available)
+                | (Activating, Canceling)
+                | (Activating, Canceled)
+                | (Activating, Succeeded)
+                | (Activating, Failed)
+                | (Activating, TimedOut)
+                | (Running, Available)
+                | (Running, Canceling)
+                | (Running, Canceled)
+                | (Running, Succeeded)
+                | (Running, Failed)
+                | (Running, TimedOut)
+                | (Canceling, Canceled)
+                | (Canceling, Succeeded)
+                | (Canceling, Failed)
+                | (Canceled, Available)
+                | (Failed, Available)
+                | (TimedOut, Available)
+        )
+    }
+}
+
+impl fmt::Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_ref())
+    }
+}
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Eq,
+    PartialEq,
+    Deserialize,
+    Serialize,
+    AsRefStr,
+    EnumString,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum RetryBackoff {
+    /// Wait the same amount of time before each retry.
+    Fixed,
+    /// Double the wait time after each retry.
+    Exponential,
 }
 
 #[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
-pub struct Job {
+pub struct RetryPolicy {
+    /// Maximum number of times a job can be taken, including the
+    /// first attempt.
+    pub max_attempts: i32,
+    pub backoff: RetryBackoff,
+    /// Delay before the first retry.
+    pub delay_millis: i32,
+}
+
+impl RetryPolicy {
+    /// Get the delay before a job that has been attempted `attempts`
+    /// times can be retried, or `None` if the job is out of attempts.
+    pub fn retry_delay_millis(&self, attempts: i32) -> Option<i64> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+        let delay = i64::from(self.delay_millis);
+        Some(match self.backoff {
+            RetryBackoff::Fixed => delay,
+            RetryBackoff::Exponential => {
+                // Cap the exponent so that the delay can't overflow
+                let exponent = (attempts - 1).clamp(0, 30) as u32;
+                delay.saturating_mul(1 << exponent)
+            }
+        })
+    }
+}
+
+/// A job, with its data as JSON by default. `Job::with_data` converts
+/// the data into a user type.
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Job<T = serde_json::Value> {
     pub id: JobId,
     pub project_name: String,
     pub project_id: ProjectId,
@@ -132,7 +902,60 @@ pub struct Job {
     pub started: Option<DateTime<Utc>>,
     pub finished: Option<DateTime<Utc>>,
     pub priority: i32,
-    pub data: serde_json::Value,
+    pub attempts: i32,
+    /// Named queue within the project that the job is in.
+    #[serde(default)]
+    pub queue: String,
+    pub data: T,
+}
+
+impl<T> Job<T> {
+    /// How long the job waited to be taken, up to `now` if it hasn't
+    /// been taken yet.
+    pub fn wait_duration(&self, now: DateTime<Utc>) -> Duration {
+        self.started.unwrap_or(now) - self.created
+    }
+
+    /// How long the job ran, up to `now` if it's still running, or
+    /// `None` if it never started.
+    pub fn run_duration(&self, now: DateTime<Utc>) -> Option<Duration> {
+        self.started
+            .map(|started| self.finished.unwrap_or(now) - started)
+    }
+}
+
+/// Formats the job as e.g. "myproj/12 (running)".
+impl<T> fmt::Display for Job<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{} ({})", self.project_name, self.id, self.state)
+    }
+}
+
+impl Job {
+    /// Get a one-line preview of the job's data.
+    pub fn data_preview(&self) -> String {
+        data_preview(&self.data)
+    }
+
+    /// Deserialize the job's data into `T`. Fails if the data doesn't
+    /// match `T`, for example if a field is missing.
+    pub fn with_data<T: DeserializeOwned>(
+        self,
+    ) -> Result<Job<T>, serde_json::Error> {
+        Ok(Job {
+            data: serde_json::from_value(self.data)?,
+            id: self.id,
+            project_name: self.project_name,
+            project_id: self.project_id,
+            state: self.state,
+            created: self.created,
+            started: self.started,
+            finished: self.finished,
+            priority: self.priority,
+            attempts: self.attempts,
+            queue: self.queue,
+        })
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -141,24 +964,212 @@ pub struct GetJobRequest {
     pub job_id: JobId,
 }
 
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Eq,
+    PartialEq,
+    Deserialize,
+    Serialize,
+    AsRefStr,
+    EnumString,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+#[non_exhaustive]
+pub enum StepState {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+    /// The step was not run, for example because an earlier step
+    /// failed.
+    Skipped,
+    /// A state added in a newer version of the server. The server
+    /// never sends this, and rejects requests that use it.
+    #[serde(other)]
+    Unknown,
+}
+
+/// One of the named steps declared when the job was added.
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct JobStep {
+    pub name: String,
+    pub state: StepState,
+    pub started: Option<DateTime<Utc>>,
+    pub finished: Option<DateTime<Utc>>,
+}
+
+/// A file produced by a job. The file itself is stored outside of
+/// jobclerk; this records where it is.
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct JobArtifact {
+    pub name: String,
+    pub url: String,
+    /// Size in bytes.
+    pub size: Option<i64>,
+    pub content_type: Option<String>,
+    pub created: DateTime<Utc>,
+}
+
 #[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct GetJobResponse {
     pub job: Job,
+    /// Steps in the order they were declared.
+    pub steps: Vec<JobStep>,
+    pub artifacts: Vec<JobArtifact>,
+    /// Job that added this job with `AddChildJobRequest`, if any.
+    pub parent_id: Option<JobId>,
+    /// Jobs this job added with `AddChildJobRequest`, in the order
+    /// they were added.
+    pub child_ids: Vec<JobId>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// Conditions for which jobs to list. This is shared by `GetJobs`,
+/// the UI's project page and the CLI, so that filters mean the same
+/// thing everywhere. Fields left empty don't filter, and a job has to
+/// match all of the others.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct JobFilter {
+    /// Only jobs in one of these states.
+    pub states: Vec<JobState>,
+    /// Only jobs taken by this runner.
+    pub runner: Option<String>,
+    /// Only jobs that have all of these requirements.
+    pub requirements: Vec<String>,
+    /// Only jobs created at or after this time.
+    pub created_after: Option<DateTime<Utc>>,
+    /// Only jobs created before this time.
+    pub created_before: Option<DateTime<Utc>>,
+    /// Only jobs whose data contains this JSON value (using JSONB
+    /// containment, e.g. `{"branch": "main"}` matches any job whose
+    /// data has a `branch` field set to `main`).
+    pub data_filter: Option<serde_json::Value>,
+    /// Only jobs whose data contains this text, ignoring case.
+    pub data_search: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct GetJobsRequest {
     pub project_name: String,
+    #[serde(flatten)]
+    pub filter: JobFilter,
+    /// Jobs are sorted by ID.
+    #[serde(default)]
+    pub page: Page,
+    /// Leave out each job's data, which is `null` in the response
+    /// instead. Listing many jobs is much smaller this way when only
+    /// their state is needed.
+    #[serde(default)]
+    pub exclude_data: bool,
 }
 
 #[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct GetJobsResponse {
     pub jobs: Vec<Job>,
+    pub next_cursor: Option<Cursor>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
+pub struct GetJobHistoryRequest {
+    pub project_name: String,
+    pub job_id: JobId,
+}
+
+/// A change in a job's state.
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct JobEvent {
+    /// `None` for the event created when the job was added.
+    pub old_state: Option<JobState>,
+    pub new_state: JobState,
+    /// Runner that held the job when the state changed, if any.
+    pub runner: Option<String>,
+    pub time: DateTime<Utc>,
+}
+
+/// A change in the state of one of a project's jobs, as sent by the
+/// project's event stream.
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ProjectJobEvent {
+    /// Increases with each event, so it can be used to resume the
+    /// stream.
+    pub id: i64,
+    pub job_id: JobId,
+    #[serde(flatten)]
+    pub event: JobEvent,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct GetJobHistoryResponse {
+    /// Events in the order they occurred.
+    pub events: Vec<JobEvent>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GetJobAttemptsRequest {
+    pub project_name: String,
+    pub job_id: JobId,
+}
+
+/// One of the times a job was taken.
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct JobAttempt {
+    /// Counts up from 1, and matches the job's `attempts` at the time
+    /// it was taken.
+    pub attempt: i32,
+    pub runner: Option<String>,
+    pub started: DateTime<Utc>,
+    /// `None` if the runner still holds the job.
+    pub finished: Option<DateTime<Utc>>,
+    /// How the attempt ended, such as `Failed` or `TimedOut`. This is
+    /// the state the job moved to, except that an attempt that failed
+    /// or timed out and was retried has that outcome rather than
+    /// `Available`.
+    pub outcome: Option<JobState>,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct GetJobAttemptsResponse {
+    /// Attempts in the order they were made.
+    pub attempts: Vec<JobAttempt>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct AddJobRequest {
     pub project_name: String,
+    /// Overrides the project's maximum runtime for this job.
+    pub max_runtime_millis: Option<i32>,
+    /// If a job with the same key was already added to the project,
+    /// its ID is returned instead of adding a new job. This allows
+    /// requests to be safely retried.
+    pub idempotency_key: Option<String>,
+    /// Only one job with this key can be in a non-terminal state in
+    /// the project at once. If such a job exists, its ID is returned
+    /// instead of adding a new job, whoever added it.
+    pub unique_key: Option<String>,
+    /// If the job is still available this long after it was added,
+    /// it is moved to the expired state instead of being run.
+    pub expires_after_millis: Option<i64>,
+    /// Capabilities a runner must have to take this job.
+    #[serde(default)]
+    pub requirements: Vec<String>,
+    /// Names of the steps the job runs, in order. Runners report
+    /// progress through the steps with `UpdateJobRequest::steps`.
+    #[serde(default)]
+    pub steps: Vec<String>,
+    /// Jobs with a lower priority are taken first. Jobs with the same
+    /// priority are taken in the order they were added.
+    #[serde(default)]
+    pub priority: i32,
+    /// Named queue within the project to add the job to. Defaults to
+    /// `DEFAULT_QUEUE`.
+    pub queue: Option<String>,
+    /// Jobs in the project that have to succeed before this job can
+    /// be taken.
+    #[serde(default)]
+    pub depends_on: Vec<JobId>,
     pub data: serde_json::Value,
 }
 
@@ -167,14 +1178,82 @@ pub struct AddJobResponse {
     pub job_id: JobId,
 }
 
+/// Add several jobs to a project as a batch, whose progress can be
+/// followed with `GetBatchRequest`. Either all of the jobs are added
+/// or none are.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct AddBatchRequest {
+    pub project_name: String,
+    /// Jobs to add, which must be in the batch's project. Jobs that
+    /// return an existing job, such as through an idempotency key,
+    /// add that job to the batch.
+    pub jobs: Vec<AddJobRequest>,
+    /// Once all of the batch's jobs finish, its `Batch` is sent to
+    /// this URL as JSON in a POST request.
+    pub webhook_url: Option<String>,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct AddBatchResponse {
+    pub batch_id: BatchId,
+    /// IDs of the batch's jobs, in the order they were in the request.
+    pub job_ids: Vec<JobId>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
+pub struct GetBatchRequest {
+    pub project_name: String,
+    pub batch_id: BatchId,
+}
+
+/// Progress of a batch of jobs.
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Batch {
+    pub id: BatchId,
+    pub project_name: String,
+    pub created: DateTime<Utc>,
+    /// Number of the batch's jobs in each state. States with no jobs
+    /// are omitted.
+    pub job_counts: BTreeMap<JobState, i64>,
+    /// True if all of the batch's jobs are in a terminal state.
+    pub finished: bool,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct GetBatchResponse {
+    pub batch: Batch,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
 pub struct TakeJobRequest {
     pub project_name: String,
     pub runner: String,
+    /// Only jobs whose requirements are all in this list can be
+    /// taken.
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Overrides the project's `heartbeat_expiration_millis` for the
+    /// taken jobs.
+    pub lease_millis: Option<i32>,
+    /// How long to wait for the first heartbeat of the taken jobs,
+    /// for runners that are slow to start (e.g. pulling a container
+    /// image). Once the first heartbeat arrives, `lease_millis` or the
+    /// project's expiration applies again.
+    pub first_heartbeat_millis: Option<i32>,
+    /// Only take jobs with at least this priority.
+    pub min_priority: Option<i32>,
+    /// Only take jobs with at most this priority. Since lower
+    /// priorities are more urgent, this lets a runner only take urgent
+    /// jobs.
+    pub max_priority: Option<i32>,
+    /// If no job is available, wait up to this long for one before
+    /// responding.
+    pub wait_millis: Option<i32>,
 }
 
 #[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
 pub struct TakeJobResponseJob {
+    pub project_name: String,
     pub job_id: JobId,
     pub job_token: JobToken,
 }
@@ -184,11 +1263,635 @@ pub struct TakeJobResponse {
     pub job: Option<TakeJobResponseJob>,
 }
 
+/// Take up to `max_jobs` jobs at once. Each job gets its own token.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TakeJobsRequest {
+    #[serde(flatten)]
+    pub params: TakeJobRequest,
+    pub max_jobs: i32,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct TakeJobsResponse {
+    pub jobs: Vec<TakeJobResponseJob>,
+}
+
+/// How to choose between projects when taking a job from several
+/// projects.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Eq,
+    PartialEq,
+    Deserialize,
+    Serialize,
+    AsRefStr,
+    EnumString,
+    Default,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ProjectFairness {
+    /// Take from the project that least recently had a job started.
+    #[default]
+    RoundRobin,
+    /// Pick a project at random, weighted by each project's weight.
+    Weighted,
+}
+
+/// Take an available job from any of several projects. The response
+/// is a `TakeJobResponse`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct TakeJobFromProjectsRequest {
+    pub project_names: Vec<String>,
+    pub runner: String,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    #[serde(default)]
+    pub fairness: ProjectFairness,
+    /// Overrides the project's `heartbeat_expiration_millis` for the
+    /// taken job.
+    pub lease_millis: Option<i32>,
+    /// How long to wait for the first heartbeat of the taken job,
+    /// for runners that are slow to start (e.g. pulling a container
+    /// image). Once the first heartbeat arrives, `lease_millis` or the
+    /// project's expiration applies again.
+    pub first_heartbeat_millis: Option<i32>,
+    /// Only take jobs with at least this priority.
+    pub min_priority: Option<i32>,
+    /// Only take jobs with at most this priority. Since lower
+    /// priorities are more urgent, this lets a runner only take urgent
+    /// jobs.
+    pub max_priority: Option<i32>,
+    /// If no job is available, wait up to this long for one before
+    /// responding.
+    pub wait_millis: Option<i32>,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct UpdateJobRequest {
     pub project_name: String,
     pub job_id: JobId,
     pub token: String,
+    /// The job's new state, or `None` to just send a heartbeat. The
+    /// first heartbeat moves an `Activating` job to `Running`, as does
+    /// setting the state to `Running`.
     pub state: Option<JobState>,
     pub data: Option<serde_json::Value>,
+    /// Changes to the state of the job's steps.
+    #[serde(default)]
+    pub steps: Vec<UpdateJobStep>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UpdateJobStep {
+    pub name: String,
+    pub state: StepState,
+}
+
+/// The job as it is after the update.
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct UpdateJobResponse {
+    pub state: JobState,
+    pub attempts: i32,
+    /// The job will be considered stuck if no heartbeat is received
+    /// before this time. `None` if the job is no longer held by the
+    /// runner.
+    pub heartbeat_deadline: Option<DateTime<Utc>>,
+    /// The job has been asked to stop. The runner should stop working
+    /// on it and update the job's state to `Canceled`.
+    pub cancel_requested: bool,
+}
+
+/// Move a job to a new state without the job's token. This is an
+/// administrative escape hatch, for example to fail a job whose
+/// runner died but which isn't being detected as stuck.
+///
+/// Moving a job to `Available` requeues it and moving it to a
+/// terminal state finishes it. Either way the current runner's token
+/// is invalidated. Only running jobs can be moved to `Canceling`,
+/// and no job can be moved to `Running`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ForceSetJobStateRequest {
+    pub project_name: String,
+    pub job_id: JobId,
+    pub state: JobState,
+}
+
+/// Cancel a job. A job that hasn't been taken yet is canceled right
+/// away. A running job is moved to `Canceling`, and its runner is told
+/// to stop in the response to its next heartbeat.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CancelJobRequest {
+    pub project_name: String,
+    pub job_id: JobId,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct CancelJobResponse {
+    /// Either `Canceled` or `Canceling`.
+    pub state: JobState,
+}
+
+/// Move a failed, canceled or timed-out job back to `Available` so
+/// that it runs again. The job's steps are reset to `Pending`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RetryJobRequest {
+    pub project_name: String,
+    pub job_id: JobId,
+}
+
+/// Push a running job's heartbeat deadline further out than a normal
+/// heartbeat would, for example before a long step that can't send
+/// heartbeats. Like a heartbeat, this moves an `Activating` job to
+/// `Running`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExtendLeaseRequest {
+    pub project_name: String,
+    pub job_id: JobId,
+    pub token: JobToken,
+    /// Amount to add to the current deadline. If the deadline has
+    /// already passed, it is added to the current time instead.
+    pub extend_by_millis: i64,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ExtendLeaseResponse {
+    pub heartbeat_deadline: DateTime<Utc>,
+}
+
+/// Append output to a running job's log.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AppendJobLogRequest {
+    pub project_name: String,
+    pub job_id: JobId,
+    pub token: JobToken,
+    pub text: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GetJobLogRequest {
+    pub project_name: String,
+    pub job_id: JobId,
+    /// Only return chunks with a sequence number greater than this.
+    /// Used to fetch new output while a job is running.
+    pub after_sequence: Option<i32>,
+    /// Chunks are sorted by sequence number.
+    #[serde(default)]
+    pub page: Page,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct JobLogChunk {
+    /// Starts at 1 and increments for each chunk appended to the job.
+    pub sequence: i32,
+    pub text: String,
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct GetJobLogResponse {
+    pub chunks: Vec<JobLogChunk>,
+    pub next_cursor: Option<Cursor>,
+}
+
+/// Record an artifact for a running job. Adding an artifact with the
+/// same name as an existing artifact replaces it.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AddJobArtifactRequest {
+    pub project_name: String,
+    pub job_id: JobId,
+    pub token: JobToken,
+    pub name: String,
+    pub url: String,
+    /// Size in bytes.
+    pub size: Option<i64>,
+    pub content_type: Option<String>,
+}
+
+/// Add a job as a child of a job the runner holds, so that a running
+/// job can fan out into more jobs. Gets an `AddJobResponse`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AddChildJobRequest {
+    pub project_name: String,
+    /// ID of the parent job.
+    pub job_id: JobId,
+    /// Token of the parent job.
+    pub token: JobToken,
+    /// The child job, which must be in the parent's project.
+    pub child: AddJobRequest,
+}
+
+/// A job in a workflow, which is added each time the workflow is
+/// started. Strings in the data can contain `{{name}}` placeholders,
+/// which are replaced with the parameters the workflow is started
+/// with.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct WorkflowNode {
+    /// Name of the node, unique within the workflow.
+    pub name: String,
+    /// Names of the nodes whose jobs have to succeed before this
+    /// node's job can be taken.
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// Capabilities a runner must have to take the node's job.
+    #[serde(default)]
+    pub requirements: Vec<String>,
+    #[serde(default)]
+    pub priority: i32,
+    pub queue: Option<String>,
+    pub max_runtime_millis: Option<i32>,
+    pub data: serde_json::Value,
+}
+
+/// Add a workflow to a project, or replace the workflow with the same
+/// name. Runs that were already started aren't changed.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PutWorkflowRequest {
+    pub project_name: String,
+    pub name: String,
+    /// The workflow's nodes, whose dependencies can't form a cycle.
+    pub nodes: Vec<WorkflowNode>,
+}
+
+/// Start a workflow by adding a job for each of its nodes.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StartWorkflowRequest {
+    pub project_name: String,
+    pub name: String,
+    /// Values for the placeholders in the workflow's nodes. Each
+    /// placeholder must have a value, and each value must be used.
+    #[serde(default)]
+    pub params: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct StartWorkflowResponse {
+    pub run_id: WorkflowRunId,
+    /// The job added for each node, by node name.
+    pub job_ids: BTreeMap<String, JobId>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GetWorkflowRunRequest {
+    pub project_name: String,
+    pub run_id: WorkflowRunId,
+}
+
+/// Overall progress of a workflow run.
+#[derive(
+    Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize, AsRefStr,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum WorkflowRunState {
+    /// Some of the jobs haven't finished.
+    Running,
+    /// All of the jobs succeeded.
+    Succeeded,
+    /// A job finished without succeeding, so the jobs that depend on
+    /// it won't run unless it is retried.
+    Failed,
+}
+
+/// The job added for one of a workflow run's nodes.
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct WorkflowRunJob {
+    pub node: String,
+    pub job_id: JobId,
+    pub state: JobState,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct GetWorkflowRunResponse {
+    pub run_id: WorkflowRunId,
+    pub workflow_name: String,
+    pub created: DateTime<Utc>,
+    pub state: WorkflowRunState,
+    /// The run's jobs, sorted by node name.
+    pub jobs: Vec<WorkflowRunJob>,
+}
+
+/// Defaults for jobs added from a template with
+/// `AddJobFromTemplateRequest`. Strings in the data can contain
+/// `{{name}}` placeholders, which are replaced with the request's
+/// parameters.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+pub struct JobTemplate {
+    /// Capabilities a runner must have to take the job.
+    #[serde(default)]
+    pub requirements: Vec<String>,
+    #[serde(default)]
+    pub priority: i32,
+    pub queue: Option<String>,
+    pub max_runtime_millis: Option<i32>,
+    pub data: serde_json::Value,
+}
+
+/// Add a job template to a project, or replace the template with the
+/// same name.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PutJobTemplateRequest {
+    pub project_name: String,
+    pub name: String,
+    pub template: JobTemplate,
+}
+
+/// Add a job with the defaults from one of the project's templates.
+/// Fields that are set override the template's.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct AddJobFromTemplateRequest {
+    pub project_name: String,
+    pub template_name: String,
+    /// Values for the template's placeholders. Each placeholder must
+    /// have a value, and each value must be used.
+    #[serde(default)]
+    pub params: BTreeMap<String, String>,
+    pub idempotency_key: Option<String>,
+    pub unique_key: Option<String>,
+    pub requirements: Option<Vec<String>>,
+    pub priority: Option<i32>,
+    pub queue: Option<String>,
+    pub max_runtime_millis: Option<i32>,
+    /// Merged into the template's data. Fields of objects are merged
+    /// recursively, and any other value replaces the template's value,
+    /// except for null which leaves the template's data as it is.
+    #[serde(default)]
+    pub data: serde_json::Value,
+}
+
+/// Delete finished jobs.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct PurgeJobsRequest {
+    pub project_name: String,
+    /// Only delete jobs that finished at least this long ago.
+    pub older_than_millis: i64,
+    /// Only delete jobs in these states, which must all be terminal.
+    /// If empty, jobs in any terminal state are deleted.
+    #[serde(default)]
+    pub states: Vec<JobState>,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct PurgeJobsResponse {
+    /// Number of jobs deleted.
+    pub deleted: u64,
+}
+
+/// Change how long a project's finished jobs are kept. The response
+/// is `Response::Empty`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SetRetentionRequest {
+    pub project_name: String,
+    /// Finished jobs are removed this long after they finish. If not
+    /// set, jobs are kept until they are purged.
+    pub retention_millis: Option<i64>,
+    /// If true, removed jobs are moved to the project's archive
+    /// instead of being deleted.
+    #[serde(default)]
+    pub archive: bool,
+}
+
+/// List the jobs a project's retention policy has archived, sorted by
+/// job ID.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct GetArchivedJobsRequest {
+    pub project_name: String,
+    #[serde(default)]
+    pub page: Page,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ArchivedJob {
+    pub job_id: JobId,
+    pub finished: Option<DateTime<Utc>>,
+    pub archived: DateTime<Utc>,
+    /// The job's columns in the database when it was archived, other
+    /// than its token.
+    pub job: serde_json::Value,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct GetArchivedJobsResponse {
+    pub jobs: Vec<ArchivedJob>,
+    pub next_cursor: Option<Cursor>,
+}
+
+/// Tell the server that a runner is alive.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct RunnerHeartbeatRequest {
+    pub runner: String,
+    /// Set when the runner is finishing its current jobs and will not
+    /// take new ones.
+    #[serde(default)]
+    pub draining: bool,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ListRunnersRequest {
+    /// Runners that haven't sent a heartbeat for this long are
+    /// considered offline. Defaults to one minute.
+    pub offline_after_millis: Option<i64>,
+    /// Runners are sorted by name.
+    #[serde(default)]
+    pub page: Page,
+}
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Eq,
+    PartialEq,
+    Deserialize,
+    Serialize,
+    AsRefStr,
+    EnumString,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum RunnerState {
+    Online,
+    Offline,
+    Draining,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct Runner {
+    pub name: String,
+    pub state: RunnerState,
+    pub last_seen: DateTime<Utc>,
+    pub capabilities: Vec<String>,
+    /// Jobs the runner is currently running.
+    pub jobs: Vec<JobId>,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ListRunnersResponse {
+    pub runners: Vec<Runner>,
+    pub next_cursor: Option<Cursor>,
+}
+
+/// A running job whose heartbeat deadline has passed.
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct StuckJob {
+    pub project_name: String,
+    pub job_id: JobId,
+    pub runner: String,
+    pub heartbeat_deadline: DateTime<Utc>,
+}
+
+/// Jobs that the next `HandleStuckJobs` request would reclaim.
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct GetStuckJobsResponse {
+    pub jobs: Vec<StuckJob>,
+}
+
+/// An available job that expired before a runner took it.
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ExpiredJob {
+    pub project_name: String,
+    pub job_id: JobId,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Stuck jobs that were reclaimed from their runners, and jobs that
+/// expired.
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct HandleStuckJobsResponse {
+    /// Jobs moved back to the available state.
+    pub requeued: Vec<StuckJob>,
+    /// Jobs marked as timed out, either because they ran past their
+    /// maximum runtime, or because their heartbeat expired and the
+    /// project's retry policy doesn't allow another attempt.
+    pub timed_out: Vec<StuckJob>,
+    /// Jobs that were being canceled, which are marked as canceled.
+    #[serde(default)]
+    pub canceled: Vec<StuckJob>,
+    /// Available jobs marked as expired.
+    #[serde(default)]
+    pub expired: Vec<ExpiredJob>,
+}
+
+/// Get recorded API mutations, newest first.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct GetAuditLogRequest {
+    /// Only get entries for this project.
+    pub project_name: Option<String>,
+    /// Only get entries for this job.
+    pub job_id: Option<JobId>,
+    /// Only get entries older than this entry, for paging through the
+    /// log.
+    pub before_id: Option<i64>,
+    /// Maximum number of entries to return. Defaults to 100.
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct AuditLogEntry {
+    pub id: i64,
+    pub time: DateTime<Utc>,
+    /// Name of the request type, e.g. "AddJob".
+    pub request: String,
+    /// Runner that sent the request, if known.
+    pub actor: Option<String>,
+    pub remote_addr: Option<String>,
+    pub project_name: Option<String>,
+    pub job_id: Option<JobId>,
+    /// The request as JSON, with job tokens removed.
+    pub summary: serde_json::Value,
+}
+
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct GetAuditLogResponse {
+    pub entries: Vec<AuditLogEntry>,
+}
+
+/// Start pushing jobs to a runner connected over a WebSocket. Jobs are
+/// taken for the runner from the projects whenever it holds fewer than
+/// `max_jobs` running jobs.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SubscribeRunnerRequest {
+    pub runner: String,
+    pub project_names: Vec<String>,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    #[serde(default)]
+    pub fairness: ProjectFairness,
+    pub max_jobs: i32,
+    /// Overrides the project's `heartbeat_expiration_millis` for the
+    /// taken jobs.
+    pub lease_millis: Option<i32>,
+    /// How long to wait for the first heartbeat of the taken jobs,
+    /// for runners that are slow to start (e.g. pulling a container
+    /// image). Once the first heartbeat arrives, `lease_millis` or the
+    /// project's expiration applies again.
+    pub first_heartbeat_millis: Option<i32>,
+    /// Only take jobs with at least this priority.
+    pub min_priority: Option<i32>,
+    /// Only take jobs with at most this priority. Since lower
+    /// priorities are more urgent, this lets a runner only take urgent
+    /// jobs.
+    pub max_priority: Option<i32>,
+}
+
+/// Something that happened to a subscribed runner.
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub enum RunnerEvent {
+    /// A job was taken for the runner.
+    JobAssigned(TakeJobResponseJob),
+    /// One of the runner's jobs has been asked to cancel.
+    CancelRequested { project_name: String, job_id: JobId },
+}
+
+/// Message sent by a runner over its WebSocket connection.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum RunnerMessage {
+    /// Start receiving jobs. Can only be sent once per connection.
+    Subscribe(SubscribeRunnerRequest),
+    /// Any API request, such as a job update with a heartbeat. The
+    /// response is sent back with the same ID.
+    Request { id: u64, request: Request },
+}
+
+/// Message sent by the server over a runner's WebSocket connection.
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub enum ServerMessage {
+    Event(RunnerEvent),
+    Response {
+        id: u64,
+        response: Response,
+    },
+    /// The runner sent something invalid. The connection stays open
+    /// unless the message couldn't be parsed.
+    Error(String),
+}
+
+/// What an API key can be used for.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Eq,
+    PartialEq,
+    Deserialize,
+    Serialize,
+    AsRefStr,
+    EnumString,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ApiKeyRole {
+    /// Any request except managing projects, which needs the server's
+    /// admin key.
+    Admin,
+    /// Add jobs and change their state, as well as viewing.
+    Submitter,
+    /// Take and update jobs, as well as viewing.
+    Runner,
+    /// Only requests that don't change anything.
+    Viewer,
 }